@@ -0,0 +1,13 @@
+use std::{env, process::Command};
+
+/// Records the version of rustc that the gateway is built with, for `gravel_build_info`
+fn main() {
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_owned());
+    let version = Command::new(rustc).arg("--version").output().ok()
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .and_then(|version| version.split_whitespace().nth(1).map(str::to_owned))
+        .unwrap_or_else(|| "unknown".to_owned());
+
+    println!("cargo:rustc-env=GRAVEL_RUSTC_VERSION={}", version);
+    println!("cargo:rerun-if-changed=build.rs");
+}
@@ -1,16 +1,47 @@
-use std::{collections::HashMap, str::FromStr, sync::Arc, fmt, time::Duration};
+use std::{borrow::Cow, collections::{BTreeMap, HashMap, HashSet, hash_map::DefaultHasher}, hash::{Hash, Hasher}, io::BufRead, path::Path, str::FromStr, sync::{Arc, atomic::{AtomicU64, AtomicUsize, Ordering}}, fmt, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
 
-use openmetrics_parser::{RenderableMetricValue, HistogramBucket, MetricsExposition, ParseError, PrometheusMetricFamily, PrometheusType, PrometheusValue, Sample, prometheus, MetricFamily, Timestamp, MetricNumber};
-use tokio::sync::RwLock;
+use openmetrics_parser::{Exemplar, RenderableMetricValue, HistogramBucket, HistogramValue, Quantile, SummaryValue, PrometheusCounterValue, ParseError, PrometheusMetricFamily, PrometheusType, PrometheusValue, Sample, openmetrics, prometheus, MetricFamily, Timestamp, MetricNumber};
+use futures::Stream;
+use serde::Serialize;
+use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use tracing::{debug, warn};
 
-use crate::pebble::{TimePebble, parse_duration, sum_merge_strategy, mean_merge_strategy};
+use crate::exposition::{ExemplarValue, OpenMetricsFamily, attach_counter_exemplars, attach_units, escape_label_value, extract_counter_created, extract_counter_exemplars, extract_units, find_negative_counter, fold_duplicate_samples, rewrite_info_and_statesets, to_graphite_lines};
+use crate::gateway_metrics::GatewayMetrics;
+use crate::idempotency::{DEFAULT_IDEMPOTENCY_KEY_TTL, IdempotencyKeys};
+use crate::relabel::RelabelRule;
+use crate::selector::Selector;
+use crate::snapshot::{BucketSnapshot, ExemplarSnapshot, FamilySnapshot, Float, Number, QuantileSnapshot, SNAPSHOT_VERSION, SeriesSnapshot, StoreSnapshot, ValueSnapshot, check_version};
+use crate::pebble::{PebbleMerge, TimePebble, parse_duration};
 
-const CLEARMODE_LABEL_NAME: &str = "clearmode";
+pub const CLEARMODE_LABEL_NAME: &str = "clearmode";
+
+/// The synthetic gauge of when each job was last pushed to, like the push gateway's `push_time_seconds`
+const LAST_PUSH_METRIC_NAME: &str = "gravel_last_push_timestamp_seconds";
+
+/// How many shards the families are split across, unless configured otherwise
+const DEFAULT_SHARDS: usize = 16;
+
+/// Bounds on how often the TTL reaper sweeps for expired series
+const MIN_REAP_INTERVAL: Duration = Duration::from_millis(10);
+const MAX_REAP_INTERVAL: Duration = Duration::from_secs(30);
 
 #[derive(Debug)]
 pub enum AggregationError {
     ParseError(ParseError),
     Error(String),
+    /// A push would have taken a family past the configured maximum number of series
+    CardinalityExceeded { family: String, limit: usize },
+    /// A metric or label name that doesn't match the Prometheus naming rules
+    InvalidName(String),
+    /// A push declared a family with a different type to the one already held
+    TypeConflict { family: String, existing: PrometheusType, pushed: PrometheusType },
+    /// A push declared a family with a different unit to the one already held
+    UnitConflict { family: String, existing: String, pushed: String },
+    /// A push had a NaN or infinite value, while those were being rejected
+    NonFiniteValue { series: String },
+    /// A push had a negative value for a counter, or something counted like one (e.g. a histogram bucket)
+    NegativeCounter { series: String },
 }
 
 impl From<ParseError> for AggregationError {
@@ -19,11 +50,17 @@ impl From<ParseError> for AggregationError {
     }
 }
 
-impl AggregationError {
-    pub fn to_string(&self) -> String{
+impl fmt::Display for AggregationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            AggregationError::ParseError(err) => err.to_string(),
-            AggregationError::Error(err) => err.to_owned(),
+            AggregationError::ParseError(err) => err.fmt(f),
+            AggregationError::Error(err) => f.write_str(err),
+            AggregationError::CardinalityExceeded { family, limit } => write!(f, "family {} would have more than {} series", family, limit),
+            AggregationError::InvalidName(name) => write!(f, "invalid metric or label name: {:?}", name),
+            AggregationError::TypeConflict { family, existing, pushed } => write!(f, "family {} is a {}, but was pushed as a {}", family, existing, pushed),
+            AggregationError::UnitConflict { family, existing, pushed } => write!(f, "family {} is in {}, but was pushed in {}", family, existing, pushed),
+            AggregationError::NonFiniteValue { series } => write!(f, "series {} has a non-finite value", series),
+            AggregationError::NegativeCounter { series } => write!(f, "series {} is a counter, but has a negative value", series),
         }
     }
 }
@@ -33,16 +70,357 @@ pub enum ClearMode {
     Aggregate,
     Replace,
     Family,
+    Min,
+    Max,
+    RunningMean,
     Mean(Duration),
     Sum(Duration)
 }
 
+/// The text formats that pushes can be in, as given by their Content-Type
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum TextFormat {
+    /// The Prometheus text format, which pushes without a Content-Type are taken to be in too. It's parsed leniently, so
+    /// it can have the OpenMetrics features that the gateway keeps (e.g. exemplars, units, and info families) as well
+    #[default]
+    Prometheus,
+    /// The OpenMetrics text format, which is parsed to the letter of the spec, so it has to end with a `# EOF`
+    OpenMetrics,
+}
+
+/// A push's families, once it's been parsed
+#[derive(Debug, Default)]
+pub struct ParsedPush {
+    pub families: Vec<PrometheusMetricFamily>,
+    /// How many malformed lines were skipped to parse it, which is only ever more than none with `skip_malformed_lines`
+    pub skipped_lines: usize,
+}
+
+/// Series that `Aggregator::take_series_by` took out together: rendered the way snapshots are, to be pushed somewhere
+/// else, and as they were held, for `Aggregator::put_back` if they can't be
+#[cfg(feature="clustering")]
+#[derive(Debug)]
+pub struct TakenSeries {
+    pub key: String,
+    pub body: String,
+    families: Vec<AggregationFamily>,
+}
+
+impl TextFormat {
+    pub fn from_content_type(content_type: Option<&str>) -> TextFormat {
+        let media_type = content_type.and_then(|content_type| content_type.split(';').next()).map(str::trim);
+        match media_type {
+            Some(media_type) if media_type.eq_ignore_ascii_case("application/openmetrics-text") => TextFormat::OpenMetrics,
+            _ => TextFormat::Prometheus,
+        }
+    }
+}
+
+/// How the quantiles of two summaries get merged. Quantiles aren't additive, so there's no
+/// correct answer here, only different approximations
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum QuantileMergePolicy {
+    /// Keep the most recently pushed value for each quantile
+    #[default]
+    Latest,
+    /// Keep the smallest value seen for each quantile
+    Min,
+    /// Keep the largest value seen for each quantile
+    Max,
+}
+
+impl FromStr for QuantileMergePolicy {
+    type Err = AggregationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "latest" => Ok(QuantileMergePolicy::Latest),
+            "min" => Ok(QuantileMergePolicy::Min),
+            "max" => Ok(QuantileMergePolicy::Max),
+            _ => Err(AggregationError::Error(format!("Invalid quantile merge policy: {}", s)))
+        }
+    }
+}
+
+/// How gauges without an explicit clearmode get merged
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum GaugeAggregation {
+    /// Add the new value to the old one
+    Sum,
+    /// Keep the smallest value seen
+    Min,
+    /// Keep the largest value seen
+    Max,
+    /// Overwrite the old value with the new one
+    #[default]
+    Last,
+    /// Keep a running mean of every value seen
+    Mean,
+}
+
+impl FromStr for GaugeAggregation {
+    type Err = AggregationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sum" => Ok(GaugeAggregation::Sum),
+            "min" => Ok(GaugeAggregation::Min),
+            "max" => Ok(GaugeAggregation::Max),
+            "last" => Ok(GaugeAggregation::Last),
+            "mean" => Ok(GaugeAggregation::Mean),
+            _ => Err(AggregationError::Error(format!("Invalid gauge aggregation: {}", s)))
+        }
+    }
+}
+
+/// How counters without an explicit clearmode get merged
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum CounterAggregation {
+    /// Add the new value to the old one
+    #[default]
+    Sum,
+    /// Keep the largest value seen, for counters that are already totals (e.g. pushed redundantly by replicas)
+    Max,
+}
+
+impl FromStr for CounterAggregation {
+    type Err = AggregationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sum" => Ok(CounterAggregation::Sum),
+            "max" => Ok(CounterAggregation::Max),
+            _ => Err(AggregationError::Error(format!("Invalid counter aggregation: {}", s)))
+        }
+    }
+}
+
+/// What to do with a pushed counter that's lower than the last value pushed to the same series,
+/// which usually means the client restarted
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum CounterResetPolicy {
+    /// Merge the new value as normal
+    #[default]
+    Accept,
+    /// Leave the aggregated value alone, as if the push never happened
+    Ignore,
+}
+
+impl FromStr for CounterResetPolicy {
+    type Err = AggregationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "accept" => Ok(CounterResetPolicy::Accept),
+            "ignore" => Ok(CounterResetPolicy::Ignore),
+            _ => Err(AggregationError::Error(format!("Invalid counter reset policy: {}", s)))
+        }
+    }
+}
+
+/// What scrapes do with histograms whose buckets don't add up: buckets that go down as their bounds go up, or a count that
+/// isn't the same as the `+Inf` bucket. Merging can't make one of those out of valid pushes, so they're only checked for
+/// on request
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum HistogramValidation {
+    /// Serve histograms without checking them
+    #[default]
+    Off,
+    /// Log the malformed ones, but serve them as they are
+    Log,
+    /// Log the malformed ones, and leave their families out of scrapes
+    Drop,
+}
+
+impl FromStr for HistogramValidation {
+    type Err = AggregationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(HistogramValidation::Off),
+            "log" => Ok(HistogramValidation::Log),
+            "drop" => Ok(HistogramValidation::Drop),
+            _ => Err(AggregationError::Error(format!("Invalid histogram validation: {}", s)))
+        }
+    }
+}
+
+/// Which wins when a push's path and one of the series in its body both give the same label
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum LabelPrecedence {
+    /// The path's label replaces the body's, like the push gateway's grouping labels do
+    #[default]
+    Path,
+    /// The body's label is kept, and the path's is only used for series that don't have it (or have it empty)
+    Body,
+}
+
+impl FromStr for LabelPrecedence {
+    type Err = AggregationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "path" => Ok(LabelPrecedence::Path),
+            "body" => Ok(LabelPrecedence::Body),
+            _ => Err(AggregationError::Error(format!("Invalid label precedence: {}", s)))
+        }
+    }
+}
+
+/// Tunables that control how an Aggregator merges pushes
+#[derive(Debug, Clone)]
+pub struct AggregatorConfig {
+    /// How to merge the quantiles of summaries
+    pub quantile_merge_policy: QuantileMergePolicy,
+
+    /// How to merge gauges that don't have a clearmode label
+    pub gauge_aggregation: GaugeAggregation,
+
+    /// How to merge counters that don't have a clearmode label
+    pub counter_aggregation: CounterAggregation,
+
+    /// How to handle counters that went backwards since their last push
+    pub counter_reset_policy: CounterResetPolicy,
+
+    /// Whether scrapes check histograms for buckets that don't add up, and what they do with the ones that don't
+    pub histogram_validation: HistogramValidation,
+
+    /// How many independently locked shards to split the families across
+    pub shards: usize,
+
+    /// The most distinct label sets a family can have, if it's limited at all. Existing series can
+    /// still be updated once a family hits this, but new ones are rejected
+    pub max_series_per_family: Option<usize>,
+
+    /// The most series that can be held across every family, if it's limited at all. Once a push takes the gateway
+    /// over this, the series that were pushed to longest ago are evicted to make room
+    pub max_total_series: Option<usize>,
+
+    /// Whether to reject pushes with NaN or infinite values, rather than storing them
+    pub reject_non_finite: bool,
+
+    /// Labels added to every pushed series that doesn't already have them. Like Prometheus' external labels, labels
+    /// from the push itself (whether in the body or the path) always win
+    pub external_labels: HashMap<String, String>,
+
+    /// Labels removed from every pushed series before it's merged, so that series which only differed by them get
+    /// merged together
+    pub drop_labels: Vec<String>,
+
+    /// Rules that rewrite the labels of every pushed series before it's merged, in order
+    pub relabel_rules: Vec<RelabelRule>,
+
+    /// How long the idempotency key of a push is remembered, so that retries of it within that time aren't merged again
+    pub idempotency_key_ttl: Duration,
+
+    /// Whether to add a `_total` suffix to the names of pushed counters that don't have one, so that a counter pushed
+    /// with and without it is merged into the one family
+    pub normalize_counter_names: bool,
+
+    /// Whether to replace invalid UTF-8 in pushed bodies with U+FFFD, rather than rejecting the push
+    pub utf8_lossy: bool,
+
+    /// Whether to skip the malformed lines of a Prometheus text push and merge the rest of it, rather than rejecting the
+    /// whole push
+    pub skip_malformed_lines: bool,
+
+    /// Whether a push's path labels or its series' own labels win when they both have the same one. This decides which
+    /// peer a series belongs to when clustering as well
+    pub label_precedence: LabelPrecedence,
+
+    /// How long the series picked out by each selector live without being pushed to, overriding the aggregator's ttl.
+    /// The first selector that matches a series decides its ttl
+    pub series_ttls: Vec<(Selector, Duration)>,
+
+    /// How often to clear the whole store, if it's cleared at all, so that each window's pushes aggregate from nothing.
+    /// Resets happen on multiples of the interval since the Unix epoch, whatever the ttls are
+    pub reset_interval: Option<Duration>,
+}
+
+impl Default for AggregatorConfig {
+    fn default() -> Self {
+        AggregatorConfig {
+            quantile_merge_policy: QuantileMergePolicy::default(),
+            gauge_aggregation: GaugeAggregation::default(),
+            counter_aggregation: CounterAggregation::default(),
+            counter_reset_policy: CounterResetPolicy::default(),
+            histogram_validation: HistogramValidation::default(),
+            shards: DEFAULT_SHARDS,
+            max_series_per_family: None,
+            max_total_series: None,
+            reject_non_finite: false,
+            external_labels: HashMap::new(),
+            drop_labels: Vec::new(),
+            relabel_rules: Vec::new(),
+            idempotency_key_ttl: DEFAULT_IDEMPOTENCY_KEY_TTL,
+            normalize_counter_names: false,
+            utf8_lossy: false,
+            skip_malformed_lines: false,
+            label_precedence: LabelPrecedence::default(),
+            series_ttls: Vec::new(),
+            reset_interval: None,
+        }
+    }
+}
+
+impl AggregatorConfig {
+    /// The ttl of the first selector that picks out the given series, if any do
+    fn series_ttl(&self, family_name: &str, sample: &Sample<GravelValue>) -> Option<Duration> {
+        if self.series_ttls.is_empty() {
+            return None;
+        }
+
+        let labelset = sample.get_labelset().ok()?;
+        self.series_ttl_with(family_name, |name| labelset.get_label_value(name))
+    }
+
+    /// Like `series_ttl`, for a series whose labels are looked up with the given function
+    fn series_ttl_with<'a, F>(&self, family_name: &str, label: F) -> Option<Duration> where F: Fn(&str) -> Option<&'a str> {
+        self.series_ttls.iter().find(|(selector, _)| selector.matches_with(family_name, &label)).map(|(_, ttl)| *ttl)
+    }
+
+    /// The ttl that the gauge of when the given job was last pushed to expires by, if a selector gives it its own
+    fn last_push_ttl(&self, job: &str) -> Option<Duration> {
+        self.series_ttl_with(LAST_PUSH_METRIC_NAME, |name| match name {
+            "job" => Some(job),
+            _ => None,
+        })
+    }
+}
+
 type GravelMetricFamily = MetricFamily<PrometheusType, GravelValue>;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum GravelValue {
     Prometheus(PrometheusValue),
-    Pebble(TimePebble)
+    Pebble(TimePebble),
+    Mean(RunningMean),
+}
+
+/// A mean of every value pushed to a series, which gets calculated at render time
+#[derive(Debug, Clone, PartialEq)]
+pub struct RunningMean {
+    sum: f64,
+    count: u64,
+}
+
+impl RunningMean {
+    fn new(value: f64) -> RunningMean {
+        RunningMean { sum: value, count: 1 }
+    }
+
+    fn append(&mut self, value: f64) {
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn mean(&self) -> f64 {
+        if self.count == 0 {
+            return 0.;
+        }
+
+        self.sum / self.count as f64
+    }
 }
 
 impl RenderableMetricValue for GravelValue {
@@ -55,16 +433,48 @@ impl RenderableMetricValue for GravelValue {
         label_values: &[&str],
     ) -> fmt::Result {
         match self {
-            GravelValue::Prometheus(v) => v.render(f, metric_name, timestamp, label_names, label_values),
+            // The Prometheus text format has no room for exemplars, so they only get added back in OpenMetrics output
+            GravelValue::Prometheus(v) => match without_exemplars(v) {
+                Some(v) => v.render(f, metric_name, timestamp, label_names, label_values),
+                None => v.render(f, metric_name, timestamp, label_names, label_values),
+            },
             GravelValue::Pebble(pebble) => {
                 let value = pebble.aggregate();
 
                 return PrometheusValue::Gauge(MetricNumber::Float(value)).render(f, metric_name, timestamp, label_names, label_values);
             }
+            GravelValue::Mean(mean) => PrometheusValue::Gauge(MetricNumber::Float(mean.mean())).render(f, metric_name, timestamp, label_names, label_values),
+        }
+    }
+}
+
+impl ExemplarValue for GravelValue {
+    fn exemplars(&self) -> Vec<Option<&Exemplar>> {
+        match self {
+            GravelValue::Prometheus(PrometheusValue::Counter(counter)) => vec![counter.exemplar.as_ref()],
+            GravelValue::Prometheus(PrometheusValue::Histogram(histogram)) => histogram.buckets.iter().map(|bucket| bucket.exemplar.as_ref()).collect(),
+            _ => Vec::new(),
         }
     }
 }
 
+/// A copy of the given value with its exemplars stripped off, or None if it didn't have any to begin with
+fn without_exemplars(value: &PrometheusValue) -> Option<PrometheusValue> {
+    match value {
+        PrometheusValue::Counter(counter) if counter.exemplar.is_some() => {
+            let mut counter = counter.clone();
+            counter.exemplar = None;
+            Some(PrometheusValue::Counter(counter))
+        },
+        PrometheusValue::Histogram(histogram) if histogram.buckets.iter().any(|bucket| bucket.exemplar.is_some()) => {
+            let mut histogram = histogram.clone();
+            histogram.buckets.iter_mut().for_each(|bucket| bucket.exemplar = None);
+            Some(PrometheusValue::Histogram(histogram))
+        },
+        _ => None,
+    }
+}
+
 impl From<PrometheusValue> for GravelValue {
     fn from(prom: PrometheusValue) -> Self {
         return GravelValue::Prometheus(prom.clone());
@@ -76,7 +486,7 @@ impl GravelValue {
         const DEFAULT_PEBBLE_GRANULARITY: usize = 100;
         match clearmode {
             ClearMode::Sum(duration) => {
-                let mut pebble = TimePebble::new(duration, DEFAULT_PEBBLE_GRANULARITY, sum_merge_strategy);
+                let mut pebble = TimePebble::new(duration, DEFAULT_PEBBLE_GRANULARITY, PebbleMerge::Sum);
                 if let GravelValue::Prometheus(prom) = self {
                     match prom {
                         PrometheusValue::Counter(counter) => pebble.append(counter.value.as_f64()),
@@ -88,7 +498,7 @@ impl GravelValue {
                 return GravelValue::Pebble(pebble);
             },
             ClearMode::Mean(duration) => {
-                let mut pebble = TimePebble::new(duration, DEFAULT_PEBBLE_GRANULARITY, mean_merge_strategy);
+                let mut pebble = TimePebble::new(duration, DEFAULT_PEBBLE_GRANULARITY, PebbleMerge::Mean);
                 if let GravelValue::Prometheus(prom) = self {
                     match prom {
                         PrometheusValue::Counter(counter) => pebble.append(counter.value.as_f64()),
@@ -99,23 +509,110 @@ impl GravelValue {
 
                 return GravelValue::Pebble(pebble);
             }
+            ClearMode::RunningMean => {
+                match self {
+                    GravelValue::Prometheus(PrometheusValue::Gauge(gauge)) => GravelValue::Mean(RunningMean::new(gauge.as_f64())),
+                    _ => self
+                }
+            }
             _ => return self
         }
     }
 }
 
+impl GravelValue {
+    fn to_snapshot(&self) -> ValueSnapshot {
+        match self {
+            GravelValue::Prometheus(PrometheusValue::Unknown(value)) => ValueSnapshot::Unknown { value: (*value).into() },
+            GravelValue::Prometheus(PrometheusValue::Gauge(value)) => ValueSnapshot::Gauge { value: (*value).into() },
+            GravelValue::Prometheus(PrometheusValue::Counter(counter)) => ValueSnapshot::Counter {
+                value: counter.value.into(),
+                exemplar: counter.exemplar.as_ref().map(ExemplarSnapshot::from),
+            },
+            GravelValue::Prometheus(PrometheusValue::Histogram(histogram)) => ValueSnapshot::Histogram {
+                sum: histogram.sum.map(Number::from),
+                count: histogram.count,
+                created: histogram.created,
+                buckets: histogram.buckets.iter().map(|bucket| BucketSnapshot {
+                    upper_bound: Float(bucket.upper_bound),
+                    count: bucket.count.into(),
+                    exemplar: bucket.exemplar.as_ref().map(ExemplarSnapshot::from),
+                }).collect(),
+            },
+            GravelValue::Prometheus(PrometheusValue::Summary(summary)) => ValueSnapshot::Summary {
+                sum: summary.sum.map(Number::from),
+                count: summary.count,
+                created: summary.created,
+                quantiles: summary.quantiles.iter().map(|quantile| QuantileSnapshot { quantile: Float(quantile.quantile), value: quantile.value.into() }).collect(),
+            },
+            GravelValue::Pebble(pebble) => ValueSnapshot::Window { window: pebble.to_snapshot() },
+            GravelValue::Mean(mean) => ValueSnapshot::Mean { sum: Float(mean.sum), count: mean.count },
+        }
+    }
+
+    /// Rebuilds a value from a snapshot, checking that it can be in a family of the given type. Windows and means render
+    /// as gauges whatever the family's type, so they can be in any of them
+    fn from_snapshot(snapshot: ValueSnapshot, family_type: &PrometheusType) -> Result<GravelValue, String> {
+        let value = match (snapshot, family_type) {
+            (ValueSnapshot::Unknown { value }, PrometheusType::Unknown) => PrometheusValue::Unknown(value.into()),
+            (ValueSnapshot::Gauge { value }, PrometheusType::Gauge) => PrometheusValue::Gauge(value.into()),
+            (ValueSnapshot::Counter { value, exemplar }, PrometheusType::Counter) => PrometheusValue::Counter(PrometheusCounterValue {
+                value: value.into(),
+                exemplar: exemplar.map(Exemplar::from),
+            }),
+            (ValueSnapshot::Histogram { sum, count, created, buckets }, PrometheusType::Histogram) => PrometheusValue::Histogram(HistogramValue {
+                sum: sum.map(MetricNumber::from),
+                count,
+                created,
+                buckets: buckets.into_iter().map(|bucket| HistogramBucket {
+                    count: bucket.count.into(),
+                    upper_bound: bucket.upper_bound.0,
+                    exemplar: bucket.exemplar.map(Exemplar::from),
+                }).collect(),
+            }),
+            (ValueSnapshot::Summary { sum, count, created, quantiles }, PrometheusType::Summary) => PrometheusValue::Summary(SummaryValue {
+                sum: sum.map(MetricNumber::from),
+                count,
+                created,
+                quantiles: quantiles.into_iter().map(|quantile| Quantile { quantile: quantile.quantile.0, value: quantile.value.into() }).collect(),
+            }),
+            (ValueSnapshot::Window { window }, _) => return TimePebble::from_snapshot(window).map(GravelValue::Pebble),
+            (ValueSnapshot::Mean { sum, count }, _) => return Ok(GravelValue::Mean(RunningMean { sum: sum.0, count })),
+            (snapshot, family_type) => return Err(format!("a {} value can't be in a {} family", snapshot.kind(), family_type)),
+        };
+
+        Ok(GravelValue::Prometheus(value))
+    }
+}
+
 impl ClearMode {
-    fn default_for_type(t: PrometheusType) -> ClearMode {
+    fn default_for_type(t: PrometheusType, config: &AggregatorConfig) -> ClearMode {
         match t {
-            PrometheusType::Counter | PrometheusType::Unknown | PrometheusType::Histogram | PrometheusType::Summary => ClearMode::Aggregate,
-            PrometheusType::Gauge => ClearMode::Replace,
+            PrometheusType::Counter => match config.counter_aggregation {
+                CounterAggregation::Sum => ClearMode::Aggregate,
+                CounterAggregation::Max => ClearMode::Max,
+            },
+            PrometheusType::Unknown | PrometheusType::Histogram | PrometheusType::Summary => ClearMode::Aggregate,
+            PrometheusType::Gauge => match config.gauge_aggregation {
+                GaugeAggregation::Sum => ClearMode::Aggregate,
+                GaugeAggregation::Min => ClearMode::Min,
+                GaugeAggregation::Max => ClearMode::Max,
+                GaugeAggregation::Last => ClearMode::Replace,
+                GaugeAggregation::Mean => ClearMode::RunningMean,
+            },
         }
     }
 
-    fn from_family<T>(family_type: PrometheusType, metric: &Sample<T>) -> ClearMode where T: RenderableMetricValue + Clone {
+    fn from_family<T>(family_type: PrometheusType, metric: &Sample<T>, config: &AggregatorConfig) -> ClearMode where T: RenderableMetricValue + Clone {
         match metric.get_labelset().unwrap().get_label_value(CLEARMODE_LABEL_NAME) {
-            Some(c) => ClearMode::from_str(c).unwrap_or(ClearMode::default_for_type(family_type)),
-            None => ClearMode::default_for_type(family_type)
+            Some(c) => match ClearMode::from_str(c) {
+                // Only plain values have a smallest or largest one, and a counter only has a largest one that's meaningful
+                Ok(ClearMode::Min) if !matches!(family_type, PrometheusType::Gauge | PrometheusType::Unknown) => ClearMode::default_for_type(family_type, config),
+                Ok(ClearMode::Max) if !matches!(family_type, PrometheusType::Gauge | PrometheusType::Unknown | PrometheusType::Counter) => ClearMode::default_for_type(family_type, config),
+                Ok(clear_mode) => clear_mode,
+                Err(_) => ClearMode::default_for_type(family_type, config),
+            },
+            None => ClearMode::default_for_type(family_type, config)
         }
     }
 }
@@ -128,9 +625,11 @@ impl FromStr for ClearMode {
             "aggregate" | "sum" => Ok(ClearMode::Aggregate),
             "replace" => Ok(ClearMode::Replace),
             "family" | "info" => Ok(ClearMode::Family),
+            "min" => Ok(ClearMode::Min),
+            "max" => Ok(ClearMode::Max),
             _ => {
                 if s.starts_with("mean") || s.starts_with("sum") {
-                    let num_preceeding = s.chars().take_while(|c| c.is_digit(10)).count();
+                    let num_preceeding = s.chars().take_while(|c| c.is_ascii_digit()).count();
                     match parse_duration(&s[num_preceeding..]) {
                         Some(duration) => {
                             if s.starts_with("mean") {
@@ -156,65 +655,163 @@ impl FromStr for ClearMode {
 #[derive(Debug)]
 struct AggregationFamily {
     base_family: GravelMetricFamily,
+
+    /// What we know about each series, keyed by its label values
+    series: HashMap<Vec<String>, SeriesState>,
 }
 
-/// Takes two sets of Histogram buckets and merges them. Assumes that they are in ascending order of upperbound
-/// (TODO: We should probably sanity check this / sort) and performs essentially a merge sort merge, summing the counts
-/// if two buckets have the same bound
-fn merge_buckets(val1: &Vec<HistogramBucket>, val2: &Vec<HistogramBucket>) -> Vec<HistogramBucket> {
-    let mut i = 0;
-    let mut j = 0;
-    let mut output = Vec::new();
-
-    // Basically merge sort on the buckets with a bit of extra logic for buckets that have the same upperbound
-    while i < val1.len() && j < val2.len() {
-        let bucket1 = &val1[i];
-        let bucket2 = &val2[j];
-        if bucket1.upper_bound < bucket2.upper_bound {
-            output.push(bucket1.clone());
-            i += 1;
-        } else if bucket1.upper_bound > bucket2.upper_bound {
-            output.push(bucket2.clone());
-            j += 1;
-        } else {
-            output.push(HistogramBucket {
-                count: bucket1.count + bucket2.count,
-                upper_bound: bucket1.upper_bound,
-                exemplar: bucket2.exemplar.clone(),
-            });
-            i += 1;
-            j += 1;
+/// A family of a push, on its way to being merged into the store
+enum PendingMerge {
+    /// Checked against the family of the same name, as the pushed family and the same from `AggregationFamily::prepare`
+    Existing(PrometheusMetricFamily, GravelMetricFamily),
+    New(AggregationFamily),
+    /// Another part of a family that's already in the push, which can only be checked once the parts before it are merged
+    Again(PrometheusMetricFamily),
+}
+
+/// A rough guess at the memory each series takes beyond its label names and values: its aggregated value, the state
+/// below, and its entries in the family's maps
+const SERIES_OVERHEAD_BYTES: usize = 256;
+
+/// A rough guess at the memory each family takes beyond its name, help, and unit
+const FAMILY_OVERHEAD_BYTES: usize = 128;
+
+/// How big a store is, as exposed on /-/metrics
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct StoreStats {
+    pub series: usize,
+    pub families: usize,
+    /// An estimate of the memory the store takes, from the lengths of its names and label values plus a fixed overhead
+    /// for each family and series
+    pub estimated_bytes: usize,
+}
+
+/// A job in a store, as served by /-/jobs
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct JobSummary {
+    pub job: String,
+    pub series: usize,
+    /// When the job was last pushed to, in seconds since the Unix epoch
+    pub last_push: Option<f64>,
+}
+
+/// The state we track for each series, on top of its aggregated value
+#[derive(Debug, Clone)]
+struct SeriesState {
+    /// When the series was last pushed to
+    last_pushed: Instant,
+
+    /// The raw value of the last counter pushed to the series, used to spot counter resets
+    last_counter_value: Option<f64>,
+
+    /// The timestamp the last push to the series carried, if it had one, used to spot replacements arriving out of order
+    timestamp: Option<Timestamp>,
+
+    /// How long the series lives without being pushed to, if a selector gave it its own ttl. This is worked out when the
+    /// series is first stored, so sweeps don't have to match every series against every selector
+    ttl: Option<Duration>,
+}
+
+impl SeriesState {
+    fn new(sample: &Sample<GravelValue>, now: Instant, ttl: Option<Duration>) -> SeriesState {
+        let last_counter_value = match &sample.value {
+            GravelValue::Prometheus(PrometheusValue::Counter(counter)) => Some(counter.value.as_f64()),
+            _ => None,
+        };
+
+        SeriesState { last_pushed: now, last_counter_value, timestamp: sample.timestamp, ttl }
+    }
+
+    /// Whether the series hasn't been pushed to within its own ttl, or the given one if it doesn't have its own
+    fn is_expired(&self, now: Instant, default_ttl: Option<Duration>) -> bool {
+        self.ttl.or(default_ttl).is_some_and(|ttl| now.saturating_duration_since(self.last_pushed) > ttl)
+    }
+
+    /// Whether a replacing push with the given timestamp is older than the last value, and so shouldn't replace it.
+    /// Without timestamps on both of them, pushes win in the order they arrive
+    fn is_newer_than(&self, timestamp: Option<Timestamp>) -> bool {
+        match (self.timestamp, timestamp) {
+            (Some(last), Some(new)) => new < last,
+            _ => false,
         }
     }
+}
 
-    for i in i..val1.len() {
-        output.push(val1[i].clone());
+/// Takes two sets of Histogram buckets and merges them, summing the counts of buckets with the same bound.
+/// Both sets must have exactly the same bounds - merging histograms with different buckets can't produce
+/// a valid cumulative histogram, so that's an error
+fn merge_buckets(val1: &[HistogramBucket], val2: &[HistogramBucket]) -> Result<Vec<HistogramBucket>, AggregationError> {
+    let sorted = |buckets: &[HistogramBucket]| {
+        let mut buckets = buckets.to_vec();
+        buckets.sort_by(|a, b| a.upper_bound.partial_cmp(&b.upper_bound).unwrap_or(std::cmp::Ordering::Equal));
+        buckets
+    };
+
+    let (val1, val2) = (sorted(val1), sorted(val2));
+    let bounds = |buckets: &[HistogramBucket]| buckets.iter().map(|b| b.upper_bound).collect::<Vec<f64>>();
+    if bounds(&val1) != bounds(&val2) {
+        return Err(AggregationError::Error(format!(
+            "Invalid histogram buckets - tried to merge buckets with bounds {:?} into {:?}",
+            bounds(&val2), bounds(&val1)
+        )));
     }
 
-    for j in j..val2.len() {
-        output.push(val1[j].clone());
+    return Ok(val1.iter().zip(val2.iter()).map(|(bucket1, bucket2)| HistogramBucket {
+        count: bucket1.count + bucket2.count,
+        upper_bound: bucket1.upper_bound,
+        exemplar: bucket2.exemplar.clone().or_else(|| bucket1.exemplar.clone()),
+    }).collect());
+}
+
+/// Merges the quantiles of two summaries, according to the given policy. Quantiles that only exist
+/// in one of the summaries are kept as is
+fn merge_quantiles(val1: &[Quantile], val2: &[Quantile], policy: QuantileMergePolicy) -> Vec<Quantile> {
+    let mut output = val1.to_vec();
+    for quantile in val2.iter() {
+        match output.iter_mut().find(|q| q.quantile == quantile.quantile) {
+            Some(existing) => {
+                existing.value = match policy {
+                    QuantileMergePolicy::Latest => quantile.value,
+                    QuantileMergePolicy::Min if quantile.value.as_f64() < existing.value.as_f64() => quantile.value,
+                    QuantileMergePolicy::Max if quantile.value.as_f64() > existing.value.as_f64() => quantile.value,
+                    _ => existing.value,
+                };
+            },
+            None => output.push(quantile.clone())
+        }
     }
 
+    output.sort_by(|a, b| a.quantile.partial_cmp(&b.quantile).unwrap_or(std::cmp::Ordering::Equal));
     return output;
 }
 
-/// Merges two metrics into one another (using the given clearmode), storing the result in the first one.
-pub fn merge_metric(into: &mut Sample<GravelValue>, merge: Sample<GravelValue>, clear_mode: ClearMode) -> Result<(), AggregationError> {
+/// Merges two metrics into one another (using the given clearmode, and config for the things clearmodes don't cover),
+/// storing the result in the first one.
+pub fn merge_metric(into: &mut Sample<GravelValue>, merge: Sample<GravelValue>, clear_mode: ClearMode, config: &AggregatorConfig) -> Result<(), AggregationError> {
     match (&mut into.value, &merge.value) {
-        (GravelValue::Prometheus(PrometheusValue::Unknown(val1)), GravelValue::Prometheus(PrometheusValue::Unknown(val2))) => {
-            match clear_mode {
-                ClearMode::Aggregate => *val1 += val2,
-                ClearMode::Replace => *val1 = *val2,
-                _ => unreachable!()
-            }
-        }
+        (GravelValue::Prometheus(PrometheusValue::Unknown(val1)), GravelValue::Prometheus(PrometheusValue::Unknown(val2))) |
         (GravelValue::Prometheus(PrometheusValue::Gauge(val1)), GravelValue::Prometheus(PrometheusValue::Gauge(val2))) => {
             match clear_mode {
                 ClearMode::Aggregate => *val1 += val2,
                 ClearMode::Replace => *val1 = *val2,
+                ClearMode::Min if val2.as_f64() < val1.as_f64() => *val1 = *val2,
+                ClearMode::Max if val2.as_f64() > val1.as_f64() => *val1 = *val2,
+                ClearMode::Min | ClearMode::Max => {},
+                // A series that's held as a plain value (e.g. since a push replaced it, or it was restored from a
+                // snapshot) starts its mean from that value
+                ClearMode::RunningMean => {
+                    let mut mean = RunningMean::new(val1.as_f64());
+                    mean.append(val2.as_f64());
+                    into.value = GravelValue::Mean(mean);
+                },
                 _ => unreachable!()
             }
         }
+        (GravelValue::Pebble(_), GravelValue::Prometheus(_)) | (GravelValue::Mean(_), GravelValue::Prometheus(_)) if clear_mode == ClearMode::Replace => {
+            // Replacing a windowed value throws away the whole window
+            into.value = merge.value.clone();
+        },
+        (GravelValue::Mean(mean), GravelValue::Prometheus(PrometheusValue::Gauge(gauge))) => mean.append(gauge.as_f64()),
         (GravelValue::Prometheus(PrometheusValue::Counter(val1)), GravelValue::Prometheus(PrometheusValue::Counter(val2))) => {
             // Counters get a bit more complicated - we take the second exemplar no matter what
             match clear_mode {
@@ -226,6 +823,11 @@ pub fn merge_metric(into: &mut Sample<GravelValue>, merge: Sample<GravelValue>,
                     val1.value = val2.value;
                     val1.exemplar = val2.exemplar.clone();
                 },
+                ClearMode::Max if val2.value.as_f64() > val1.value.as_f64() => {
+                    val1.value = val2.value;
+                    val1.exemplar = val2.exemplar.clone();
+                },
+                ClearMode::Max => {},
                 _ => unreachable!()
             }
         }
@@ -243,7 +845,7 @@ pub fn merge_metric(into: &mut Sample<GravelValue>, merge: Sample<GravelValue>,
             };
 
             let buckets = match clear_mode {
-                ClearMode::Aggregate => merge_buckets(&val1.buckets, &val2.buckets),
+                ClearMode::Aggregate => merge_buckets(&val1.buckets, &val2.buckets)?,
                 ClearMode::Replace => val2.buckets.clone(),
                 _ => unreachable!()
             };
@@ -260,7 +862,24 @@ pub fn merge_metric(into: &mut Sample<GravelValue>, merge: Sample<GravelValue>,
                 _ => {}
             }
         },
-        (GravelValue::Prometheus(PrometheusValue::Summary(_)), GravelValue::Prometheus(PrometheusValue::Summary(_))) => return Err(AggregationError::Error("cannot merge summaries".to_string())),
+        (GravelValue::Prometheus(PrometheusValue::Summary(val1)), GravelValue::Prometheus(PrometheusValue::Summary(val2))) => {
+            // Sums and counts are additive, like a histogram. Quantiles aren't, so they follow the configured policy
+            match clear_mode {
+                ClearMode::Aggregate => {
+                    val1.sum = match (val1.sum, val2.sum) {
+                        (Some(a), Some(b)) => Some(a + b),
+                        (a, b) => b.or(a),
+                    };
+                    val1.count = match (val1.count, val2.count) {
+                        (Some(a), Some(b)) => Some(a + b),
+                        (a, b) => b.or(a),
+                    };
+                    val1.quantiles = merge_quantiles(&val1.quantiles, &val2.quantiles, config.quantile_merge_policy);
+                },
+                ClearMode::Replace => *val1 = val2.clone(),
+                _ => unreachable!()
+            }
+        },
         _ => unreachable!(),
     };
 
@@ -269,22 +888,36 @@ pub fn merge_metric(into: &mut Sample<GravelValue>, merge: Sample<GravelValue>,
 
 impl AggregationFamily {
     // Constructs a new AggregationFamily, over the given MetricFamily
-    fn new(base_family: PrometheusMetricFamily) -> Self {
+    fn new(base_family: PrometheusMetricFamily, config: &AggregatorConfig) -> Result<Self, AggregationError> {
         let mut base_family: GravelMetricFamily = base_family.clone_and_convert_type();
         let family_type = base_family.family_type.clone();
+        let family_name = base_family.family_name.clone();
+        let now = Instant::now();
+        let mut series = HashMap::new();
         for metric in base_family.iter_samples_mut() {
-            let clear_mode = ClearMode::from_family(family_type.clone(), &metric);
+            let ttl = config.series_ttl(&family_name, metric);
+            series.insert(series_key(metric), SeriesState::new(metric, now, ttl));
+            let clear_mode = ClearMode::from_family(family_type.clone(), metric, config);
             metric.value = metric.value.clone().convert_with_clearmode(clear_mode);
         }
 
         let base_family = base_family.without_label(CLEARMODE_LABEL_NAME).unwrap_or(base_family);
-        Self { base_family }
+        check_cardinality(&base_family.family_name, series.len(), config)?;
+        let mut family = Self { base_family, series };
+        family.sort_samples();
+        Ok(family)
+    }
+
+    /// Converts a pushed family to be merged into this one, with its labels in the same order as ours
+    fn prepare(&self, prom_family: &PrometheusMetricFamily) -> Result<GravelMetricFamily, AggregationError> {
+        let new_family: GravelMetricFamily = prom_family.clone_and_convert_type();
+        // Samples are matched on their label values, so the new family needs its labels in the same order as ours
+        with_label_order(new_family, self.base_family.get_label_names())
     }
 
-    /// Merges the given metrics family into this one, respecting (and then removing) the clear mode 
-    /// label from each sample
-    fn merge(&mut self, prom_family: PrometheusMetricFamily) -> Result<(), AggregationError> {
-        let new_family = prom_family.clone_and_convert_type();
+    /// Checks that a family from `prepare` can be merged into this one, without changing anything, so that a push
+    /// whose families can't all be merged doesn't get any of them merged
+    fn check_merge(&self, new_family: &GravelMetricFamily, config: &AggregatorConfig) -> Result<(), AggregationError> {
         // Sanity checks to make sure that it makes sense to merge these families
         if new_family.family_name != self.base_family.family_name {
             return Err(AggregationError::Error(format!(
@@ -294,104 +927,1542 @@ impl AggregationFamily {
         }
 
         if new_family.family_type != self.base_family.family_type {
-            return Err(AggregationError::Error(format!(
-                "Invalid metric types - tried to merge {:?} with {:?}",
-                new_family.family_type, self.base_family.family_type
-            )));
+            return Err(AggregationError::TypeConflict {
+                family: self.base_family.family_name.clone(),
+                existing: self.base_family.family_type.clone(),
+                pushed: new_family.family_type.clone(),
+            });
+        }
+
+        // Pushes without a unit (which is all of them, from older clients) are fine, but they can't disagree on it
+        if !new_family.unit.is_empty() && !self.base_family.unit.is_empty() && new_family.unit != self.base_family.unit {
+            return Err(AggregationError::UnitConflict {
+                family: self.base_family.family_name.clone(),
+                existing: self.base_family.unit.clone(),
+                pushed: new_family.unit.clone(),
+            });
+        }
+
+        let clear_modes: Vec<ClearMode> = new_family.iter_samples().map(|metric| ClearMode::from_family(new_family.family_type.clone(), metric, config)).collect();
+        if clear_modes.contains(&ClearMode::Family) {
+            let series: HashSet<Vec<String>> = new_family.iter_samples().map(series_key).collect();
+            return check_cardinality(&self.base_family.family_name, series.len(), config);
+        }
+
+        let mut added: HashSet<Vec<String>> = HashSet::new();
+        for (metric, clear_mode) in new_family.iter_samples().zip(clear_modes) {
+            let key = series_key(metric);
+            if !self.series.contains_key(&key) && added.insert(key) {
+                check_cardinality(&self.base_family.family_name, self.series.len() + added.len(), config)?;
+            }
+
+            // Summed histograms are the only values that can fail to merge, if their buckets differ
+            if let (GravelValue::Prometheus(PrometheusValue::Histogram(new)), ClearMode::Aggregate) = (&metric.value, clear_mode) {
+                let cmp_metric = metric.without_label(CLEARMODE_LABEL_NAME).unwrap_or(metric.clone());
+                if let Some(GravelValue::Prometheus(PrometheusValue::Histogram(existing))) = self.base_family.get_sample_matches(&cmp_metric).map(|s| &s.value) {
+                    merge_buckets(&existing.buckets, &new.buckets)?;
+                }
+            }
         }
 
+        Ok(())
+    }
+
+    /// Merges the given metrics family into this one, respecting (and then removing) the clear mode label from each
+    /// sample. `new_family` is the same family from `prepare`, which should have passed `check_merge` already. Returns the
+    /// number of counter resets seen in the push
+    fn merge(&mut self, prom_family: PrometheusMetricFamily, new_family: GravelMetricFamily, config: &AggregatorConfig) -> Result<u64, AggregationError> {
+        let unit = if self.base_family.unit.is_empty() { new_family.unit.clone() } else { self.base_family.unit.clone() };
+
+        // The first push to give the family some help text wins, so that pushes without it (or with different versions of it)
+        // don't make the help text come and go
+        let help = if self.base_family.help.is_empty() { new_family.help.clone() } else { self.base_family.help.clone() };
+
         // We should clear the whole family if any of the samples has a clearmode="family" label
         let should_clear_family = new_family.iter_samples().any(|metric| {
-            ClearMode::from_family(new_family.family_type.clone(), metric) == ClearMode::Family
+            ClearMode::from_family(new_family.family_type.clone(), metric, config) == ClearMode::Family
         });
 
+        let mut resets = 0;
+        let mut added_series = false;
         if should_clear_family {
-            self.base_family = new_family.without_label(CLEARMODE_LABEL_NAME).unwrap_or(new_family);
+            // The push becomes the whole family, so build it up exactly like a brand new one
+            *self = AggregationFamily::new(prom_family, config)?;
         }
         else {
+            let now = Instant::now();
             for metric in new_family.into_iter_samples() {
                 // TODO: This is really inefficient for large families. Should probably optimise it
                 // Go uses "label fingerprinting" to generate hashes of labelsets.
 
                 // We want to compare without the clearmode label - it's not stored, so doesn't exist in our internal representation
-                let cmp_metric = metric.without_label(CLEARMODE_LABEL_NAME).unwrap_or(metric.clone());
-                let clear_mode = ClearMode::from_family(self.base_family.family_type.clone(), &metric);
+                let mut cmp_metric = metric.without_label(CLEARMODE_LABEL_NAME).unwrap_or(metric.clone());
+                let clear_mode = ClearMode::from_family(self.base_family.family_type.clone(), &metric, config);
+                let key = series_key(&metric);
+                if !self.series.contains_key(&key) {
+                    check_cardinality(&self.base_family.family_name, self.series.len() + 1, config)?;
+                }
+
+                // A replacement that was overtaken by a newer one on the way here is dropped, although it still counts as a push
+                if clear_mode == ClearMode::Replace && self.series.get(&key).is_some_and(|s| s.is_newer_than(metric.timestamp)) {
+                    if let Some(state) = self.series.get_mut(&key) {
+                        state.last_pushed = now;
+                    }
+                    continue;
+                }
+
+                let ttl = match self.series.get(&key) {
+                    Some(state) => state.ttl,
+                    None => config.series_ttl(&self.base_family.family_name, &metric),
+                };
+                let state = SeriesState::new(&metric, now, ttl);
+                // Only summed counters get thrown off by a reset - replacing one that went down is expected
+                let is_reset = match (self.series.get(&key).and_then(|s| s.last_counter_value), state.last_counter_value) {
+                    (Some(last), Some(new)) => clear_mode == ClearMode::Aggregate && new < last,
+                    _ => false,
+                };
+                self.series.insert(key, state);
+
+                if is_reset {
+                    resets += 1;
+                }
+
                 match self.base_family.get_sample_matches_mut(&cmp_metric)
                 {
                     None => {
-                        // Just add the metric if its a new labelset
-                        self.base_family.add_sample(cmp_metric)?
+                        // Just add the metric if its a new labelset, converting it the same way new families are
+                        cmp_metric.value = cmp_metric.value.convert_with_clearmode(clear_mode);
+                        self.base_family.add_sample(cmp_metric)?;
+                        added_series = true;
                     },
+                    Some(_) if is_reset && config.counter_reset_policy == CounterResetPolicy::Ignore => {},
                     Some(s) => {
-                        // Otherwise we have to merge
-                        merge_metric(s, metric, clear_mode)?;
+                        // Otherwise we have to merge. A replaced value is only as recent as the push it came from
+                        if clear_mode == ClearMode::Replace {
+                            s.timestamp = metric.timestamp;
+                        }
+                        merge_metric(s, metric, clear_mode, config)?;
                     }
                 }
             }
         }
+
+        self.base_family.help = help;
+        self.base_family.unit = unit;
+
+        // New series get added at the end, so they need moving into place
+        if added_series {
+            self.sort_samples();
+        }
         
-        return Ok(());
+        return Ok(resets);
     }
-}
 
-/// Aggregator is an struct that stores a number of metric families, and has the ability to merge
-/// new metric families into itself
-#[derive(Debug, Clone)]
-pub struct Aggregator {
-    /// The families in this Aggregator
-    families: Arc<RwLock<HashMap<String, AggregationFamily>>>,
-}
+    /// An empty family with the same name, labels, and metadata as the base family
+    fn empty_base_family(&self) -> GravelMetricFamily {
+        GravelMetricFamily::new(
+            self.base_family.family_name.clone(),
+            self.base_family.get_label_names().to_vec(),
+            self.base_family.family_type.clone(),
+            self.base_family.help.clone(),
+            self.base_family.unit.clone(),
+        )
+    }
 
-/// A utility function that adds a set of labels to all the metrics in an exposition
-/// This is used to handle the push gateway /metrics/job/foo URL syntax to add a job=foo label
-fn add_extra_labels(mut exposition: MetricsExposition<PrometheusType, PrometheusValue>, extra_labels: &HashMap<&str, &str>) -> Result<MetricsExposition<PrometheusType, PrometheusValue>, ParseError> {
-    exposition.families = exposition.families.into_iter().map(|(name, family)| (name, family.with_labels(extra_labels.iter().map(|(&k, &v)| (k, v))))).collect();
+    /// Orders the samples by their label values, so that scrapes always list series in the same order
+    fn sort_samples(&mut self) {
+        let mut samples: Vec<Sample<GravelValue>> = self.base_family.iter_samples().cloned().collect();
+        samples.sort_by_cached_key(series_key);
 
-    return Ok(exposition);
-}
+        // Samples were already unique in the old family, so this can't fail
+        self.base_family = self.empty_base_family().with_samples(samples).unwrap();
+    }
 
-// are_label_names_equivalent checks wether two sets of label names are equivalent,
-// minus label names that are irrelevant to the final push (basically just the clearmode)
-fn are_label_names_equivalent(existing: &[String], new: &[String]) -> bool {
-    for new_label in new.iter() {
-        if new_label == CLEARMODE_LABEL_NAME {
-            continue
-        }
+    /// A copy of the family, to put back if a push that changes it fails part way through
+    fn duplicate(&self) -> AggregationFamily {
+        let samples: Vec<Sample<GravelValue>> = self.base_family.iter_samples().cloned().collect();
+        // Samples were already unique in the family, so this can't fail
+        AggregationFamily { base_family: self.empty_base_family().with_samples(samples).unwrap(), series: self.series.clone() }
+    }
 
-        if !existing.contains(new_label) {
-            return false;
-        }
+    /// Rebuilds the base family, keeping only the samples for which `keep` returns true
+    fn retain_samples<F>(&mut self, keep: F) where F: Fn(&Sample<GravelValue>) -> bool {
+        // Samples were already unique in the old family, so this can't fail
+        self.base_family = self.empty_base_family().with_samples(self.base_family.iter_samples().filter(|s| keep(s)).cloned()).unwrap();
+
+        let remaining: HashSet<Vec<String>> = self.base_family.iter_samples().map(series_key).collect();
+        self.series.retain(|key, _| remaining.contains(key));
     }
 
-    return true;
-}
+    /// Removes every series that hasn't been pushed to within its own ttl, or the given one if it doesn't have its own
+    fn expire(&mut self, now: Instant, default_ttl: Option<Duration>) {
+        let stale: HashSet<Vec<String>> = self.series.iter()
+            .filter(|(_, state)| state.is_expired(now, default_ttl))
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        if stale.is_empty() {
+            return;
+        }
+
+        self.retain_samples(|sample| !stale.contains(&series_key(sample)));
+    }
+
+    fn is_empty(&self) -> bool {
+        self.base_family.iter_samples().next().is_none()
+    }
+
+    /// Splits the family up by the key that `key_for` gives each series' labels, along with what's tracked about them
+    #[cfg(feature="clustering")]
+    fn split_by<F>(mut self, key_for: F) -> HashMap<String, AggregationFamily> where F: Fn(&HashMap<&str, &str>) -> String {
+        let mut samples: HashMap<String, Vec<Sample<GravelValue>>> = HashMap::new();
+        for sample in self.base_family.iter_samples() {
+            let key = match sample.get_labelset() {
+                Ok(labelset) => key_for(&labelset.iter().map(|(name, value)| (name.as_str(), value.as_str())).collect()),
+                Err(_) => key_for(&HashMap::new()),
+            };
+            samples.entry(key).or_default().push(sample.clone());
+        }
+
+        let mut series = std::mem::take(&mut self.series);
+        samples.into_iter().map(|(key, samples)| {
+            let states = samples.iter().map(series_key).filter_map(|key| series.remove(&key).map(|state| (key, state))).collect();
+            // The samples were already unique in the family, so this can't fail
+            (key, AggregationFamily { base_family: self.empty_base_family().with_samples(samples).unwrap(), series: states })
+        }).collect()
+    }
+
+    /// Puts series that were taken out of this family back, other than any that have been pushed to again since, which
+    /// are newer than the ones that were taken
+    #[cfg(feature="clustering")]
+    fn put_back(&mut self, taken: AggregationFamily) -> Result<(), AggregationError> {
+        let names = self.base_family.get_label_names().to_vec();
+        let taken_names = taken.base_family.get_label_names();
+        if names.len() != taken_names.len() || !are_label_names_equivalent(&names, taken_names) {
+            return Err(AggregationError::Error(format!("{} has different labels to the series that were taken out of it", self.base_family.family_name)));
+        }
+
+        // Series are keyed by their label values, so the taken ones need their keys in the same order as ours
+        let order: Vec<usize> = names.iter().filter_map(|name| taken_names.iter().position(|taken| taken == name)).collect();
+        let mut states: HashMap<Vec<String>, SeriesState> = taken.series.into_iter()
+            .map(|(key, state)| (order.iter().map(|&idx| key[idx].clone()).collect(), state))
+            .collect();
+
+        let mut added = false;
+        for sample in with_label_order(taken.base_family, &names)?.into_iter_samples() {
+            let key = series_key(&sample);
+            let state = match states.remove(&key) {
+                Some(state) if !self.series.contains_key(&key) => state,
+                _ => continue,
+            };
+
+            self.base_family.add_sample(sample)?;
+            self.series.insert(key, state);
+            added = true;
+        }
+
+        if added {
+            self.sort_samples();
+        }
+
+        Ok(())
+    }
+
+    /// Forgets the last pushed value of every counter, so that the next push to each of them can't be counted as a reset.
+    /// Used when restoring from a snapshot, where the values are aggregates rather than anything a client pushed
+    fn forget_counter_values(&mut self) {
+        for state in self.series.values_mut() {
+            state.last_counter_value = None;
+        }
+    }
+
+    fn to_snapshot(&self, now: (Instant, f64)) -> FamilySnapshot {
+        let family = &self.base_family;
+        let series = family.iter_samples().map(|sample| {
+            let key = series_key(sample);
+            let state = self.series.get(&key);
+            SeriesSnapshot {
+                timestamp: sample.timestamp,
+                value: sample.value.to_snapshot(),
+                last_pushed: instant_to_epoch_secs(state.map_or(now.0, |state| state.last_pushed), now),
+                last_counter_value: state.and_then(|state| state.last_counter_value),
+                label_values: key,
+            }
+        }).collect();
+
+        FamilySnapshot {
+            name: family.family_name.clone(),
+            family_type: family.family_type.to_string(),
+            help: family.help.clone(),
+            unit: family.unit.clone(),
+            label_names: family.get_label_names().to_vec(),
+            series,
+        }
+    }
+
+    /// Rebuilds a family from a snapshot, checking everything that a push would've been checked for on the way in
+    fn from_snapshot(snapshot: FamilySnapshot, now: (Instant, f64), config: &AggregatorConfig) -> Result<AggregationFamily, AggregationError> {
+        let FamilySnapshot { name, family_type, help, unit, label_names, series: snapshot_series } = snapshot;
+        let invalid = |reason: String| AggregationError::Error(format!("invalid snapshot of {}: {}", name, reason));
+        if !is_valid_name(&name, true) {
+            return Err(AggregationError::InvalidName(name));
+        }
+        check_label_names(label_names.iter().map(String::as_str))?;
+        let family_type = parse_family_type(&family_type).ok_or_else(|| invalid(format!("unknown type {}", family_type)))?;
+
+        let mut series = HashMap::new();
+        let mut samples = Vec::with_capacity(snapshot_series.len());
+        for sample in snapshot_series {
+            if sample.label_values.len() != label_names.len() {
+                return Err(invalid(format!("series {:?} doesn't have a value for every label", sample.label_values)));
+            }
+
+            let value = GravelValue::from_snapshot(sample.value, &family_type).map_err(invalid)?;
+            let label_values = &sample.label_values;
+            let label_value = |label: &str| label_names.iter().position(|name| name == label).map(|idx| label_values[idx].as_str());
+            let state = SeriesState {
+                last_pushed: epoch_secs_to_instant(sample.last_pushed, now),
+                last_counter_value: sample.last_counter_value,
+                timestamp: sample.timestamp,
+                ttl: config.series_ttl_with(&name, label_value),
+            };
+            if series.insert(sample.label_values.clone(), state).is_some() {
+                return Err(invalid(format!("series {:?} is in it more than once", sample.label_values)));
+            }
+            samples.push(Sample::new(sample.label_values, sample.timestamp, value));
+        }
+
+        let base_family = GravelMetricFamily::new(name.clone(), label_names, family_type, help, unit).with_samples(samples)?;
+        let mut family = AggregationFamily { base_family, series };
+        family.sort_samples();
+        Ok(family)
+    }
+}
+
+/// Parses a family type as it's written in `# TYPE` lines
+fn parse_family_type(s: &str) -> Option<PrometheusType> {
+    match s {
+        "counter" => Some(PrometheusType::Counter),
+        "gauge" => Some(PrometheusType::Gauge),
+        "histogram" => Some(PrometheusType::Histogram),
+        "summary" => Some(PrometheusType::Summary),
+        "unknown" => Some(PrometheusType::Unknown),
+        _ => None,
+    }
+}
+
+/// The current time, along with the same time in seconds since the Unix epoch, for converting between the two. Instants
+/// don't mean anything outside of the process, so snapshots have the times they hold in seconds since the epoch
+fn now_with_epoch_secs() -> (Instant, f64) {
+    (Instant::now(), SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64())
+}
+
+fn instant_to_epoch_secs(at: Instant, (now, now_secs): (Instant, f64)) -> f64 {
+    now_secs - now.saturating_duration_since(at).as_secs_f64()
+}
+
+/// The inverse of `instant_to_epoch_secs`. Times in the future (e.g. from a gateway whose clock was ahead) become now
+fn epoch_secs_to_instant(secs: f64, (now, now_secs): (Instant, f64)) -> Instant {
+    let ago = Duration::try_from_secs_f64(now_secs - secs).unwrap_or_default();
+    now.checked_sub(ago).unwrap_or(now)
+}
+
+/// Rebuilds the given family so that its labels are ordered the same as `order`. Labels in the family
+/// that aren't in `order` (e.g. the clearmode) are kept, after the ordered ones
+fn with_label_order(family: GravelMetricFamily, order: &[String]) -> Result<GravelMetricFamily, AggregationError> {
+    let current = family.get_label_names();
+    let label_names: Vec<String> = order.iter().filter(|name| current.contains(name))
+        .chain(current.iter().filter(|name| !order.contains(name)))
+        .cloned()
+        .collect();
+
+    if label_names == current {
+        return Ok(family);
+    }
+
+    let mut samples = Vec::new();
+    for sample in family.iter_samples() {
+        let labelset = sample.get_labelset()?;
+        let label_values = label_names.iter().map(|name| labelset.get_label_value(name).unwrap_or_default().to_owned()).collect();
+        samples.push(Sample::new(label_values, sample.timestamp, sample.value.clone()));
+    }
+
+    let reordered = GravelMetricFamily::new(family.family_name.clone(), label_names, family.family_type.clone(), family.help.clone(), family.unit.clone());
+    return Ok(reordered.with_samples(samples)?);
+}
+
+/// Whether the given name matches `[a-zA-Z_][a-zA-Z0-9_]*`, plus colons in metric names
+pub fn is_valid_name(name: &str, allow_colons: bool) -> bool {
+    let valid_char = |c: char| c.is_ascii_alphanumeric() || c == '_' || (allow_colons && c == ':');
+    match name.chars().next() {
+        Some(first) => !first.is_ascii_digit() && name.chars().all(valid_char),
+        None => false,
+    }
+}
+
+/// Errors with the first of the given label names that isn't a valid Prometheus label name
+pub fn check_label_names<'a>(names: impl IntoIterator<Item = &'a str>) -> Result<(), AggregationError> {
+    match names.into_iter().find(|name| !is_valid_name(name, false)) {
+        Some(name) => Err(AggregationError::InvalidName(name.to_owned())),
+        None => Ok(()),
+    }
+}
+
+/// Errors if the family's name, or any of its label names, aren't valid Prometheus names
+fn check_family_names(family: &PrometheusMetricFamily) -> Result<(), AggregationError> {
+    if !is_valid_name(&family.family_name, true) {
+        return Err(AggregationError::InvalidName(family.family_name.clone()));
+    }
+
+    check_label_names(family.get_label_names().iter().map(|name| name.as_str()))
+}
+
+/// Errors with the first series of the given family that has a NaN or infinite value anywhere in it
+fn check_finite(family: &PrometheusMetricFamily) -> Result<(), AggregationError> {
+    let is_finite = |n: &MetricNumber| n.as_f64().is_finite();
+    for sample in family.iter_samples() {
+        let finite = match &sample.value {
+            PrometheusValue::Unknown(n) | PrometheusValue::Gauge(n) => is_finite(n),
+            PrometheusValue::Counter(counter) => is_finite(&counter.value),
+            PrometheusValue::Histogram(histogram) => histogram.sum.iter().chain(histogram.buckets.iter().map(|b| &b.count)).all(is_finite),
+            PrometheusValue::Summary(summary) => summary.sum.iter().chain(summary.quantiles.iter().map(|q| &q.value)).all(is_finite),
+        };
+
+        if !finite {
+            return Err(AggregationError::NonFiniteValue { series: describe_series(family, sample) });
+        }
+    }
+
+    Ok(())
+}
+
+/// Errors with the first series of the given family that has a negative count: a counter's value, a histogram's bucket
+/// counts, or the value of an untyped series named like a counter (with a `_total`, `_bucket`, or `_count` suffix)
+fn check_non_negative_counters(family: &PrometheusMetricFamily) -> Result<(), AggregationError> {
+    let is_negative = |n: &MetricNumber| n.as_f64() < 0.;
+    let counter_like = ["_total", "_bucket", "_count"].iter().any(|suffix| family.family_name.ends_with(suffix));
+    for sample in family.iter_samples() {
+        let negative = match &sample.value {
+            PrometheusValue::Counter(counter) => is_negative(&counter.value),
+            PrometheusValue::Histogram(histogram) => histogram.buckets.iter().any(|bucket| is_negative(&bucket.count)),
+            PrometheusValue::Unknown(n) => counter_like && is_negative(n),
+            PrometheusValue::Gauge(_) | PrometheusValue::Summary(_) => false,
+        };
+
+        if negative {
+            return Err(AggregationError::NegativeCounter { series: describe_series(family, sample) });
+        }
+    }
+
+    Ok(())
+}
+
+/// Errors with what's wrong with the first series of the given family whose histogram has cumulative buckets that go down
+/// as their bounds go up, or a count that isn't the same as its `+Inf` bucket
+fn check_histogram(family: &GravelMetricFamily) -> Result<(), String> {
+    let format_bound = |bound: f64| match bound {
+        f64::INFINITY => "+Inf".to_owned(),
+        bound => bound.to_string(),
+    };
+
+    for sample in family.iter_samples() {
+        let histogram = match &sample.value {
+            GravelValue::Prometheus(PrometheusValue::Histogram(histogram)) => histogram,
+            _ => continue,
+        };
+
+        let mut buckets: Vec<&HistogramBucket> = histogram.buckets.iter().collect();
+        buckets.sort_by(|a, b| a.upper_bound.partial_cmp(&b.upper_bound).unwrap_or(std::cmp::Ordering::Equal));
+        if let Some(pair) = buckets.windows(2).find(|pair| pair[1].count.as_f64() < pair[0].count.as_f64()) {
+            return Err(format!(
+                "series {} has a bucket of {} at le=\"{}\" after one of {} at le=\"{}\"",
+                describe_series(family, sample), pair[1].count.as_f64(), format_bound(pair[1].upper_bound), pair[0].count.as_f64(), format_bound(pair[0].upper_bound),
+            ));
+        }
+
+        let inf_bucket = buckets.last().filter(|bucket| bucket.upper_bound == f64::INFINITY);
+        if let (Some(count), Some(inf_bucket)) = (histogram.count, inf_bucket) {
+            if count as f64 != inf_bucket.count.as_f64() {
+                return Err(format!("series {} has a count of {}, but a +Inf bucket of {}", describe_series(family, sample), count, inf_bucket.count.as_f64()));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether a family can be served under the given histogram validation. Malformed histograms are logged, whether or not
+/// they're left out
+fn passes_validation(family: &GravelMetricFamily, validation: HistogramValidation) -> bool {
+    if validation == HistogramValidation::Off {
+        return true;
+    }
+
+    match check_histogram(family) {
+        Ok(()) => true,
+        Err(problem) => {
+            warn!(family = %family.family_name, %problem, dropped = validation == HistogramValidation::Drop, "histogram is malformed");
+            validation != HistogramValidation::Drop
+        },
+    }
+}
+
+/// A series' name with its labels, as it'd appear in a scrape, for error messages
+fn describe_series<V>(family: &MetricFamily<PrometheusType, V>, sample: &Sample<V>) -> String where V: RenderableMetricValue + Clone {
+    let labels: Vec<String> = match sample.get_labelset() {
+        Ok(labelset) => labelset.iter().map(|(name, value)| format!("{}=\"{}\"", name, value)).collect(),
+        Err(_) => Vec::new(),
+    };
+
+    match labels.is_empty() {
+        true => family.family_name.clone(),
+        false => format!("{}{{{}}}", family.family_name, labels.join(",")),
+    }
+}
+
+/// The names that a family of the given type shows up under in a scrape: its series names, and the name its HELP and TYPE
+/// lines are for (which, in OpenMetrics, is a counter's name without its `_total` suffix)
+fn exposed_names(name: &str, family_type: &PrometheusType) -> Vec<String> {
+    match family_type {
+        PrometheusType::Counter => std::iter::once(name).chain(name.strip_suffix("_total")).map(str::to_owned).collect(),
+        PrometheusType::Histogram => ["", "_bucket", "_sum", "_count"].iter().map(|suffix| format!("{}{}", name, suffix)).collect(),
+        PrometheusType::Summary => ["", "_sum", "_count"].iter().map(|suffix| format!("{}{}", name, suffix)).collect(),
+        PrometheusType::Gauge | PrometheusType::Unknown => vec![name.to_owned()],
+    }
+}
+
+/// The names of the families that could show up under the given name in a scrape, for finding the ones that
+/// `exposed_names` would collide with
+fn possible_owners(exposed_name: &str) -> Vec<String> {
+    let stripped = ["_bucket", "_sum", "_count"].iter().filter_map(|suffix| exposed_name.strip_suffix(suffix));
+    std::iter::once(exposed_name.to_owned()).chain(stripped.map(str::to_owned)).chain(std::iter::once(format!("{}_total", exposed_name))).collect()
+}
+
+/// Errors if a family with the given number of series would be over the configured limit
+fn check_cardinality(family_name: &str, series: usize, config: &AggregatorConfig) -> Result<(), AggregationError> {
+    match config.max_series_per_family {
+        Some(limit) if series > limit => Err(AggregationError::CardinalityExceeded { family: family_name.to_owned(), limit }),
+        _ => Ok(()),
+    }
+}
+
+/// The label values of a sample, minus the clearmode, which identify its series within a family
+fn series_key(sample: &Sample<GravelValue>) -> Vec<String> {
+    match sample.get_labelset() {
+        Ok(labelset) => labelset.iter().filter(|(name, _)| *name != CLEARMODE_LABEL_NAME).map(|(_, value)| value.clone()).collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+/// A copy of the given family with only the samples picked out by at least one of the given selectors, or None if there aren't any
+fn filter_family(family: &GravelMetricFamily, selectors: &[Selector]) -> Option<GravelMetricFamily> {
+    let selectors: Vec<&Selector> = selectors.iter().filter(|selector| selector.could_match_family(&family.family_name)).collect();
+    if selectors.is_empty() {
+        return None;
+    }
+
+    let samples: Vec<Sample<GravelValue>> = family.iter_samples().filter(|sample| match sample.get_labelset() {
+        Ok(labelset) => selectors.iter().any(|selector| selector.matches(&family.family_name, &labelset)),
+        Err(_) => false,
+    }).cloned().collect();
+
+    if samples.is_empty() {
+        return None;
+    }
+
+    let filtered = GravelMetricFamily::new(family.family_name.clone(), family.get_label_names().to_vec(), family.family_type.clone(), family.help.clone(), family.unit.clone());
+    // The samples were already unique in the family, so this can't fail
+    Some(filtered.with_samples(samples).unwrap())
+}
+
+/// Checks whether the given sample has every one of the given labels (i.e. its labels are a superset)
+fn sample_matches_labels(sample: &Sample<GravelValue>, labels: &HashMap<&str, &str>) -> bool {
+    match sample.get_labelset() {
+        Ok(labelset) => labels.iter().all(|(&name, &value)| labelset.get_label_value(name) == Some(escape_label_value(value).as_ref())),
+        Err(_) => false,
+    }
+}
+
+/// Renders a family in the Prometheus text format, which doesn't have units. Snapshots keep them though, so that they
+/// survive a restart
+fn render_prometheus(family: &GravelMetricFamily) -> String {
+    let rendered = family.to_string();
+    if family.unit.is_empty() {
+        return rendered;
+    }
+
+    return rendered.split_inclusive('\n').filter(|line| !line.starts_with("# UNIT ")).collect();
+}
+
+/// Renders a family in the OpenMetrics format. Counters' `_created` series are held in gauge families of their own (see
+/// `extract_counter_created`), which are rendered as part of their counters instead
+fn render_openmetrics(family: &GravelMetricFamily, families: &HashMap<&str, &GravelMetricFamily>) -> String {
+    render_with_created(family, families, false)
+}
+
+/// Renders a family the way `render_openmetrics` does, or as a Prometheus text push with the same features in it
+fn render_with_created(family: &GravelMetricFamily, families: &HashMap<&str, &GravelMetricFamily>, prometheus: bool) -> String {
+    let is_gauge = |family: &&&GravelMetricFamily| family.family_type == PrometheusType::Gauge;
+    let is_counter = |family: &&&GravelMetricFamily| family.family_type == PrometheusType::Counter;
+    let wrap = |family| match prometheus {
+        true => OpenMetricsFamily::new(family).in_prometheus_push_format(),
+        false => OpenMetricsFamily::new(family),
+    };
+    match family.family_type {
+        PrometheusType::Counter => {
+            let created = family.family_name.strip_suffix("_total").and_then(|base| families.get(format!("{}_created", base).as_str())).filter(is_gauge);
+            wrap(family).with_created(created.copied()).to_string()
+        },
+        PrometheusType::Gauge if family.family_name.strip_suffix("_created").and_then(|base| families.get(format!("{}_total", base).as_str())).filter(is_counter).is_some() => String::new(),
+        _ => wrap(family).to_string(),
+    }
+}
+
+/// Renders parsed families back into an exposition in the given format, for a push that's split up between peers. A
+/// Prometheus text push can have exemplars, units, and `_created` series in it as well, so they're kept in both formats
+#[cfg(feature="clustering")]
+pub fn render_families(families: &[PrometheusMetricFamily], format: TextFormat) -> String {
+    let counters: HashSet<&str> = families.iter().filter(|family| family.family_type == PrometheusType::Counter).filter_map(|family| family.family_name.strip_suffix("_total")).collect();
+    let families: Vec<GravelMetricFamily> = families.iter().map(|family| {
+        let family: GravelMetricFamily = family.clone_and_convert_type();
+        // Counters' `_created` series are parsed with a clearmode label that the counter's series don't have, and
+        // they're given it back when they're parsed again
+        match family.family_name.strip_suffix("_created").filter(|base| counters.contains(base)) {
+            Some(_) => family.without_label(CLEARMODE_LABEL_NAME).unwrap_or(family),
+            None => family,
+        }
+    }).collect();
+    let by_name: HashMap<&str, &GravelMetricFamily> = families.iter().map(|family| (family.family_name.as_str(), family)).collect();
+
+    let mut rendered: String = families.iter().map(|family| render_with_created(family, &by_name, format == TextFormat::Prometheus)).collect();
+    if format == TextFormat::OpenMetrics {
+        rendered.push_str("# EOF\n");
+    }
+    rendered
+}
+
+/// A partition of an Aggregator's families, behind its own lock
+type Shard = RwLock<HashMap<String, AggregationFamily>>;
+
+/// The write locks of some of an Aggregator's shards, by their index
+type LockedShards<'a> = HashMap<usize, RwLockWriteGuard<'a, HashMap<String, AggregationFamily>>>;
+
+/// How many series a store holds. Everything that adds or removes series updates it while it still holds their shards'
+/// locks, so that the total can be checked against the budget without locking every shard
+#[derive(Debug, Default)]
+struct SeriesCount(AtomicUsize);
+
+impl SeriesCount {
+    fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Accounts for the series in some families going from `before` to `after`
+    fn update(&self, before: usize, after: usize) {
+        match after >= before {
+            true => self.0.fetch_add(after - before, Ordering::Relaxed),
+            false => self.0.fetch_sub(before - after, Ordering::Relaxed),
+        };
+    }
+}
+
+/// Where every store's generations are drawn from. It's shared, so that no two stores (like two tenants) are ever at the
+/// same generation, and starts from the clock, so that a restarted gateway doesn't go back through the ones it had before
+static NEXT_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+fn next_generation() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64;
+    let _ = NEXT_GENERATION.compare_exchange(0, now, Ordering::Relaxed, Ordering::Relaxed);
+    NEXT_GENERATION.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Which version of its contents a store holds, so that scrapes can be told apart without rendering them. Everything
+/// that changes what a scrape would return moves it on to a new generation, but only once the change has been made, so
+/// a scrape that reads the generation before it renders may have changes that the generation doesn't, but never misses one
+/// that it does
+#[derive(Debug)]
+struct Generation(AtomicU64);
+
+impl Generation {
+    fn new() -> Generation {
+        Generation(AtomicU64::new(next_generation()))
+    }
+
+    fn get(&self) -> u64 {
+        self.0.load(Ordering::Acquire)
+    }
+
+    fn bump(&self) {
+        self.0.store(next_generation(), Ordering::Release);
+    }
+}
+
+/// How many series are in a shard's families
+fn series_in(families: &HashMap<String, AggregationFamily>) -> usize {
+    families.values().map(|family| family.series.len()).sum()
+}
+
+/// When a job was last successfully pushed to
+#[derive(Debug)]
+struct LastPush {
+    /// Seconds since the Unix epoch, as exposed in the gauge
+    timestamp: f64,
+    /// The same time, for comparing against the ttl
+    at: Instant,
+    /// The ttl of the job's series in the gauge, if a selector gave it its own
+    ttl: Option<Duration>,
+}
+
+type LastPushes = RwLock<HashMap<String, LastPush>>;
+
+/// Forgets the jobs that haven't been pushed to within the ttl, so that their timestamps expire along with their series
+async fn expire_last_pushes(last_pushes: &LastPushes, generation: &Generation, default_ttl: Option<Duration>) {
+    let now = Instant::now();
+    let mut last_pushes = last_pushes.write().await;
+    let before = last_pushes.len();
+    last_pushes.retain(|_, push| push.ttl.or(default_ttl).is_none_or(|ttl| now.saturating_duration_since(push.at) <= ttl));
+    if last_pushes.len() != before {
+        generation.bump();
+    }
+}
+
+/// Removes every family and last push from a store
+async fn clear_store(shards: &[Shard], series: &SeriesCount, generation: &Generation, last_pushes: &LastPushes) {
+    let mut guards = Vec::with_capacity(shards.len());
+    for shard in shards {
+        guards.push(shard.write().await);
+    }
+
+    for families in guards.iter_mut() {
+        series.update(series_in(families), 0);
+        families.clear();
+    }
+
+    last_pushes.write().await.clear();
+    generation.bump();
+}
+
+/// Expires stale series from every family. The write lock is only taken for one family at a time,
+/// so pushes and scrapes never wait on a whole sweep
+async fn expire_families(shards: &[Shard], series: &SeriesCount, generation: &Generation, ttl: Option<Duration>) {
+    for shard in shards {
+        let names: Vec<String> = shard.read().await.keys().cloned().collect();
+        for name in names {
+            let mut families = shard.write().await;
+            if let Some(family) = families.get_mut(&name) {
+                let before = family.series.len();
+                family.expire(Instant::now(), ttl);
+                if family.series.len() == before {
+                    continue;
+                }
+
+                series.update(before, family.series.len());
+                generation.bump();
+                if family.is_empty() {
+                    families.remove(&name);
+                }
+            }
+        }
+    }
+}
+
+/// Aggregator is an struct that stores a number of metric families, and has the ability to merge
+/// new metric families into itself
+#[derive(Debug, Clone)]
+pub struct Aggregator {
+    /// The families in this Aggregator, sharded by family name so that pushes to
+    /// different families don't contend on the same lock
+    shards: Arc<Vec<Shard>>,
+
+    /// How pushes get merged into the families
+    config: Arc<AggregatorConfig>,
+
+    /// The gateway's own metrics, which pushes get counted in
+    metrics: Arc<GatewayMetrics>,
+
+    /// When each job was last pushed to, keyed by the job label of the push
+    last_pushes: Arc<LastPushes>,
+
+    /// The idempotency keys of recent pushes, by job
+    idempotency_keys: Arc<IdempotencyKeys>,
+
+    /// How many series are held across every family
+    series: Arc<SeriesCount>,
+
+    /// Which version of its contents the store holds, for scrapes' ETags
+    generation: Arc<Generation>,
+
+    /// How long series live without being pushed to, if they expire at all
+    ttl: Option<Duration>,
+}
+
+/// Every family across the given shards, ordered by name so that output doesn't depend on how they were sharded
+fn sorted_families<'a>(shards: &'a [RwLockReadGuard<'_, HashMap<String, AggregationFamily>>]) -> Vec<&'a AggregationFamily> {
+    let mut families: Vec<(&String, &AggregationFamily)> = shards.iter().flat_map(|families| families.iter()).collect();
+    families.sort_by_key(|(name, _)| *name);
+    return families.into_iter().map(|(_, family)| family).collect();
+}
+
+/// A utility function that adds a set of labels to all the metrics in an exposition
+/// This is used to handle the push gateway /metrics/job/foo URL syntax to add a job=foo label
+/// The labels are added in order of name, so that families get the same label order whatever order the labels came in.
+/// Series that already have one of them keep their own value if the body's labels take precedence
+fn add_extra_labels(families: Vec<PrometheusMetricFamily>, extra_labels: &HashMap<&str, &str>, precedence: LabelPrecedence) -> Result<Vec<PrometheusMetricFamily>, AggregationError> {
+    let mut extra_labels: Vec<(&str, Cow<str>)> = extra_labels.iter().map(|(&k, &v)| (k, escape_label_value(v))).collect();
+    extra_labels.sort_unstable();
+    if precedence == LabelPrecedence::Path || extra_labels.is_empty() {
+        return Ok(families.into_iter().map(|family| family.with_labels(extra_labels.iter().map(|(k, v)| (*k, v.as_ref())))).collect());
+    }
+
+    // An empty label is the same as no label at all, so it doesn't stop the path's one being added
+    return rewrite_labels(families, |labels| {
+        for (name, value) in extra_labels.iter() {
+            match labels.iter_mut().find(|(label, _)| label == name) {
+                Some((_, existing)) if existing.is_empty() => *existing = value.to_string(),
+                Some(_) => {},
+                None => labels.push((name.to_string(), value.to_string())),
+            }
+        }
+    });
+}
+
+/// Adds the given labels to every family that doesn't already have them, in order of name
+fn add_external_labels(families: Vec<PrometheusMetricFamily>, external_labels: &HashMap<String, String>) -> Vec<PrometheusMetricFamily> {
+    if external_labels.is_empty() {
+        return families;
+    }
+
+    let mut external_labels: Vec<(&str, Cow<str>)> = external_labels.iter().map(|(k, v)| (k.as_str(), escape_label_value(v))).collect();
+    external_labels.sort_unstable();
+    return families.into_iter().map(|family| {
+        let missing: Vec<(&str, &str)> = external_labels.iter().map(|(k, v)| (*k, v.as_ref())).filter(|(name, _)| !family.get_label_names().iter().any(|n| n == name)).collect();
+        family.with_labels(missing)
+    }).collect();
+}
+
+/// Adds a `_total` suffix to the name of every counter family that doesn't have one, like OpenMetrics requires
+fn add_total_suffixes(mut families: Vec<PrometheusMetricFamily>) -> Vec<PrometheusMetricFamily> {
+    for family in families.iter_mut() {
+        if family.family_type == PrometheusType::Counter && !family.family_name.ends_with("_total") {
+            family.family_name.push_str("_total");
+        }
+    }
+
+    return families;
+}
+
+/// Removes the given labels from every family, so that series which only differed by them are merged together
+fn drop_labels(families: Vec<PrometheusMetricFamily>, drop_labels: &[String]) -> Result<Vec<PrometheusMetricFamily>, AggregationError> {
+    if drop_labels.is_empty() {
+        return Ok(families);
+    }
+
+    return rewrite_labels(families, |labels| labels.retain(|(name, _)| !drop_labels.contains(name)));
+}
+
+/// Applies the relabel rules to every series, in order
+fn relabel(families: Vec<PrometheusMetricFamily>, rules: &[RelabelRule]) -> Result<Vec<PrometheusMetricFamily>, AggregationError> {
+    if rules.is_empty() {
+        return Ok(families);
+    }
+
+    return rewrite_labels(families, |labels| rules.iter().for_each(|rule| rule.apply(labels)));
+}
+
+/// Rewrites the labels of every series in the given families. Series that end up with the same labels are split out into
+/// extra families of the same name, to be merged in one after the other as if they'd been pushed separately
+fn rewrite_labels<F>(families: Vec<PrometheusMetricFamily>, rewrite: F) -> Result<Vec<PrometheusMetricFamily>, AggregationError> where F: Fn(&mut Vec<(String, String)>) {
+    let mut rewritten = Vec::with_capacity(families.len());
+    for family in families {
+        let mut series = Vec::new();
+        let mut label_names: Vec<String> = Vec::new();
+        for sample in family.iter_samples() {
+            let mut labels: Vec<(String, String)> = sample.get_labelset()?.iter().map(|(name, value)| (name.clone(), value.clone())).collect();
+            rewrite(&mut labels);
+            for (name, _) in labels.iter() {
+                if !label_names.contains(name) {
+                    label_names.push(name.clone());
+                }
+            }
+            series.push((labels, sample));
+        }
+
+        // Every sample in a family has the same label names, so series without one of them get it empty
+        let samples = series.into_iter().map(|(labels, sample)| {
+            let label_values = label_names.iter()
+                .map(|name| labels.iter().find(|(label, _)| label == name).map(|(_, value)| value.clone()).unwrap_or_default())
+                .collect();
+            (label_values, sample)
+        }).collect();
+
+        rewritten.extend(split_duplicate_samples(&family, label_names, samples)?);
+    }
+
+    return Ok(rewritten);
+}
+
+/// Builds families like the given one, but with the given labels, and samples with the given label values. Samples with the
+/// same label values as an earlier one go into the next family along, so that every family only has one sample per series
+fn split_duplicate_samples(family: &PrometheusMetricFamily, label_names: Vec<String>, samples: Vec<(Vec<String>, &Sample<PrometheusValue>)>) -> Result<Vec<PrometheusMetricFamily>, AggregationError> {
+    let mut layers: Vec<Vec<Sample<PrometheusValue>>> = Vec::new();
+    let mut seen: HashMap<Vec<String>, usize> = HashMap::new();
+    for (label_values, sample) in samples {
+        let layer = seen.entry(label_values.clone()).or_insert(0);
+        if *layer == layers.len() {
+            layers.push(Vec::new());
+        }
+
+        layers[*layer].push(Sample::new(label_values, sample.timestamp, sample.value.clone()));
+        *layer += 1;
+    }
+
+    let mut families = Vec::with_capacity(layers.len());
+    for samples in layers {
+        let layer = PrometheusMetricFamily::new(family.family_name.clone(), label_names.clone(), family.family_type.clone(), family.help.clone(), family.unit.clone());
+        families.push(layer.with_samples(samples)?);
+    }
+
+    return Ok(families);
+}
+
+// are_label_names_equivalent checks wether two sets of label names are equivalent,
+// minus label names that are irrelevant to the final push (basically just the clearmode)
+fn are_label_names_equivalent(existing: &[String], new: &[String]) -> bool {
+    for new_label in new.iter() {
+        if new_label == CLEARMODE_LABEL_NAME {
+            continue
+        }
+
+        if !existing.contains(new_label) {
+            return false;
+        }
+    }
+
+    return true;
+}
+
+/// Turns `\r\n` line endings into `\n`, and makes sure a non-empty exposition ends with one, because the parser
+/// needs its last line to be terminated. Some clients leave that off, or are on Windows
+fn normalize_line_endings(s: &str) -> Cow<'_, str> {
+    if !s.contains('\r') && (s.is_empty() || s.ends_with('\n')) {
+        return Cow::Borrowed(s);
+    }
+
+    let mut normalized = s.replace("\r\n", "\n");
+    if !normalized.ends_with('\n') {
+        normalized.push('\n');
+    }
+    Cow::Owned(normalized)
+}
+
+/// Turns an OpenMetrics exposition into what the Prometheus parser expects (along with the OpenMetrics features that
+/// are picked out of it beforehand). That means dropping the `# EOF`, and adding the `_total` suffix that OpenMetrics
+/// leaves off counters' HELP, TYPE, and UNIT lines, since their samples already have it
+fn openmetrics_to_text(s: &str) -> Cow<'_, str> {
+    let counters: HashSet<&str> = s.lines()
+        .filter_map(|line| {
+            let mut parts = line.strip_prefix("# TYPE ")?.split_whitespace();
+            match (parts.next(), parts.next()) {
+                (Some(name), Some("counter")) => Some(name),
+                _ => None,
+            }
+        })
+        .collect();
+
+    let mut text = String::with_capacity(s.len() + counters.len() * 16);
+    for line in s.lines() {
+        if line == "# EOF" {
+            continue;
+        }
+
+        let metadata = ["# HELP ", "# TYPE ", "# UNIT "].iter().find_map(|prefix| line.strip_prefix(prefix).map(|rest| (*prefix, rest)));
+        match metadata {
+            Some((prefix, rest)) => {
+                let name_end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+                text.push_str(prefix);
+                text.push_str(&rest[..name_end]);
+                if counters.contains(&rest[..name_end]) {
+                    text.push_str("_total");
+                }
+                text.push_str(&rest[name_end..]);
+            },
+            None => text.push_str(line),
+        }
+        text.push('\n');
+    }
+    Cow::Owned(text)
+}
+
+/// Adds a `_total` suffix to the name of every family declared as a counter without one, in its HELP and TYPE lines
+/// and its samples, since the parser rejects counters that don't have it
+fn add_total_suffixes_to_text(s: &str) -> Cow<'_, str> {
+    let counters: HashSet<&str> = s.lines()
+        .filter_map(|line| {
+            let mut parts = line.strip_prefix("# TYPE ")?.split_whitespace();
+            match (parts.next(), parts.next()) {
+                (Some(name), Some("counter")) if !name.ends_with("_total") => Some(name),
+                _ => None,
+            }
+        })
+        .collect();
+    if counters.is_empty() {
+        return Cow::Borrowed(s);
+    }
+
+    let mut renamed = String::with_capacity(s.len() + counters.len() * 16);
+    for line in s.lines() {
+        let (prefix, rest) = match line.strip_prefix("# HELP ").map(|rest| ("# HELP ", rest)).or_else(|| line.strip_prefix("# TYPE ").map(|rest| ("# TYPE ", rest))) {
+            Some((prefix, rest)) => (prefix, rest),
+            None => ("", line),
+        };
+
+        let name_end = rest.find(|c: char| c == '{' || c.is_whitespace()).unwrap_or(rest.len());
+        renamed.push_str(prefix);
+        renamed.push_str(&rest[..name_end]);
+        if counters.contains(&rest[..name_end]) {
+            renamed.push_str("_total");
+        }
+        renamed.push_str(&rest[name_end..]);
+        renamed.push('\n');
+    }
+    Cow::Owned(renamed)
+}
+
+/// Parses a Prometheus text exposition into its families
+fn parse_exposition(s: &str, config: &AggregatorConfig) -> Result<Vec<PrometheusMetricFamily>, AggregationError> {
+    let s = normalize_line_endings(s);
+    let s = match config.normalize_counter_names {
+        true => Cow::Owned(add_total_suffixes_to_text(&s).into_owned()),
+        false => s,
+    };
+    let s = rewrite_info_and_statesets(&s);
+    let units = extract_units(&s);
+    let s = extract_counter_created(&s);
+    let (s, exemplars) = extract_counter_exemplars(&s);
+    let s = fold_duplicate_samples(&s);
+    // The parser rejects negative counters itself, but without saying which series it was
+    let mut metrics = prometheus::parse_prometheus(&s).map_err(|err| match find_negative_counter(&s) {
+        Some(series) => AggregationError::NegativeCounter { series },
+        None => AggregationError::ParseError(err),
+    })?;
+    attach_counter_exemplars(&mut metrics, exemplars);
+    attach_units(&mut metrics, &units);
+    Ok(metrics.families.into_values().collect())
+}
+
+/// Parses an OpenMetrics exposition, which has to be read in full, since it's only valid if it ends with a `# EOF`. It's
+/// checked against the OpenMetrics grammar first, but then parsed like a Prometheus one, so that its families end up just
+/// like they would have if they'd been pushed that way
+fn parse_openmetrics_reader<R: BufRead>(mut reader: R, config: &AggregatorConfig) -> Result<Vec<PrometheusMetricFamily>, AggregationError> {
+    let mut body = Vec::new();
+    reader.read_to_end(&mut body).map_err(|e| AggregationError::Error(format!("Failed to read body: {}", e)))?;
+    let body = match std::str::from_utf8(&body) {
+        Ok(body) => Cow::Borrowed(body),
+        Err(_) if config.utf8_lossy => String::from_utf8_lossy(&body),
+        Err(_) => return Err(AggregationError::Error("Invalid UTF-8 in body".to_owned())),
+    };
+
+    openmetrics::parse_openmetrics(&body)?;
+    parse_exposition(&openmetrics_to_text(&body), config)
+}
+
+/// The name of the family that a HELP or TYPE line is about, if it's one of those
+fn metadata_name(line: &str) -> Option<&str> {
+    let rest = line.strip_prefix("# HELP ").or_else(|| line.strip_prefix("# TYPE "))?;
+    rest.split_whitespace().next()
+}
+
+/// Whether the given lines fail to parse because they aren't valid text format at all, rather than because of what
+/// they say (e.g. a histogram without a `+Inf` bucket, which is just as likely to be from the lines being cut short)
+fn is_malformed(lines: &str, config: &AggregatorConfig) -> bool {
+    matches!(parse_exposition(lines, config), Err(AggregationError::ParseError(ParseError::ParseError(_))))
+}
+
+/// Drops the lines of an exposition that are malformed on their own, returning the rest of it along with how many were
+/// dropped. Each sample is checked along with the HELP and TYPE lines of the family before it, so that it's parsed as
+/// the type that it would be in the whole exposition
+fn skip_malformed_lines(s: &str, config: &AggregatorConfig) -> (String, usize) {
+    let mut kept = String::with_capacity(s.len());
+    let mut metadata = String::new();
+    let mut metadata_family: Option<&str> = None;
+    let mut skipped = 0;
+    for line in s.lines() {
+        let malformed = match metadata_name(line) {
+            Some(name) => {
+                if metadata_family != Some(name) {
+                    metadata.clear();
+                    metadata_family = Some(name);
+                }
+                is_malformed(&format!("{}\n", line), config)
+            },
+            None if line.trim().is_empty() || line.trim_start().starts_with('#') => false,
+            None => is_malformed(&format!("{}{}\n", metadata, line), config),
+        };
+
+        if malformed {
+            debug!(line, "skipping malformed line");
+            skipped += 1;
+            continue;
+        }
+
+        if metadata_name(line).is_some() {
+            metadata.push_str(line);
+            metadata.push('\n');
+        }
+        kept.push_str(line);
+        kept.push('\n');
+    }
+
+    (kept, skipped)
+}
+
+/// Parses an exposition like `parse_exposition`, except that if it fails to parse and the config says to skip
+/// malformed lines, it's parsed again without them. Malformed lines are only looked for once the exposition has failed
+/// to parse, so that pushes without any don't pay for it. Gives the families, along with how many lines were skipped
+fn parse_exposition_skipping_malformed(s: &str, config: &AggregatorConfig) -> Result<(Vec<PrometheusMetricFamily>, usize), AggregationError> {
+    let err = match parse_exposition(s, config) {
+        Ok(families) => return Ok((families, 0)),
+        Err(err) if !config.skip_malformed_lines => return Err(err),
+        Err(err) => err,
+    };
+
+    // Without any malformed lines to skip, the push was rejected for what it said, which skipping lines can't fix. With
+    // nothing but malformed lines, there's nothing left to merge
+    let (kept, skipped) = skip_malformed_lines(s, config);
+    if skipped == 0 || kept.trim().is_empty() {
+        return Err(err);
+    }
+
+    Ok((parse_exposition(&kept, config)?, skipped))
+}
+
+/// Parses a Prometheus text exposition into its families, a family at a time. All of a family's lines have to be
+/// together, so a HELP or TYPE line for a different name to the last one starts a new family, and the lines before
+/// it can be parsed on their own. Invalid UTF-8 is an error, unless the config says to replace it
+fn parse_exposition_lines<R: BufRead>(mut reader: R, config: &AggregatorConfig) -> Result<ParsedPush, AggregationError> {
+    let mut families: Vec<PrometheusMetricFamily> = Vec::new();
+    let mut skipped_lines = 0;
+    let mut parse_block = |block: &str, families: &mut Vec<PrometheusMetricFamily>| -> Result<(), AggregationError> {
+        let (parsed, skipped) = parse_exposition_skipping_malformed(block, config)?;
+        families.extend(parsed);
+        skipped_lines += skipped;
+        Ok(())
+    };
+    let mut block = String::new();
+    let mut block_name: Option<String> = None;
+    let mut line = Vec::new();
+
+    loop {
+        line.clear();
+        let read = reader.read_until(b'\n', &mut line).map_err(|e| AggregationError::Error(format!("Failed to read body: {}", e)))?;
+        if read == 0 {
+            break;
+        }
+
+        let line = match std::str::from_utf8(&line) {
+            Ok(line) => Cow::Borrowed(line),
+            Err(_) if config.utf8_lossy => String::from_utf8_lossy(&line),
+            Err(_) => return Err(AggregationError::Error("Invalid UTF-8 in body".to_owned())),
+        };
+        let line = line.as_ref();
+        if let Some(name) = metadata_name(line) {
+            if block_name.as_deref().is_some_and(|current| current != name) {
+                parse_block(&block, &mut families)?;
+                block.clear();
+            }
+            block_name = Some(name.to_owned());
+        }
+        block.push_str(line.trim_end_matches('\n').trim_end_matches('\r'));
+        block.push('\n');
+    }
+
+    if !block.is_empty() || families.is_empty() {
+        parse_block(&block, &mut families)?;
+    }
+
+    // Parsed in one go, a family that's split up is an error, so it has to be here too
+    let mut names = HashSet::new();
+    if let Some(family) = families.iter().find(|family| !names.insert(family.family_name.as_str())) {
+        return Err(AggregationError::Error(format!("Found a metric family called {}, after that family was finalised", family.family_name)));
+    }
+
+    Ok(ParsedPush { families, skipped_lines })
+}
 
 impl Aggregator {
+    /// Constructs an aggregator with the default config. The binary always builds its config from flags
+    #[cfg(test)]
     pub fn new() -> Aggregator {
-        return Aggregator {
-            families: Arc::new(RwLock::new(HashMap::new())),
+        return Aggregator::with_config(AggregatorConfig::default());
+    }
+
+    /// Constructs an aggregator whose series never expire, unless the config gives some of them a ttl. Expiry happens on a
+    /// background task, so with any series ttls, this must be called from within a Tokio runtime
+    pub fn with_config(config: AggregatorConfig) -> Aggregator {
+        return Aggregator::build(config, None);
+    }
+
+    /// Constructs an aggregator that evicts series which haven't been pushed to within the given ttl.
+    /// Expiry happens on a background task, so this must be called from within a Tokio runtime
+    pub fn with_ttl(config: AggregatorConfig, ttl: Duration) -> Aggregator {
+        return Aggregator::build(config, Some(ttl));
+    }
+
+    fn build(config: AggregatorConfig, ttl: Option<Duration>) -> Aggregator {
+        let shards = (0..config.shards.max(1)).map(|_| RwLock::new(HashMap::new())).collect();
+        let agg = Aggregator {
+            shards: Arc::new(shards),
+            idempotency_keys: Arc::new(IdempotencyKeys::new(config.idempotency_key_ttl)),
+            config: Arc::new(config),
+            metrics: Arc::new(GatewayMetrics::default()),
+            last_pushes: Arc::new(RwLock::new(HashMap::new())),
+            series: Arc::new(SeriesCount::default()),
+            generation: Arc::new(Generation::new()),
+            ttl,
+        };
+
+        agg.spawn_reaper();
+        agg.spawn_resetter();
+        return agg;
+    }
+
+    /// Constructs an empty aggregator for a tenant, which merges pushes (and expires series) the same way as this one.
+    /// Its families are its own, but it counts what it does in this one's gateway metrics
+    pub fn new_tenant(&self) -> Aggregator {
+        let shards = (0..self.shards.len()).map(|_| RwLock::new(HashMap::new())).collect();
+        let agg = Aggregator {
+            shards: Arc::new(shards),
+            idempotency_keys: Arc::new(IdempotencyKeys::new(self.config.idempotency_key_ttl)),
+            config: Arc::clone(&self.config),
+            metrics: Arc::clone(&self.metrics),
+            last_pushes: Arc::new(RwLock::new(HashMap::new())),
+            series: Arc::new(SeriesCount::default()),
+            generation: Arc::new(Generation::new()),
+            ttl: self.ttl,
+        };
+
+        agg.spawn_reaper();
+        agg.spawn_resetter();
+        return agg;
+    }
+
+    /// Spawns the task that periodically expires series, if any of them expire. It only holds a weak reference to the
+    /// families, so it shuts down once every handle to this aggregator has been dropped
+    fn spawn_reaper(&self) {
+        // Sweeps have to be often enough for the shortest ttl
+        let shortest_ttl = self.ttl.into_iter().chain(self.config.series_ttls.iter().map(|(_, ttl)| *ttl)).min();
+        let period = match shortest_ttl {
+            Some(ttl) => (ttl / 2).clamp(MIN_REAP_INTERVAL, MAX_REAP_INTERVAL),
+            None => return,
+        };
+
+        let ttl = self.ttl;
+        let shards = Arc::downgrade(&self.shards);
+        let series = Arc::downgrade(&self.series);
+        let generation = Arc::downgrade(&self.generation);
+        let last_pushes = Arc::downgrade(&self.last_pushes);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(period);
+            loop {
+                interval.tick().await;
+                match (shards.upgrade(), series.upgrade(), generation.upgrade(), last_pushes.upgrade()) {
+                    (Some(shards), Some(series), Some(generation), Some(last_pushes)) => {
+                        expire_families(&shards, &series, &generation, ttl).await;
+                        expire_last_pushes(&last_pushes, &generation, ttl).await;
+                    },
+                    _ => return,
+                }
+            }
+        });
+    }
+
+    /// Spawns the task that clears the store on every boundary of the reset interval, if there is one. Like the reaper,
+    /// it shuts down once every handle to this aggregator has been dropped
+    fn spawn_resetter(&self) {
+        let period = match self.config.reset_interval {
+            Some(period) if !period.is_zero() => period,
+            _ => return,
         };
+
+        // Lined up with the epoch rather than when the gateway started, so that every tenant (and every peer) resets at once
+        let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+        let until_boundary = Duration::from_nanos((period.as_nanos() - since_epoch % period.as_nanos()) as u64);
+        let shards = Arc::downgrade(&self.shards);
+        let series = Arc::downgrade(&self.series);
+        let generation = Arc::downgrade(&self.generation);
+        let last_pushes = Arc::downgrade(&self.last_pushes);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + until_boundary, period);
+            loop {
+                interval.tick().await;
+                match (shards.upgrade(), series.upgrade(), generation.upgrade(), last_pushes.upgrade()) {
+                    (Some(shards), Some(series), Some(generation), Some(last_pushes)) => {
+                        clear_store(&shards, &series, &generation, &last_pushes).await;
+                        debug!("reset the store");
+                    },
+                    _ => return,
+                }
+            }
+        });
     }
 
     /// Takes a string representing a Prometheus exposition format, parses that and 
-    /// merges the metrics into this aggregator
+    /// merges the metrics into this aggregator. Pushes go through `parse_and_merge_reader`, so this is only for tests, as the
+    /// buffered parse that the reader's is checked against
+    #[cfg(test)]
     pub async fn parse_and_merge(&mut self, s: &str, extra_labels: &HashMap<&str, &str>) -> Result<(), AggregationError> {
-        let metrics = add_extra_labels(prometheus::parse_prometheus(&s)?, extra_labels)?;
-        let mut families = self.families.write().await;
+        check_label_names(extra_labels.keys().copied())?;
+        let (families, skipped_lines) = match parse_exposition_skipping_malformed(s, &self.config) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                self.metrics.record_parse_error();
+                return Err(e);
+            }
+        };
 
-        for (name, metrics) in metrics.families {
-            match families.get_mut(&name) {
+        self.metrics.record_skipped_lines(skipped_lines as u64);
+        self.merge_families(families, extra_labels).await
+    }
+
+    /// Parses an exposition in the given format and merges it into this aggregator, returning how many malformed lines were
+    /// skipped. This is how text pushes that haven't already been parsed (to find their owners, when clustering) are
+    /// merged. Prometheus text is read a line at a time, and each family is parsed once its last line has been read, so a
+    /// malformed push fails without the rest of it being parsed. That only saves parsing, though: the routes still
+    /// buffer the whole body first (to check its size, decompress it, and forward it), so a push is still in memory in
+    /// full while it's merged. The families are only merged once the whole push has parsed, so a push that fails doesn't
+    /// get half merged
+    pub async fn parse_and_merge_reader<R: BufRead>(&mut self, reader: R, format: TextFormat, extra_labels: &HashMap<&str, &str>) -> Result<usize, AggregationError> {
+        check_label_names(extra_labels.keys().copied())?;
+        let parsed = self.parse_reader(reader, format)?;
+        self.merge_families(parsed.families, extra_labels).await?;
+        Ok(parsed.skipped_lines)
+    }
+
+    /// Whether a push's path labels or its series' own labels win, for working out which peers a push belongs to
+    #[cfg(feature="clustering")]
+    pub fn label_precedence(&self) -> LabelPrecedence {
+        self.config.label_precedence
+    }
+
+    /// Parses an exposition in the given format, the way `parse_and_merge_reader` does, but without merging it, for pushes
+    /// that need looking at first. Prometheus text is parsed a line at a time, and is the only format whose malformed
+    /// lines can be skipped, since OpenMetrics pushes are only valid as a whole
+    pub fn parse_reader<R: BufRead>(&self, reader: R, format: TextFormat) -> Result<ParsedPush, AggregationError> {
+        let parsed = match format {
+            TextFormat::Prometheus => parse_exposition_lines(reader, &self.config),
+            TextFormat::OpenMetrics => parse_openmetrics_reader(reader, &self.config).map(|families| ParsedPush { families, skipped_lines: 0 }),
+        };
+
+        match parsed {
+            Ok(parsed) => {
+                self.metrics.record_skipped_lines(parsed.skipped_lines as u64);
+                Ok(parsed)
+            },
+            Err(e) => {
+                self.metrics.record_parse_error();
+                Err(e)
+            }
+        }
+    }
+
+    /// Merges already parsed families into this aggregator, adding the given labels to all of them. Pushes in every
+    /// exposition format end up here
+    pub async fn merge_families(&mut self, families: Vec<PrometheusMetricFamily>, extra_labels: &HashMap<&str, &str>) -> Result<(), AggregationError> {
+        check_label_names(extra_labels.keys().copied())?;
+        let families = match self.config.normalize_counter_names {
+            true => add_total_suffixes(families),
+            false => families,
+        };
+        let families = relabel(add_extra_labels(families, extra_labels, self.config.label_precedence)?, &self.config.relabel_rules)?;
+        let families = add_external_labels(drop_labels(families, &self.config.drop_labels)?, &self.config.external_labels);
+
+        for family in families.iter() {
+            check_family_names(family)?;
+            if self.config.reject_non_finite {
+                check_finite(family)?;
+            }
+            check_non_negative_counters(family)?;
+        }
+        self.check_type_conflicts(&families).await?;
+
+        debug!(families = ?families.iter().map(|family| &family.family_name).collect::<Vec<_>>(), "merging push");
+
+        // Like the push gateway, timestamps are per job, so pushes without one don't get one. If the body's labels win,
+        // the jobs that were pushed to are the ones its series ended up with
+        let mut jobs: Vec<String> = Vec::new();
+        match self.config.label_precedence {
+            LabelPrecedence::Path => jobs.extend(extra_labels.get("job").map(|job| job.to_string())),
+            LabelPrecedence::Body => for sample in families.iter().flat_map(|family| family.iter_samples()) {
+                if let Some(job) = sample.get_labelset()?.get_label_value("job").filter(|job| !job.is_empty() && !jobs.iter().any(|seen| seen == job)) {
+                    jobs.push(job.to_owned());
+                }
+            },
+        }
+
+        // Every family is checked before any of them are merged, so that a push that fails part way through (e.g. on a
+        // family's cardinality limit) doesn't leave the families before that merged. The push's shards are held for all
+        // of it, and taken in order so that pushes can't deadlock on each other
+        let mut indexes: Vec<usize> = families.iter().map(|family| self.shard_index(&family.family_name)).collect();
+        indexes.sort_unstable();
+        indexes.dedup();
+        let mut shards = self.lock_shards(indexes).await;
+
+        // A push that would take the store over its series budget has to make room first, which needs every shard, to
+        // find the series that were pushed to longest ago wherever they are. The others (i.e. nearly all of them) don't
+        let mut eviction = None;
+        if let Some(budget) = self.config.max_total_series {
+            let mut pushed = self.pushed_series(&shards, &families);
+            if self.series.get() + pushed.1 > budget && shards.len() < self.shards.len() {
+                drop(shards);
+                shards = self.lock_shards(0..self.shards.len()).await;
+                // Other pushes may have added or evicted series while we didn't have the locks
+                pushed = self.pushed_series(&shards, &families);
+            }
+
+            let excess = (self.series.get() + pushed.1).saturating_sub(budget);
+            if excess > 0 {
+                eviction = Some((excess, pushed.0));
+            }
+        }
+
+        let mut merges = Vec::with_capacity(families.len());
+        let mut seen: HashSet<String> = HashSet::new();
+        for metrics in families {
+            let idx = self.shard_index(&metrics.family_name);
+            if !seen.insert(metrics.family_name.clone()) {
+                merges.push((idx, PendingMerge::Again(metrics)));
+                continue;
+            }
+
+            match shards[&idx].get(&metrics.family_name) {
                 Some(f) => {
                     if !are_label_names_equivalent(f.base_family.get_label_names(), metrics.get_label_names()) {
                         // The new push has different label names - abort
                         return Err(AggregationError::Error("invalid push - new push has different label names than the existing family".to_string()))
                     }
-                    // If we have the family already, merge this new stuff into it
-                    f.merge(metrics)?;
+                    let new_family = f.prepare(&metrics)?;
+                    f.check_merge(&new_family, &self.config)?;
+                    merges.push((idx, PendingMerge::Existing(metrics, new_family)));
                 }
-                None => {
+                None => merges.push((idx, PendingMerge::New(AggregationFamily::new(metrics, &self.config)?))),
+            }
+        }
+
+        // The push can be merged, so it's safe to make room for it. Its own series are kept, since they'd only come back
+        if let Some((excess, pushed)) = eviction {
+            self.evict_oldest_series(&mut shards, excess, &pushed);
+        }
+
+        let touched = |shards: &LockedShards| seen.iter()
+            .filter_map(|name| shards[&self.shard_index(name)].get(name))
+            .map(|family| family.series.len())
+            .sum::<usize>();
+        let before = touched(&shards);
+
+        // A family that's in the push more than once (e.g. when relabeling collapses series together) can only be checked
+        // a part at a time, as the ones before it are merged. So that they can still fail without changing anything, the
+        // families that the push touches are copied first, to be put back if they do
+        let mut originals: Vec<(usize, String, Option<AggregationFamily>)> = Vec::new();
+        if merges.iter().any(|(_, merge)| matches!(merge, PendingMerge::Again(_))) {
+            for name in seen.iter() {
+                let idx = self.shard_index(name);
+                let original = shards[&idx].get(name).map(AggregationFamily::duplicate);
+                originals.push((idx, name.clone(), original));
+            }
+        }
+
+        for (idx, merge) in merges {
+            let families = shards.get_mut(&idx).unwrap();
+            let resets = match merge {
+                PendingMerge::Existing(metrics, new_family) => match families.get_mut(&metrics.family_name) {
+                    // If we have the family already, merge this new stuff into it
+                    Some(f) => f.merge(metrics, new_family, &self.config),
+                    // Making room for the push evicted everything else in the family
+                    None => AggregationFamily::new(metrics, &self.config).map(|family| {
+                        families.insert(family.base_family.family_name.clone(), family);
+                        0
+                    }),
+                },
+                PendingMerge::New(family) => {
                     // Otherwise, just add the new family
-                    families.insert(name, AggregationFamily::new(metrics));
+                    families.insert(family.base_family.family_name.clone(), family);
+                    Ok(0)
+                },
+                PendingMerge::Again(metrics) => match families.get_mut(&metrics.family_name) {
+                    Some(f) if !are_label_names_equivalent(f.base_family.get_label_names(), metrics.get_label_names()) => {
+                        Err(AggregationError::Error("invalid push - new push has different label names than the existing family".to_string()))
+                    },
+                    Some(f) => f.prepare(&metrics).and_then(|new_family| {
+                        f.check_merge(&new_family, &self.config)?;
+                        f.merge(metrics, new_family, &self.config)
+                    }),
+                    None => AggregationFamily::new(metrics, &self.config).map(|family| {
+                        families.insert(family.base_family.family_name.clone(), family);
+                        0
+                    }),
+                },
+            };
+
+            match resets {
+                Ok(resets) => self.metrics.record_counter_resets(resets),
+                Err(e) => {
+                    for (idx, name, original) in originals {
+                        let families = shards.get_mut(&idx).unwrap();
+                        match original {
+                            Some(original) => families.insert(name, original),
+                            None => families.remove(&name),
+                        };
+                    }
+                    self.series.update(before, touched(&shards));
+                    return Err(e);
+                }
+            }
+        }
+        self.series.update(before, touched(&shards));
+
+        // Only a push with more new series than the whole budget is still over it, and has to lose some of its own
+        if let Some(budget) = self.config.max_total_series.filter(|&budget| self.series.get() > budget) {
+            let excess = self.series.get() - budget;
+            self.evict_oldest_series(&mut shards, excess, &HashSet::new());
+        }
+        drop(shards);
+
+        if !jobs.is_empty() {
+            let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64();
+            let mut last_pushes = self.last_pushes.write().await;
+            for job in jobs {
+                let ttl = self.config.last_push_ttl(&job);
+                last_pushes.insert(job, LastPush { timestamp, at: Instant::now(), ttl });
+            }
+        }
+
+        self.generation.bump();
+        return Ok(());
+    }
+
+    /// Errors if any of the families has a different type to one already held with the same name, or to one that it would
+    /// show up under the same name as in a scrape (e.g. a `latency_count` gauge alongside a `latency` histogram),
+    /// which can't be merged into a valid scrape. This is checked for the whole push up front, so that nothing is merged
+    async fn check_type_conflicts(&self, families: &[PrometheusMetricFamily]) -> Result<(), AggregationError> {
+        for family in families {
+            let names = exposed_names(&family.family_name, &family.family_type);
+            for owner in names.iter().flat_map(|name| possible_owners(name)) {
+                let shard = self.shard_for(&owner).read().await;
+                let existing = match shard.get(&owner) {
+                    Some(existing) => &existing.base_family,
+                    None => continue,
+                };
+
+                let conflicts = match existing.family_name == family.family_name {
+                    true => existing.family_type != family.family_type,
+                    false => existing.family_type != family.family_type && exposed_names(&existing.family_name, &existing.family_type).iter().any(|name| names.contains(name)),
+                };
+                if conflicts {
+                    return Err(AggregationError::TypeConflict {
+                        family: existing.family_name.clone(),
+                        existing: existing.family_type.clone(),
+                        pushed: family.family_type.clone(),
+                    });
                 }
             }
         }
@@ -399,15 +2470,509 @@ impl Aggregator {
         return Ok(());
     }
 
+    /// Takes the write locks of the given shards, in the order given
+    async fn lock_shards(&self, indexes: impl IntoIterator<Item = usize>) -> LockedShards<'_> {
+        let mut shards = HashMap::new();
+        for idx in indexes {
+            shards.insert(idx, self.shards[idx].write().await);
+        }
+
+        return shards;
+    }
+
+    /// Every series in a push, by family name and label values, and how many of them are new. Families that the push
+    /// can't be merged into are left to fail the push's checks, and a family that it has more than once (or that it
+    /// replaces) can end up with fewer new series than this says
+    fn pushed_series(&self, shards: &LockedShards, families: &[PrometheusMetricFamily]) -> (HashSet<(String, Vec<String>)>, usize) {
+        let mut pushed = HashSet::new();
+        let mut new = 0;
+        for metrics in families {
+            let existing = shards[&self.shard_index(&metrics.family_name)].get(&metrics.family_name);
+            let converted = match existing {
+                Some(f) if are_label_names_equivalent(f.base_family.get_label_names(), metrics.get_label_names()) => f.prepare(metrics),
+                Some(_) => continue,
+                None => Ok(metrics.clone_and_convert_type()),
+            };
+
+            for key in converted.iter().flat_map(|family| family.iter_samples()).map(series_key) {
+                if !existing.is_some_and(|f| f.series.contains_key(&key)) && !pushed.contains(&(metrics.family_name.clone(), key.clone())) {
+                    new += 1;
+                }
+                pushed.insert((metrics.family_name.clone(), key));
+            }
+        }
+
+        return (pushed, new);
+    }
+
+    /// Evicts up to `excess` of the series that were pushed to longest ago (by the same time that the ttl goes by), other
+    /// than the ones to keep. Series that are being pushed to regularly are the last to go. This needs every shard
+    fn evict_oldest_series(&self, shards: &mut LockedShards, excess: usize, keep: &HashSet<(String, Vec<String>)>) {
+        let mut evicted: HashMap<(usize, String), HashSet<Vec<String>>> = HashMap::new();
+        {
+            let mut series: Vec<(Instant, usize, &String, &Vec<String>)> = shards.iter()
+                .flat_map(|(&shard, families)| families.iter().flat_map(move |(name, family)| {
+                    family.series.iter().map(move |(key, state)| (state.last_pushed, shard, name, key))
+                }))
+                .filter(|(_, _, name, key)| !keep.contains(&((*name).clone(), (*key).clone())))
+                .collect();
+            if series.len() > excess {
+                series.select_nth_unstable_by_key(excess, |(last_pushed, ..)| *last_pushed);
+            }
+            for (_, shard, name, key) in series.into_iter().take(excess) {
+                evicted.entry((shard, name.clone())).or_default().insert(key.clone());
+            }
+        }
+
+        let count: usize = evicted.values().map(HashSet::len).sum();
+        for ((shard, name), keys) in evicted {
+            let families = shards.get_mut(&shard).unwrap();
+            if let Some(family) = families.get_mut(&name) {
+                family.retain_samples(|sample| !keys.contains(&series_key(sample)));
+                if family.is_empty() {
+                    families.remove(&name);
+                }
+            }
+        }
+
+        self.series.update(count, 0);
+        self.generation.bump();
+        self.metrics.record_evictions(count as u64);
+    }
+
+    /// Removes every family from this aggregator, taking the write lock of every shard
+    /// before clearing any of them
+    pub async fn clear(&mut self) {
+        clear_store(&self.shards, &self.series, &self.generation, &self.last_pushes).await;
+    }
+
+    /// Removes every series whose labels are a superset of the given labels, dropping
+    /// any families that end up empty
+    pub async fn delete_matching(&mut self, labels: &HashMap<&str, &str>) {
+        for shard in self.shards.iter() {
+            let mut families = shard.write().await;
+            let before = series_in(&families);
+            for family in families.values_mut() {
+                family.retain_samples(|sample| !sample_matches_labels(sample, labels));
+            }
+
+            families.retain(|_, family| !family.is_empty());
+            self.series.update(before, series_in(&families));
+        }
+
+        // The timestamps only have a job label, so they match if that's all that's being deleted by
+        self.last_pushes.write().await.retain(|job, _| !labels.iter().all(|(&name, &value)| name == "job" && value == job));
+        self.generation.bump();
+    }
+
     /// Converts this aggregator into a Prometheus text exposition format
     /// that can be scraped by a Prometheus
+    #[cfg(test)]
     pub async fn to_string(&self) -> String {
-        let families = self.families.read().await;
-        let mut family_strings = String::new();
-        for (_, family) in families.iter() {
-            family_strings.push_str(&family.base_family.to_string());
-        }
+        self.to_filtered_string(&[]).await
+    }
+
+    /// Like `to_string`, but with only the series picked out by at least one of the given selectors. With no selectors,
+    /// every series is included. Scrapes go through `render_stream`, so this is only for tests
+    #[cfg(test)]
+    pub async fn to_filtered_string(&self, selectors: &[Selector]) -> String {
+        self.render(true, selectors, |family, _| render_prometheus(family)).await
+    }
 
+    /// Like `to_openmetrics_string`, but with only the series picked out by at least one of the given selectors
+    #[cfg(test)]
+    pub async fn to_filtered_openmetrics_string(&self, selectors: &[Selector]) -> String {
+        let mut family_strings = self.render(true, selectors, render_openmetrics).await;
+        family_strings.push_str("# EOF\n");
         family_strings
     }
+
+    /// Converts this aggregator into an OpenMetrics text exposition format, including
+    /// the terminating `# EOF`
+    #[cfg(test)]
+    pub async fn to_openmetrics_string(&self) -> String {
+        self.to_filtered_openmetrics_string(&[]).await
+    }
+
+    /// Converts this aggregator into Graphite plaintext, with every path starting with the given prefix. See
+    /// `to_graphite_lines` for how series are named
+    pub async fn to_graphite_string(&self, prefix: Option<&str>) -> String {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        self.render(true, &[], |family, _| to_graphite_lines(&render_prometheus(family), prefix, now)).await
+    }
+
+    /// Renders every family in order of name, optionally including the synthetic last push timestamps. With any selectors,
+    /// only the series that match at least one of them are included. Each family is rendered along with every family
+    /// being rendered, by name, for formats that render families together
+    async fn render<F>(&self, include_last_pushes: bool, selectors: &[Selector], render_family: F) -> String where F: Fn(&GravelMetricFamily, &HashMap<&str, &GravelMetricFamily>) -> String {
+        let shards = self.read_shards().await;
+        let last_pushes = match include_last_pushes {
+            true => self.last_push_family().await,
+            false => None,
+        };
+
+        let mut families: Vec<&GravelMetricFamily> = sorted_families(&shards).into_iter().map(|family| &family.base_family).collect();
+        families.retain(|family| passes_validation(family, self.config.histogram_validation));
+        if let Some(last_pushes) = last_pushes.as_ref() {
+            let idx = families.partition_point(|family| family.family_name.as_str() < LAST_PUSH_METRIC_NAME);
+            families.insert(idx, last_pushes);
+        }
+
+        let filtered: Vec<GravelMetricFamily>;
+        if !selectors.is_empty() {
+            filtered = families.into_iter().filter_map(|family| filter_family(family, selectors)).collect();
+            families = filtered.iter().collect();
+        }
+
+        let by_name: HashMap<&str, &GravelMetricFamily> = families.iter().map(|family| (family.family_name.as_str(), *family)).collect();
+        families.iter().map(|family| render_family(family, &by_name)).collect()
+    }
+
+    /// Renders the same as `to_filtered_string` (or `to_filtered_openmetrics_string`), but a family at a time as the stream
+    /// is read, so that scraping a huge store doesn't need all of it in memory at once. Each family's shard is only locked
+    /// for long enough to copy that family out, so unlike the buffered render, the families aren't all read at the same
+    /// instant - a push that lands part way through a scrape may only be seen in the families that come after it
+    pub fn render_stream(&self, selectors: Vec<Selector>, openmetrics: bool) -> impl Stream<Item = String> + Send + 'static {
+        let state = (self.clone(), selectors, None::<std::vec::IntoIter<String>>, false);
+        futures::stream::unfold(state, move |(agg, selectors, names, finished)| async move {
+            if finished {
+                return None;
+            }
+
+            let mut names = match names {
+                Some(names) => names,
+                None => agg.family_names().await.into_iter(),
+            };
+
+            for name in names.by_ref() {
+                if let Some(rendered) = agg.render_family_named(&name, &selectors, openmetrics).await {
+                    return Some((rendered, (agg, selectors, Some(names), false)));
+                }
+            }
+
+            match openmetrics {
+                true => Some(("# EOF\n".to_owned(), (agg, selectors, Some(names), true))),
+                false => None,
+            }
+        })
+    }
+
+    /// Which version of its contents this aggregator holds. It moves on whenever anything that a scrape would return
+    /// changes (pushes, deletes, resets, expiry, evictions and restores), and is never the same for two aggregators
+    pub fn generation(&self) -> u64 {
+        self.generation.get()
+    }
+
+    /// The name of every family, including the last push timestamps if there are any, in the order they're rendered in
+    async fn family_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = Vec::new();
+        for shard in self.shards.iter() {
+            names.extend(shard.read().await.keys().cloned());
+        }
+        names.sort();
+
+        if !self.last_pushes.read().await.is_empty() {
+            let idx = names.partition_point(|name| name.as_str() < LAST_PUSH_METRIC_NAME);
+            names.insert(idx, LAST_PUSH_METRIC_NAME.to_owned());
+        }
+
+        names
+    }
+
+    /// A copy of the family with the given name, with only the series that the selectors pick out (or all of them without
+    /// any selectors). None if it doesn't exist (anymore), or none of its series were picked out
+    async fn family_named(&self, name: &str, selectors: &[Selector]) -> Option<GravelMetricFamily> {
+        let copy = |family: &GravelMetricFamily| match selectors.is_empty() {
+            true => Some(family.clone_and_convert_type()),
+            false => filter_family(family, selectors),
+        };
+
+        if name == LAST_PUSH_METRIC_NAME {
+            return copy(&self.last_push_family().await?);
+        }
+
+        let families = self.shard_for(name).read().await;
+        let family = &families.get(name)?.base_family;
+        match passes_validation(family, self.config.histogram_validation) {
+            true => copy(family),
+            false => None,
+        }
+    }
+
+    /// Renders one family the same as `render` would have amongst all of them. Only `render_openmetrics` looks at the
+    /// other families, for the `_created` gauges of counters, so those are the only others that are copied out
+    async fn render_family_named(&self, name: &str, selectors: &[Selector], openmetrics: bool) -> Option<String> {
+        let family = self.family_named(name, selectors).await?;
+        if !openmetrics {
+            return Some(render_prometheus(&family));
+        }
+
+        let mut related = Vec::new();
+        if let Some(base) = name.strip_suffix("_total") {
+            related.extend(self.family_named(&format!("{}_created", base), selectors).await);
+        }
+        if let Some(base) = name.strip_suffix("_created") {
+            related.extend(self.family_named(&format!("{}_total", base), selectors).await);
+        }
+
+        let by_name: HashMap<&str, &GravelMetricFamily> = std::iter::once(&family).chain(related.iter()).map(|family| (family.family_name.as_str(), family)).collect();
+        Some(render_openmetrics(&family, &by_name))
+    }
+
+    /// A gauge of when each job was last pushed to, or nothing if there haven't been any pushes
+    async fn last_push_family(&self) -> Option<GravelMetricFamily> {
+        let last_pushes = self.last_pushes.read().await;
+        if last_pushes.is_empty() {
+            return None;
+        }
+
+        let mut jobs: Vec<(&String, &LastPush)> = last_pushes.iter().collect();
+        jobs.sort_by_key(|(job, _)| *job);
+        let samples = jobs.into_iter().map(|(job, push)| {
+            Sample::new(vec![escape_label_value(job).into_owned()], None, PrometheusValue::Gauge(MetricNumber::Float(push.timestamp)))
+        });
+
+        let family = PrometheusMetricFamily::new(
+            LAST_PUSH_METRIC_NAME.to_owned(),
+            vec!["job".to_owned()],
+            PrometheusType::Gauge,
+            "When each job was last pushed to, in seconds since the epoch".to_owned(),
+            String::new(),
+        );
+
+        // Each job only has the one timestamp, so this can't fail
+        return family.with_samples(samples).ok().map(|family| family.clone_and_convert_type());
+    }
+
+    /// Writes every family to the given file in the Prometheus text format, so that they can be restored with
+    /// `restore_from`. The snapshot is written alongside the file and then renamed over it, so that a crash part
+    /// way through can't leave a partial snapshot behind
+    pub async fn snapshot_to(&self, path: &Path) -> std::io::Result<()> {
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+
+        // The timestamps would come back as a real family, and they're meaningless after a restart anyway
+        tokio::fs::write(&tmp_path, self.render(false, &[], |family, _| family.to_string()).await).await?;
+        tokio::fs::rename(&tmp_path, path).await
+    }
+
+    /// Takes every series out of this aggregator, grouped by the key that `key_for` gives their labels. Each group is
+    /// rendered the same way snapshots are, so that it can be pushed somewhere else (i.e. to the peer that owns it,
+    /// when draining) and merged into whatever's already there
+    #[cfg(feature="clustering")]
+    pub async fn take_series_by<F>(&mut self, key_for: F) -> Vec<TakenSeries> where F: Fn(&HashMap<&str, &str>) -> String {
+        let mut shards = Vec::with_capacity(self.shards.len());
+        for shard in self.shards.iter() {
+            shards.push(shard.write().await);
+        }
+
+        let mut groups: HashMap<String, TakenSeries> = HashMap::new();
+        for families in shards.iter_mut() {
+            self.series.update(series_in(families), 0);
+            for (_, family) in families.drain() {
+                for (key, part) in family.split_by(&key_for) {
+                    let group = groups.entry(key.clone()).or_insert_with(|| TakenSeries { key, body: String::new(), families: Vec::new() });
+                    group.body.push_str(&part.base_family.to_string());
+                    group.families.push(part);
+                }
+            }
+        }
+
+        self.generation.bump();
+
+        let mut groups: Vec<TakenSeries> = groups.into_values().collect();
+        groups.sort_by(|a, b| a.key.cmp(&b.key));
+        return groups;
+    }
+
+    /// Puts series from `take_series_by` back as they were, i.e. when they couldn't be handed over. Any of them that have
+    /// been pushed to since they were taken keep the newer series
+    #[cfg(feature="clustering")]
+    pub async fn put_back(&mut self, taken: TakenSeries) -> Result<(), AggregationError> {
+        let mut result = Ok(());
+        for family in taken.families {
+            let name = family.base_family.family_name.clone();
+            let mut families = self.shard_for(&name).write().await;
+            let before = families.get(&name).map_or(0, |existing| existing.series.len());
+            match families.get_mut(&name) {
+                Some(existing) => if let Err(e) = existing.put_back(family) {
+                    result = Err(e);
+                },
+                None => {
+                    families.insert(name.clone(), family);
+                },
+            }
+            self.series.update(before, families.get(&name).map_or(0, |family| family.series.len()));
+        }
+
+        self.generation.bump();
+        return result;
+    }
+
+    /// Loads the families from a snapshot written by `snapshot_to`, replacing any that already exist. A missing
+    /// snapshot has nothing to restore, so that's not an error. Nothing is restored from a snapshot that can't be parsed.
+    /// Clear modes aren't kept in snapshots, so restored series are merged into with the default clear mode for their type
+    pub async fn restore_from(&mut self, path: &Path) -> Result<(), AggregationError> {
+        let snapshot = match tokio::fs::read_to_string(path).await {
+            Ok(snapshot) => snapshot,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(AggregationError::Error(format!("failed to read snapshot {}: {}", path.display(), e))),
+        };
+
+        let mut exposition = prometheus::parse_prometheus(&snapshot)?;
+        attach_units(&mut exposition, &extract_units(&snapshot));
+
+        let mut families = Vec::new();
+        for (name, metrics) in exposition.families {
+            let mut family = AggregationFamily::new(metrics, &self.config)?;
+            family.forget_counter_values();
+            families.push((name, family));
+        }
+
+        for (name, family) in families {
+            let added = family.series.len();
+            let replaced = self.shard_for(&name).write().await.insert(name, family);
+            self.series.update(replaced.map_or(0, |replaced| replaced.series.len()), added);
+        }
+
+        self.generation.bump();
+        return Ok(());
+    }
+
+    /// Captures everything in this aggregator, including what's tracked about each series to merge pushes into it (like
+    /// when it was last pushed to, and the windows and means that clear modes keep), for `restore_snapshot`
+    pub async fn to_snapshot(&self) -> StoreSnapshot {
+        let shards = self.read_shards().await;
+        let now = now_with_epoch_secs();
+        let families = sorted_families(&shards).into_iter().map(|family| family.to_snapshot(now)).collect();
+        let last_pushes = self.last_pushes.read().await.iter().map(|(job, push)| (job.clone(), push.timestamp)).collect();
+        return StoreSnapshot { version: SNAPSHOT_VERSION, families, last_pushes };
+    }
+
+    /// Replaces everything in this aggregator with what's in a snapshot from `to_snapshot`. The whole snapshot is checked
+    /// before anything is replaced, so one that's invalid (or from another version) leaves the aggregator as it was
+    pub async fn restore_snapshot(&mut self, snapshot: StoreSnapshot) -> Result<(), AggregationError> {
+        check_version(snapshot.version).map_err(AggregationError::Error)?;
+
+        let now = now_with_epoch_secs();
+        let mut families: HashMap<String, AggregationFamily> = HashMap::with_capacity(snapshot.families.len());
+        for family in snapshot.families {
+            let family = AggregationFamily::from_snapshot(family, now, &self.config)?;
+            let name = family.base_family.family_name.clone();
+            if families.insert(name.clone(), family).is_some() {
+                return Err(AggregationError::Error(format!("invalid snapshot: {} is in it more than once", name)));
+            }
+        }
+
+        let mut shards = Vec::with_capacity(self.shards.len());
+        for shard in self.shards.iter() {
+            shards.push(shard.write().await);
+        }
+
+        for families in shards.iter_mut() {
+            self.series.update(series_in(families), 0);
+            families.clear();
+        }
+
+        for (name, family) in families {
+            self.series.update(0, family.series.len());
+            shards[self.shard_index(&name)].insert(name, family);
+        }
+
+        *self.last_pushes.write().await = snapshot.last_pushes.into_iter()
+            .map(|(job, timestamp)| {
+                let ttl = self.config.last_push_ttl(&job);
+                (job, LastPush { timestamp, at: epoch_secs_to_instant(timestamp, now), ttl })
+            })
+            .collect();
+
+        self.generation.bump();
+        return Ok(());
+    }
+
+    /// The gateway's own metrics, shared between every clone of this aggregator
+    pub fn metrics(&self) -> &GatewayMetrics {
+        &self.metrics
+    }
+
+    /// The idempotency keys of recent pushes, shared between every clone of this aggregator
+    pub fn idempotency_keys(&self) -> &IdempotencyKeys {
+        &self.idempotency_keys
+    }
+
+    /// How many series are held across every family, from the count that's kept as they're added and removed. Only
+    /// tests need it on its own, since /-/metrics gets it from `store_stats`
+    #[cfg(test)]
+    pub fn series_count(&self) -> usize {
+        self.series.get()
+    }
+
+    /// How big the store is, for capacity planning. This walks every series, so it always reflects evictions and deletes
+    pub async fn store_stats(&self) -> StoreStats {
+        let shards = self.read_shards().await;
+        let mut stats = StoreStats::default();
+        for family in shards.iter().flat_map(|families| families.values()) {
+            let base_family = &family.base_family;
+            let label_names_len: usize = base_family.get_label_names().iter().map(String::len).sum();
+            stats.families += 1;
+            stats.series += family.series.len();
+            stats.estimated_bytes += FAMILY_OVERHEAD_BYTES + base_family.family_name.len() + base_family.help.len() + base_family.unit.len();
+            stats.estimated_bytes += family.series.keys().map(|label_values| {
+                SERIES_OVERHEAD_BYTES + label_names_len + label_values.iter().map(String::len).sum::<usize>()
+            }).sum::<usize>();
+        }
+
+        stats
+    }
+
+    /// Every job with series in the store or a push to it on record, with how many series it has and when it was last
+    /// pushed to, ordered by job. Series without a job label don't count towards any
+    pub async fn job_summary(&self) -> Vec<JobSummary> {
+        let shards = self.read_shards().await;
+        let mut jobs: BTreeMap<String, JobSummary> = BTreeMap::new();
+        for family in shards.iter().flat_map(|families| families.values()) {
+            let job_idx = match family.base_family.get_label_names().iter().position(|name| name == "job") {
+                Some(job_idx) => job_idx,
+                None => continue,
+            };
+
+            for job in family.series.keys().map(|label_values| &label_values[job_idx]).filter(|job| !job.is_empty()) {
+                match jobs.get_mut(job) {
+                    Some(summary) => summary.series += 1,
+                    None => {
+                        jobs.insert(job.clone(), JobSummary { job: job.clone(), series: 1, last_push: None });
+                    },
+                }
+            }
+        }
+
+        // A job can have been pushed to without any series being left, e.g. if they've all been deleted
+        for (job, push) in self.last_pushes.read().await.iter() {
+            jobs.entry(job.clone()).or_insert_with(|| JobSummary { job: job.clone(), series: 0, last_push: None }).last_push = Some(push.timestamp);
+        }
+
+        return jobs.into_values().collect();
+    }
+
+    /// The shard that holds the family with the given name
+    fn shard_for(&self, family_name: &str) -> &Shard {
+        return &self.shards[self.shard_index(family_name)];
+    }
+
+    /// The index of the shard that holds the family with the given name
+    fn shard_index(&self, family_name: &str) -> usize {
+        let mut hasher = DefaultHasher::new();
+        family_name.hash(&mut hasher);
+        return (hasher.finish() % self.shards.len() as u64) as usize;
+    }
+
+    /// Takes the read lock of every shard, so that a render (e.g. a snapshot) sees a consistent view
+    async fn read_shards(&self) -> Vec<RwLockReadGuard<'_, HashMap<String, AggregationFamily>>> {
+        let mut shards = Vec::with_capacity(self.shards.len());
+        for shard in self.shards.iter() {
+            shards.push(shard.read().await);
+        }
+
+        return shards;
+    }
 }
@@ -1,7 +1,8 @@
-use openmetrics_parser::{Exemplar, MetricNumber, PrometheusCounterValue, PrometheusValue, Sample};
+use openmetrics_parser::{Exemplar, MetricNumber, PrometheusCounterValue, PrometheusType, PrometheusValue, Sample};
 
 use crate::aggregator::*;
-use std::{collections::HashMap, str::FromStr};
+use crate::selector::Selector;
+use std::{collections::HashMap, str::FromStr, time::{Duration, SystemTime, UNIX_EPOCH}};
 
 #[test]
 fn test_clear_mode_parsing() {
@@ -22,7 +23,7 @@ fn test_clear_mode_replace() {
     let mut sample = Sample::new(vec![], None, GravelValue::Prometheus(PrometheusValue::Gauge(MetricNumber::Int(1))));
     merge_metric(&mut sample, 
                 Sample::new(vec![], None, GravelValue::Prometheus(PrometheusValue::Gauge(MetricNumber::Int(2)))),
-                      ClearMode::Replace).unwrap();
+                      ClearMode::Replace, &AggregatorConfig::default()).unwrap();
 
     assert_eq!(sample.value, GravelValue::Prometheus(PrometheusValue::Gauge(MetricNumber::Int(2))));
 
@@ -43,7 +44,7 @@ fn test_clear_mode_replace() {
                         }),
                     }
                 ))),
-                ClearMode::Replace).unwrap();
+                ClearMode::Replace, &AggregatorConfig::default()).unwrap();
 
     assert_eq!(sample.value, GravelValue::Prometheus(PrometheusValue::Counter(PrometheusCounterValue{
         value: MetricNumber::Int(1000),
@@ -70,7 +71,7 @@ fn test_clear_mode_replace() {
                         exemplar: None
                     }
                 ))),
-                ClearMode::Replace).unwrap();
+                ClearMode::Replace, &AggregatorConfig::default()).unwrap();
 
     assert_eq!(sample.value, GravelValue::Prometheus(PrometheusValue::Counter(PrometheusCounterValue{
         value: MetricNumber::Int(1000),
@@ -83,7 +84,7 @@ fn test_clear_mode_aggregate() {
     let mut sample = Sample::new(vec![], None, GravelValue::Prometheus(PrometheusValue::Gauge(MetricNumber::Int(1))));
     merge_metric(&mut sample, 
                 Sample::new(vec![], None, GravelValue::Prometheus(PrometheusValue::Gauge(MetricNumber::Int(2)))),
-                      ClearMode::Aggregate).unwrap();
+                      ClearMode::Aggregate, &AggregatorConfig::default()).unwrap();
 
     assert_eq!(sample.value, GravelValue::Prometheus(PrometheusValue::Gauge(MetricNumber::Int(3))));
 
@@ -104,7 +105,7 @@ fn test_clear_mode_aggregate() {
                         }),
                     }
                 ))),
-                ClearMode::Aggregate).unwrap();
+                ClearMode::Aggregate, &AggregatorConfig::default()).unwrap();
 
     assert_eq!(sample.value, GravelValue::Prometheus(PrometheusValue::Counter(PrometheusCounterValue{
         value: MetricNumber::Int(1001),
@@ -131,7 +132,7 @@ fn test_clear_mode_aggregate() {
                         exemplar: None
                     }
                 ))),
-                ClearMode::Aggregate).unwrap();
+                ClearMode::Aggregate, &AggregatorConfig::default()).unwrap();
 
     assert_eq!(sample.value, GravelValue::Prometheus(PrometheusValue::Counter(PrometheusCounterValue{
         value: MetricNumber::Int(1001),
@@ -150,3 +151,1359 @@ async fn test_push_with_different_label_names() {
     assert!(agg.parse_and_merge("requests_num_total2{clearmode=\"mean5m\"} 1\n", &HashMap::new()).await.is_ok(), "failed to add metric with clearmode");
     assert!(agg.parse_and_merge("requests_num_total2{clearmode=\"mean5m\"} 1\n", &HashMap::new()).await.is_ok(), "failed to add second metric with clearmode");
 }
+
+#[tokio::test]
+async fn test_merge_with_different_label_order() {
+    let mut agg = Aggregator::new();
+    agg.parse_and_merge("requests_total{a=\"1\",b=\"2\"} 1\n", &HashMap::new()).await.unwrap();
+
+    // Path labels get appended in whatever order they're given, so pushes can disagree on the label order
+    let mut labels = HashMap::new();
+    labels.insert("a", "1");
+    agg.parse_and_merge("requests_total{b=\"2\"} 2\n", &labels).await.unwrap();
+
+    assert_eq!(agg.to_string().await, "requests_total{a=\"1\",b=\"2\"} 3\n");
+}
+
+#[tokio::test]
+async fn test_output_is_sorted() {
+    let mut agg = Aggregator::new();
+    agg.parse_and_merge("# TYPE zebras gauge\nzebras{pen=\"b\"} 2\nzebras{pen=\"a\"} 1\n# TYPE apples_total counter\napples_total{tree=\"3\"} 3\napples_total{tree=\"1\"} 1\n", &HashMap::new()).await.unwrap();
+    agg.parse_and_merge("# TYPE apples_total counter\napples_total{tree=\"2\"} 2\n# TYPE mangoes gauge\nmangoes 1\n", &HashMap::new()).await.unwrap();
+
+    // Path labels come in a HashMap, but should always come out in the same order
+    let mut labels = HashMap::new();
+    labels.insert("farm", "orchard");
+    labels.insert("instance", "b");
+    labels.insert("zone", "north");
+    agg.parse_and_merge("# TYPE pears gauge\npears 2\n", &labels).await.unwrap();
+    labels.insert("instance", "a");
+    agg.parse_and_merge("# TYPE pears gauge\npears 1\n", &labels).await.unwrap();
+
+    let expected = "# TYPE apples_total counter
+apples_total{tree=\"1\"} 1
+apples_total{tree=\"2\"} 2
+apples_total{tree=\"3\"} 3
+# TYPE mangoes gauge
+mangoes 1
+# TYPE pears gauge
+pears{farm=\"orchard\",instance=\"a\",zone=\"north\"} 1
+pears{farm=\"orchard\",instance=\"b\",zone=\"north\"} 2
+# TYPE zebras gauge
+zebras{pen=\"a\"} 1
+zebras{pen=\"b\"} 2
+";
+
+    assert_eq!(agg.to_string().await, expected);
+    assert_eq!(agg.to_string().await, agg.to_string().await, "scrapes should be stable");
+}
+
+#[tokio::test]
+async fn test_merge_with_different_help() {
+    let mut agg = Aggregator::new();
+    agg.parse_and_merge("# TYPE requests_total counter\nrequests_total 1\n", &HashMap::new()).await.unwrap();
+    agg.parse_and_merge("# HELP requests_total The number of requests\n# TYPE requests_total counter\nrequests_total 1\n", &HashMap::new()).await.unwrap();
+    agg.parse_and_merge("# HELP requests_total Some other help\n# TYPE requests_total counter\nrequests_total 1\n", &HashMap::new()).await.unwrap();
+    agg.parse_and_merge("# TYPE requests_total counter\nrequests_total{clearmode=\"family\"} 1\n", &HashMap::new()).await.unwrap();
+
+    assert_eq!(agg.to_string().await, "# HELP requests_total The number of requests\n# TYPE requests_total counter\nrequests_total 1\n");
+}
+
+#[tokio::test]
+async fn test_merge_with_different_type() {
+    let mut agg = Aggregator::new();
+    agg.parse_and_merge("# TYPE requests_total counter\nrequests_total 1\n", &HashMap::new()).await.unwrap();
+
+    match agg.parse_and_merge("# TYPE requests_total gauge\nrequests_total 2\n", &HashMap::new()).await {
+        Err(AggregationError::TypeConflict { family, .. }) => assert_eq!(family, "requests_total"),
+        other => panic!("expected a type conflict, got {:?}", other),
+    }
+    assert_eq!(agg.to_string().await, "# TYPE requests_total counter\nrequests_total 1\n");
+}
+
+#[tokio::test]
+async fn test_merge_with_colliding_type() {
+    let histogram = "# TYPE latency histogram\nlatency_bucket{le=\"1\"} 1\nlatency_bucket{le=\"+Inf\"} 2\nlatency_sum 3\nlatency_count 2\n";
+    let mut agg = Aggregator::with_config(AggregatorConfig { normalize_counter_names: true, ..AggregatorConfig::default() });
+    agg.parse_and_merge(histogram, &HashMap::new()).await.unwrap();
+
+    // A counter of the same name (which becomes latency_total) would share its HELP and TYPE lines in OpenMetrics scrapes,
+    // and a gauge that's named after one of its series would duplicate it. Neither gets merged, including alongside others
+    for push in ["# TYPE latency counter\nlatency 1\n", "# TYPE other gauge\nother 1\n# TYPE latency_count gauge\nlatency_count 5\n"] {
+        match agg.parse_and_merge(push, &HashMap::new()).await {
+            Err(AggregationError::TypeConflict { family, existing, pushed }) => {
+                assert_eq!(family, "latency");
+                assert_eq!(existing, PrometheusType::Histogram);
+                assert_ne!(pushed, PrometheusType::Histogram);
+            },
+            other => panic!("expected a type conflict, got {:?}", other),
+        }
+    }
+
+    let mut expected = Aggregator::new();
+    expected.parse_and_merge(histogram, &HashMap::new()).await.unwrap();
+    assert_eq!(agg.to_string().await, expected.to_string().await);
+}
+
+#[tokio::test]
+async fn test_openmetrics_output() {
+    let mut agg = Aggregator::new();
+    agg.parse_and_merge("# HELP requests_total The number of requests\n# TYPE requests_total counter\nrequests_total{path=\"/\"} 3\n", &HashMap::new()).await.unwrap();
+
+    assert_eq!(agg.to_openmetrics_string().await, "# TYPE requests counter\n# HELP requests The number of requests\nrequests_total{path=\"/\"} 3\n# EOF\n");
+
+    let empty = Aggregator::new();
+    assert_eq!(empty.to_openmetrics_string().await, "# EOF\n");
+}
+
+#[tokio::test]
+async fn test_units() {
+    let mut agg = Aggregator::new();
+    agg.parse_and_merge("# TYPE foo gauge\n# UNIT foo seconds\nfoo{pod=\"a\"} 1\n", &HashMap::new()).await.unwrap();
+    // Pushes without the unit keep it, and a counter's unit can be given without the suffix
+    agg.parse_and_merge("# TYPE foo gauge\nfoo{pod=\"b\"} 2\n", &HashMap::new()).await.unwrap();
+    agg.parse_and_merge("# TYPE requests_total counter\n# UNIT requests requests\nrequests_total 3\n", &HashMap::new()).await.unwrap();
+
+    assert_eq!(agg.to_openmetrics_string().await, "# TYPE foo gauge
+# UNIT foo seconds
+foo{pod=\"a\"} 1
+foo{pod=\"b\"} 2
+# TYPE requests counter
+# UNIT requests requests
+requests_total 3
+# EOF
+");
+
+    // The Prometheus format doesn't have units
+    assert_eq!(agg.to_string().await, "# TYPE foo gauge\nfoo{pod=\"a\"} 1\nfoo{pod=\"b\"} 2\n# TYPE requests_total counter\nrequests_total 3\n");
+
+    match agg.parse_and_merge("# TYPE foo gauge\n# UNIT foo milliseconds\nfoo{pod=\"a\"} 1000\n", &HashMap::new()).await {
+        Err(AggregationError::UnitConflict { family, existing, pushed }) => assert_eq!((family.as_str(), existing.as_str(), pushed.as_str()), ("foo", "seconds", "milliseconds")),
+        other => panic!("expected a unit conflict, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_exemplars() {
+    let mut agg = Aggregator::new();
+    agg.parse_and_merge("# TYPE requests_total counter\nrequests_total 1 # {trace_id=\"abc\"} 1 1620000000\n", &HashMap::new()).await.unwrap();
+    agg.parse_and_merge("# TYPE requests_total counter\nrequests_total 2 # {trace_id=\"def\",span_id=\"1\"} 0.5\n", &HashMap::new()).await.unwrap();
+    agg.parse_and_merge("# TYPE latency_seconds histogram\nlatency_seconds_bucket{le=\"0.1\"} 1 # {trace_id=\"ghi\"} 0.05\nlatency_seconds_bucket{le=\"+Inf\"} 1\nlatency_seconds_sum 0.05\nlatency_seconds_count 1\n", &HashMap::new()).await.unwrap();
+
+    // The most recent exemplar wins
+    assert_eq!(agg.to_openmetrics_string().await, "# TYPE latency_seconds histogram
+latency_seconds_bucket{le=\"0.1\"} 1 # {trace_id=\"ghi\"} 0.05
+latency_seconds_bucket{le=\"+Inf\"} 1
+latency_seconds_sum 0.05
+latency_seconds_count 1
+# TYPE requests counter
+requests_total 3 # {span_id=\"1\",trace_id=\"def\"} 0.5
+# EOF
+");
+
+    // The Prometheus text format has no exemplars
+    assert!(!agg.to_string().await.contains("trace_id"), "unexpected exemplar in {}", agg.to_string().await);
+}
+
+#[tokio::test]
+async fn test_streaming_parse_matches_buffered() {
+    let push = "untyped_value 3
+# HELP requests_total Requests handled
+# TYPE requests_total counter
+requests_total{path=\"/\"} 1 # {trace_id=\"abc\"} 1
+requests_total{path=\"/about\"} 2
+# TYPE latency_seconds histogram
+latency_seconds_bucket{le=\"0.1\"} 1
+latency_seconds_bucket{le=\"+Inf\"} 2
+latency_seconds_sum 0.3
+latency_seconds_count 2
+# TYPE rpc_seconds summary
+rpc_seconds{quantile=\"0.5\"} 0.2
+rpc_seconds_sum 1
+rpc_seconds_count 5
+# TYPE up gauge
+up 1
+";
+    let labels: HashMap<&str, &str> = vec![("instance", "a")].into_iter().collect();
+
+    // Pushes are merged with the reader, so it has to end up with exactly what the buffered parse would
+    let mut buffered = Aggregator::new();
+    let mut streamed = Aggregator::new();
+    for _ in 0..2 {
+        buffered.parse_and_merge(push, &labels).await.unwrap();
+        streamed.parse_and_merge_reader(push.as_bytes(), TextFormat::Prometheus, &labels).await.unwrap();
+    }
+    assert_eq!(streamed.to_openmetrics_string().await, buffered.to_openmetrics_string().await);
+
+    // A family that's split up is rejected just the same
+    let split = "# TYPE a gauge\na 1\n# TYPE b gauge\nb 1\n# TYPE a gauge\na{x=\"1\"} 2\n";
+    assert_eq!(
+        streamed.parse_and_merge_reader(split.as_bytes(), TextFormat::Prometheus, &HashMap::new()).await.unwrap_err().to_string(),
+        buffered.parse_and_merge(split, &HashMap::new()).await.unwrap_err().to_string()
+    );
+
+    // As is invalid UTF-8, and a push that fails part way through doesn't merge the families before the failure
+    let mut agg = Aggregator::new();
+    assert!(agg.parse_and_merge_reader(&b"# TYPE up gauge\nup 1\n# TYPE down gauge\ndown{x=\"\xff\"} 1\n"[..], TextFormat::Prometheus, &HashMap::new()).await.is_err());
+    assert!(agg.parse_and_merge_reader(&b"# TYPE up gauge\nup 1\n# TYPE down gauge\ndown{ 1\n"[..], TextFormat::Prometheus, &HashMap::new()).await.is_err());
+    assert!(agg.to_string().await.is_empty());
+}
+
+#[tokio::test]
+async fn test_missing_trailing_newline() {
+    let push = "# TYPE requests_total counter\nrequests_total{path=\"/\"} 1\nrequests_total{path=\"/about\"} 2";
+    let expected = "# TYPE requests_total counter
+requests_total{path=\"/\"} 1
+requests_total{path=\"/about\"} 2
+";
+
+    let mut buffered = Aggregator::new();
+    buffered.parse_and_merge(push, &HashMap::new()).await.unwrap();
+    assert_eq!(buffered.to_string().await, expected);
+
+    let mut streamed = Aggregator::new();
+    streamed.parse_and_merge_reader(push.as_bytes(), TextFormat::Prometheus, &HashMap::new()).await.unwrap();
+    assert_eq!(streamed.to_string().await, expected);
+}
+
+#[tokio::test]
+async fn test_crlf_line_endings() {
+    let push = "# HELP up Whether it's up\r\n# TYPE up gauge\r\nup{pod=\"a\"} 1\r\nup{pod=\"b\"} 0\r\n";
+    let expected = "# HELP up Whether it's up
+# TYPE up gauge
+up{pod=\"a\"} 1
+up{pod=\"b\"} 0
+";
+
+    let mut buffered = Aggregator::new();
+    buffered.parse_and_merge(push, &HashMap::new()).await.unwrap();
+    assert_eq!(buffered.to_string().await, expected);
+
+    // Including when the last line has no ending at all
+    let mut streamed = Aggregator::new();
+    streamed.parse_and_merge_reader(push.trim_end().as_bytes(), TextFormat::Prometheus, &HashMap::new()).await.unwrap();
+    assert_eq!(streamed.to_string().await, expected);
+}
+
+/// A store with a bit of everything in it, including a counter with `_created` series and the last push timestamps
+async fn large_store() -> Aggregator {
+    let mut agg = Aggregator::new();
+    let mut push = String::new();
+    for family in 0..200 {
+        push.push_str(&format!("# HELP family_{0}_total Family {0}\n# TYPE family_{0}_total counter\n", family));
+        for series in 0..50 {
+            push.push_str(&format!("family_{}_total{{series=\"{}\"}} {}\n", family, series, series));
+        }
+    }
+    push.push_str("# TYPE latency_seconds histogram\nlatency_seconds_bucket{le=\"0.1\"} 1\nlatency_seconds_bucket{le=\"+Inf\"} 2\nlatency_seconds_sum 0.3\nlatency_seconds_count 2\n");
+    agg.parse_and_merge(&push, &HashMap::from([("job", "big")])).await.unwrap();
+    agg.parse_and_merge("# TYPE jobs_total counter\njobs_total 2\njobs_created 1700000000\n", &HashMap::new()).await.unwrap();
+    agg
+}
+
+async fn streamed(agg: &Aggregator, selectors: Vec<Selector>, openmetrics: bool) -> String {
+    use futures::StreamExt;
+    agg.render_stream(selectors, openmetrics).collect::<Vec<String>>().await.concat()
+}
+
+#[tokio::test]
+async fn test_render_stream() {
+    let agg = large_store().await;
+    assert_eq!(streamed(&agg, vec![], false).await, agg.to_string().await);
+    assert_eq!(streamed(&agg, vec![], true).await, agg.to_openmetrics_string().await);
+
+    let selectors = vec![Selector::from_str("family_7_total{series=\"3\"}").unwrap(), Selector::from_str("jobs_total").unwrap()];
+    assert_eq!(streamed(&agg, selectors.clone(), false).await, agg.to_filtered_string(&selectors).await);
+    assert_eq!(streamed(&agg, selectors.clone(), true).await, agg.to_filtered_openmetrics_string(&selectors).await);
+
+    let empty = Aggregator::new();
+    assert_eq!(streamed(&empty, vec![], false).await, "");
+    assert_eq!(streamed(&empty, vec![], true).await, "# EOF\n");
+}
+
+#[tokio::test]
+async fn test_filtered_output() {
+    let mut agg = Aggregator::new();
+    agg.parse_and_merge("# TYPE requests_total counter\nrequests_total{job=\"api\",path=\"/\"} 1\nrequests_total{job=\"web\",path=\"/\"} 2\n# TYPE up gauge\nup{job=\"api\"} 1\n# TYPE version gauge\nversion 3\n", &HashMap::new()).await.unwrap();
+    let selectors = |selectors: &[&str]| -> Vec<Selector> { selectors.iter().map(|s| Selector::from_str(s).unwrap()).collect() };
+
+    assert_eq!(agg.to_filtered_string(&selectors(&["up"])).await, "# TYPE up gauge\nup{job=\"api\"} 1\n");
+    assert_eq!(agg.to_filtered_string(&selectors(&["{job=\"api\"}"])).await, "# TYPE requests_total counter\nrequests_total{job=\"api\",path=\"/\"} 1\n# TYPE up gauge\nup{job=\"api\"} 1\n");
+    assert_eq!(agg.to_filtered_string(&selectors(&["requests_total{job=\"web\", path=\"/\"}"])).await, "# TYPE requests_total counter\nrequests_total{job=\"web\",path=\"/\"} 2\n");
+
+    // Missing labels match as empty ones, and series matching any selector are included
+    assert_eq!(agg.to_filtered_string(&selectors(&["{job=\"\"}", "up"])).await, "# TYPE up gauge\nup{job=\"api\"} 1\n# TYPE version gauge\nversion 3\n");
+    assert_eq!(agg.to_filtered_string(&selectors(&["requests_total{job!=\"api\"}"])).await, "# TYPE requests_total counter\nrequests_total{job=\"web\",path=\"/\"} 2\n");
+    assert_eq!(agg.to_filtered_string(&selectors(&["nothing"])).await, "");
+    assert_eq!(agg.to_filtered_string(&[]).await, agg.to_string().await);
+
+    for invalid in &["", "{}", "up{job}", "up{job=\"api\"", "{job=~\"a.*\"}", "{1job=\"api\"}"] {
+        assert!(Selector::from_str(invalid).is_err(), "expected {} to be rejected", invalid);
+    }
+}
+
+const HISTOGRAM_PUSH: &str = "# TYPE latency_seconds histogram
+latency_seconds_bucket{le=\"0.1\"} 1
+latency_seconds_bucket{le=\"0.5\"} 2
+latency_seconds_bucket{le=\"1\"} 4
+latency_seconds_bucket{le=\"5\"} 5
+latency_seconds_bucket{le=\"+Inf\"} 6
+latency_seconds_sum 7.5
+latency_seconds_count 6
+";
+
+#[tokio::test]
+async fn test_histogram_aggregation() {
+    let mut agg = Aggregator::new();
+    agg.parse_and_merge(HISTOGRAM_PUSH, &HashMap::new()).await.unwrap();
+    agg.parse_and_merge(HISTOGRAM_PUSH, &HashMap::new()).await.unwrap();
+
+    let output = agg.to_string().await;
+    assert_eq!(output, "# TYPE latency_seconds histogram
+latency_seconds_bucket{le=\"0.1\"} 2
+latency_seconds_bucket{le=\"0.5\"} 4
+latency_seconds_bucket{le=\"1\"} 8
+latency_seconds_bucket{le=\"5\"} 10
+latency_seconds_bucket{le=\"+Inf\"} 12
+latency_seconds_sum 15
+latency_seconds_count 12
+");
+
+    // Cumulative bucket counts must never decrease
+    let counts: Vec<i64> = output.lines().filter(|l| l.starts_with("latency_seconds_bucket"))
+        .map(|l| l.rsplit(' ').next().unwrap().parse().unwrap())
+        .collect();
+    assert!(counts.windows(2).all(|w| w[0] <= w[1]), "non monotonic buckets: {:?}", counts);
+}
+
+#[tokio::test]
+async fn test_histogram_bucket_mismatch() {
+    let mut agg = Aggregator::new();
+    agg.parse_and_merge(HISTOGRAM_PUSH, &HashMap::new()).await.unwrap();
+
+    let mismatched = "# TYPE latency_seconds histogram
+latency_seconds_bucket{le=\"0.1\"} 1
+latency_seconds_bucket{le=\"2\"} 2
+latency_seconds_bucket{le=\"+Inf\"} 3
+latency_seconds_sum 1
+latency_seconds_count 3
+";
+    assert!(agg.parse_and_merge(mismatched, &HashMap::new()).await.is_err(), "merged histograms with different buckets");
+    assert!(agg.to_string().await.contains("latency_seconds_count 6"), "failed merge should leave the stored histogram alone");
+}
+
+fn summary_push(p50: f64, p99: f64, sum: f64, count: u64) -> String {
+    format!("# TYPE rpc_seconds summary
+rpc_seconds{{quantile=\"0.5\"}} {}
+rpc_seconds{{quantile=\"0.99\"}} {}
+rpc_seconds_sum {}
+rpc_seconds_count {}
+", p50, p99, sum, count)
+}
+
+#[tokio::test]
+async fn test_summary_aggregation() {
+    let mut agg = Aggregator::new();
+    agg.parse_and_merge(&summary_push(0.2, 0.9, 10., 5), &HashMap::new()).await.unwrap();
+    agg.parse_and_merge(&summary_push(0.1, 1.5, 20., 7), &HashMap::new()).await.unwrap();
+
+    // Sums and counts add up, quantiles take the latest value by default
+    assert_eq!(agg.to_string().await, "# TYPE rpc_seconds summary
+rpc_seconds{quantile=\"0.5\"} 0.1
+rpc_seconds{quantile=\"0.99\"} 1.5
+rpc_seconds_sum 30
+rpc_seconds_count 12
+");
+}
+
+#[tokio::test]
+async fn test_summary_quantile_policy() {
+    let mut agg = Aggregator::with_config(AggregatorConfig {
+        quantile_merge_policy: QuantileMergePolicy::Max,
+        ..Default::default()
+    });
+    agg.parse_and_merge(&summary_push(0.2, 0.9, 10., 5), &HashMap::new()).await.unwrap();
+    agg.parse_and_merge(&summary_push(0.1, 1.5, 20., 7), &HashMap::new()).await.unwrap();
+
+    let output = agg.to_string().await;
+    assert!(output.contains("rpc_seconds{quantile=\"0.5\"} 0.2\n"), "{}", output);
+    assert!(output.contains("rpc_seconds{quantile=\"0.99\"} 1.5\n"), "{}", output);
+    assert!(output.contains("rpc_seconds_count 12\n"), "{}", output);
+}
+
+#[tokio::test]
+async fn test_summary_invalid_quantile() {
+    let mut agg = Aggregator::new();
+    let push = "# TYPE rpc_seconds summary\nrpc_seconds{quantile=\"median\"} 1\nrpc_seconds_sum 1\nrpc_seconds_count 1\n";
+    assert!(matches!(agg.parse_and_merge(push, &HashMap::new()).await, Err(AggregationError::ParseError(_))));
+}
+
+#[tokio::test]
+async fn test_gauge_aggregation_modes() {
+    let cases = [
+        (GaugeAggregation::Sum, "6"),
+        (GaugeAggregation::Min, "2"),
+        (GaugeAggregation::Max, "4"),
+        (GaugeAggregation::Last, "2"),
+        (GaugeAggregation::Mean, "3"),
+    ];
+
+    for (mode, expected) in cases {
+        let mut agg = Aggregator::with_config(AggregatorConfig {
+            gauge_aggregation: mode,
+            ..Default::default()
+        });
+        agg.parse_and_merge("# TYPE queue_depth gauge\nqueue_depth 4\n", &HashMap::new()).await.unwrap();
+        agg.parse_and_merge("# TYPE queue_depth gauge\nqueue_depth 2\n", &HashMap::new()).await.unwrap();
+
+        assert_eq!(agg.to_string().await, format!("# TYPE queue_depth gauge\nqueue_depth {}\n", expected), "unexpected value for {:?}", mode);
+    }
+}
+
+#[tokio::test]
+async fn test_gauge_mean_after_replace() {
+    let mut agg = Aggregator::with_config(AggregatorConfig {
+        gauge_aggregation: GaugeAggregation::Mean,
+        ..Default::default()
+    });
+
+    // The replaced series is held as a plain gauge, so the next push without a clearmode starts a mean from it
+    agg.parse_and_merge("# TYPE queue_depth gauge\nqueue_depth{clearmode=\"replace\"} 1\n", &HashMap::new()).await.unwrap();
+    agg.parse_and_merge("# TYPE queue_depth gauge\nqueue_depth 2\n", &HashMap::new()).await.unwrap();
+    assert_eq!(agg.to_string().await, "# TYPE queue_depth gauge\nqueue_depth 1.5\n");
+
+    agg.parse_and_merge("# TYPE queue_depth gauge\nqueue_depth 6\n", &HashMap::new()).await.unwrap();
+    assert_eq!(agg.to_string().await, "# TYPE queue_depth gauge\nqueue_depth 3\n");
+}
+
+#[tokio::test]
+async fn test_gauge_clearmode_overrides_aggregation() {
+    let mut agg = Aggregator::with_config(AggregatorConfig {
+        gauge_aggregation: GaugeAggregation::Max,
+        ..Default::default()
+    });
+    agg.parse_and_merge("# TYPE queue_depth gauge\nqueue_depth{clearmode=\"replace\"} 4\n", &HashMap::new()).await.unwrap();
+    agg.parse_and_merge("# TYPE queue_depth gauge\nqueue_depth{clearmode=\"replace\"} 2\n", &HashMap::new()).await.unwrap();
+
+    assert_eq!(agg.to_string().await, "# TYPE queue_depth gauge\nqueue_depth 2\n");
+}
+
+#[tokio::test]
+async fn test_counter_aggregation_modes() {
+    // The same cluster-wide total, pushed by two replicas
+    for (mode, expected) in [(CounterAggregation::Sum, "250"), (CounterAggregation::Max, "130")] {
+        let mut agg = Aggregator::with_config(AggregatorConfig {
+            counter_aggregation: mode,
+            ..Default::default()
+        });
+        agg.parse_and_merge("# TYPE orders_total counter\norders_total 120\n", &HashMap::new()).await.unwrap();
+        agg.parse_and_merge("# TYPE orders_total counter\norders_total 130\n", &HashMap::new()).await.unwrap();
+
+        assert_eq!(agg.to_string().await, format!("# TYPE orders_total counter\norders_total {}\n", expected), "unexpected value for {:?}", mode);
+    }
+
+    // A replica that's behind the others is ignored, rather than counted as a reset
+    let mut agg = Aggregator::with_config(AggregatorConfig { counter_aggregation: CounterAggregation::Max, ..Default::default() });
+    agg.parse_and_merge("# TYPE orders_total counter\norders_total 130\n", &HashMap::new()).await.unwrap();
+    agg.parse_and_merge("# TYPE orders_total counter\norders_total 120\n", &HashMap::new()).await.unwrap();
+    assert_eq!(agg.to_string().await, "# TYPE orders_total counter\norders_total 130\n");
+    assert_eq!(agg.metrics().counter_resets(), 0);
+}
+
+#[tokio::test]
+async fn test_counter_clearmode_overrides_aggregation() {
+    let mut agg = Aggregator::new();
+    agg.parse_and_merge("# TYPE orders_total counter\norders_total{clearmode=\"max\"} 120\n", &HashMap::new()).await.unwrap();
+    agg.parse_and_merge("# TYPE orders_total counter\norders_total{clearmode=\"max\"} 130\n", &HashMap::new()).await.unwrap();
+    assert_eq!(agg.to_string().await, "# TYPE orders_total counter\norders_total 130\n");
+
+    let mut agg = Aggregator::with_config(AggregatorConfig { counter_aggregation: CounterAggregation::Max, ..Default::default() });
+    agg.parse_and_merge("# TYPE orders_total counter\norders_total{clearmode=\"aggregate\"} 120\n", &HashMap::new()).await.unwrap();
+    agg.parse_and_merge("# TYPE orders_total counter\norders_total{clearmode=\"aggregate\"} 130\n", &HashMap::new()).await.unwrap();
+    assert_eq!(agg.to_string().await, "# TYPE orders_total counter\norders_total 250\n");
+}
+
+#[tokio::test]
+async fn test_clearmode_aggregate_push() {
+    let mut agg = Aggregator::new();
+    agg.parse_and_merge("foo{clearmode=\"aggregate\"} 1\n", &HashMap::new()).await.unwrap();
+    agg.parse_and_merge("foo{clearmode=\"aggregate\"} 1\n", &HashMap::new()).await.unwrap();
+
+    let output = agg.to_string().await;
+    assert_eq!(output, "foo 2\n");
+    assert!(!output.contains("clearmode"));
+
+    // Aggregating is the default without a clearmode too, and the two are the same series
+    agg.parse_and_merge("foo 1\n", &HashMap::new()).await.unwrap();
+    assert_eq!(agg.to_string().await, "foo 3\n");
+}
+
+#[tokio::test]
+async fn test_clearmode_aggregate_with_labels() {
+    let mut agg = Aggregator::new();
+    agg.parse_and_merge("# TYPE foo_total counter\nfoo_total{path=\"/\",clearmode=\"aggregate\"} 1\nfoo_total{path=\"/bar\",clearmode=\"aggregate\"} 5\n", &HashMap::new()).await.unwrap();
+    agg.parse_and_merge("# TYPE foo_total counter\nfoo_total{path=\"/\",clearmode=\"aggregate\"} 2\n", &HashMap::new()).await.unwrap();
+
+    let output = agg.to_string().await;
+    assert!(output.contains("foo_total{path=\"/\"} 3\n"), "{}", output);
+    assert!(output.contains("foo_total{path=\"/bar\"} 5\n"), "{}", output);
+    assert!(!output.contains("clearmode"), "{}", output);
+}
+
+#[tokio::test]
+async fn test_clearmode_applies_to_new_series_in_existing_family() {
+    let mut agg = Aggregator::new();
+    agg.parse_and_merge("# TYPE mem gauge\nmem{pod=\"a\",clearmode=\"mean5m\"} 1\n", &HashMap::new()).await.unwrap();
+
+    // A second labelset of an existing family should become a pebble too, rather than a plain gauge
+    agg.parse_and_merge("# TYPE mem gauge\nmem{pod=\"b\",clearmode=\"mean5m\"} 2\n", &HashMap::new()).await.unwrap();
+    agg.parse_and_merge("# TYPE mem gauge\nmem{pod=\"b\",clearmode=\"mean5m\"} 4\n", &HashMap::new()).await.unwrap();
+
+    let output = agg.to_string().await;
+    assert!(output.contains("mem{pod=\"b\"} 3\n"), "{}", output);
+}
+
+#[cfg(feature="clustering")]
+#[tokio::test]
+async fn test_put_back_taken_series() {
+    let mut agg = Aggregator::new();
+    agg.parse_and_merge("# TYPE mem gauge\nmem{pod=\"a\",clearmode=\"mean5m\"} 1\n", &HashMap::new()).await.unwrap();
+    agg.parse_and_merge("# TYPE mem gauge\nmem{pod=\"b\",clearmode=\"mean5m\"} 2\n", &HashMap::new()).await.unwrap();
+    agg.parse_and_merge("# TYPE mem gauge\nmem{pod=\"b\",clearmode=\"mean5m\"} 4\n", &HashMap::new()).await.unwrap();
+    let before = agg.to_string().await;
+
+    let taken = agg.take_series_by(|labels| labels.get("pod").unwrap_or(&"").to_string()).await;
+    assert_eq!(taken.iter().map(|taken| (taken.key.as_str(), taken.body.as_str())).collect::<Vec<_>>(), vec![
+        ("a", "# TYPE mem gauge\nmem{pod=\"a\"} 1\n"),
+        ("b", "# TYPE mem gauge\nmem{pod=\"b\"} 3\n"),
+    ]);
+    assert_eq!(agg.to_string().await, "");
+    assert_eq!(agg.series_count(), 0);
+
+    // A series that's pushed to while it's out is newer than the one that was taken, so it stays
+    agg.parse_and_merge("# TYPE mem gauge\nmem{pod=\"a\"} 7\n", &HashMap::new()).await.unwrap();
+    for taken in taken {
+        agg.put_back(taken).await.unwrap();
+    }
+    assert_eq!(agg.to_string().await, before.replace("mem{pod=\"a\"} 1", "mem{pod=\"a\"} 7"));
+    assert_eq!(agg.series_count(), 2);
+
+    // The series that were put back are as they were, rather than rendered and parsed again, so the mean carries on
+    agg.parse_and_merge("# TYPE mem gauge\nmem{pod=\"b\",clearmode=\"mean5m\"} 9\n", &HashMap::new()).await.unwrap();
+    assert!(agg.to_string().await.contains("mem{pod=\"b\"} 5\n"), "{}", agg.to_string().await);
+}
+
+#[tokio::test]
+async fn test_clearmode_replace_push() {
+    let mut agg = Aggregator::new();
+    agg.parse_and_merge("q{clearmode=\"replace\"} 5\n", &HashMap::new()).await.unwrap();
+    agg.parse_and_merge("q{clearmode=\"replace\"} 2\n", &HashMap::new()).await.unwrap();
+
+    assert_eq!(agg.to_string().await, "q 2\n");
+
+    // Replace also works on types that aggregate by default
+    agg.parse_and_merge("# TYPE c_total counter\nc_total{clearmode=\"replace\"} 5\n", &HashMap::new()).await.unwrap();
+    agg.parse_and_merge("# TYPE c_total counter\nc_total{clearmode=\"replace\"} 2\n", &HashMap::new()).await.unwrap();
+    assert!(agg.to_string().await.contains("c_total 2\n"));
+}
+
+#[tokio::test]
+async fn test_clearmode_replace_windowed_series() {
+    let mut agg = Aggregator::new();
+    agg.parse_and_merge("# TYPE mem gauge\nmem{clearmode=\"mean5m\"} 10\n", &HashMap::new()).await.unwrap();
+    agg.parse_and_merge("# TYPE mem gauge\nmem{clearmode=\"replace\"} 2\n", &HashMap::new()).await.unwrap();
+
+    assert_eq!(agg.to_string().await, "# TYPE mem gauge\nmem 2\n");
+}
+
+#[tokio::test]
+async fn test_clearmode_family_push() {
+    let mut agg = Aggregator::new();
+    agg.parse_and_merge("# TYPE http_requests gauge
+http_requests{path=\"/a\"} 1
+http_requests{path=\"/b\"} 2
+http_requests{path=\"/c\"} 3
+", &HashMap::new()).await.unwrap();
+
+    agg.parse_and_merge("# TYPE http_requests gauge\nhttp_requests{path=\"/d\",clearmode=\"family\"} 4\n", &HashMap::new()).await.unwrap();
+    assert_eq!(agg.to_string().await, "# TYPE http_requests gauge\nhttp_requests{path=\"/d\"} 4\n");
+
+    // Other series pushed alongside the clearing one survive, in the order they were pushed
+    agg.parse_and_merge("# TYPE http_requests gauge
+http_requests{path=\"/e\",clearmode=\"family\"} 5
+http_requests{path=\"/f\",clearmode=\"replace\"} 6
+", &HashMap::new()).await.unwrap();
+    assert_eq!(agg.to_string().await, "# TYPE http_requests gauge\nhttp_requests{path=\"/e\"} 5\nhttp_requests{path=\"/f\"} 6\n");
+}
+
+#[tokio::test]
+async fn test_clearmode_family_converts_other_series() {
+    let mut agg = Aggregator::new();
+    agg.parse_and_merge("# TYPE mem gauge\nmem{pod=\"a\"} 1\n", &HashMap::new()).await.unwrap();
+    agg.parse_and_merge("# TYPE mem gauge\nmem{pod=\"b\",clearmode=\"family\"} 1\nmem{pod=\"c\",clearmode=\"mean5m\"} 2\n", &HashMap::new()).await.unwrap();
+    agg.parse_and_merge("# TYPE mem gauge\nmem{pod=\"c\",clearmode=\"mean5m\"} 4\n", &HashMap::new()).await.unwrap();
+
+    assert_eq!(agg.to_string().await, "# TYPE mem gauge\nmem{pod=\"b\"} 1\nmem{pod=\"c\"} 3\n");
+}
+
+#[tokio::test]
+async fn test_ttl_expires_stale_series() {
+    let mut agg = Aggregator::with_ttl(AggregatorConfig::default(), Duration::from_millis(100));
+    agg.parse_and_merge("# TYPE stale gauge\nstale 1\n# TYPE fresh gauge\nfresh{pod=\"a\"} 1\nfresh{pod=\"b\"} 1\n", &HashMap::new()).await.unwrap();
+
+    // Keep one series alive by pushing to it more often than the ttl
+    for _ in 0..4 {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        agg.parse_and_merge("# TYPE fresh gauge\nfresh{pod=\"a\"} 2\n", &HashMap::new()).await.unwrap();
+    }
+
+    assert_eq!(agg.to_string().await, "# TYPE fresh gauge\nfresh{pod=\"a\"} 2\n");
+}
+
+#[tokio::test]
+async fn test_generation_follows_changes() {
+    let mut agg = Aggregator::with_ttl(AggregatorConfig::default(), Duration::from_millis(100));
+    assert_ne!(agg.generation(), agg.new_tenant().generation());
+
+    let generation = agg.generation();
+    agg.parse_and_merge("# TYPE mem gauge\nmem 1\n", &HashMap::new()).await.unwrap();
+    assert_ne!(agg.generation(), generation);
+
+    // A push that fails changes nothing, so it stays at the same generation
+    let generation = agg.generation();
+    assert!(agg.parse_and_merge("# TYPE mem counter\nmem 1\n", &HashMap::new()).await.is_err());
+    assert_eq!(agg.generation(), generation);
+
+    // Expiring the series is a change too, even though nothing asked for it
+    tokio::time::sleep(Duration::from_millis(250)).await;
+    assert_eq!(agg.to_string().await, "");
+    assert_ne!(agg.generation(), generation);
+
+    let generation = agg.generation();
+    agg.clear().await;
+    assert_ne!(agg.generation(), generation);
+}
+
+#[tokio::test]
+async fn test_series_ttls_override_the_ttl() {
+    let mut agg = Aggregator::with_ttl(AggregatorConfig {
+        series_ttls: vec![("{job=\"batch\"}".parse().unwrap(), Duration::from_secs(3600))],
+        ..Default::default()
+    }, Duration::from_millis(100));
+    let job = |job| vec![("job", job)].into_iter().collect::<HashMap<&str, &str>>();
+    agg.parse_and_merge("# TYPE rows_processed gauge\nrows_processed 10\n", &job("batch")).await.unwrap();
+    agg.parse_and_merge("# TYPE rows_processed gauge\nrows_processed 20\n", &job("api")).await.unwrap();
+
+    // The api job expires by the ttl, but the batch job outlives it, along with when it was last pushed to
+    tokio::time::sleep(Duration::from_millis(250)).await;
+    let scrape = agg.to_string().await;
+    assert!(scrape.ends_with("# TYPE rows_processed gauge\nrows_processed{job=\"batch\"} 10\n"), "{}", scrape);
+    assert!(last_push_timestamp(&scrape, "batch").is_some(), "{}", scrape);
+    assert!(last_push_timestamp(&scrape, "api").is_none(), "{}", scrape);
+}
+
+#[tokio::test]
+async fn test_reset_interval_clears_the_store() {
+    let interval = Duration::from_millis(200);
+    let until_boundary = || {
+        let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos();
+        Duration::from_nanos((interval.as_nanos() - since_epoch % interval.as_nanos()) as u64)
+    };
+
+    // The reset wins over a ttl that would have kept the series for much longer
+    let mut agg = Aggregator::with_ttl(AggregatorConfig { reset_interval: Some(interval), ..Default::default() }, Duration::from_secs(3600));
+    if until_boundary() < Duration::from_millis(100) {
+        tokio::time::sleep(until_boundary() + Duration::from_millis(10)).await;
+    }
+
+    let job = vec![("job", "batch")].into_iter().collect::<HashMap<&str, &str>>();
+    agg.parse_and_merge("# TYPE rows_processed_total counter\nrows_processed_total 10\n", &job).await.unwrap();
+    agg.parse_and_merge("# TYPE rows_processed_total counter\nrows_processed_total 5\n", &job).await.unwrap();
+    assert!(agg.to_string().await.contains("rows_processed_total{job=\"batch\"} 15\n"));
+
+    // Once the boundary's passed, the next window aggregates from nothing
+    tokio::time::sleep(until_boundary() + Duration::from_millis(50)).await;
+    assert_eq!(agg.to_string().await, "");
+    agg.parse_and_merge("# TYPE rows_processed_total counter\nrows_processed_total 5\n", &job).await.unwrap();
+    assert!(agg.to_string().await.contains("rows_processed_total{job=\"batch\"} 5\n"));
+}
+
+#[tokio::test]
+async fn test_counter_reset_detection() {
+    let mut agg = Aggregator::new();
+    agg.parse_and_merge("# TYPE c_total counter\nc_total 10\n", &HashMap::new()).await.unwrap();
+    agg.parse_and_merge("# TYPE c_total counter\nc_total 12\n", &HashMap::new()).await.unwrap();
+    assert_eq!(agg.metrics().counter_resets(), 0);
+
+    agg.parse_and_merge("# TYPE c_total counter\nc_total 3\n", &HashMap::new()).await.unwrap();
+    assert_eq!(agg.metrics().counter_resets(), 1);
+
+    // The count is the gateway's own, so it stays out of the aggregated metrics
+    assert_eq!(agg.to_string().await, "# TYPE c_total counter\nc_total 25\n");
+}
+
+#[tokio::test]
+async fn test_counter_reset_ignore_policy() {
+    let mut agg = Aggregator::with_config(AggregatorConfig {
+        counter_reset_policy: CounterResetPolicy::Ignore,
+        ..Default::default()
+    });
+
+    agg.parse_and_merge("# TYPE c_total counter\nc_total{job=\"a\"} 10\nc_total{job=\"b\"} 10\n", &HashMap::new()).await.unwrap();
+    agg.parse_and_merge("# TYPE c_total counter\nc_total{job=\"a\"} 3\nc_total{job=\"b\"} 11\n", &HashMap::new()).await.unwrap();
+    // The decrease is counted but not merged, and the next push is compared against it
+    agg.parse_and_merge("# TYPE c_total counter\nc_total{job=\"a\"} 4\n", &HashMap::new()).await.unwrap();
+
+    assert_eq!(agg.to_string().await, "# TYPE c_total counter\nc_total{job=\"a\"} 14\nc_total{job=\"b\"} 21\n");
+    assert_eq!(agg.metrics().counter_resets(), 1);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_concurrent_pushes_to_distinct_families() {
+    let agg = Aggregator::new();
+
+    let mut handles = Vec::new();
+    for i in 0..64 {
+        let mut agg = agg.clone();
+        handles.push(tokio::spawn(async move {
+            for _ in 0..10 {
+                let push = format!("# TYPE family_{}_total counter\nfamily_{}_total 1\n", i, i);
+                agg.parse_and_merge(&push, &HashMap::new()).await.unwrap();
+            }
+        }));
+    }
+
+    for handle in handles {
+        handle.await.unwrap();
+    }
+
+    let mut names: Vec<String> = (0..64).map(|i| format!("family_{}_total", i)).collect();
+    names.sort();
+    let expected: String = names.iter().map(|name| format!("# TYPE {} counter\n{} 10\n", name, name)).collect();
+    assert_eq!(agg.to_string().await, expected);
+
+    // The output shouldn't depend on how the families were sharded
+    let mut single = Aggregator::with_config(AggregatorConfig { shards: 1, ..Default::default() });
+    for name in names.iter() {
+        single.parse_and_merge(&format!("# TYPE {} counter\n{} 10\n", name, name), &HashMap::new()).await.unwrap();
+    }
+    assert_eq!(single.to_string().await, expected);
+}
+
+#[tokio::test]
+async fn test_max_series_per_family() {
+    let mut agg = Aggregator::with_config(AggregatorConfig {
+        max_series_per_family: Some(2),
+        ..Default::default()
+    });
+
+    agg.parse_and_merge("# TYPE up gauge\nup{pod=\"a\"} 1\nup{pod=\"b\"} 1\n", &HashMap::new()).await.unwrap();
+
+    match agg.parse_and_merge("# TYPE up gauge\nup{pod=\"c\"} 1\n", &HashMap::new()).await {
+        Err(AggregationError::CardinalityExceeded { family, limit }) => {
+            assert_eq!(family, "up");
+            assert_eq!(limit, 2);
+        },
+        other => panic!("expected the third series to be rejected, got {:?}", other),
+    }
+
+    // Series that already exist can still be updated at the limit
+    agg.parse_and_merge("# TYPE up gauge\nup{pod=\"a\"} 0\n", &HashMap::new()).await.unwrap();
+    assert_eq!(agg.to_string().await, "# TYPE up gauge\nup{pod=\"a\"} 0\nup{pod=\"b\"} 1\n");
+
+    // A brand new family is held to the limit too
+    assert!(agg.parse_and_merge("# TYPE down gauge\ndown{pod=\"a\"} 1\ndown{pod=\"b\"} 1\ndown{pod=\"c\"} 1\n", &HashMap::new()).await.is_err());
+
+    // A push with a family over the limit doesn't get any of its other families merged either
+    let before = agg.to_string().await;
+    assert!(agg.parse_and_merge("# TYPE a_gauge gauge\na_gauge 1\n# TYPE up gauge\nup{pod=\"a\"} 5\nup{pod=\"c\"} 1\n# TYPE z_gauge gauge\nz_gauge 1\n", &HashMap::new()).await.is_err());
+    assert_eq!(agg.to_string().await, before);
+}
+
+#[tokio::test]
+async fn test_failed_merges_change_nothing() {
+    let mut agg = Aggregator::new();
+    agg.parse_and_merge("# TYPE latency histogram\nlatency_bucket{le=\"1\"} 1\nlatency_bucket{le=\"+Inf\"} 1\nlatency_sum 1\nlatency_count 1\n# TYPE requests_total counter\nrequests_total 1\n", &HashMap::new()).await.unwrap();
+    let before = agg.to_string().await;
+
+    // The counter comes first, but the histogram's buckets don't match, so neither of them is merged
+    let push = "# TYPE requests_total counter\nrequests_total 1\n# TYPE latency histogram\nlatency_bucket{le=\"2\"} 1\nlatency_bucket{le=\"+Inf\"} 1\nlatency_sum 1\nlatency_count 1\n";
+    assert!(agg.parse_and_merge(push, &HashMap::new()).await.is_err());
+    assert_eq!(agg.to_string().await, before);
+
+    // Likewise when series that are collapsed together by dropping labels can't be merged with each other
+    let mut agg = Aggregator::with_config(AggregatorConfig { drop_labels: vec!["instance".to_owned()], ..Default::default() });
+    agg.parse_and_merge("# TYPE requests_total counter\nrequests_total 1\n", &HashMap::new()).await.unwrap();
+    let before = agg.to_string().await;
+    let push = "# TYPE requests_total counter\nrequests_total 1\n# TYPE latency histogram
+latency_bucket{instance=\"a\",le=\"1\"} 1\nlatency_bucket{instance=\"a\",le=\"+Inf\"} 1\nlatency_sum{instance=\"a\"} 1\nlatency_count{instance=\"a\"} 1
+latency_bucket{instance=\"b\",le=\"2\"} 1\nlatency_bucket{instance=\"b\",le=\"+Inf\"} 1\nlatency_sum{instance=\"b\"} 1\nlatency_count{instance=\"b\"} 1\n";
+    assert!(agg.parse_and_merge(push, &HashMap::new()).await.is_err());
+    assert_eq!(agg.to_string().await, before);
+}
+
+#[tokio::test]
+async fn test_max_total_series() {
+    let mut agg = Aggregator::with_config(AggregatorConfig {
+        max_total_series: Some(3),
+        ..Default::default()
+    });
+
+    // Each push gets a later last pushed time than the one before it
+    for push in ["# TYPE up gauge\nup{pod=\"a\"} 1\n", "# TYPE up gauge\nup{pod=\"b\"} 1\n", "# TYPE requests_total counter\nrequests_total 1\n", "# TYPE up gauge\nup{pod=\"a\"} 1\n"] {
+        agg.parse_and_merge(push, &HashMap::new()).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(2)).await;
+    }
+    assert_eq!(agg.series_count(), 3);
+
+    // b is the one that's gone longest without a push, since a was pushed to again
+    agg.parse_and_merge("# TYPE down gauge\ndown 1\n", &HashMap::new()).await.unwrap();
+    assert_eq!(agg.series_count(), 3);
+    assert_eq!(agg.metrics().families(StoreStats::default()).iter().find(|family| family.family_name == "gravel_evicted_series_total").unwrap().to_string(),
+        "# HELP gravel_evicted_series_total Series evicted to stay within the total series budget\n# TYPE gravel_evicted_series_total counter\ngravel_evicted_series_total 1\n");
+
+    let scrape = agg.to_string().await;
+    assert!(!scrape.contains("pod=\"b\""), "{}", scrape);
+    assert!(scrape.contains("up{pod=\"a\"} 1\n") && scrape.contains("requests_total 1\n") && scrape.contains("down 1\n"), "{}", scrape);
+
+    // A family whose last series is evicted goes with it
+    tokio::time::sleep(Duration::from_millis(2)).await;
+    agg.parse_and_merge("# TYPE up gauge\nup{pod=\"c\"} 1\nup{pod=\"d\"} 1\n", &HashMap::new()).await.unwrap();
+    assert_eq!(agg.to_string().await, "# TYPE down gauge\ndown 1\n# TYPE up gauge\nup{pod=\"c\"} 1\nup{pod=\"d\"} 1\n");
+}
+
+#[tokio::test]
+async fn test_series_count() {
+    let mut agg = Aggregator::with_config(AggregatorConfig {
+        max_total_series: Some(4),
+        max_series_per_family: Some(3),
+        ..Default::default()
+    });
+
+    // The count that the budget is checked against keeps up with everything that adds or removes series
+    async fn check(agg: &Aggregator, expected: usize) {
+        assert_eq!(agg.series_count(), expected);
+        assert_eq!(agg.store_stats().await.series, expected);
+    }
+
+    agg.parse_and_merge("# TYPE up gauge\nup{pod=\"a\"} 1\nup{pod=\"b\"} 1\n", &HashMap::from([("job", "foo")])).await.unwrap();
+    agg.parse_and_merge("# TYPE requests_total counter\nrequests_total 1\n", &HashMap::from([("job", "bar")])).await.unwrap();
+    check(&agg, 3).await;
+
+    // A push that fails doesn't add any, or evict any to make room
+    assert!(agg.parse_and_merge("# TYPE down gauge\ndown 1\n# TYPE up gauge\nup{pod=\"c\"} 1\nup{pod=\"d\"} 1\n", &HashMap::from([("job", "foo")])).await.is_err());
+    check(&agg, 3).await;
+    assert!(agg.metrics().families(StoreStats::default()).iter().find(|family| family.family_name == "gravel_evicted_series_total").unwrap().to_string().ends_with(" 0\n"));
+
+    tokio::time::sleep(Duration::from_millis(2)).await;
+    agg.parse_and_merge("# TYPE down gauge\ndown{pod=\"x\"} 1\ndown{pod=\"y\"} 1\n", &HashMap::from([("job", "baz")])).await.unwrap();
+    check(&agg, 4).await;
+
+    agg.delete_matching(&HashMap::from([("job", "foo")])).await;
+    check(&agg, agg.store_stats().await.series).await;
+
+    let snapshot = agg.to_snapshot().await;
+    agg.clear().await;
+    check(&agg, 0).await;
+    agg.restore_snapshot(snapshot).await.unwrap();
+    check(&agg, agg.store_stats().await.series).await;
+
+    // A push with more new series than the whole budget can't keep all of them
+    agg.parse_and_merge("# TYPE a gauge\na{pod=\"1\"} 1\na{pod=\"2\"} 1\na{pod=\"3\"} 1\n# TYPE b gauge\nb{pod=\"1\"} 1\nb{pod=\"2\"} 1\n", &HashMap::new()).await.unwrap();
+    check(&agg, 4).await;
+}
+
+#[tokio::test]
+async fn test_out_of_order_replacements() {
+    let mut agg = Aggregator::new();
+    agg.parse_and_merge("# TYPE temperature gauge\ntemperature 2 2000\n", &HashMap::new()).await.unwrap();
+
+    // The older push arrived late, so it loses to the newer one
+    agg.parse_and_merge("# TYPE temperature gauge\ntemperature 1 1000\n", &HashMap::new()).await.unwrap();
+    assert_eq!(agg.to_string().await, "# TYPE temperature gauge\ntemperature 2 2000\n");
+
+    agg.parse_and_merge("# TYPE temperature gauge\ntemperature 3 3000\n", &HashMap::new()).await.unwrap();
+    assert_eq!(agg.to_string().await, "# TYPE temperature gauge\ntemperature 3 3000\n");
+
+    // Without a timestamp, the push that arrives last wins
+    agg.parse_and_merge("# TYPE temperature gauge\ntemperature 4\n", &HashMap::new()).await.unwrap();
+    assert_eq!(agg.to_string().await, "# TYPE temperature gauge\ntemperature 4\n");
+    agg.parse_and_merge("# TYPE temperature gauge\ntemperature 5 1000\n", &HashMap::new()).await.unwrap();
+    assert_eq!(agg.to_string().await, "# TYPE temperature gauge\ntemperature 5 1000\n");
+
+    // Summed series don't care about the order
+    agg.parse_and_merge("# TYPE requests_total counter\nrequests_total 1 2000\n", &HashMap::new()).await.unwrap();
+    agg.parse_and_merge("# TYPE requests_total counter\nrequests_total 1 1000\n", &HashMap::new()).await.unwrap();
+    assert!(agg.to_string().await.contains("requests_total 2"));
+}
+
+#[tokio::test]
+async fn test_external_labels() {
+    let mut external_labels = HashMap::new();
+    external_labels.insert("gateway".to_owned(), "east-1".to_owned());
+    external_labels.insert("region".to_owned(), "us".to_owned());
+    let mut agg = Aggregator::with_config(AggregatorConfig {
+        external_labels,
+        ..Default::default()
+    });
+
+    let mut labels = HashMap::new();
+    labels.insert("job", "api");
+    agg.parse_and_merge("# TYPE requests_total counter\nrequests_total 1\n", &labels).await.unwrap();
+    agg.parse_and_merge("# TYPE requests_total counter\nrequests_total 2\n", &labels).await.unwrap();
+
+    // Labels in the push win, whether they're in the body or the path
+    agg.parse_and_merge("# TYPE up gauge\nup{gateway=\"west-1\"} 1\n", &HashMap::new()).await.unwrap();
+    labels.insert("region", "eu");
+    agg.parse_and_merge("# TYPE version gauge\nversion 1\n", &labels).await.unwrap();
+
+    let output = agg.to_string().await;
+    let output: Vec<&str> = output.lines().filter(|line| !line.starts_with('#') && !line.starts_with("gravel_")).collect();
+    assert_eq!(output, vec![
+        "requests_total{job=\"api\",gateway=\"east-1\",region=\"us\"} 3",
+        "up{gateway=\"west-1\",region=\"us\"} 1",
+        "version{job=\"api\",region=\"eu\",gateway=\"east-1\"} 1",
+    ]);
+}
+
+#[tokio::test]
+async fn test_drop_labels() {
+    let mut agg = Aggregator::with_config(AggregatorConfig {
+        drop_labels: vec!["instance".to_owned()],
+        ..Default::default()
+    });
+
+    agg.parse_and_merge("# TYPE c_total counter\nc_total{instance=\"a\"} 1\n", &HashMap::new()).await.unwrap();
+    agg.parse_and_merge("# TYPE c_total counter\nc_total{instance=\"b\"} 1\n", &HashMap::new()).await.unwrap();
+    assert_eq!(agg.to_string().await, "# TYPE c_total counter\nc_total 2\n");
+
+    // Series that collapse into one within a single push are merged too
+    agg.parse_and_merge("# TYPE c_total counter\nc_total{instance=\"a\"} 1\nc_total{instance=\"b\"} 2\n", &HashMap::new()).await.unwrap();
+    let mut labels = HashMap::new();
+    labels.insert("instance", "c");
+    agg.parse_and_merge("# TYPE c_total counter\nc_total 3\n", &labels).await.unwrap();
+    assert_eq!(agg.to_string().await, "# TYPE c_total counter\nc_total 8\n");
+}
+
+fn relabeling_aggregator(rules: &str) -> Aggregator {
+    Aggregator::with_config(AggregatorConfig {
+        relabel_rules: serde_json::from_str(rules).unwrap(),
+        ..Default::default()
+    })
+}
+
+#[tokio::test]
+async fn test_relabel_rename() {
+    let mut agg = relabeling_aggregator(r#"[{"source_label": "host", "target_label": "instance", "action": "rename"}]"#);
+    agg.parse_and_merge("# TYPE up gauge\nup{host=\"a\"} 1\n", &HashMap::new()).await.unwrap();
+    assert_eq!(agg.to_string().await, "# TYPE up gauge\nup{instance=\"a\"} 1\n");
+
+    // Series that don't have the source label are left alone
+    let mut agg = relabeling_aggregator(r#"[{"source_label": "host", "regex": "(.+)", "target_label": "instance", "action": "rename"}]"#);
+    agg.parse_and_merge("# TYPE up gauge\nup{instance=\"b\"} 1\n", &HashMap::new()).await.unwrap();
+    assert_eq!(agg.to_string().await, "# TYPE up gauge\nup{instance=\"b\"} 1\n");
+}
+
+#[tokio::test]
+async fn test_relabel_rewrites_values() {
+    let mut agg = relabeling_aggregator(r#"[{"source_label": "path", "regex": "/api/(v\\d+)/users/\\d+", "replacement": "/api/$1/users/:id"}]"#);
+    agg.parse_and_merge("# TYPE requests_total counter\nrequests_total{path=\"/api/v1/users/123\"} 1\nrequests_total{path=\"/api/v1/users/456\"} 2\n", &HashMap::new()).await.unwrap();
+    agg.parse_and_merge("# TYPE requests_total counter\nrequests_total{path=\"/api/v1/users/789\"} 3\nrequests_total{path=\"/health\"} 1\n", &HashMap::new()).await.unwrap();
+
+    assert_eq!(agg.to_string().await, "# TYPE requests_total counter
+requests_total{path=\"/api/v1/users/:id\"} 6
+requests_total{path=\"/health\"} 1
+");
+}
+
+#[tokio::test]
+async fn test_reject_non_finite() {
+    const NAN_GAUGE: &str = "# TYPE temperature gauge\ntemperature{room=\"a\"} NaN\n";
+    const INF_COUNTER: &str = "# TYPE requests_total counter\nrequests_total +Inf\n";
+
+    // By default, non-finite values are stored like any other
+    let mut agg = Aggregator::new();
+    agg.parse_and_merge(NAN_GAUGE, &HashMap::new()).await.unwrap();
+    agg.parse_and_merge(INF_COUNTER, &HashMap::new()).await.unwrap();
+    assert_eq!(agg.to_string().await, "# TYPE requests_total counter\nrequests_total +Inf\n# TYPE temperature gauge\ntemperature{room=\"a\"} NaN\n");
+
+    let mut agg = Aggregator::with_config(AggregatorConfig {
+        reject_non_finite: true,
+        ..Default::default()
+    });
+
+    let mut labels = HashMap::new();
+    labels.insert("job", "thermostat");
+    match agg.parse_and_merge(NAN_GAUGE, &labels).await {
+        Err(AggregationError::NonFiniteValue { series }) => assert_eq!(series, "temperature{room=\"a\",job=\"thermostat\"}"),
+        other => panic!("expected the NaN to be rejected, got {:?}", other),
+    }
+
+    match agg.parse_and_merge(INF_COUNTER, &HashMap::new()).await {
+        Err(AggregationError::NonFiniteValue { series }) => assert_eq!(series, "requests_total"),
+        other => panic!("expected the +Inf to be rejected, got {:?}", other),
+    }
+
+    assert_eq!(agg.to_string().await, "");
+}
+
+#[tokio::test]
+async fn test_histogram_validation() {
+    // The parser won't take a histogram that isn't cumulative, so they're put in the store by restoring a snapshot
+    let histogram = |buckets: [i64; 3], count: u64| serde_json::from_str::<crate::snapshot::StoreSnapshot>(&format!(
+        "{{\"version\":1,\"last_pushes\":{{}},\"families\":[{{\"name\":\"latency\",\"type\":\"histogram\",\"help\":\"\",\"unit\":\"\",\"label_names\":[],\"series\":[{{\"label_values\":[],\"timestamp\":null,\"last_pushed\":0,\"last_counter_value\":null,\"value\":{{\"kind\":\"histogram\",\"sum\":2,\"count\":{},\"created\":null,\"buckets\":[{{\"upper_bound\":0.5,\"count\":{},\"exemplar\":null}},{{\"upper_bound\":1,\"count\":{},\"exemplar\":null}},{{\"upper_bound\":\"+Inf\",\"count\":{},\"exemplar\":null}}]}}}}]}}]}}",
+        count, buckets[0], buckets[1], buckets[2],
+    )).unwrap();
+
+    // Buckets that go down, and a count that doesn't match the +Inf bucket. Neither can come of merging valid pushes
+    for (buckets, count) in [([5, 3, 5], 5), ([1, 3, 5], 4)] {
+        for (validation, kept) in [(HistogramValidation::Off, true), (HistogramValidation::Log, true), (HistogramValidation::Drop, false)] {
+            let mut agg = Aggregator::with_config(AggregatorConfig { histogram_validation: validation, ..Default::default() });
+            agg.restore_snapshot(histogram(buckets, count)).await.unwrap();
+            agg.parse_and_merge("# TYPE queued gauge\nqueued 3\n", &HashMap::new()).await.unwrap();
+
+            for scrape in [agg.to_string().await, agg.to_openmetrics_string().await] {
+                assert_eq!(scrape.contains("latency_bucket"), kept, "{:?}: {}", validation, scrape);
+                assert!(scrape.contains("queued 3\n"), "{:?}: {}", validation, scrape);
+            }
+        }
+    }
+
+    // Valid histograms are served as usual
+    let mut agg = Aggregator::with_config(AggregatorConfig { histogram_validation: HistogramValidation::Drop, ..Default::default() });
+    agg.restore_snapshot(histogram([1, 3, 5], 5)).await.unwrap();
+    assert!(agg.to_string().await.contains("latency_count 5\n"));
+}
+
+#[tokio::test]
+async fn test_skip_malformed_lines() {
+    let mut agg = Aggregator::with_config(AggregatorConfig { skip_malformed_lines: true, ..Default::default() });
+
+    let body = "# TYPE latency histogram
+latency_bucket{le=\"0.5\"} 1
+latency_bucket{le=\"1\"} 3
+latency_bucket{le=\"+Inf\"} 3
+latency_bucket{le=\"2\" 3
+latency_sum 2
+latency_count 3
+# TYPE queued gauge
+queued{queue=\"a\"} 3
+queued{queue=\"b\"} 4 5 6
+";
+    agg.parse_and_merge(body, &HashMap::new()).await.unwrap();
+    assert_eq!(agg.to_string().await, "# TYPE latency histogram
+latency_bucket{le=\"0.5\"} 1
+latency_bucket{le=\"1\"} 3
+latency_bucket{le=\"+Inf\"} 3
+latency_sum 2
+latency_count 3
+# TYPE queued gauge
+queued{queue=\"a\"} 3
+");
+
+    // Skipping lines only ever fixes malformed ones, so pushes that are wrong in what they say are still rejected
+    let err = agg.parse_and_merge("# TYPE latency histogram\nlatency_bucket{le=\"0.5\"} 5\nlatency_bucket{le=\"+Inf\"} 3\nlatency_sum 2\nlatency_count 3\n", &HashMap::new()).await;
+    assert!(err.is_err(), "{:?}", err);
+
+    // The streaming parser skips them the same way
+    let mut agg = Aggregator::with_config(AggregatorConfig { skip_malformed_lines: true, ..Default::default() });
+    agg.parse_and_merge_reader(body.as_bytes(), TextFormat::Prometheus, &HashMap::new()).await.unwrap();
+    assert!(agg.to_string().await.ends_with("latency_count 3\n# TYPE queued gauge\nqueued{queue=\"a\"} 3\n"));
+}
+
+#[tokio::test]
+async fn test_reject_negative_counters() {
+    let mut agg = Aggregator::new();
+    let mut labels = HashMap::new();
+    labels.insert("job", "api");
+    match agg.parse_and_merge("# TYPE requests_total counter\nrequests_total{code=\"200\"} -5\n", &labels).await {
+        Err(AggregationError::NegativeCounter { series }) => assert_eq!(series, "requests_total{code=\"200\"}"),
+        other => panic!("expected the negative counter to be rejected, got {:?}", other),
+    }
+
+    // Untyped series named like counters are counters too, but gauges can go wherever they like
+    assert!(matches!(agg.parse_and_merge("retries_total -1\n", &HashMap::new()).await, Err(AggregationError::NegativeCounter { .. })));
+    agg.parse_and_merge("# TYPE temperature gauge\ntemperature -5\n", &HashMap::new()).await.unwrap();
+    assert_eq!(agg.to_string().await, "# TYPE temperature gauge\ntemperature -5\n");
+}
+
+#[tokio::test]
+async fn test_duplicate_series_in_a_push_are_folded() {
+    let mut agg = Aggregator::new();
+    agg.parse_and_merge("# TYPE c_total counter\nc_total 10\n", &HashMap::new()).await.unwrap();
+    agg.parse_and_merge("# TYPE c_total counter\nc_total 1\nc_total 2\n", &HashMap::new()).await.unwrap();
+    assert_eq!(agg.to_string().await, "# TYPE c_total counter\nc_total 13\n");
+
+    // Everything that isn't a counter takes the last value, wherever its repeats are in the push
+    let mut agg = Aggregator::new();
+    agg.parse_and_merge("# TYPE g gauge\ng{a=\"1\",b=\"2\"} 1\ng{a=\"2\",b=\"2\"} 5\ng{b=\"2\",a=\"1\"} 3\n", &HashMap::new()).await.unwrap();
+    assert_eq!(agg.to_string().await, "# TYPE g gauge\ng{a=\"1\",b=\"2\"} 3\ng{a=\"2\",b=\"2\"} 5\n");
+
+    let mut agg = Aggregator::new();
+    agg.parse_and_merge("# TYPE h histogram\nh_bucket{le=\"1\"} 1\nh_bucket{le=\"+Inf\"} 2\nh_sum 1.5\nh_count 2\nh_bucket{le=\"1\"} 1\nh_bucket{le=\"+Inf\"} 1\nh_sum 0.25\nh_count 1\n", &HashMap::new()).await.unwrap();
+    assert_eq!(agg.to_string().await, "# TYPE h histogram\nh_bucket{le=\"1\"} 2\nh_bucket{le=\"+Inf\"} 3\nh_sum 1.75\nh_count 3\n");
+}
+
+#[tokio::test]
+async fn test_invalid_names_are_rejected() {
+    let mut agg = Aggregator::new();
+    assert!(agg.parse_and_merge("1bad 1\n", &HashMap::new()).await.is_err());
+
+    let labels: HashMap<&str, &str> = vec![("foo-bar", "x")].into_iter().collect();
+    match agg.parse_and_merge("foo 1\n", &labels).await {
+        Err(AggregationError::InvalidName(name)) => assert_eq!(name, "foo-bar"),
+        other => panic!("expected an invalid name, got {:?}", other),
+    }
+
+    let labels: HashMap<&str, &str> = vec![("a:b", "x")].into_iter().collect();
+    assert!(matches!(agg.parse_and_merge("foo 1\n", &labels).await, Err(AggregationError::InvalidName(_))));
+
+    let labels: HashMap<&str, &str> = vec![("_ok_2", "x")].into_iter().collect();
+    agg.parse_and_merge("foo 1\n", &labels).await.unwrap();
+    assert_eq!(agg.to_string().await, "foo{_ok_2=\"x\"} 1\n");
+}
+
+/// A path in the temp dir that's unique to the calling test
+fn snapshot_path(name: &str) -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("gravel-{}-{}.snapshot", name, std::process::id()))
+}
+
+#[tokio::test]
+async fn test_snapshot_and_restore() {
+    let path = snapshot_path("restore");
+    let mut agg = Aggregator::new();
+    agg.parse_and_merge("# TYPE requests_total counter\nrequests_total{code=\"200\"} 10\n# TYPE temp gauge\n# UNIT temp celsius\ntemp 21.5\n# TYPE latency histogram\nlatency_bucket{le=\"1\"} 2\nlatency_bucket{le=\"+Inf\"} 3\nlatency_sum 4\nlatency_count 3\n", &HashMap::new()).await.unwrap();
+    agg.snapshot_to(&path).await.unwrap();
+
+    let mut restored = Aggregator::new();
+    restored.restore_from(&path).await.unwrap();
+    assert_eq!(restored.to_string().await, agg.to_string().await);
+    assert_eq!(restored.to_openmetrics_string().await, agg.to_openmetrics_string().await);
+
+    // The restored value is an aggregate, so a lower push isn't a reset, and it adds to it like normal
+    restored.parse_and_merge("# TYPE requests_total counter\nrequests_total{code=\"200\"} 1\n", &HashMap::new()).await.unwrap();
+    assert_eq!(restored.metrics().counter_resets(), 0);
+    assert!(restored.to_string().await.contains("requests_total{code=\"200\"} 11\n"));
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn test_restore_from_bad_snapshot() {
+    // No snapshot just means nothing to restore
+    let mut agg = Aggregator::new();
+    agg.restore_from(&snapshot_path("missing")).await.unwrap();
+
+    let path = snapshot_path("corrupt");
+    std::fs::write(&path, "# TYPE requests_total counter\nrequests_total{code=\"2").unwrap();
+    assert!(agg.restore_from(&path).await.is_err());
+    assert!(agg.to_string().await.is_empty());
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+/// The last push timestamp of the given job in a scrape
+fn last_push_timestamp(scrape: &str, job: &str) -> Option<f64> {
+    let prefix = format!("gravel_last_push_timestamp_seconds{{job=\"{}\"}} ", job);
+    scrape.lines().find_map(|line| line.strip_prefix(&prefix)).map(|value| value.parse().unwrap())
+}
+
+#[tokio::test]
+async fn test_last_push_timestamps() {
+    let mut agg = Aggregator::with_ttl(AggregatorConfig::default(), Duration::from_millis(200));
+    let job = |job| vec![("job", job)].into_iter().collect::<HashMap<&str, &str>>();
+
+    agg.parse_and_merge("requests_total 1\n", &job("foo")).await.unwrap();
+    agg.parse_and_merge("requests_total 1\n", &job("bar")).await.unwrap();
+    let first = last_push_timestamp(&agg.to_string().await, "foo").expect("no timestamp for foo");
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    agg.parse_and_merge("requests_total 1\n", &job("foo")).await.unwrap();
+    let scrape = agg.to_string().await;
+    assert!(last_push_timestamp(&scrape, "foo").unwrap() > first, "{}", scrape);
+    assert!(scrape.starts_with("# HELP gravel_last_push_timestamp_seconds"), "{}", scrape);
+
+    // Pushes that fail, or don't have a job, don't count
+    assert!(agg.parse_and_merge("requests_total{ 1\n", &job("baz")).await.is_err());
+    agg.parse_and_merge("other_total 1\n", &HashMap::new()).await.unwrap();
+    assert_eq!(agg.to_string().await.lines().filter(|line| line.starts_with("gravel_last_push_timestamp_seconds")).count(), 2);
+
+    // bar's timestamp expires with its series, but foo is still recent enough
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    let scrape = agg.to_string().await;
+    assert!(last_push_timestamp(&scrape, "bar").is_none(), "{}", scrape);
+    assert!(last_push_timestamp(&scrape, "foo").is_some(), "{}", scrape);
+}
+
+
+#[tokio::test]
+async fn test_escaped_label_values() {
+    // An escaped newline and an escaped backslash followed by an n are different values, so different series
+    let push = r#"foo{msg="line1\nline2"} 1
+foo{msg="line1\\nline2"} 2
+foo{msg="say \"hi\""} 3
+foo{msg=""} 4
+"#;
+
+    let mut agg = Aggregator::new();
+    for _ in 0..2 {
+        agg.parse_and_merge(push, &HashMap::new()).await.unwrap();
+    }
+    assert_eq!(agg.to_string().await, r#"foo{msg=""} 8
+foo{msg="line1\\nline2"} 4
+foo{msg="line1\nline2"} 2
+foo{msg="say \"hi\""} 6
+"#);
+
+    // Values from the path aren't escaped yet, so they are on the way in, and matched against the same way
+    let mut agg = Aggregator::new();
+    let labels: HashMap<&str, &str> = vec![("job", "a \"b\"\nc\\d")].into_iter().collect();
+    agg.parse_and_merge("foo 1\n", &labels).await.unwrap();
+    agg.parse_and_merge(r#"foo{job="a \"b\"\nc\\d"} 1"#, &HashMap::new()).await.unwrap();
+    let scrape = agg.to_string().await;
+    assert!(scrape.starts_with("foo{job=\"a \\\"b\\\"\\nc\\\\d\"} 2\n"), "{}", scrape);
+    assert!(scrape.contains("gravel_last_push_timestamp_seconds{job=\"a \\\"b\\\"\\nc\\\\d\"} "), "{}", scrape);
+
+    agg.delete_matching(&labels).await;
+    assert!(agg.to_string().await.is_empty());
+}
+
+#[tokio::test]
+async fn test_normalize_counter_names() {
+    let legacy = "# TYPE http_requests counter\nhttp_requests{path=\"/\"} 1\n";
+    let openmetrics = "# TYPE http_requests_total counter\nhttp_requests_total{path=\"/\"} 2\n";
+
+    // Without normalizing, a counter without the suffix doesn't parse
+    let mut agg = Aggregator::new();
+    assert!(agg.parse_and_merge(legacy, &HashMap::new()).await.is_err());
+
+    let mut agg = Aggregator::with_config(AggregatorConfig {
+        normalize_counter_names: true,
+        ..AggregatorConfig::default()
+    });
+    agg.parse_and_merge(legacy, &HashMap::new()).await.unwrap();
+    agg.parse_and_merge_reader(openmetrics.as_bytes(), TextFormat::Prometheus, &HashMap::new()).await.unwrap();
+    agg.parse_and_merge("# TYPE queue_depth gauge\nqueue_depth 4\n", &HashMap::new()).await.unwrap();
+    assert_eq!(agg.to_string().await, "# TYPE http_requests_total counter
+http_requests_total{path=\"/\"} 3
+# TYPE queue_depth gauge
+queue_depth 4
+");
+}
+
+#[tokio::test]
+async fn test_counter_created() {
+    let first = "# TYPE jobs_total counter\njobs_total{queue=\"a\"} 1\njobs_created{queue=\"a\"} 1700000100\n";
+    let second = "# TYPE jobs_total counter\njobs_total{queue=\"a\"} 2\njobs_created{queue=\"a\"} 1700000000\n";
+
+    let mut agg = Aggregator::new();
+    agg.parse_and_merge(first, &HashMap::new()).await.unwrap();
+    agg.parse_and_merge_reader(second.as_bytes(), TextFormat::Prometheus, &HashMap::new()).await.unwrap();
+    // A later client doesn't make the counter any newer
+    agg.parse_and_merge(first, &HashMap::new()).await.unwrap();
+
+    assert_eq!(agg.to_openmetrics_string().await, "# TYPE jobs counter
+jobs_total{queue=\"a\"} 4
+jobs_created{queue=\"a\"} 1700000000
+# EOF
+");
+
+    // The Prometheus format doesn't have them, so they're a gauge of their own
+    assert_eq!(agg.to_string().await, "# TYPE jobs_created gauge
+jobs_created{queue=\"a\"} 1700000000
+# TYPE jobs_total counter
+jobs_total{queue=\"a\"} 4
+");
+}
+
+#[cfg(feature="clustering")]
+#[tokio::test]
+async fn test_render_families_round_trips() {
+    let push = "# TYPE jobs counter
+# UNIT jobs jobs
+jobs_total{queue=\"a\"} 2 # {trace_id=\"abc\"} 1
+jobs_created{queue=\"a\"} 1700000000
+# TYPE latency_seconds histogram
+latency_seconds_bucket{le=\"0.1\"} 1 # {trace_id=\"def\"} 0.05
+latency_seconds_bucket{le=\"+Inf\"} 1
+latency_seconds_sum 0.05
+latency_seconds_count 1
+# EOF
+";
+
+    let agg = Aggregator::new();
+    let families = agg.parse_reader(push.as_bytes(), TextFormat::OpenMetrics).unwrap().families;
+    let rendered = render_families(&families, TextFormat::OpenMetrics);
+    assert!(rendered.contains("jobs_created{queue=\"a\"} 1700000000\n") && rendered.ends_with("# EOF\n"), "unexpected render {}", rendered);
+    let reparsed = agg.parse_reader(rendered.as_bytes(), TextFormat::OpenMetrics).unwrap().families;
+
+    let prometheus = render_families(&families, TextFormat::Prometheus);
+    let reparsed_prometheus = agg.parse_reader(prometheus.as_bytes(), TextFormat::Prometheus).unwrap().families;
+
+    // Merging what was rendered is the same as merging the push itself, exemplars, units, and `_created` series included
+    let mut pushed = Aggregator::new();
+    pushed.merge_families(families, &HashMap::new()).await.unwrap();
+    assert!(pushed.to_openmetrics_string().await.contains("jobs_created"));
+    for reparsed in [reparsed, reparsed_prometheus] {
+        let mut forwarded = Aggregator::new();
+        forwarded.merge_families(reparsed, &HashMap::new()).await.unwrap();
+        assert_eq!(forwarded.to_openmetrics_string().await, pushed.to_openmetrics_string().await);
+    }
+}
+
+#[tokio::test]
+async fn test_info_metrics_are_replaced() {
+    let push = "# HELP build Build information\n# TYPE build info\nbuild_info{version=\"1.2.0\"} 1\n";
+
+    let mut agg = Aggregator::new();
+    agg.parse_and_merge(push, &HashMap::new()).await.unwrap();
+    agg.parse_and_merge_reader(push.as_bytes(), TextFormat::Prometheus, &HashMap::new()).await.unwrap();
+    assert_eq!(agg.to_string().await, "# HELP build_info Build information\n# TYPE build_info gauge\nbuild_info{version=\"1.2.0\"} 1\n");
+
+    // An explicit clearmode still wins
+    agg.parse_and_merge("# TYPE build info\nbuild_info{version=\"1.3.0\",clearmode=\"family\"} 1\n", &HashMap::new()).await.unwrap();
+    assert_eq!(agg.to_string().await, "# HELP build_info Build information\n# TYPE build_info gauge\nbuild_info{version=\"1.3.0\"} 1\n");
+}
+
+#[tokio::test]
+async fn test_stateset_transitions() {
+    let mut agg = Aggregator::new();
+    agg.parse_and_merge("# TYPE door stateset\ndoor{door=\"open\"} 1\ndoor{door=\"closed\"} 0\n", &HashMap::new()).await.unwrap();
+    agg.parse_and_merge("# TYPE door stateset\ndoor{door=\"open\"} 0\ndoor{door=\"closed\"} 1\n", &HashMap::new()).await.unwrap();
+
+    assert_eq!(agg.to_string().await, "# TYPE door gauge\ndoor{door=\"closed\"} 1\ndoor{door=\"open\"} 0\n");
+}
+
+#[tokio::test]
+async fn test_label_precedence() {
+    let push = "# TYPE requests_total counter\nrequests_total{job=\"body-job\",path=\"/a\"} 1\nrequests_total{job=\"\",path=\"/b\"} 2\n";
+    let labels = HashMap::from([("job", "path-job")]);
+
+    // By default, the path wins, like it does with the push gateway
+    let mut agg = Aggregator::new();
+    agg.parse_and_merge(push, &labels).await.unwrap();
+    let output = agg.to_string().await;
+    assert!(output.contains("gravel_last_push_timestamp_seconds{job=\"path-job\"}"), "{}", output);
+    assert_eq!(output.split_once("# TYPE requests_total counter\n").unwrap().1, "requests_total{job=\"path-job\",path=\"/a\"} 1
+requests_total{job=\"path-job\",path=\"/b\"} 2
+");
+
+    // Otherwise the body does, unless its label is empty
+    let mut agg = Aggregator::with_config(AggregatorConfig { label_precedence: LabelPrecedence::Body, ..AggregatorConfig::default() });
+    agg.parse_and_merge(push, &labels).await.unwrap();
+    let output = agg.to_string().await;
+    assert!(output.contains("gravel_last_push_timestamp_seconds{job=\"body-job\"}"), "{}", output);
+    assert!(output.contains("gravel_last_push_timestamp_seconds{job=\"path-job\"}"), "{}", output);
+    assert_eq!(output.split_once("# TYPE requests_total counter\n").unwrap().1, "requests_total{job=\"body-job\",path=\"/a\"} 1
+requests_total{job=\"path-job\",path=\"/b\"} 2
+");
+}
+
+#[tokio::test]
+async fn test_graphite_string() {
+    let mut agg = Aggregator::new();
+    agg.parse_and_merge("# TYPE http_requests_total counter\nhttp_requests_total{method=\"GET\",path=\"/api/v1\",host=\"\"} 3 1700000000000\n# TYPE up gauge\nup 1 1700000001000\n", &HashMap::new()).await.unwrap();
+
+    assert_eq!(agg.to_graphite_string(None).await, "http_requests_total.method.GET.path._api_v1 3 1700000000\nup 1 1700000001\n");
+    assert_eq!(agg.to_graphite_string(Some("gravel.prod")).await, "gravel.prod.http_requests_total.method.GET.path._api_v1 3 1700000000\ngravel.prod.up 1 1700000001\n");
+}
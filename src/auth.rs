@@ -1,73 +1,289 @@
-use std::{fs::File, io::{self, BufRead, BufReader}, path::PathBuf};
+use std::{collections::{HashMap, HashSet, hash_map::DefaultHasher}, fs::File, hash::{Hash, Hasher}, io::{self, BufRead, BufReader}, path::PathBuf};
 
 pub trait Authenticator {
     fn authenticate(&self, token: &str) -> Result<bool, anyhow::Error>;
+
+    /// Checks whether an already authenticated request is allowed to touch the series with the given labels
+    /// (e.g. from the /metrics/job/foo path). By default, anyone who can authenticate can push anything
+    fn authorize(&self, _token: &str, _labels: &HashMap<&str, &str>) -> bool {
+        true
+    }
+
+    /// Checks whether an already authenticated request is allowed to touch the given tenant's store, or the default
+    /// store if there's no tenant. By default, anyone who can authenticate can push to any tenant
+    fn authorize_tenant(&self, _token: &str, _tenant: Option<&str>) -> bool {
+        true
+    }
+
+    /// Checks whether a client is authenticated by the TLS certificate it presented, which the server has already checked
+    /// was signed by the client CA. By default, only the auth header can authenticate a request
+    fn authenticate_certificate(&self, _certificate: &ClientCertificate) -> bool {
+        false
+    }
+
+    /// Tells authenticated clients apart by their credentials (e.g. to give each its own rate limit), with a hash of them
+    /// rather than the credentials themselves. None if the credentials don't say who the client is, like without auth
+    fn client_id(&self, token: &str) -> Option<String> {
+        let mut hasher = DefaultHasher::new();
+        token.hash(&mut hasher);
+        Some(format!("{:016x}", hasher.finish()))
+    }
+}
+
+/// The names in a client's verified TLS certificate: its subject's common name, followed by its DNS subject alternative names
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClientCertificate {
+    pub names: Vec<String>,
 }
 
 #[cfg(feature="auth")]
 #[derive(Clone)]
 pub struct BasicAuthenticator {
+    /// bcrypt hashes of each user's password, keyed by their username
+    users: HashMap<String, String>,
+
+    /// bcrypt hashes of passwords that are allowed with any username
     allowed_hashes: Vec<String>
 }
 
 #[cfg(feature="auth")]
 impl BasicAuthenticator {
-    fn load_from_file(path: PathBuf) -> Result<BasicAuthenticator, io::Error> {
-        let mut allowed_hashes = Vec::new();
-        for line in BufReader::new(File::open(path)?).lines() {
-            match line {
-                Ok(line) => allowed_hashes.push(line),
-                Err(e) => return Err(e)
-            }
-        }
+    /// Constructs an authenticator that accepts the given users, which map usernames to bcrypt hashes of their passwords
+    pub fn new(users: HashMap<String, String>) -> BasicAuthenticator {
+        return BasicAuthenticator {
+            users,
+            allowed_hashes: Vec::new()
+        };
+    }
+
+}
 
-        return Ok(BasicAuthenticator {
-            allowed_hashes
-        });
+/// Pulls the username and password out of an `Authorization: Basic <base64>` header, returning None
+/// if it isn't one or can't be decoded
+#[cfg(feature="auth")]
+fn parse_basic_auth(header: &str) -> Option<(String, String)> {
+    let (scheme, credentials) = header.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
     }
+
+    let credentials = String::from_utf8(base64::decode(credentials.trim()).ok()?).ok()?;
+    let (username, password) = credentials.split_once(':')?;
+    return Some((username.to_owned(), password.to_owned()));
 }
 
 #[cfg(feature="auth")]
 impl Authenticator for BasicAuthenticator {
     fn authenticate(&self, header: &str) -> Result<bool, anyhow::Error> {
         use bcrypt::verify;
-        // Header is in the format "Basic <token>", so here we extract the second bit
-        match header.split_ascii_whitespace().nth(1) {
-            Some(token) => {
-                let token: Option<String> = match base64::decode(token) {
-                    Ok(token_bytes) => {
-                        match String::from_utf8(token_bytes) {
-                            // If we have a valid utc-8 base64 auth, split it on the : (format is username:password), and take the second 
-                            // part (i.e. just take the password).
-                            Ok(token_str) if token_str.contains(':') => token_str.split(':').nth(1).map_or(None, |s| Some(s.to_owned())),
-
-                            // If we fail do decode it as a valid utf-8 basic auth header, for whatever reason, treat it as plain text
-                            Ok(token_str) => Some(token_str),
-                            Err(_) => Some(token.to_owned())
-                        }
-                    },
-
-                    // For backwards compatibility reasons, if we fail to base64 decode the header then we
-                    // still accept it as plain text
-                    Err(_) => Some(token.to_owned())
-                };
-
-                if let Some(token) = token {
-                    for hash in self.allowed_hashes.iter() {
-                        return Ok(verify(token, hash).unwrap_or(false))
-                    }
-                }
-
-                Ok(false)
-            }
-            None => Ok(false)
-        }
+        let (username, password) = match parse_basic_auth(header) {
+            Some(credentials) => credentials,
+            None => return Ok(false)
+        };
+
+        let mut hashes = self.users.get(&username).into_iter().chain(self.allowed_hashes.iter());
+        Ok(hashes.any(|hash| verify(&password, hash).unwrap_or(false)))
     }
 }
 
+/// Loads an htpasswd style file of `username:hash` lines, returning the users and any hashes allowed with any
+/// username. For backwards compatibility, a line that's just a hash allows that password with any username
 #[cfg(feature="auth")]
-pub fn basic_auth(config_file_path: PathBuf) -> Result<BasicAuthenticator, io::Error> {
-    BasicAuthenticator::load_from_file(config_file_path)
+fn load_htpasswd(path: PathBuf) -> Result<(HashMap<String, String>, Vec<String>), io::Error> {
+    let mut users = HashMap::new();
+    let mut allowed_hashes = Vec::new();
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        match line.split_once(':') {
+            Some((username, hash)) => {
+                users.insert(username.to_owned(), hash.to_owned());
+            },
+            None => allowed_hashes.push(line.to_owned())
+        }
+    }
+
+    return Ok((users, allowed_hashes));
+}
+
+/// Accepts requests with an `Authorization: Bearer <token>` header, for any of a set of tokens
+pub struct BearerAuthenticator {
+    tokens: HashSet<String>
+}
+
+impl BearerAuthenticator {
+    pub fn new(tokens: Vec<String>) -> BearerAuthenticator {
+        return BearerAuthenticator {
+            tokens: tokens.into_iter().collect()
+        };
+    }
+}
+
+/// Loads a file of allowed tokens, one per line
+fn load_tokens(path: PathBuf) -> Result<Vec<String>, io::Error> {
+    let mut tokens = Vec::new();
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        if !line.trim().is_empty() {
+            tokens.push(line.trim().to_owned());
+        }
+    }
+
+    return Ok(tokens);
+}
+
+/// Pulls the token out of an `Authorization: Bearer <token>` header
+fn parse_bearer_token(header: &str) -> Option<&str> {
+    match header.trim().split_once(' ') {
+        Some((scheme, token)) if scheme.eq_ignore_ascii_case("bearer") && !token.trim().is_empty() => Some(token.trim()),
+        _ => None
+    }
+}
+
+impl Authenticator for BearerAuthenticator {
+    fn authenticate(&self, header: &str) -> Result<bool, anyhow::Error> {
+        let token = match parse_bearer_token(header) {
+            Some(token) => token,
+            None => return Ok(false)
+        };
+
+        // Check every token, so that how long this takes doesn't give away which one (if any) was close
+        Ok(self.tokens.iter().fold(false, |found, allowed| constant_time_eq(allowed.as_bytes(), token.as_bytes()) | found))
+    }
+}
+
+/// Compares two byte strings in time that only depends on their lengths, not their contents
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b.iter()).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Bearer token authentication, where each token is only allowed to push to the jobs matching its patterns.
+/// Patterns can use `*` to match any run of characters, e.g. `team-a-*`
+pub struct JobAuthenticator {
+    tokens: BearerAuthenticator,
+    allowed_jobs: HashMap<String, Vec<String>>
+}
+
+impl JobAuthenticator {
+    /// Constructs an authenticator from a map of tokens to the job patterns they can push to
+    pub fn new(allowed_jobs: HashMap<String, Vec<String>>) -> JobAuthenticator {
+        return JobAuthenticator {
+            tokens: BearerAuthenticator::new(allowed_jobs.keys().cloned().collect()),
+            allowed_jobs
+        };
+    }
+
+    fn load_from_file(path: PathBuf) -> Result<JobAuthenticator, io::Error> {
+        return Ok(JobAuthenticator::new(load_token_patterns(path, "job")?));
+    }
+}
+
+impl Authenticator for JobAuthenticator {
+    fn authenticate(&self, header: &str) -> Result<bool, anyhow::Error> {
+        self.tokens.authenticate(header)
+    }
+
+    fn authorize(&self, header: &str, labels: &HashMap<&str, &str>) -> bool {
+        let job = labels.get("job").copied().unwrap_or_default();
+        let patterns = parse_bearer_token(header).and_then(|token| self.allowed_jobs.get(token));
+        patterns.is_some_and(|patterns| patterns.iter().any(|pattern| matches_pattern(pattern, job)))
+    }
+}
+
+/// Loads a file of `<token> <pattern>,<pattern>...` lines, mapping each token to its patterns for what it can push to
+fn load_token_patterns(path: PathBuf, what: &str) -> Result<HashMap<String, Vec<String>>, io::Error> {
+    let mut patterns = HashMap::new();
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        match line.split_once(char::is_whitespace) {
+            Some((token, token_patterns)) => {
+                let token_patterns = token_patterns.split(',').map(|p| p.trim().to_owned()).filter(|p| !p.is_empty()).collect();
+                patterns.insert(token.to_owned(), token_patterns);
+            },
+            None => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Expected `<token> <{} patterns>`, got a line without any {}s: {}", what, what, line)))
+        }
+    }
+
+    return Ok(patterns);
+}
+
+/// Bearer token authentication, where each token is only allowed to push to the tenants matching its patterns. The
+/// default store (i.e. /metrics, without a tenant) counts as the tenant with an empty ID, so only `*` matches it
+pub struct TenantAuthenticator {
+    tokens: BearerAuthenticator,
+    allowed_tenants: HashMap<String, Vec<String>>
+}
+
+impl TenantAuthenticator {
+    /// Constructs an authenticator from a map of tokens to the tenant patterns they can push to
+    pub fn new(allowed_tenants: HashMap<String, Vec<String>>) -> TenantAuthenticator {
+        return TenantAuthenticator {
+            tokens: BearerAuthenticator::new(allowed_tenants.keys().cloned().collect()),
+            allowed_tenants
+        };
+    }
+}
+
+impl Authenticator for TenantAuthenticator {
+    fn authenticate(&self, header: &str) -> Result<bool, anyhow::Error> {
+        self.tokens.authenticate(header)
+    }
+
+    fn authorize_tenant(&self, header: &str, tenant: Option<&str>) -> bool {
+        let patterns = parse_bearer_token(header).and_then(|token| self.allowed_tenants.get(token));
+        patterns.is_some_and(|patterns| patterns.iter().any(|pattern| matches_pattern(pattern, tenant.unwrap_or_default())))
+    }
+}
+
+pub fn tenant_auth(config_file_path: PathBuf) -> Result<TenantAuthenticator, io::Error> {
+    Ok(TenantAuthenticator::new(load_token_patterns(config_file_path, "tenant")?))
+}
+
+/// Checks whether the value matches the given pattern, where a `*` in the pattern matches any run of characters
+pub fn matches_pattern(pattern: &str, value: &str) -> bool {
+    let mut parts = pattern.split('*');
+    // There's always at least one part, even for an empty pattern
+    let first = parts.next().unwrap_or_default();
+    let mut rest = match value.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false
+    };
+
+    let parts: Vec<&str> = parts.collect();
+    if parts.is_empty() {
+        // No wildcards, so it has to be an exact match
+        return rest.is_empty();
+    }
+
+    for (i, part) in parts.iter().enumerate() {
+        if i == parts.len() - 1 {
+            return rest.ends_with(part);
+        }
+
+        match rest.find(part) {
+            Some(idx) => rest = &rest[idx + part.len()..],
+            None => return false
+        }
+    }
+
+    return true;
+}
+
+pub fn job_auth(config_file_path: PathBuf) -> Result<JobAuthenticator, io::Error> {
+    JobAuthenticator::load_from_file(config_file_path)
 }
 
 pub struct PassThroughAuthenticator{}
@@ -80,4 +296,92 @@ impl Authenticator for PassThroughAuthenticator {
     fn authenticate(&self, _: &str) -> Result<bool, anyhow::Error> {
         Ok(true)
     }
+
+    /// Anything passes, so the client could have sent anything
+    fn client_id(&self, _: &str) -> Option<String> {
+        None
+    }
+}
+
+/// The built-in authenticators, for picking one declaratively
+#[derive(Debug, Clone, PartialEq)]
+pub enum AuthConfig {
+    /// Anyone can push, which is what the gateway does without any auth flags
+    None,
+    /// Basic auth for the given users, which map usernames to bcrypt hashes of their passwords
+    #[cfg(feature="auth")]
+    Basic { users: HashMap<String, String> },
+    /// Bearer auth for any of the given tokens
+    Bearer { tokens: Vec<String> },
+    /// Bearer auth for any of the tokens in a file, one per line, as given to `--bearer-token-file`
+    BearerFile { path: PathBuf },
+    /// Basic auth for the users in an htpasswd style file, as given to `--basic-auth-file`
+    #[cfg(feature="auth")]
+    Htpasswd { path: PathBuf },
+}
+
+impl AuthConfig {
+    /// Constructs the authenticator that the config asks for, which can only fail if it has a file to load
+    pub fn build(self) -> Result<Box<dyn Authenticator + Send + Sync>, io::Error> {
+        return match self {
+            AuthConfig::None => Ok(Box::new(pass_through_auth())),
+            #[cfg(feature="auth")]
+            AuthConfig::Basic { users } => Ok(Box::new(BasicAuthenticator::new(users))),
+            AuthConfig::Bearer { tokens } => Ok(Box::new(BearerAuthenticator::new(tokens))),
+            AuthConfig::BearerFile { path } => AuthConfig::Bearer { tokens: load_tokens(path)? }.build(),
+            #[cfg(feature="auth")]
+            AuthConfig::Htpasswd { path } => match load_htpasswd(path)? {
+                (users, allowed_hashes) if allowed_hashes.is_empty() => AuthConfig::Basic { users }.build(),
+                (users, allowed_hashes) => Ok(Box::new(BasicAuthenticator { users, allowed_hashes })),
+            },
+        };
+    }
+}
+
+/// Authenticates clients by their TLS certificates, letting in any whose certificate has one of the allowed names (or
+/// any at all, if no names are given). Requests without an allowed certificate fall back to the auth header, if any
+/// other authentication has been set up, and are otherwise refused
+#[cfg(feature="tls")]
+pub struct ClientCertAuthenticator {
+    allowed_names: Vec<String>,
+    fallback: Option<Box<dyn Authenticator + Send + Sync>>,
+}
+
+#[cfg(feature="tls")]
+impl ClientCertAuthenticator {
+    /// Constructs an authenticator that accepts certificates with names matching any of the given patterns, where a `*`
+    /// matches any run of characters
+    pub fn new(allowed_names: Vec<String>, fallback: Option<Box<dyn Authenticator + Send + Sync>>) -> ClientCertAuthenticator {
+        return ClientCertAuthenticator {
+            allowed_names,
+            fallback,
+        };
+    }
+}
+
+#[cfg(feature="tls")]
+impl Authenticator for ClientCertAuthenticator {
+    fn authenticate(&self, header: &str) -> Result<bool, anyhow::Error> {
+        match &self.fallback {
+            Some(fallback) => fallback.authenticate(header),
+            None => Ok(false),
+        }
+    }
+
+    fn authorize(&self, header: &str, labels: &HashMap<&str, &str>) -> bool {
+        self.fallback.as_ref().is_none_or(|fallback| fallback.authorize(header, labels))
+    }
+
+    fn authorize_tenant(&self, header: &str, tenant: Option<&str>) -> bool {
+        self.fallback.as_ref().is_none_or(|fallback| fallback.authorize_tenant(header, tenant))
+    }
+
+    fn authenticate_certificate(&self, certificate: &ClientCertificate) -> bool {
+        self.allowed_names.is_empty() || certificate.names.iter()
+            .any(|name| self.allowed_names.iter().any(|pattern| matches_pattern(pattern, name)))
+    }
+
+    fn client_id(&self, header: &str) -> Option<String> {
+        self.fallback.as_ref().and_then(|fallback| fallback.client_id(header))
+    }
 }
@@ -0,0 +1,179 @@
+use std::collections::HashMap;
+
+use crate::auth::{AuthConfig, Authenticator, BearerAuthenticator, JobAuthenticator, matches_pattern};
+
+fn bearer() -> BearerAuthenticator {
+    BearerAuthenticator::new(vec!["pipeline-a".to_owned(), "pipeline-b".to_owned()])
+}
+
+#[test]
+fn test_bearer_accepts_any_configured_token() {
+    let auth = bearer();
+    assert!(auth.authenticate("Bearer pipeline-a").unwrap());
+    assert!(auth.authenticate("Bearer pipeline-b").unwrap());
+    assert!(auth.authenticate("bearer pipeline-b").unwrap());
+}
+
+#[test]
+fn test_bearer_rejects_unknown_tokens() {
+    let auth = bearer();
+    assert!(!auth.authenticate("Bearer pipeline-c").unwrap());
+    assert!(!auth.authenticate("Bearer pipeline-").unwrap());
+    assert!(!auth.authenticate("Bearer ").unwrap());
+    assert!(!auth.authenticate("").unwrap());
+}
+
+#[test]
+fn test_bearer_requires_prefix() {
+    let auth = bearer();
+    assert!(!auth.authenticate("pipeline-a").unwrap());
+    assert!(!auth.authenticate("Basic pipeline-a").unwrap());
+    assert!(!auth.authenticate("Bearerpipeline-a").unwrap());
+}
+
+#[test]
+fn test_job_patterns() {
+    assert!(matches_pattern("web", "web"));
+    assert!(!matches_pattern("web", "webserver"));
+    assert!(matches_pattern("team-a-*", "team-a-web"));
+    assert!(matches_pattern("team-a-*", "team-a-"));
+    assert!(!matches_pattern("team-a-*", "team-b-web"));
+    assert!(matches_pattern("*-batch", "nightly-batch"));
+    assert!(matches_pattern("team-*-batch", "team-a-nightly-batch"));
+    assert!(!matches_pattern("team-*-batch", "team-batch"));
+    assert!(matches_pattern("*", ""));
+    assert!(!matches_pattern("", "web"));
+}
+
+#[test]
+fn test_job_authenticator() {
+    let mut allowed_jobs = HashMap::new();
+    allowed_jobs.insert("token-a".to_owned(), vec!["team-a-*".to_owned(), "shared".to_owned()]);
+    let auth = JobAuthenticator::new(allowed_jobs);
+
+    assert!(auth.authenticate("Bearer token-a").unwrap());
+    assert!(!auth.authenticate("Bearer token-b").unwrap());
+
+    let job = |job| vec![("job", job)].into_iter().collect::<HashMap<_, _>>();
+    assert!(auth.authorize("Bearer token-a", &job("team-a-web")));
+    assert!(auth.authorize("Bearer token-a", &job("shared")));
+    assert!(!auth.authorize("Bearer token-a", &job("team-b-web")));
+    assert!(!auth.authorize("Bearer token-a", &HashMap::new()));
+}
+
+#[test]
+fn test_auth_config() {
+    let none = AuthConfig::None.build().unwrap();
+    assert!(none.authenticate("").unwrap());
+    assert!(none.authenticate("Bearer anything").unwrap());
+
+    let bearer = AuthConfig::Bearer { tokens: vec!["pipeline-a".to_owned()] }.build().unwrap();
+    assert!(bearer.authenticate("Bearer pipeline-a").unwrap());
+    assert!(!bearer.authenticate("Bearer pipeline-b").unwrap());
+    assert!(!bearer.authenticate("").unwrap());
+
+    let path = std::env::temp_dir().join(format!("gravel-{}.tokens", std::process::id()));
+    std::fs::write(&path, "pipeline-a\n\n pipeline-b \n").unwrap();
+    let bearer_file = AuthConfig::BearerFile { path: path.clone() }.build().unwrap();
+    assert!(bearer_file.authenticate("Bearer pipeline-a").unwrap());
+    assert!(bearer_file.authenticate("Bearer pipeline-b").unwrap());
+    assert!(!bearer_file.authenticate("Bearer pipeline-c").unwrap());
+    assert!(!bearer_file.authenticate("").unwrap());
+
+    std::fs::remove_file(&path).unwrap();
+    assert!(AuthConfig::BearerFile { path }.build().is_err());
+}
+
+#[cfg(feature="auth")]
+mod basic {
+    use std::collections::HashMap;
+
+    use crate::auth::{Authenticator, BasicAuthenticator};
+
+    fn authenticator() -> BasicAuthenticator {
+        // The minimum cost, so that the tests don't spend all their time hashing
+        let mut users = HashMap::new();
+        users.insert("ci".to_owned(), bcrypt::hash("hunter2", 4).unwrap());
+        BasicAuthenticator::new(users)
+    }
+
+    fn header(credentials: &str) -> String {
+        format!("Basic {}", base64::encode(credentials))
+    }
+
+    #[test]
+    fn test_valid_credentials() {
+        let auth = authenticator();
+        assert!(auth.authenticate(&header("ci:hunter2")).unwrap());
+        assert!(auth.authenticate(&format!("basic {}", base64::encode("ci:hunter2"))).unwrap());
+    }
+
+    #[test]
+    fn test_invalid_credentials() {
+        let auth = authenticator();
+        assert!(!auth.authenticate(&header("ci:hunter3")).unwrap());
+        assert!(!auth.authenticate(&header("someone:hunter2")).unwrap());
+        assert!(!auth.authenticate(&header(":hunter2")).unwrap());
+    }
+
+    #[test]
+    fn test_auth_config() {
+        use crate::auth::AuthConfig;
+
+        let users = HashMap::from([("ci".to_owned(), bcrypt::hash("hunter2", 4).unwrap())]);
+        let basic = AuthConfig::Basic { users: users.clone() }.build().unwrap();
+        assert!(basic.authenticate(&header("ci:hunter2")).unwrap());
+        assert!(!basic.authenticate(&header("ci:hunter3")).unwrap());
+        assert!(!basic.authenticate(&header("someone:hunter2")).unwrap());
+        assert!(!basic.authenticate("").unwrap());
+
+        let path = std::env::temp_dir().join(format!("gravel-{}.htpasswd", std::process::id()));
+        std::fs::write(&path, format!("ci:{}\n", users["ci"])).unwrap();
+        let htpasswd = AuthConfig::Htpasswd { path: path.clone() }.build().unwrap();
+        assert!(htpasswd.authenticate(&header("ci:hunter2")).unwrap());
+        assert!(!htpasswd.authenticate(&header("ci:hunter3")).unwrap());
+        assert!(!htpasswd.authenticate(&header("someone:hunter2")).unwrap());
+        assert!(!htpasswd.authenticate("").unwrap());
+
+        // A bare hash is allowed with any username
+        std::fs::write(&path, format!("ci:{}\n{}\n", users["ci"], bcrypt::hash("shared", 4).unwrap())).unwrap();
+        let htpasswd = AuthConfig::Htpasswd { path: path.clone() }.build().unwrap();
+        assert!(htpasswd.authenticate(&header("ci:hunter2")).unwrap());
+        assert!(htpasswd.authenticate(&header("someone:shared")).unwrap());
+        assert!(!htpasswd.authenticate(&header("someone:hunter2")).unwrap());
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(AuthConfig::Htpasswd { path }.build().is_err());
+    }
+
+    #[test]
+    fn test_malformed_headers() {
+        let auth = authenticator();
+        assert!(!auth.authenticate("").unwrap());
+        assert!(!auth.authenticate("Basic").unwrap());
+        assert!(!auth.authenticate("Basic not-base64!").unwrap());
+        assert!(!auth.authenticate("Basic hunter2").unwrap());
+        assert!(!auth.authenticate(&header("no colon")).unwrap());
+        assert!(!auth.authenticate(&format!("Bearer {}", base64::encode("ci:hunter2"))).unwrap());
+    }
+}
+
+#[cfg(feature="tls")]
+#[test]
+fn test_client_cert_authenticator() {
+    use crate::auth::{ClientCertAuthenticator, ClientCertificate};
+
+    let pusher = ClientCertificate { names: vec!["pusher".to_owned(), "pusher.example.com".to_owned()] };
+    let other = ClientCertificate { names: vec!["other.example.org".to_owned()] };
+
+    // Without an allowlist, any certificate from the CA will do, and tokens don't without a fallback
+    let any = ClientCertAuthenticator::new(Vec::new(), None);
+    assert!(any.authenticate_certificate(&pusher) && any.authenticate_certificate(&other));
+    assert!(!any.authenticate("Bearer pipeline-a").unwrap());
+
+    let allowlisted = ClientCertAuthenticator::new(vec!["*.example.com".to_owned()], Some(Box::new(bearer())));
+    assert!(allowlisted.authenticate_certificate(&pusher));
+    assert!(!allowlisted.authenticate_certificate(&other));
+    assert!(allowlisted.authenticate("Bearer pipeline-a").unwrap());
+    assert!(!allowlisted.authenticate("Bearer pipeline-c").unwrap());
+}
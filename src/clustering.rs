@@ -1,28 +1,54 @@
-use std::{hash::{Hash, BuildHasher, Hasher, BuildHasherDefault}, str::FromStr, io::BufRead};
+use std::{collections::HashMap, hash::{Hash, BuildHasher, BuildHasherDefault}, str::FromStr, io::{self, BufRead}, net::{IpAddr, ToSocketAddrs}, sync::{Arc, Mutex, RwLock, Weak, atomic::{AtomicBool, Ordering}}, time::{Duration, Instant}};
+use openmetrics_parser::{MetricNumber, PrometheusMetricFamily, PrometheusType, PrometheusValue, Sample};
 use trust_dns_resolver::{Resolver, error::ResolveError};
 use trust_dns_resolver::Name;
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tracing::{info, warn};
 use twox_hash::XxHash64;
 
-struct HashRing<T: Hash, H: BuildHasher> {
+/// How many points on the hash ring each peer gets, unless configured otherwise
+pub const DEFAULT_VIRTUAL_NODES: usize = 100;
+
+/// A consistent hash ring. Each node is hashed onto the ring at a number of points (virtual nodes), and a value
+/// belongs to the first node clockwise from where it hashes to. Adding or removing a node only moves the values
+/// next to its points, and the virtual nodes keep that spread evenly over the other nodes. A node with a weight gets
+/// that many times the points, and so that many times the share of the values
+struct HashRing<T: Hash + Clone + Eq, H: BuildHasher> {
+    /// Every point on the ring, sorted by hash
     keys: Vec<(u64, T)>,
+
+    /// The distinct nodes on the ring, in the order they were added
+    nodes: Vec<T>,
+
+    /// How many points each node gets on the ring
+    virtual_nodes: usize,
+
+    /// How many times over each node gets its points. Nodes that aren't in here get them once. These are kept even
+    /// for nodes that aren't on the ring, so that they get their weight if they join it later
+    weights: HashMap<T, usize>,
+
     hasher: H,
 }
 
 fn hash_one<T: Hash, H: BuildHasher>(hasher: &H, val: &T) -> u64 {
-    let mut hasher = hasher.build_hasher();
-    val.hash(&mut hasher);
-    hasher.finish()
+    hasher.hash_one(val)
 }
 
-impl<T: Hash, H: BuildHasher> HashRing<T, H> {
-    pub fn new_with_nodes(hasher: H, nodes: impl IntoIterator<Item=T>) -> Self {
-        let mut keys: Vec<(u64, T)> = nodes.into_iter().map(|v| (hash_one(&hasher, &v), v)).collect();
-        keys.sort_by_key(|&(k, _)| k);
-
-        HashRing {
-            keys,
+impl<T: Hash + Clone + Eq, H: BuildHasher> HashRing<T, H> {
+    pub fn new_with_nodes(hasher: H, nodes: impl IntoIterator<Item=T>, virtual_nodes: usize, weights: HashMap<T, usize>) -> Self {
+        let mut ring = HashRing {
+            keys: Vec::new(),
+            nodes: Vec::new(),
+            virtual_nodes: virtual_nodes.max(1),
+            weights,
             hasher,
+        };
+
+        for node in nodes {
+            ring.add_node(node);
         }
+
+        ring
     }
 
     fn get_key<V: Hash>(&self, val: &V) -> u64 {
@@ -30,58 +56,586 @@ impl<T: Hash, H: BuildHasher> HashRing<T, H> {
     }
 
     pub fn add_node(&mut self, node: T) {
-        let key = self.get_key(&node);
-        let idx = self.keys.binary_search_by_key(&key, |&(k, _)| k).unwrap_or_else(|idx| idx);
-        self.keys.insert(idx, (key, node));
+        if self.nodes.contains(&node) {
+            return;
+        }
+
+        let weight = self.weights.get(&node).copied().unwrap_or(1).max(1);
+        for i in 0..self.virtual_nodes * weight {
+            let key = self.get_key(&(&node, i));
+            let idx = self.keys.binary_search_by_key(&key, |&(k, _)| k).unwrap_or_else(|idx| idx);
+            self.keys.insert(idx, (key, node.clone()));
+        }
+
+        self.nodes.push(node);
     }
 
+    pub fn remove_node(&mut self, node: &T) {
+        self.keys.retain(|(_, n)| n != node);
+        self.nodes.retain(|n| n != node);
+    }
+
+    #[cfg(test)]
     pub fn get_node_for_val<V: Hash>(&self, val: &V) -> Option<&T> {
-        if self.keys.len() == 0 {
+        if self.keys.is_empty() {
             return None;   
         }
 
+        // The first point at or after the value's hash, wrapping around to the start of the ring
         let key = self.get_key(val);
-        for k in self.keys.iter() {
-            if key >= k.0 {
-                return Some(&k.1);
+        let idx = self.keys.partition_point(|&(k, _)| k < key);
+        return Some(&self.keys[idx % self.keys.len()].1);
+    }
+
+    /// The first `n` distinct nodes clockwise from where the value hashes to, i.e. its owner followed by its successors
+    pub fn get_nodes_for_val<V: Hash>(&self, val: &V, n: usize) -> Vec<&T> {
+        let mut nodes = Vec::new();
+        if self.keys.is_empty() {
+            return nodes;
+        }
+
+        let n = n.min(self.nodes.len());
+        let start = self.keys.partition_point(|&(k, _)| k < self.get_key(val));
+        for i in 0..self.keys.len() {
+            let node = &self.keys[(start + i) % self.keys.len()].1;
+            if !nodes.contains(&node) {
+                nodes.push(node);
+                if nodes.len() == n {
+                    break;
+                }
             }
         }
 
-        return Some(&self.keys.get(&self.keys.len() - 1).unwrap().1);
+        nodes
+    }
+
+    pub fn nodes(&self) -> &[T] {
+        &self.nodes
+    }
+}
+
+/// How forwards to a peer get retried when they fail
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// How many times to retry a forward after the first attempt fails
+    pub max_retries: u32,
+
+    /// How long to wait before the first retry. Each retry after that waits twice as long as the last
+    pub base_delay: Duration,
+
+    /// How long a single attempt can take before it's abandoned
+    pub attempt_timeout: Duration,
+}
+
+impl RetryPolicy {
+    /// How long to wait before the given retry (counting from 0)
+    pub fn backoff(&self, retry: u32) -> Duration {
+        self.base_delay.saturating_mul(1 << retry.min(16))
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 3,
+            base_delay: Duration::from_millis(100),
+            attempt_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// How the HTTP client used to forward pushes to peers is set up. The client is shared between every
+/// forward, so that connections (and TLS sessions) get reused
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    /// The most idle connections to keep open to each peer
+    pub pool_max_idle_per_host: usize,
+
+    /// How long an idle connection is kept open before it's closed
+    pub pool_idle_timeout: Duration,
+
+    /// How long to wait for a connection to a peer to be established. This is separate to
+    /// `RetryPolicy::attempt_timeout`, which covers the whole request
+    pub connect_timeout: Duration,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            pool_max_idle_per_host: 32,
+            pool_idle_timeout: Duration::from_secs(90),
+            connect_timeout: Duration::from_secs(2),
+        }
     }
 }
 
+impl ClientConfig {
+    fn build_client(&self) -> reqwest::Client {
+        // This can only fail if the TLS backend can't be initialised, in which case `reqwest::Client::new` would panic too
+        reqwest::Client::builder()
+            .pool_max_idle_per_host(self.pool_max_idle_per_host)
+            .pool_idle_timeout(self.pool_idle_timeout)
+            .connect_timeout(self.connect_timeout)
+            .build()
+            .expect("failed to build the HTTP client for forwarding to peers")
+    }
+}
+
+/// What a forward does when the most forwards that are allowed at once are already in flight
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ForwardLimitPolicy {
+    /// Wait for one of the forwards in flight to finish
+    #[default]
+    Queue,
+    /// Fail straight away, rather than holding the push up behind a backlog of forwards
+    Fail,
+}
+
+impl FromStr for ForwardLimitPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "queue" => Ok(ForwardLimitPolicy::Queue),
+            "fail" => Ok(ForwardLimitPolicy::Fail),
+            _ => Err(format!("Invalid forward limit policy: {}", s))
+        }
+    }
+}
+
+/// The most forwards that can be in flight at once, unless configured otherwise
+pub const DEFAULT_MAX_CONCURRENT_FORWARDS: usize = 256;
+
+/// Caps how many forwards can be in flight at once, so that a burst of pushes for other peers can't open an unbounded
+/// number of connections
+#[derive(Debug)]
+pub struct ForwardLimiter {
+    max_concurrent_forwards: usize,
+    policy: ForwardLimitPolicy,
+    permits: Semaphore,
+}
+
+impl ForwardLimiter {
+    pub fn new(max_concurrent_forwards: usize, policy: ForwardLimitPolicy) -> ForwardLimiter {
+        let max_concurrent_forwards = max_concurrent_forwards.max(1);
+        ForwardLimiter {
+            max_concurrent_forwards,
+            policy,
+            permits: Semaphore::new(max_concurrent_forwards),
+        }
+    }
+
+    /// Takes a slot for a forward, which is given back when the permit is dropped. If they're all taken, this waits for
+    /// one to be given back, or returns None if the policy is to fail
+    pub async fn acquire(&self) -> Option<SemaphorePermit<'_>> {
+        match self.policy {
+            ForwardLimitPolicy::Queue => self.permits.acquire().await.ok(),
+            ForwardLimitPolicy::Fail => self.permits.try_acquire().ok(),
+        }
+    }
+
+    /// How many forwards are holding slots right now. Forwards that are queued for one don't count
+    pub fn in_flight(&self) -> usize {
+        self.max_concurrent_forwards - self.permits.available_permits()
+    }
+}
+
+impl Default for ForwardLimiter {
+    fn default() -> Self {
+        ForwardLimiter::new(DEFAULT_MAX_CONCURRENT_FORWARDS, ForwardLimitPolicy::default())
+    }
+}
+
+/// When to stop forwarding to a peer that keeps failing
+#[derive(Debug, Clone)]
+pub struct CircuitBreakerConfig {
+    /// How many forwards in a row have to fail before the peer's circuit opens
+    pub failure_threshold: u32,
+
+    /// How long an open circuit fails forwards straight away, before letting one through to see if the peer has recovered
+    pub cooldown: Duration,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        CircuitBreakerConfig {
+            failure_threshold: 5,
+            cooldown: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct PeerHealth {
+    consecutive_failures: u32,
+    open_until: Option<Instant>,
+}
+
+/// Tracks the health of each peer, so that forwards to a dead one can fail fast rather than timing out
+#[derive(Debug, Default)]
+pub struct CircuitBreaker {
+    config: CircuitBreakerConfig,
+    peers: Mutex<HashMap<String, PeerHealth>>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: CircuitBreakerConfig) -> CircuitBreaker {
+        CircuitBreaker {
+            config,
+            peers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether a forward to the given peer should be attempted. Once an open circuit's cooldown has passed,
+    /// forwards are let through again, and the first result decides whether it closes or stays open
+    pub fn allow(&self, peer: &str) -> bool {
+        let peers = self.peers.lock().unwrap();
+        match peers.get(peer).and_then(|health| health.open_until) {
+            Some(open_until) => Instant::now() >= open_until,
+            None => true,
+        }
+    }
+
+    pub fn record_success(&self, peer: &str) {
+        self.peers.lock().unwrap().remove(peer);
+    }
+
+    pub fn record_failure(&self, peer: &str) {
+        let mut peers = self.peers.lock().unwrap();
+        let health = peers.entry(peer.to_owned()).or_default();
+        health.consecutive_failures += 1;
+        if health.consecutive_failures >= self.config.failure_threshold {
+            health.open_until = Some(Instant::now() + self.config.cooldown);
+        }
+    }
+
+    /// Whether the given peer has failed enough to trip its circuit, and hasn't succeeded since
+    pub fn is_open(&self, peer: &str) -> bool {
+        self.peers.lock().unwrap().get(peer).is_some_and(|health| health.open_until.is_some())
+    }
+}
+
+/// The label that pushes are sharded by, if nothing else is configured
+const DEFAULT_KEY_LABEL: &str = "job";
+
+type PeerRing = HashRing<String, BuildHasherDefault<XxHash64>>;
+
+/// Somewhere to look up the current set of peers, for clusters whose membership changes over time
+pub trait PeerResolver: Send + Sync + 'static {
+    fn resolve(&self) -> Result<Vec<String>, io::Error>;
+}
+
+/// Resolves a DNS name, e.g. a Kubernetes headless service, to a peer for every address it has
+pub struct DnsPeerResolver {
+    /// The name to resolve, along with the port the peers listen on, e.g. `gravel.monitoring.svc:4278`
+    address: String,
+}
+
+impl DnsPeerResolver {
+    pub fn new(address: String) -> DnsPeerResolver {
+        DnsPeerResolver { address }
+    }
+}
+
+impl PeerResolver for DnsPeerResolver {
+    fn resolve(&self) -> Result<Vec<String>, io::Error> {
+        let mut peers: Vec<String> = self.address.to_socket_addrs()?.map(|addr| addr.to_string()).collect();
+        peers.sort();
+        peers.dedup();
+        Ok(peers)
+    }
+}
+
+/// Adds the scheme to a peer's address, if it doesn't already have one
+fn peer_url(peer: String) -> String {
+    if peer.contains("::/") {
+        return peer;
+    }
+
+    "http://".to_owned() + &peer
+}
+
 pub struct ClusterConfig {
     self_url: String,
-    peers: HashRing<String, BuildHasherDefault<XxHash64>>
+    /// Shared with the task that rediscovers peers, if there is one
+    peers: Arc<RwLock<PeerRing>>,
+    retry_policy: RetryPolicy,
+    client: reqwest::Client,
+    circuit_breaker: CircuitBreaker,
+    forward_limiter: ForwardLimiter,
+    replication_factor: usize,
+    key_labels: Vec<String>,
+    /// Set once we're draining, which takes us off the ring (and keeps us off it when the peers are rediscovered)
+    draining: Arc<AtomicBool>,
+}
+
+/// A ring of the given peers and ourselves
+fn new_ring(self_url: &str, peers: Vec<String>, virtual_nodes: usize, weights: HashMap<String, usize>) -> PeerRing {
+    let mut ring = HashRing::new_with_nodes(BuildHasherDefault::default(), peers.into_iter().map(peer_url), virtual_nodes, weights);
+    ring.add_node(self_url.to_owned());
+    ring
+}
+
+fn refresh_ring(ring: &RwLock<PeerRing>, self_url: &str, draining: &AtomicBool, resolver: &dyn PeerResolver) -> Result<bool, io::Error> {
+    let peers = resolver.resolve()?;
+    if peers.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "no peers found"));
+    }
+
+    let (virtual_nodes, weights) = {
+        let ring = ring.read().unwrap();
+        (ring.virtual_nodes, ring.weights.clone())
+    };
+    let mut new = new_ring(self_url, peers, virtual_nodes, weights);
+    if draining.load(Ordering::Relaxed) {
+        new.remove_node(&self_url.to_owned());
+    }
+
+    let mut ring = ring.write().unwrap();
+    let mut old_nodes = ring.nodes().to_vec();
+    let mut new_nodes = new.nodes().to_vec();
+    old_nodes.sort();
+    new_nodes.sort();
+    if old_nodes == new_nodes {
+        return Ok(false);
+    }
+
+    *ring = new;
+    Ok(true)
 }
 
 impl ClusterConfig {
-    pub fn new_from_static(mut self_url: String, mut peers: Vec<String>) -> ClusterConfig {
-        for peer in peers.iter_mut() {
-            if !peer.contains("::/") {
-                *peer = "http://".to_owned() + &peer;
-            }
+    pub fn new_from_static(self_url: String, peers: Vec<String>) -> ClusterConfig {
+        let self_url = peer_url(self_url);
+        let peers = new_ring(&self_url, peers, DEFAULT_VIRTUAL_NODES, HashMap::new());
+
+        ClusterConfig {
+            self_url,
+            peers: Arc::new(RwLock::new(peers)),
+            retry_policy: RetryPolicy::default(),
+            client: ClientConfig::default().build_client(),
+            circuit_breaker: CircuitBreaker::default(),
+            forward_limiter: ForwardLimiter::default(),
+            replication_factor: 1,
+            key_labels: vec![DEFAULT_KEY_LABEL.to_owned()],
+            draining: Arc::new(AtomicBool::new(false)),
         }
+    }
 
-        if !self_url.contains("::/") {
-            self_url = "http://".to_owned() + &self_url;
+    /// Rebuilds the hash ring with the given number of points per peer. More points spread jobs more evenly,
+    /// at the cost of a bigger ring to search
+    pub fn with_virtual_nodes(self, virtual_nodes: usize) -> ClusterConfig {
+        {
+            let mut ring = self.peers.write().unwrap();
+            *ring = HashRing::new_with_nodes(BuildHasherDefault::default(), ring.nodes().to_vec(), virtual_nodes, ring.weights.clone());
         }
+        self
+    }
 
-        let hasher = BuildHasherDefault::<XxHash64>::default();
-        let mut peers = HashRing::new_with_nodes(hasher, peers);
-        peers.add_node(self_url.clone());
-        
-        ClusterConfig {
-            self_url,
-            peers
+    /// Rebuilds the hash ring with the given weights, by peer address (which can be this gateway's own). A peer with a
+    /// weight of 2 gets twice the points on the ring, and so about twice the jobs, of a peer without one
+    pub fn with_peer_weights(self, weights: HashMap<String, usize>) -> ClusterConfig {
+        {
+            let weights = weights.into_iter().map(|(peer, weight)| (peer_url(peer), weight)).collect();
+            let mut ring = self.peers.write().unwrap();
+            *ring = HashRing::new_with_nodes(BuildHasherDefault::default(), ring.nodes().to_vec(), ring.virtual_nodes, weights);
+        }
+        self
+    }
+
+    /// Looks the peers up with the given resolver, replacing the ring with them if they've changed. If the lookup fails
+    /// (or finds no peers at all) the last peers that were found are kept, rather than leaving the cluster empty.
+    /// Returns whether the peers changed
+    pub fn refresh_peers(&self, resolver: &dyn PeerResolver) -> Result<bool, io::Error> {
+        refresh_ring(&self.peers, &self.self_url, &self.draining, resolver)
+    }
+
+    /// Spawns a task that looks the peers up with the given resolver every `interval`, rebuilding the ring when they
+    /// change. It only holds a weak reference to the ring, so it shuts down once this config has been dropped
+    pub fn spawn_peer_discovery(&self, resolver: Box<dyn PeerResolver>, interval: Duration) {
+        let ring: Weak<RwLock<PeerRing>> = Arc::downgrade(&self.peers);
+        let self_url = self.self_url.clone();
+        let draining = Arc::clone(&self.draining);
+        let resolver: Arc<dyn PeerResolver> = Arc::from(resolver);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                let ring = match ring.upgrade() {
+                    Some(ring) => ring,
+                    None => return,
+                };
+
+                // Lookups block, so they're kept off the runtime's worker threads
+                let (self_url, draining, resolver) = (self_url.clone(), Arc::clone(&draining), Arc::clone(&resolver));
+                let result = tokio::task::spawn_blocking(move || refresh_ring(&ring, &self_url, &draining, resolver.as_ref())).await;
+                match result {
+                    Ok(Ok(true)) => info!("peers changed, rebuilt the hash ring"),
+                    Ok(Ok(false)) => {},
+                    Ok(Err(e)) => warn!(error = %e, "failed to look up peers, keeping the last ones found"),
+                    Err(e) => warn!(error = %e, "peer lookup panicked, keeping the last ones found"),
+                }
+            }
+        });
+    }
+
+    /// Sets how many peers each push is sent to. A push succeeds once a majority of them have accepted it
+    pub fn with_replication_factor(mut self, replication_factor: usize) -> ClusterConfig {
+        self.replication_factor = replication_factor.max(1);
+        self
+    }
+
+    /// Sets the labels whose values decide which peer a push goes to
+    pub fn with_key_labels(mut self, key_labels: Vec<String>) -> ClusterConfig {
+        if !key_labels.is_empty() {
+            self.key_labels = key_labels;
         }
+        self
+    }
+
+    /// Composes the key that a push with the given labels is sharded by, from the values of the key labels.
+    /// If the push has none of them, it falls back to the job label, and then to the empty key
+    pub fn sharding_key(&self, labels: &HashMap<&str, &str>) -> String {
+        if !self.key_labels.iter().any(|label| labels.contains_key(label.as_str())) {
+            return labels.get(DEFAULT_KEY_LABEL).unwrap_or(&"").to_string();
+        }
+
+        let values: Vec<&str> = self.key_labels.iter().map(|label| *labels.get(label.as_str()).unwrap_or(&"")).collect();
+        return values.join("\0");
+    }
+
+    pub fn with_circuit_breaker(mut self, config: CircuitBreakerConfig) -> ClusterConfig {
+        self.circuit_breaker = CircuitBreaker::new(config);
+        self
+    }
+
+    pub fn circuit_breaker(&self) -> &CircuitBreaker {
+        &self.circuit_breaker
+    }
+
+    /// The peers that we currently can't forward to, because their circuits are open
+    pub fn unreachable_peers(&self) -> Vec<String> {
+        let mut peers: Vec<String> = self.peers.read().unwrap().nodes().iter().filter(|peer| !self.is_self(peer) && self.circuit_breaker.is_open(peer)).cloned().collect();
+        peers.sort();
+        peers
+    }
+
+    /// Takes us off the hash ring, so that every key is owned by the other peers from now on. Pushes that we get
+    /// after this are forwarded on to them
+    pub fn drain(&self) {
+        self.draining.store(true, Ordering::Relaxed);
+        self.peers.write().unwrap().remove_node(&self.self_url);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    /// Whether a majority of the cluster (counting ourselves) is reachable
+    pub fn has_quorum(&self) -> bool {
+        let nodes = self.peers.read().unwrap().nodes().len();
+        nodes - self.unreachable_peers().len() > nodes / 2
+    }
+
+    /// A `gravel_peer_circuit_open` gauge for every peer, which is 1 while forwards to that peer are being failed fast
+    pub fn circuit_state_family(&self) -> PrometheusMetricFamily {
+        let mut peers: Vec<String> = self.peers.read().unwrap().nodes().iter().filter(|peer| !self.is_self(peer)).cloned().collect();
+        peers.sort();
+
+        let samples = peers.into_iter().map(|peer| {
+            let open = if self.circuit_breaker.is_open(&peer) { 1 } else { 0 };
+            Sample::new(vec![peer], None, PrometheusValue::Gauge(MetricNumber::Int(open)))
+        });
+
+        let family = PrometheusMetricFamily::new(
+            "gravel_peer_circuit_open".to_owned(),
+            vec!["peer".to_owned()],
+            PrometheusType::Gauge,
+            String::new(),
+            String::new(),
+        );
+
+        // Peers are unique in the ring, so this can't fail
+        family.with_samples(samples).unwrap()
+    }
+
+    /// Limits how many forwards can be in flight at once, with what to do with the ones over the limit
+    pub fn with_forward_limit(mut self, max_concurrent_forwards: usize, policy: ForwardLimitPolicy) -> ClusterConfig {
+        self.forward_limiter = ForwardLimiter::new(max_concurrent_forwards, policy);
+        self
+    }
+
+    pub fn forward_limiter(&self) -> &ForwardLimiter {
+        &self.forward_limiter
+    }
+
+    /// A `gravel_forwards_in_flight` gauge of how many forwards to peers are being sent right now
+    pub fn forwards_in_flight_family(&self) -> PrometheusMetricFamily {
+        let sample = Sample::new(Vec::new(), None, PrometheusValue::Gauge(MetricNumber::Int(self.forward_limiter.in_flight() as i64)));
+        let family = PrometheusMetricFamily::new(
+            "gravel_forwards_in_flight".to_owned(),
+            Vec::new(),
+            PrometheusType::Gauge,
+            "Forwards to peers currently being sent".to_owned(),
+            String::new(),
+        );
+
+        // There's only the one sample, so this can't fail
+        family.with_samples(vec![sample]).unwrap()
+    }
+
+    pub fn with_client_config(mut self, client_config: &ClientConfig) -> ClusterConfig {
+        self.client = client_config.build_client();
+        self
+    }
+
+    /// The client to forward pushes with. It's cheap to clone, and clones share a connection pool
+    pub fn client(&self) -> &reqwest::Client {
+        &self.client
+    }
+
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> ClusterConfig {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    pub fn retry_policy(&self) -> &RetryPolicy {
+        &self.retry_policy
     }
 
     pub fn is_self(&self, url: &str) -> bool {
         url == self.self_url
     }
 
+    /// Whether a request from the given address came from one of our peers, going by the addresses that their hosts
+    /// resolve to (looking them up again every time, since they can move). Only peers forward pushes to us, so this is
+    /// what decides whether a request that says it was forwarded is believed
+    pub async fn is_peer_address(&self, addr: IpAddr) -> bool {
+        let addr = addr.to_canonical();
+        let peers: Vec<String> = self.peers.read().unwrap().nodes().iter().filter(|peer| !self.is_self(peer)).cloned().collect();
+        for peer in peers {
+            let url = match reqwest::Url::parse(&peer) {
+                Ok(url) => url,
+                Err(_) => continue,
+            };
+
+            let host = url.host_str().unwrap_or_default().trim_start_matches('[').trim_end_matches(']');
+            let found = match host.parse::<IpAddr>() {
+                Ok(ip) => ip.to_canonical() == addr,
+                Err(_) => match tokio::net::lookup_host((host, url.port_or_known_default().unwrap_or(80))).await {
+                    Ok(mut found) => found.any(|peer| peer.ip().to_canonical() == addr),
+                    Err(_) => false,
+                },
+            };
+
+            if found {
+                return true;
+            }
+        }
+
+        false
+    }
+
     pub fn new_from_srv(self_url: String, srv: &str) -> Result<ClusterConfig, ResolveError> {
         let mut peers = Vec::new();
 
@@ -113,7 +667,13 @@ impl ClusterConfig {
         return Ok(ClusterConfig::new_from_static(self_url, peers));
     }
 
-    pub fn get_peer_for_key<T: Hash>(&self, key: &T) -> Option<&String> {
-        self.peers.get_node_for_val(key)
+    #[cfg(test)]
+    pub fn get_peer_for_key<T: Hash>(&self, key: &T) -> Option<String> {
+        self.peers.read().unwrap().get_node_for_val(key).cloned()
+    }
+
+    /// The peers (possibly including ourselves) that should hold the metrics for the given key
+    pub fn get_peers_for_key<T: Hash>(&self, key: &T) -> Vec<String> {
+        self.peers.read().unwrap().get_nodes_for_val(key, self.replication_factor).into_iter().cloned().collect()
     }
 }
@@ -0,0 +1,138 @@
+use std::{collections::HashMap, io, sync::Mutex};
+
+use crate::clustering::{ClusterConfig, PeerResolver};
+
+fn peers(n: usize) -> Vec<String> {
+    (1..=n).map(|i| format!("peer{}:4278", i)).collect()
+}
+
+fn assignments(cluster_conf: &ClusterConfig, jobs: &[String]) -> Vec<String> {
+    jobs.iter().map(|job| cluster_conf.get_peer_for_key(&job.as_str()).unwrap().clone()).collect()
+}
+
+#[test]
+fn test_jobs_spread_across_peers() {
+    let cluster_conf = ClusterConfig::new_from_static("self:4278".to_owned(), peers(4));
+    let jobs: Vec<String> = (0..10000).map(|i| format!("job{}", i)).collect();
+
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for peer in assignments(&cluster_conf, &jobs) {
+        *counts.entry(peer).or_default() += 1;
+    }
+
+    // 5 nodes (including ourselves), so each should get roughly 2000 jobs
+    assert_eq!(counts.len(), 5);
+    for (peer, count) in counts {
+        assert!((1000..3000).contains(&count), "{} got {} of the jobs", peer, count);
+    }
+}
+
+#[test]
+fn test_adding_a_peer_moves_few_jobs() {
+    let jobs: Vec<String> = (0..10000).map(|i| format!("job{}", i)).collect();
+    let before = assignments(&ClusterConfig::new_from_static("self:4278".to_owned(), peers(9)), &jobs);
+    let after = assignments(&ClusterConfig::new_from_static("self:4278".to_owned(), peers(10)), &jobs);
+
+    // Going from 10 to 11 nodes should move about 1/11 of the jobs, and only ever onto the new peer
+    let moved: Vec<&String> = before.iter().zip(after.iter()).filter(|(b, a)| b != a).map(|(_, a)| a).collect();
+    assert!(moved.iter().all(|peer| peer.as_str() == "http://peer10:4278"));
+
+    let fraction = moved.len() as f64 / jobs.len() as f64;
+    assert!(fraction > 0.04 && fraction < 0.15, "{} of the jobs moved", fraction);
+}
+
+#[test]
+fn test_virtual_nodes_are_configurable() {
+    let jobs: Vec<String> = (0..1000).map(|i| format!("job{}", i)).collect();
+    let cluster_conf = ClusterConfig::new_from_static("self:4278".to_owned(), peers(3));
+    let rebuilt = ClusterConfig::new_from_static("self:4278".to_owned(), peers(3)).with_virtual_nodes(1);
+
+    // Fewer points per peer means different (lumpier) boundaries on the ring
+    assert_ne!(assignments(&cluster_conf, &jobs), assignments(&rebuilt, &jobs));
+}
+
+#[test]
+fn test_peer_weights() {
+    let weights = vec![("peer2:4278".to_owned(), 2), ("self:4278".to_owned(), 0)].into_iter().collect();
+    let cluster_conf = ClusterConfig::new_from_static("self:4278".to_owned(), peers(3)).with_peer_weights(weights);
+    let jobs: Vec<String> = (0..20000).map(|i| format!("job{}", i)).collect();
+
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for peer in assignments(&cluster_conf, &jobs) {
+        *counts.entry(peer).or_default() += 1;
+    }
+
+    // Weights of 1:2:1 for the peers, and ourselves at the default of 1 (a weight of 0 can't take us off the ring),
+    // so peer2 should get about 8000 of the jobs and the rest about 4000 each
+    assert_eq!(counts.len(), 4);
+    for (peer, count) in counts {
+        let expected = if peer == "http://peer2:4278" { 8000 } else { 4000 };
+        assert!((count as f64 / expected as f64 - 1.).abs() < 0.25, "{} got {} of the jobs, expected about {}", peer, count, expected);
+    }
+}
+
+#[test]
+fn test_sharding_key_labels() {
+    let labels: HashMap<&str, &str> = vec![("job", "foo"), ("tenant", "acme"), ("instance", "a:80")].into_iter().collect();
+
+    let cluster_conf = ClusterConfig::new_from_static("self:4278".to_owned(), peers(4));
+    assert_eq!(cluster_conf.sharding_key(&labels), "foo");
+
+    let cluster_conf = cluster_conf.with_key_labels(vec!["tenant".to_owned(), "instance".to_owned()]);
+    assert_eq!(cluster_conf.sharding_key(&labels), "acme\0a:80");
+
+    // Missing key labels are empty, as long as some of them are there
+    let partial: HashMap<&str, &str> = vec![("job", "foo"), ("instance", "a:80")].into_iter().collect();
+    assert_eq!(cluster_conf.sharding_key(&partial), "\0a:80");
+
+    // Falling back to the job, and then to nothing, when none of them are
+    let job_only: HashMap<&str, &str> = vec![("job", "foo")].into_iter().collect();
+    assert_eq!(cluster_conf.sharding_key(&job_only), "foo");
+    assert_eq!(cluster_conf.sharding_key(&HashMap::new()), "");
+}
+
+/// Hands out the given lookup results in order, one per lookup
+struct MockResolver(Mutex<Vec<Result<Vec<String>, io::Error>>>);
+
+impl PeerResolver for MockResolver {
+    fn resolve(&self) -> Result<Vec<String>, io::Error> {
+        self.0.lock().unwrap().remove(0)
+    }
+}
+
+#[test]
+fn test_peers_are_rediscovered() {
+    let resolver = MockResolver(Mutex::new(vec![
+        Ok(peers(2)),
+        Ok(peers(2)),
+        Ok(peers(3)),
+        Err(io::Error::other("lookup failed")),
+        Ok(Vec::new()),
+        Ok(vec!["peer3:4278".to_owned()]),
+    ]));
+
+    let jobs: Vec<String> = (0..1000).map(|i| format!("job{}", i)).collect();
+    let cluster_conf = ClusterConfig::new_from_static("self:4278".to_owned(), Vec::new());
+    assert!(assignments(&cluster_conf, &jobs).iter().all(|peer| peer == "http://self:4278"));
+
+    assert!(cluster_conf.refresh_peers(&resolver).unwrap());
+    assert_eq!(assignments(&cluster_conf, &jobs), assignments(&ClusterConfig::new_from_static("self:4278".to_owned(), peers(2)), &jobs));
+
+    // The same peers again don't change anything
+    assert!(!cluster_conf.refresh_peers(&resolver).unwrap());
+
+    assert!(cluster_conf.refresh_peers(&resolver).unwrap());
+    let three_peers = assignments(&cluster_conf, &jobs);
+    assert_eq!(three_peers, assignments(&ClusterConfig::new_from_static("self:4278".to_owned(), peers(3)), &jobs));
+    assert!(three_peers.iter().any(|peer| peer == "http://peer3:4278"));
+
+    // Failed and empty lookups keep the last peers that were found
+    assert!(cluster_conf.refresh_peers(&resolver).is_err());
+    assert!(cluster_conf.refresh_peers(&resolver).is_err());
+    assert_eq!(assignments(&cluster_conf, &jobs), three_peers);
+
+    assert!(cluster_conf.refresh_peers(&resolver).unwrap());
+    let remaining = assignments(&cluster_conf, &jobs);
+    assert!(remaining.iter().all(|peer| peer == "http://self:4278" || peer == "http://peer3:4278"));
+    assert_eq!(cluster_conf.get_peer_for_key(&"job1"), remaining.get(1).cloned());
+}
@@ -0,0 +1,747 @@
+use std::{borrow::Cow, collections::{HashMap, HashSet}, fmt};
+
+use openmetrics_parser::{Exemplar, LabelSet, MetricFamily, MetricNumber, MetricsExposition, PrometheusType, PrometheusValue, RenderableMetricValue, Sample, Timestamp};
+
+use crate::aggregator::CLEARMODE_LABEL_NAME;
+
+/// A value that can carry exemplars. Exemplars only have a place in the OpenMetrics format, so values are expected to
+/// render without them, and `OpenMetricsFamily` adds them back on
+pub trait ExemplarValue {
+    /// The exemplar for each line that the value renders as, in order. Lines past the end of the list have no exemplar
+    fn exemplars(&self) -> Vec<Option<&Exemplar>>;
+}
+
+/// Renders a single sample value, so that its lines can be picked apart afterwards
+struct RenderedValue<'a, V> {
+    value: &'a V,
+    metric_name: &'a str,
+    timestamp: Option<&'a Timestamp>,
+    label_names: &'a [&'a str],
+    label_values: &'a [&'a str],
+}
+
+impl<'a, V> fmt::Display for RenderedValue<'a, V> where V: RenderableMetricValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.value.render(f, self.metric_name, self.timestamp, self.label_names, self.label_values)
+    }
+}
+
+/// Renders an exemplar as the ` # {labels} value [timestamp]` suffix of a line, with its labels in order of name
+fn render_exemplar(exemplar: &Exemplar) -> String {
+    let mut labels: Vec<(&String, &String)> = exemplar.labels.iter().collect();
+    labels.sort();
+    let labels: Vec<String> = labels.into_iter().map(|(name, value)| format!("{}=\"{}\"", name, value)).collect();
+
+    let mut out = format!(" # {{{}}} {}", labels.join(","), MetricNumber::Float(exemplar.id));
+    if let Some(timestamp) = exemplar.timestamp {
+        out.push_str(&format!(" {}", MetricNumber::Float(timestamp)));
+    }
+
+    out
+}
+
+/// Wraps a metric family so that it renders in the OpenMetrics text format, rather than
+/// the Prometheus 0.0.4 one that `MetricFamily`s Display impl uses
+pub struct OpenMetricsFamily<'a, V> {
+    family: &'a MetricFamily<PrometheusType, V>,
+    /// For a counter, the gauge family its `_created` series were split off into by `extract_counter_created`
+    created: Option<&'a MetricFamily<PrometheusType, V>>,
+    /// Set to render Prometheus text with the OpenMetrics features in it that pushes of Prometheus text can have
+    prometheus: bool,
+}
+
+impl<'a, V> OpenMetricsFamily<'a, V> {
+    pub fn new(family: &'a MetricFamily<PrometheusType, V>) -> Self {
+        OpenMetricsFamily { family, created: None, prometheus: false }
+    }
+
+    /// Renders each series of the given family after the counter series with the same labels, as its `_created` series
+    pub fn with_created(mut self, created: Option<&'a MetricFamily<PrometheusType, V>>) -> Self {
+        self.created = created;
+        self
+    }
+
+    /// Renders the family as a Prometheus text push that still has its exemplars, units, and `_created` series, which
+    /// is to say with Prometheus metadata names and timestamps in milliseconds
+    pub fn in_prometheus_push_format(mut self) -> Self {
+        self.prometheus = true;
+        self
+    }
+
+    /// The name of the family in the OpenMetrics metadata. OpenMetrics counters are named
+    /// without the `_total` suffix that their samples carry
+    fn descriptor_name(&self) -> &str {
+        let family = self.family;
+        match family.family_type {
+            PrometheusType::Counter if !self.prometheus => family.family_name.strip_suffix("_total").unwrap_or(&family.family_name),
+            _ => &family.family_name
+        }
+    }
+}
+
+impl<'a, V> fmt::Display for OpenMetricsFamily<'a, V> where V: RenderableMetricValue + ExemplarValue + Clone {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let family = self.family;
+        let name = self.descriptor_name();
+        let created = match self.created {
+            Some(created) => render_created(created)?,
+            None => HashMap::new(),
+        };
+
+        writeln!(f, "# TYPE {} {}", name, family.family_type)?;
+
+        if !family.unit.is_empty() {
+            writeln!(f, "# UNIT {} {}", name, family.unit)?;
+        }
+
+        if !family.help.is_empty() {
+            writeln!(f, "# HELP {} {}", name, family.help)?;
+        }
+
+        let label_names: Vec<&str> = family.get_label_names().iter().map(|s| s.as_str()).collect();
+        for sample in family.iter_samples() {
+            let labelset = match sample.get_labelset() {
+                Ok(labelset) => labelset,
+                Err(_) => return Err(fmt::Error)
+            };
+
+            let label_values: Vec<&str> = labelset.iter_values().map(|s| s.as_str()).collect();
+
+            // Prometheus timestamps are in milliseconds, OpenMetrics ones are in seconds
+            let timestamp = match self.prometheus {
+                true => sample.timestamp,
+                false => sample.timestamp.map(|t| t / 1000.),
+            };
+            let rendered = RenderedValue {
+                value: &sample.value,
+                metric_name: &family.family_name,
+                timestamp: timestamp.as_ref(),
+                label_names: &label_names,
+                label_values: &label_values,
+            }.to_string();
+
+            let mut exemplars = sample.value.exemplars().into_iter();
+            for line in rendered.lines() {
+                f.write_str(line)?;
+                if let Some(exemplar) = exemplars.next().flatten() {
+                    f.write_str(&render_exemplar(exemplar))?;
+                }
+                writeln!(f)?;
+            }
+
+            if let Some(line) = created.get(&sorted_labels(&labelset)) {
+                f.write_str(line)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A sample's labels, in order of name, for matching up samples of different families which may have their labels
+/// in different orders
+fn sorted_labels(labelset: &LabelSet<'_>) -> Vec<(String, String)> {
+    let mut labels: Vec<(String, String)> = labelset.iter().map(|(name, value)| (name.clone(), value.clone())).collect();
+    labels.sort();
+    labels
+}
+
+/// Renders each sample of a family of `_created` series without a timestamp, by its labels
+fn render_created<V>(created: &MetricFamily<PrometheusType, V>) -> Result<HashMap<Vec<(String, String)>, String>, fmt::Error> where V: RenderableMetricValue + Clone {
+    let label_names: Vec<&str> = created.get_label_names().iter().map(|s| s.as_str()).collect();
+    let mut lines = HashMap::new();
+    for sample in created.iter_samples() {
+        let labelset = sample.get_labelset().map_err(|_| fmt::Error)?;
+        let label_values: Vec<&str> = labelset.iter_values().map(|s| s.as_str()).collect();
+        let rendered = RenderedValue {
+            value: &sample.value,
+            metric_name: &created.family_name,
+            timestamp: None,
+            label_names: &label_names,
+            label_values: &label_values,
+        }.to_string();
+        lines.insert(sorted_labels(&labelset), rendered);
+    }
+
+    Ok(lines)
+}
+
+/// An exemplar found on a counter sample, along with the labels of the sample it belongs to
+pub struct CounterExemplar {
+    metric_name: String,
+    labels: HashMap<String, String>,
+    exemplar: Exemplar,
+}
+
+/// The parser reads the exemplars on counters, but then drops them on the floor, so this strips them off every counter sample
+/// of a push beforehand, so that `attach_counter_exemplars` can put them back on after it's been parsed. Exemplars elsewhere
+/// are left alone - the parser keeps the ones on histogram buckets already, and rejects the rest
+pub fn extract_counter_exemplars(exposition: &str) -> (Cow<'_, str>, Vec<CounterExemplar>) {
+    let mut counters = HashSet::new();
+    let mut exemplars = Vec::new();
+    let mut stripped = String::with_capacity(exposition.len());
+
+    for line in exposition.split_inclusive('\n') {
+        let mut words = line.split_whitespace();
+        if let (Some("#"), Some("TYPE"), Some(name), Some("counter")) = (words.next(), words.next(), words.next(), words.next()) {
+            counters.insert(name);
+        }
+
+        match split_counter_exemplar(line, &counters) {
+            Some((sample, exemplar)) => {
+                stripped.push_str(sample);
+                stripped.push('\n');
+                exemplars.push(exemplar);
+            },
+            None => stripped.push_str(line),
+        }
+    }
+
+    if exemplars.is_empty() {
+        return (Cow::Borrowed(exposition), exemplars);
+    }
+
+    (Cow::Owned(stripped), exemplars)
+}
+
+/// Puts exemplars taken off by `extract_counter_exemplars` back onto the samples they came from
+pub fn attach_counter_exemplars(exposition: &mut MetricsExposition<PrometheusType, PrometheusValue>, exemplars: Vec<CounterExemplar>) {
+    for exemplar in exemplars {
+        let family = match exposition.families.get_mut(&exemplar.metric_name) {
+            Some(family) => family,
+            None => continue,
+        };
+
+        let label_values: Option<Vec<String>> = family.get_label_names().iter().map(|name| exemplar.labels.get(name).cloned()).collect();
+        let label_values = match label_values {
+            Some(values) if values.len() == exemplar.labels.len() => values,
+            _ => continue,
+        };
+
+        if let Some(Sample { value: PrometheusValue::Counter(counter), .. }) = family.get_sample_by_label_values_mut(&label_values) {
+            counter.exemplar = Some(exemplar.exemplar);
+        }
+    }
+}
+
+/// The Prometheus parser doesn't know about the `_created` series that OpenMetrics counters can have, and rejects them as
+/// samples of the wrong family. This moves the ones of every counter into a gauge family of their own, named after the
+/// series, with a `clearmode="min"` label so that merging them keeps the earliest creation time. `OpenMetricsFamily`
+/// renders them back alongside their counters
+pub fn extract_counter_created(exposition: &str) -> Cow<'_, str> {
+    let mut created_names = HashSet::new();
+    let mut created: Vec<(String, Vec<String>)> = Vec::new();
+    let mut stripped = String::with_capacity(exposition.len());
+
+    for line in exposition.split_inclusive('\n') {
+        let mut words = line.split_whitespace();
+        if let (Some("#"), Some("TYPE"), Some(name), Some("counter")) = (words.next(), words.next(), words.next(), words.next()) {
+            if let Some(base) = name.strip_suffix("_total") {
+                created_names.insert(format!("{}_created", base));
+            }
+        }
+
+        match split_created_sample(line, &created_names) {
+            Some((name, sample)) => match created.iter_mut().find(|(existing, _)| *existing == name) {
+                Some((_, samples)) => samples.push(sample),
+                None => created.push((name, vec![sample])),
+            },
+            None => stripped.push_str(line),
+        }
+    }
+
+    if created.is_empty() {
+        return Cow::Borrowed(exposition);
+    }
+
+    for (name, samples) in created {
+        stripped.push_str(&format!("# TYPE {} gauge\n", name));
+        for sample in samples {
+            stripped.push_str(&sample);
+            stripped.push('\n');
+        }
+    }
+
+    Cow::Owned(stripped)
+}
+
+/// Picks out a sample of one of the given `_created` series, returning its name and the sample rewritten with a
+/// `clearmode="min"` label (in place of any clearmode it already had). Returns None for every other line
+fn split_created_sample(line: &str, created_names: &HashSet<String>) -> Option<(String, String)> {
+    let line = line.trim_end();
+    if line.starts_with('#') {
+        return None;
+    }
+
+    let name_end = line.find(|c: char| c == '{' || c.is_whitespace())?;
+    let name = &line[..name_end];
+    if !created_names.contains(name) {
+        return None;
+    }
+
+    let (labels, rest) = match line[name_end..].strip_prefix('{') {
+        Some(rest) => parse_labels(rest)?,
+        None => (HashMap::new(), &line[name_end..]),
+    };
+
+    let mut labels: Vec<(String, String)> = labels.into_iter().filter(|(label, _)| label != CLEARMODE_LABEL_NAME).collect();
+    labels.push((CLEARMODE_LABEL_NAME.to_owned(), "min".to_owned()));
+    labels.sort();
+    let labels: Vec<String> = labels.into_iter().map(|(label, value)| format!("{}=\"{}\"", label, value)).collect();
+
+    Some((name.to_owned(), format!("{}{{{}}} {}", name, labels.join(","), rest.trim())))
+}
+
+/// The Prometheus parser doesn't know about OpenMetrics `info` and `stateset` families either, and neither can be summed
+/// like the counters it would otherwise take them for: an info series is always 1, and only one state of a stateset is.
+/// This turns both into gauges, with a `clearmode="replace"` label on every sample that doesn't have a clearmode already,
+/// so that pushes replace their series rather than adding to them. Info families are declared without the `_info` suffix
+/// that their samples have, so that's added to their names in their HELP and TYPE lines
+pub fn rewrite_info_and_statesets(exposition: &str) -> Cow<'_, str> {
+    let mut info = HashSet::new();
+    let mut statesets = HashSet::new();
+    for line in exposition.lines() {
+        let mut words = line.split_whitespace();
+        match (words.next(), words.next(), words.next(), words.next()) {
+            (Some("#"), Some("TYPE"), Some(name), Some("info")) => { info.insert(name); },
+            (Some("#"), Some("TYPE"), Some(name), Some("stateset")) => { statesets.insert(name); },
+            _ => {},
+        }
+    }
+
+    if info.is_empty() && statesets.is_empty() {
+        return Cow::Borrowed(exposition);
+    }
+
+    let info_name = |name: &str| match name.ends_with("_info") {
+        true => name.to_owned(),
+        false => format!("{}_info", name),
+    };
+    let sample_names: HashSet<String> = info.iter().map(|name| info_name(name)).chain(statesets.iter().map(|name| name.to_string())).collect();
+
+    let mut rewritten = String::with_capacity(exposition.len());
+    for line in exposition.lines() {
+        let metadata = line.strip_prefix("# HELP ").map(|rest| ("# HELP ", rest)).or_else(|| line.strip_prefix("# TYPE ").map(|rest| ("# TYPE ", rest)));
+        match metadata {
+            Some((prefix, rest)) => {
+                let name_end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+                let name = &rest[..name_end];
+                rewritten.push_str(prefix);
+                match info.contains(name) {
+                    true => rewritten.push_str(&info_name(name)),
+                    false => rewritten.push_str(name),
+                }
+                match (prefix, info.contains(name) || statesets.contains(name)) {
+                    ("# TYPE ", true) => rewritten.push_str(" gauge"),
+                    _ => rewritten.push_str(&rest[name_end..]),
+                }
+            },
+            None => match with_replace_clearmode(line, &sample_names) {
+                Some(sample) => rewritten.push_str(&sample),
+                None => rewritten.push_str(line),
+            },
+        }
+        rewritten.push('\n');
+    }
+
+    Cow::Owned(rewritten)
+}
+
+/// Adds a `clearmode="replace"` label to a sample of one of the given series, unless it has a clearmode already.
+/// Returns None for every other line
+fn with_replace_clearmode(line: &str, sample_names: &HashSet<String>) -> Option<String> {
+    let line = line.trim_end();
+    if line.starts_with('#') {
+        return None;
+    }
+
+    let name_end = line.find(|c: char| c == '{' || c.is_whitespace())?;
+    let name = &line[..name_end];
+    if !sample_names.contains(name) {
+        return None;
+    }
+
+    let (labels, rest) = match line[name_end..].strip_prefix('{') {
+        Some(rest) => parse_labels(rest)?,
+        None => (HashMap::new(), &line[name_end..]),
+    };
+    if labels.contains_key(CLEARMODE_LABEL_NAME) {
+        return None;
+    }
+
+    let mut labels: Vec<(String, String)> = labels.into_iter().collect();
+    labels.push((CLEARMODE_LABEL_NAME.to_owned(), "replace".to_owned()));
+    labels.sort();
+    let labels: Vec<String> = labels.into_iter().map(|(label, value)| format!("{}=\"{}\"", label, value)).collect();
+
+    Some(format!("{}{{{}}} {}", name, labels.join(","), rest.trim()))
+}
+
+/// The first sample of a counter in the exposition with a negative value, as it was written (without its value)
+pub fn find_negative_counter(exposition: &str) -> Option<String> {
+    let mut counters = HashSet::new();
+    for line in exposition.lines() {
+        let mut words = line.split_whitespace();
+        if let (Some("#"), Some("TYPE"), Some(name), Some("counter")) = (words.next(), words.next(), words.next(), words.next()) {
+            counters.insert(name);
+            continue;
+        }
+
+        let line = line.trim();
+        if line.starts_with('#') {
+            continue;
+        }
+
+        let name_end = match line.find(|c: char| c == '{' || c.is_whitespace()) {
+            Some(name_end) => name_end,
+            None => continue,
+        };
+        if !counters.contains(&line[..name_end]) {
+            continue;
+        }
+
+        let rest = match line[name_end..].strip_prefix('{') {
+            Some(rest) => match parse_labels(rest) {
+                Some((_, rest)) => rest,
+                None => continue,
+            },
+            None => &line[name_end..],
+        };
+
+        let negative = rest.split_whitespace().next().and_then(|value| value.parse::<f64>().ok()).is_some_and(|value| value < 0.);
+        if negative {
+            return Some(line[..line.len() - rest.len()].trim_end().to_owned());
+        }
+    }
+
+    None
+}
+
+/// A series' name, along with its labels in order
+type SeriesKey<'a> = (&'a str, Vec<(String, String)>);
+
+/// The parser rejects an exposition with the same series in it more than once, which clients sometimes do by accident.
+/// This folds the repeats of each series into its first line instead. Counters (along with histograms' buckets, sums, and
+/// counts, and summaries' sums and counts) are summed, like they would be across pushes, and for everything else the last
+/// value wins. The last line's timestamp (and exemplar) wins either way
+pub fn fold_duplicate_samples(exposition: &str) -> Cow<'_, str> {
+    let mut types: HashMap<&str, &str> = HashMap::new();
+    // The line each series was first seen on, with what it was written as and its value so far
+    let mut seen: HashMap<SeriesKey, (usize, &str, String)> = HashMap::new();
+    let mut lines: Vec<Cow<'_, str>> = Vec::new();
+    let mut folded = false;
+
+    for line in exposition.lines() {
+        let mut words = line.split_whitespace();
+        if let (Some("#"), Some("TYPE"), Some(name), Some(family_type)) = (words.next(), words.next(), words.next(), words.next()) {
+            types.insert(name, family_type);
+        }
+
+        let sample = match split_sample_line(line) {
+            Some(sample) => sample,
+            None => {
+                lines.push(Cow::Borrowed(line));
+                continue;
+            },
+        };
+
+        let mut labels: Vec<(String, String)> = sample.labels.into_iter().collect();
+        labels.sort();
+        let (idx, series, value) = match seen.get_mut(&(sample.name, labels.clone())) {
+            Some(first) => first,
+            None => {
+                seen.insert((sample.name, labels), (lines.len(), sample.series, sample.value.to_owned()));
+                lines.push(Cow::Borrowed(line));
+                continue;
+            },
+        };
+
+        let new_value = match is_summed(sample.name, &types) {
+            true => add_values(value, sample.value),
+            false => Some(sample.value.to_owned()),
+        };
+
+        // If the values aren't numbers, the line's left for the parser to reject
+        match new_value {
+            Some(new_value) => {
+                *value = new_value;
+                lines[*idx] = match sample.rest.is_empty() {
+                    true => Cow::Owned(format!("{} {}", series, value)),
+                    false => Cow::Owned(format!("{} {} {}", series, value, sample.rest)),
+                };
+                folded = true;
+            },
+            None => lines.push(Cow::Borrowed(line)),
+        }
+    }
+
+    if !folded {
+        return Cow::Borrowed(exposition);
+    }
+
+    let mut deduplicated = String::with_capacity(exposition.len());
+    for line in lines {
+        deduplicated.push_str(&line);
+        deduplicated.push('\n');
+    }
+    Cow::Owned(deduplicated)
+}
+
+/// A sample line of an exposition, split up into its parts
+struct SampleLine<'a> {
+    name: &'a str,
+    /// The name and labels of the series, as they were written
+    series: &'a str,
+    labels: HashMap<String, String>,
+    value: &'a str,
+    /// Whatever comes after the value: its timestamp and exemplar, if it has them
+    rest: &'a str,
+}
+
+fn split_sample_line(line: &str) -> Option<SampleLine<'_>> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+
+    let name_end = line.find(|c: char| c == '{' || c.is_whitespace())?;
+    let (labels, after_labels) = match line[name_end..].strip_prefix('{') {
+        Some(rest) => parse_labels(rest)?,
+        None => (HashMap::new(), &line[name_end..]),
+    };
+
+    let series = &line[..line.len() - after_labels.len()];
+    let after_labels = after_labels.trim_start();
+    let value_end = after_labels.find(char::is_whitespace).unwrap_or(after_labels.len());
+    Some(SampleLine {
+        name: &line[..name_end],
+        series,
+        labels,
+        value: &after_labels[..value_end],
+        rest: after_labels[value_end..].trim_start(),
+    })
+}
+
+/// Whether repeats of the given series get summed, going by the type of the family it's in
+fn is_summed(name: &str, types: &HashMap<&str, &str>) -> bool {
+    if types.get(name) == Some(&"counter") {
+        return true;
+    }
+
+    ["_bucket", "_sum", "_count"].iter().any(|suffix| {
+        let family_type = name.strip_suffix(suffix).and_then(|family| types.get(family));
+        match family_type {
+            Some(&"histogram") => true,
+            Some(&"summary") => *suffix != "_bucket",
+            _ => false,
+        }
+    })
+}
+
+/// Adds two sample values as they're written, keeping them as ints if they both are
+fn add_values(a: &str, b: &str) -> Option<String> {
+    if let Some(sum) = a.parse::<i64>().ok().zip(b.parse::<i64>().ok()).and_then(|(a, b)| a.checked_add(b)) {
+        return Some(sum.to_string());
+    }
+
+    let sum = parse_float(a)? + parse_float(b)?;
+    Some(match sum {
+        sum if sum.is_nan() => "NaN".to_owned(),
+        sum if sum == f64::INFINITY => "+Inf".to_owned(),
+        sum if sum == f64::NEG_INFINITY => "-Inf".to_owned(),
+        sum => sum.to_string(),
+    })
+}
+
+fn parse_float(value: &str) -> Option<f64> {
+    match value {
+        "+Inf" => Some(f64::INFINITY),
+        "-Inf" => Some(f64::NEG_INFINITY),
+        "NaN" => Some(f64::NAN),
+        value => value.parse().ok().filter(|value: &f64| value.is_finite()),
+    }
+}
+
+/// The Prometheus parser skips `# UNIT` lines as comments, so this picks them out of a push beforehand, for `attach_units`
+/// to put on the families once it's been parsed. Returns the unit for each family name they were given for
+pub fn extract_units(exposition: &str) -> HashMap<String, String> {
+    let mut units = HashMap::new();
+    for line in exposition.lines() {
+        let mut words = line.split_whitespace();
+        if let (Some("#"), Some("UNIT"), Some(name), Some(unit), None) = (words.next(), words.next(), words.next(), words.next(), words.next()) {
+            units.insert(name.to_owned(), unit.to_owned());
+        }
+    }
+
+    units
+}
+
+/// Sets the unit of every family that was given one. Like their other metadata, OpenMetrics counters' units are given
+/// for the name without the `_total` suffix, so either name will do
+pub fn attach_units(exposition: &mut MetricsExposition<PrometheusType, PrometheusValue>, units: &HashMap<String, String>) {
+    if units.is_empty() {
+        return;
+    }
+
+    for family in exposition.families.values_mut() {
+        let descriptor_name = OpenMetricsFamily::new(family).descriptor_name().to_owned();
+        if let Some(unit) = units.get(&family.family_name).or_else(|| units.get(&descriptor_name)) {
+            family.unit = unit.clone();
+        }
+    }
+}
+
+/// Splits a line with an exemplar on a sample of one of the given counters into the sample and the exemplar.
+/// Returns None for every other line, including ones with exemplars that don't parse
+fn split_counter_exemplar<'a>(line: &'a str, counters: &HashSet<&str>) -> Option<(&'a str, CounterExemplar)> {
+    let line = line.trim_end();
+    if line.starts_with('#') {
+        return None;
+    }
+
+    let name_end = line.find(|c: char| c == '{' || c.is_whitespace())?;
+    let metric_name = &line[..name_end];
+    if !counters.contains(metric_name) {
+        return None;
+    }
+
+    let (labels, rest) = match line[name_end..].strip_prefix('{') {
+        Some(rest) => parse_labels(rest)?,
+        None => (HashMap::new(), &line[name_end..]),
+    };
+
+    let hash = rest.find('#')?;
+    let sample = &line[..line.len() - rest.len() + hash];
+    let (exemplar_labels, exemplar_rest) = parse_labels(rest[hash + 1..].trim_start().strip_prefix('{')?)?;
+
+    let mut numbers = exemplar_rest.split_whitespace();
+    let id = numbers.next()?.parse().ok()?;
+    let timestamp = match numbers.next() {
+        Some(timestamp) => Some(timestamp.parse().ok()?),
+        None => None,
+    };
+    if numbers.next().is_some() {
+        return None;
+    }
+
+    let exemplar = Exemplar::new(exemplar_labels, id, timestamp);
+    Some((sample.trim_end(), CounterExemplar { metric_name: metric_name.to_owned(), labels, exemplar }))
+}
+
+/// Parses `name="value",...}` (i.e. a label set after its opening brace), returning the labels and whatever comes after
+/// the closing brace. Like the parser, values are kept with their escapes as is
+fn parse_labels(mut s: &str) -> Option<(HashMap<String, String>, &str)> {
+    let mut labels = HashMap::new();
+    loop {
+        s = s.trim_start();
+        if let Some(rest) = s.strip_prefix('}') {
+            return Some((labels, rest));
+        }
+
+        let eq = s.find('=')?;
+        let name = s[..eq].trim();
+        let value = s[eq + 1..].trim_start().strip_prefix('"')?;
+
+        let mut escaped = false;
+        let end = value.char_indices().find(|&(_, c)| {
+            let is_end = c == '"' && !escaped;
+            escaped = c == '\\' && !escaped;
+            is_end
+        })?.0;
+
+        labels.insert(name.to_owned(), value[..end].to_owned());
+        s = value[end + 1..].trim_start();
+        s = s.strip_prefix(',').unwrap_or(s);
+    }
+}
+
+/// Escapes a label value that didn't come from an exposition (e.g. one from a push's path) the way the parser would
+/// have it, since that's how label values are kept and rendered
+pub fn escape_label_value(value: &str) -> Cow<'_, str> {
+    if !value.contains(['\\', '"', '\n']) {
+        return Cow::Borrowed(value);
+    }
+
+    Cow::Owned(value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n"))
+}
+
+/// Like `escape_label_value`, but for help text, which doesn't have its quotes escaped
+pub fn escape_help(help: &str) -> Cow<'_, str> {
+    if !help.contains(['\\', '\n']) {
+        return Cow::Borrowed(help);
+    }
+
+    Cow::Owned(help.replace('\\', "\\\\").replace('\n', "\\n"))
+}
+
+/// Undoes `escape_label_value` (or `escape_help`)
+pub fn unescape_label_value(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some(next) => unescaped.push(next),
+            None => unescaped.push(c),
+        }
+    }
+
+    unescaped
+}
+
+/// Makes a metric name, label name, or label value into a single Graphite path node, replacing anything that isn't
+/// a letter, digit, `-`, `_`, or `:` (in particular the `.`s that separate nodes) with `_`
+fn graphite_node(s: &str) -> String {
+    s.chars().map(|c| if c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | ':') { c } else { '_' }).collect()
+}
+
+/// Converts a Prometheus text exposition into Graphite plaintext, with a `<path> <value> <timestamp>` line for every
+/// sample. The path is the prefix (if any), then the metric name, then the name and value of each label in order of
+/// name, so `requests_total{method="GET",code="200"}` becomes `requests_total.code.200.method.GET`. Labels with empty
+/// values are left out, the same as Prometheus treats them as missing. Samples without a timestamp get `now`, in
+/// seconds since the epoch, since Graphite needs one
+pub fn to_graphite_lines(exposition: &str, prefix: Option<&str>, now: u64) -> String {
+    let mut out = String::new();
+    for line in exposition.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let name_end = line.find(['{', ' ']).unwrap_or(line.len());
+        let (name, rest) = line.split_at(name_end);
+        let (labels, rest) = match rest.strip_prefix('{') {
+            Some(labels) => match parse_labels(labels) {
+                Some(parsed) => parsed,
+                None => continue,
+            },
+            None => (HashMap::new(), rest),
+        };
+
+        let mut values = rest.split_whitespace();
+        let value = match values.next() {
+            Some(value) => value,
+            None => continue,
+        };
+        // Prometheus timestamps are in milliseconds, Graphite ones are in seconds
+        let timestamp = values.next().and_then(|t| t.parse::<f64>().ok()).map(|t| (t / 1000.) as u64).unwrap_or(now);
+
+        let mut labels: Vec<(String, String)> = labels.into_iter().filter(|(_, value)| !value.is_empty()).collect();
+        labels.sort();
+
+        let mut path: Vec<String> = prefix.into_iter().map(String::from).collect();
+        path.push(graphite_node(name));
+        for (name, value) in labels {
+            path.push(graphite_node(&name));
+            path.push(graphite_node(&unescape_label_value(&value)));
+        }
+
+        out.push_str(&format!("{} {} {}\n", path.join("."), value, timestamp));
+    }
+
+    out
+}
@@ -0,0 +1,255 @@
+use std::{collections::BTreeMap, sync::{Mutex, atomic::{AtomicU64, Ordering}}, time::Duration};
+
+use openmetrics_parser::{HistogramBucket, HistogramValue, MetricNumber, PrometheusCounterValue, PrometheusMetricFamily, PrometheusType, PrometheusValue, Sample};
+
+use crate::aggregator::StoreStats;
+
+/// The buckets of `gravel_merge_duration_seconds`, from a tiny push up to a huge one
+const MERGE_DURATION_BUCKETS: &[f64] = &[0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1., 2.5];
+
+/// The buckets of `gravel_ingest_body_bytes`, going up by 4x from 256B to the default body limit of 10MiB and beyond
+const BODY_BYTES_BUCKETS: &[f64] = &[256., 1024., 4096., 16384., 65536., 262144., 1048576., 4194304., 16777216.];
+
+/// The buckets of `gravel_forward_duration_seconds`, from a peer on the same network up to one that needed retries
+#[cfg(feature="clustering")]
+const FORWARD_DURATION_BUCKETS: &[f64] = &[0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1., 2.5, 5., 10., 30.];
+
+/// The `result` label of `gravel_forward_total` for each `ForwardOutcome`, in the same order
+const FORWARD_OUTCOMES: [&str; 3] = ["success", "connection_error", "error_status"];
+
+/// How a forward that was sent to a peer went
+#[cfg(feature="clustering")]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ForwardOutcome {
+    Success,
+    /// The peer couldn't be connected to, or didn't respond in time
+    ConnectionError,
+    /// The peer responded, but not with a 2xx
+    ErrorStatus,
+}
+
+/// The outcomes and durations of the forwards sent to one peer
+#[derive(Debug)]
+struct PeerForwards {
+    /// How many forwards had each outcome, indexed like `FORWARD_OUTCOMES`
+    outcomes: [u64; 3],
+    durations: Histogram,
+}
+
+/// A histogram that can be observed into from any number of pushes at once without locking
+#[derive(Debug)]
+struct Histogram {
+    bounds: &'static [f64],
+    /// How many observations fell in each bucket (rather than at or below it), with an extra one for the
+    /// observations over the last bound
+    buckets: Vec<AtomicU64>,
+    /// The sum of every observation, as the bits of an f64
+    sum: AtomicU64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Histogram {
+        Histogram {
+            bounds,
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum: AtomicU64::new(0f64.to_bits()),
+        }
+    }
+
+    fn observe(&self, value: f64) {
+        let bucket = self.bounds.iter().position(|bound| value <= *bound).unwrap_or(self.bounds.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        // This can't fail, since the closure always returns Some
+        let _ = self.sum.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |sum| Some((f64::from_bits(sum) + value).to_bits()));
+    }
+
+    fn value(&self) -> HistogramValue {
+        let mut count = 0;
+        let bounds = self.bounds.iter().copied().chain(std::iter::once(f64::INFINITY));
+        let buckets = bounds.zip(self.buckets.iter()).map(|(upper_bound, bucket)| {
+            count += bucket.load(Ordering::Relaxed);
+            HistogramBucket { count: MetricNumber::Int(count as i64), upper_bound, exemplar: None }
+        }).collect();
+
+        HistogramValue {
+            sum: Some(MetricNumber::Float(f64::from_bits(self.sum.load(Ordering::Relaxed)))),
+            count: Some(count),
+            created: None,
+            buckets,
+        }
+    }
+}
+
+/// Counters for what the gateway itself has been doing. These are kept apart from the aggregated
+/// metrics, and exposed on their own at /-/metrics
+#[derive(Debug)]
+pub struct GatewayMetrics {
+    pushes: AtomicU64,
+    parse_errors: AtomicU64,
+    skipped_lines: AtomicU64,
+    bytes_ingested: AtomicU64,
+    counter_resets: AtomicU64,
+    evicted_series: AtomicU64,
+    skipped_fields: AtomicU64,
+    forwards_succeeded: AtomicU64,
+    forwards_failed: AtomicU64,
+    body_bytes: Histogram,
+    merge_durations: Histogram,
+    /// Forwards by the peer they were sent to. Peers come and go, so these can't be fixed up front like the rest
+    peer_forwards: Mutex<BTreeMap<String, PeerForwards>>,
+}
+
+impl Default for GatewayMetrics {
+    fn default() -> Self {
+        GatewayMetrics {
+            pushes: AtomicU64::new(0),
+            parse_errors: AtomicU64::new(0),
+            skipped_lines: AtomicU64::new(0),
+            bytes_ingested: AtomicU64::new(0),
+            counter_resets: AtomicU64::new(0),
+            evicted_series: AtomicU64::new(0),
+            skipped_fields: AtomicU64::new(0),
+            forwards_succeeded: AtomicU64::new(0),
+            forwards_failed: AtomicU64::new(0),
+            body_bytes: Histogram::new(BODY_BYTES_BUCKETS),
+            merge_durations: Histogram::new(MERGE_DURATION_BUCKETS),
+            peer_forwards: Mutex::new(BTreeMap::new()),
+        }
+    }
+}
+
+impl GatewayMetrics {
+    /// Records a push being received, with the size of its (decoded) body
+    pub fn record_push(&self, bytes: usize) {
+        self.pushes.fetch_add(1, Ordering::Relaxed);
+        self.bytes_ingested.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.body_bytes.observe(bytes as f64);
+    }
+
+    /// Records how long a push took to parse and merge, whether or not it was merged in the end
+    pub fn record_merge(&self, duration: Duration) {
+        self.merge_durations.observe(duration.as_secs_f64());
+    }
+
+
+    pub fn record_parse_error(&self) {
+        self.parse_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_skipped_lines(&self, lines: u64) {
+        self.skipped_lines.fetch_add(lines, Ordering::Relaxed);
+    }
+
+    pub fn record_counter_resets(&self, resets: u64) {
+        self.counter_resets.fetch_add(resets, Ordering::Relaxed);
+    }
+
+    pub fn record_evictions(&self, series: u64) {
+        self.evicted_series.fetch_add(series, Ordering::Relaxed);
+    }
+
+    pub fn record_skipped_fields(&self, fields: u64) {
+        self.skipped_fields.fetch_add(fields, Ordering::Relaxed);
+    }
+
+    #[cfg(feature="clustering")]
+    pub fn record_forward(&self, succeeded: bool) {
+        let counter = if succeeded { &self.forwards_succeeded } else { &self.forwards_failed };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a forward that was sent to the given peer, with how long it took (including any retries)
+    #[cfg(feature="clustering")]
+    pub fn record_peer_forward(&self, peer: &str, outcome: ForwardOutcome, duration: Duration) {
+        let mut peers = self.peer_forwards.lock().unwrap();
+        if !peers.contains_key(peer) {
+            peers.insert(peer.to_owned(), PeerForwards { outcomes: [0; 3], durations: Histogram::new(FORWARD_DURATION_BUCKETS) });
+        }
+
+        let forwards = peers.get_mut(peer).unwrap();
+        forwards.outcomes[outcome as usize] += 1;
+        forwards.durations.observe(duration.as_secs_f64());
+    }
+
+    /// How many pushed counters have gone backwards since their last push
+    #[cfg(test)]
+    pub fn counter_resets(&self) -> u64 {
+        self.counter_resets.load(Ordering::Relaxed)
+    }
+
+    /// Renders these metrics as families, along with how big the aggregator's store is
+    pub fn families(&self, store: StoreStats) -> Vec<PrometheusMetricFamily> {
+        let forwards = vec![
+            (vec!["success".to_owned()], self.forwards_succeeded.load(Ordering::Relaxed)),
+            (vec!["failure".to_owned()], self.forwards_failed.load(Ordering::Relaxed)),
+        ];
+
+        let (peer_outcomes, peer_durations) = {
+            let peers = self.peer_forwards.lock().unwrap();
+            let outcomes = peers.iter().flat_map(|(peer, forwards)| {
+                FORWARD_OUTCOMES.iter().zip(forwards.outcomes).map(move |(outcome, count)| (vec![peer.clone(), (*outcome).to_owned()], count))
+            }).collect();
+            let durations = peers.iter().map(|(peer, forwards)| (vec![peer.clone()], forwards.durations.value())).collect();
+            (outcomes, durations)
+        };
+
+        vec![
+            counter("gravel_pushes_total", "Pushes received", Vec::new(), vec![(Vec::new(), self.pushes.load(Ordering::Relaxed))]),
+            counter("gravel_push_parse_errors_total", "Pushes that failed to parse", Vec::new(), vec![(Vec::new(), self.parse_errors.load(Ordering::Relaxed))]),
+            counter("gravel_parse_errors_total", "Malformed lines that were skipped in pushes", Vec::new(), vec![(Vec::new(), self.skipped_lines.load(Ordering::Relaxed))]),
+            counter("gravel_ingested_bytes_total", "Bytes of pushed bodies received, after decoding", Vec::new(), vec![(Vec::new(), self.bytes_ingested.load(Ordering::Relaxed))]),
+            counter("gravel_counter_resets_total", "Pushed counters that were lower than their last push", Vec::new(), vec![(Vec::new(), self.counter_resets.load(Ordering::Relaxed))]),
+            counter("gravel_evicted_series_total", "Series evicted to stay within the total series budget", Vec::new(), vec![(Vec::new(), self.evicted_series.load(Ordering::Relaxed))]),
+            counter("gravel_line_protocol_skipped_fields_total", "Line protocol fields that were skipped for not being numbers", Vec::new(), vec![(Vec::new(), self.skipped_fields.load(Ordering::Relaxed))]),
+            counter("gravel_forwards_total", "Pushes forwarded to peers, by whether they were accepted", vec!["result".to_owned()], forwards),
+            counter("gravel_forward_total", "Forwards sent to each peer, by how they went", vec!["peer".to_owned(), "result".to_owned()], peer_outcomes),
+            gauge("gravel_series", "Series currently held by the aggregator", store.series as i64),
+            gauge("gravel_series_total", "Distinct series currently held by the aggregator", store.series as i64),
+            gauge("gravel_families_total", "Families currently held by the aggregator", store.families as i64),
+            gauge("gravel_store_bytes_estimate", "A rough estimate of the memory taken by the aggregator's series", store.estimated_bytes as i64),
+            histogram("gravel_ingest_body_bytes", "Sizes of pushed bodies, after decoding", Vec::new(), vec![(Vec::new(), self.body_bytes.value())]),
+            histogram("gravel_merge_duration_seconds", "How long pushes took to parse and merge", Vec::new(), vec![(Vec::new(), self.merge_durations.value())]),
+            histogram("gravel_forward_duration_seconds", "How long forwards to each peer took, including retries", vec!["peer".to_owned()], peer_durations),
+            build_info(),
+        ]
+    }
+}
+
+fn family(name: &str, help: &str, label_names: Vec<String>, family_type: PrometheusType) -> PrometheusMetricFamily {
+    PrometheusMetricFamily::new(name.to_owned(), label_names, family_type, help.to_owned(), String::new())
+}
+
+fn counter(name: &str, help: &str, label_names: Vec<String>, values: Vec<(Vec<String>, u64)>) -> PrometheusMetricFamily {
+    let samples = values.into_iter().map(|(label_values, value)| {
+        Sample::new(label_values, None, PrometheusValue::Counter(PrometheusCounterValue { value: MetricNumber::Int(value as i64), exemplar: None }))
+    });
+
+    // The label values are all distinct, so this can't fail
+    family(name, help, label_names, PrometheusType::Counter).with_samples(samples).unwrap()
+}
+
+fn gauge(name: &str, help: &str, value: i64) -> PrometheusMetricFamily {
+    let sample = Sample::new(Vec::new(), None, PrometheusValue::Gauge(MetricNumber::Int(value)));
+    family(name, help, Vec::new(), PrometheusType::Gauge).with_samples(vec![sample]).unwrap()
+}
+
+/// The features the gateway was built with, comma separated
+fn enabled_features() -> String {
+    let features = [("auth", cfg!(feature="auth")), ("clustering", cfg!(feature="clustering")), ("statsd", cfg!(feature="statsd")), ("tls", cfg!(feature="tls"))];
+    features.iter().filter(|(_, enabled)| *enabled).map(|(name, _)| *name).collect::<Vec<_>>().join(",")
+}
+
+/// A constant gauge of 1, labelled with what the gateway was built from
+fn build_info() -> PrometheusMetricFamily {
+    let label_names = vec!["version".to_owned(), "rustc".to_owned(), "features".to_owned()];
+    let label_values = vec![env!("CARGO_PKG_VERSION").to_owned(), env!("GRAVEL_RUSTC_VERSION").to_owned(), enabled_features()];
+    let sample = Sample::new(label_values, None, PrometheusValue::Gauge(MetricNumber::Int(1)));
+    family("gravel_build_info", "The version of the gateway, and what it was built with", label_names, PrometheusType::Gauge).with_samples(vec![sample]).unwrap()
+}
+
+fn histogram(name: &str, help: &str, label_names: Vec<String>, values: Vec<(Vec<String>, HistogramValue)>) -> PrometheusMetricFamily {
+    let samples = values.into_iter().map(|(label_values, value)| Sample::new(label_values, None, PrometheusValue::Histogram(value)));
+
+    // The label values are all distinct, so this can't fail
+    family(name, help, label_names, PrometheusType::Histogram).with_samples(samples).unwrap()
+}
@@ -0,0 +1,58 @@
+use std::{collections::{HashMap, VecDeque}, sync::Mutex, time::{Duration, Instant}};
+
+/// How long an idempotency key is remembered, unless configured otherwise
+pub const DEFAULT_IDEMPOTENCY_KEY_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// The most keys remembered for each job. Once a job has this many, its oldest key is forgotten to make room
+const MAX_KEYS_PER_JOB: usize = 1024;
+
+/// Remembers the idempotency keys of recent pushes, so that a push that's retried (e.g. because the client timed out
+/// waiting for the first attempt) is only merged once. Keys are scoped to the job they were pushed to, and each job
+/// only keeps so many, so that a job that pushes a lot can't use up memory without limit
+#[derive(Debug)]
+pub struct IdempotencyKeys {
+    ttl: Duration,
+    /// Each job's keys, oldest first
+    jobs: Mutex<HashMap<String, VecDeque<(String, Instant)>>>,
+}
+
+impl IdempotencyKeys {
+    pub fn new(ttl: Duration) -> IdempotencyKeys {
+        IdempotencyKeys {
+            ttl,
+            jobs: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records that a push to the given job with the given key is being merged. Returns false if one already has been
+    /// within the ttl, in which case this one shouldn't be
+    pub fn claim(&self, job: &str, key: &str) -> bool {
+        let now = Instant::now();
+        let mut jobs = self.jobs.lock().unwrap();
+        let keys = jobs.entry(job.to_owned()).or_default();
+        while keys.front().is_some_and(|(_, at)| now.saturating_duration_since(*at) > self.ttl) {
+            keys.pop_front();
+        }
+
+        if keys.iter().any(|(existing, _)| existing == key) {
+            return false;
+        }
+
+        if keys.len() >= MAX_KEYS_PER_JOB {
+            keys.pop_front();
+        }
+        keys.push_back((key.to_owned(), now));
+        true
+    }
+
+    /// Forgets a key that was claimed by a push that then failed, so that retrying it isn't skipped
+    pub fn release(&self, job: &str, key: &str) {
+        let mut jobs = self.jobs.lock().unwrap();
+        if let Some(keys) = jobs.get_mut(job) {
+            keys.retain(|(existing, _)| existing != key);
+            if keys.is_empty() {
+                jobs.remove(job);
+            }
+        }
+    }
+}
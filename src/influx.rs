@@ -0,0 +1,203 @@
+use std::{collections::HashMap, str::FromStr};
+
+use openmetrics_parser::{MetricNumber, ParseError, PrometheusMetricFamily, PrometheusType, PrometheusValue, Sample};
+
+use crate::{aggregator::is_valid_name, exposition::escape_label_value};
+
+/// The units that line protocol timestamps can be in, as given by the `precision` query parameter
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Precision {
+    #[default]
+    Nanoseconds,
+    Microseconds,
+    Milliseconds,
+    Seconds,
+}
+
+impl Precision {
+    /// Converts a timestamp in this precision into the milliseconds that Prometheus timestamps are in
+    fn to_millis(self, timestamp: i64) -> f64 {
+        match self {
+            Precision::Nanoseconds => timestamp as f64 / 1e6,
+            Precision::Microseconds => timestamp as f64 / 1e3,
+            Precision::Milliseconds => timestamp as f64,
+            Precision::Seconds => timestamp as f64 * 1e3,
+        }
+    }
+}
+
+impl FromStr for Precision {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ns" | "n" => Ok(Precision::Nanoseconds),
+            "us" | "u" => Ok(Precision::Microseconds),
+            "ms" => Ok(Precision::Milliseconds),
+            "s" => Ok(Precision::Seconds),
+            _ => Err(format!("Invalid precision {}: must be one of ns, us, ms or s", s)),
+        }
+    }
+}
+
+/// The families decoded from a line protocol write, along with how many of its fields weren't numbers (i.e. strings and
+/// booleans), which don't have a Prometheus equivalent and so were skipped
+pub struct LineProtocolWrite {
+    pub families: Vec<PrometheusMetricFamily>,
+    pub skipped_fields: usize,
+}
+
+/// A family's name, its label names (from the first line that had it), and its samples by their label values
+type DecodedFamily = (String, Vec<String>, HashMap<Vec<String>, Sample<PrometheusValue>>);
+
+fn invalid(line: &str, reason: &str) -> ParseError {
+    ParseError::ParseError(format!("Invalid line protocol line {}: {}", line, reason))
+}
+
+/// Turns a measurement, field, or tag name into a valid Prometheus name, replacing anything that isn't allowed with `_`
+pub fn sanitize_name(name: &str) -> String {
+    let mut sanitized: String = name.chars().map(|c| if c.is_ascii_alphanumeric() || c == '_' { c } else { '_' }).collect();
+    if !is_valid_name(&sanitized, false) {
+        sanitized.insert(0, '_');
+    }
+
+    sanitized
+}
+
+/// Splits off the front of `s` up to the first unescaped, unquoted occurrence of any of the given delimiters,
+/// returning it along with the rest of `s` (starting at the delimiter)
+fn split_unescaped<'a>(s: &'a str, delimiters: &[char], quotes: bool) -> (&'a str, &'a str) {
+    let mut escaped = false;
+    let mut quoted = false;
+    for (i, c) in s.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' if quotes => quoted = !quoted,
+            c if !quoted && delimiters.contains(&c) => return (&s[..i], &s[i..]),
+            _ => {},
+        }
+    }
+
+    (s, "")
+}
+
+/// Removes the backslashes from the escaped characters of a measurement, tag, or field name
+fn unescape(s: &str) -> String {
+    let mut unescaped = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some(next)) if matches!(next, ',' | '=' | ' ' | '\\' | '"') => {
+                unescaped.push(next);
+                chars.next();
+            },
+            _ => unescaped.push(c),
+        }
+    }
+
+    unescaped
+}
+
+/// Parses a field value, which is None if it's a string or boolean rather than a number
+fn parse_field_value(line: &str, value: &str) -> Result<Option<MetricNumber>, ParseError> {
+    if value.starts_with('"') {
+        return Ok(None);
+    }
+
+    if matches!(value, "t" | "T" | "true" | "True" | "TRUE" | "f" | "F" | "false" | "False" | "FALSE") {
+        return Ok(None);
+    }
+
+    let number = match value.strip_suffix('i').or_else(|| value.strip_suffix('u')) {
+        Some(int) => int.parse::<i64>().map(MetricNumber::Int).ok(),
+        None => value.parse::<f64>().ok().map(|float| match float.fract() == 0. && float.abs() < i64::MAX as f64 {
+            // Like protobuf pushes, whole values are kept as ints so that they render the same as text pushes
+            true => MetricNumber::Int(float as i64),
+            false => MetricNumber::Float(float),
+        }),
+    };
+
+    number.map(Some).ok_or_else(|| invalid(line, &format!("field value {} isn't a number, string, or boolean", value)))
+}
+
+/// Decodes an InfluxDB line protocol write (`measurement,tag=value field=1 1465839830100400200`) into gauge families.
+/// Every numeric field becomes a `<measurement>_<field>` series, labelled with the line's tags. Lines for the same
+/// series in one write replace each other, as they would in InfluxDB
+pub fn decode_line_protocol(body: &str, precision: Precision) -> Result<LineProtocolWrite, ParseError> {
+    let mut families: Vec<DecodedFamily> = Vec::new();
+    let mut skipped_fields = 0;
+    for line in body.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (series, rest) = split_unescaped(line, &[' '], false);
+        let (fields, timestamp) = split_unescaped(rest.trim_start(), &[' '], true);
+        let timestamp = match timestamp.trim() {
+            "" => None,
+            timestamp => Some(precision.to_millis(timestamp.parse().map_err(|_| invalid(line, "timestamp isn't an integer"))?)),
+        };
+
+        let (measurement, mut tags) = split_unescaped(series, &[','], false);
+        if measurement.is_empty() {
+            return Err(invalid(line, "no measurement"));
+        }
+        let measurement = sanitize_name(&unescape(measurement));
+
+        let mut labels = Vec::new();
+        while let Some(rest) = tags.strip_prefix(',') {
+            let (tag, rest) = split_unescaped(rest, &[','], false);
+            let (name, value) = split_unescaped(tag, &['='], false);
+            let value = value.strip_prefix('=').ok_or_else(|| invalid(line, &format!("tag {} has no value", name)))?;
+            labels.push((sanitize_name(&unescape(name)), escape_label_value(&unescape(value)).into_owned()));
+            tags = rest;
+        }
+        labels.sort();
+        let (label_names, label_values): (Vec<String>, Vec<String>) = labels.into_iter().unzip();
+
+        if fields.is_empty() {
+            return Err(invalid(line, "no fields"));
+        }
+
+        let mut fields = fields;
+        loop {
+            let (field, rest) = split_unescaped(fields, &[','], true);
+            let (name, value) = split_unescaped(field, &['='], true);
+            let value = value.strip_prefix('=').ok_or_else(|| invalid(line, &format!("field {} has no value", name)))?;
+            match parse_field_value(line, value)? {
+                Some(number) => {
+                    let name = format!("{}_{}", measurement, sanitize_name(&unescape(name)));
+                    let idx = match families.iter().position(|(family, _, _)| *family == name) {
+                        Some(idx) => idx,
+                        None => {
+                            families.push((name.clone(), label_names.clone(), HashMap::new()));
+                            families.len() - 1
+                        },
+                    };
+
+                    let (_, family_labels, samples) = &mut families[idx];
+                    if *family_labels != label_names {
+                        return Err(invalid(line, &format!("{} has different tags than an earlier line for it", name)));
+                    }
+                    samples.insert(label_values.clone(), Sample::new(label_values.clone(), timestamp, PrometheusValue::Gauge(number)));
+                },
+                None => skipped_fields += 1,
+            }
+
+            fields = match rest.strip_prefix(',') {
+                Some(rest) => rest,
+                None => break,
+            };
+        }
+    }
+
+    let families = families.into_iter().map(|(name, label_names, samples)| {
+        let mut samples: Vec<(Vec<String>, Sample<PrometheusValue>)> = samples.into_iter().collect();
+        samples.sort_by(|a, b| a.0.cmp(&b.0));
+        PrometheusMetricFamily::new(name, label_names, PrometheusType::Gauge, String::new(), String::new())
+            .with_samples(samples.into_iter().map(|(_, sample)| sample))
+    }).collect::<Result<Vec<_>, ParseError>>()?;
+
+    Ok(LineProtocolWrite { families, skipped_fields })
+}
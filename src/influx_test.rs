@@ -0,0 +1,38 @@
+use crate::influx::{Precision, decode_line_protocol};
+
+fn rendered(body: &str, precision: Precision) -> (Vec<String>, usize) {
+    let write = decode_line_protocol(body, precision).unwrap();
+    (write.families.iter().map(|family| family.to_string()).collect(), write.skipped_fields)
+}
+
+#[test]
+fn test_decode_line_protocol() {
+    let body = "cpu,host=a,region=eu usage_idle=90.5,usage_user=3i\ncpu,region=eu,host=b usage_idle=80 1000000000\n";
+    let (families, skipped) = rendered(body, Precision::Nanoseconds);
+    assert_eq!(families, vec![
+        "# TYPE cpu_usage_idle gauge\ncpu_usage_idle{host=\"a\",region=\"eu\"} 90.5\ncpu_usage_idle{host=\"b\",region=\"eu\"} 80 1000\n",
+        "# TYPE cpu_usage_user gauge\ncpu_usage_user{host=\"a\",region=\"eu\"} 3\n",
+    ]);
+    assert_eq!(skipped, 0);
+}
+
+#[test]
+fn test_line_protocol_escapes_and_skipped_fields() {
+    // Strings (which can have spaces and commas in them) and booleans are skipped, and names are made valid
+    let body = "disk\\ io,mount=/var\\,log,path=C:\\\\ used=1u,label=\"a, b\",ok=true 5\n# a comment\n\n";
+    let (families, skipped) = rendered(body, Precision::Seconds);
+    assert_eq!(families, vec!["# TYPE disk_io_used gauge\ndisk_io_used{mount=\"/var,log\",path=\"C:\\\\\"} 1 5000\n"]);
+    assert_eq!(skipped, 2);
+
+    // The last line for a series wins
+    let (families, _) = rendered("mem free=1 1\nmem free=2 2\n", Precision::Milliseconds);
+    assert_eq!(families, vec!["# TYPE mem_free gauge\nmem_free 2 2\n"]);
+}
+
+#[test]
+fn test_invalid_line_protocol() {
+    for body in ["cpu\n", "cpu usage\n", "cpu usage=abc\n", "cpu,host usage=1\n", "cpu usage=1 soon\n", "cpu,host=a usage=1\ncpu usage=1\n"] {
+        assert!(decode_line_protocol(body, Precision::Nanoseconds).is_err(), "{}", body);
+    }
+    assert!("m".parse::<Precision>().is_err());
+}
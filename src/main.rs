@@ -1,28 +1,57 @@
-use std::{net::ToSocketAddrs, path::PathBuf};
+#![allow(clippy::needless_return)]
 
-use aggregator::Aggregator;
-use clap::{App, Arg};
-use slog::{Drain, error, info, o};
+use std::{collections::HashMap, net::ToSocketAddrs, path::PathBuf, str::FromStr};
 
-use crate::{auth::pass_through_auth, routes::RoutesConfig};
+use aggregator::{Aggregator, AggregatorConfig, check_label_names};
+use clap::{App, Arg, ArgMatches};
+use futures::FutureExt;
+use slog::{Drain, Logger, error, info, o};
+use warp::http::header::HeaderName;
+
+use crate::{auth::{AuthConfig, Authenticator, job_auth, tenant_auth}, relabel::load_relabel_rules, rate_limit::RateLimit, routes::{RoutesConfig, SharedRoutesConfig}};
 
 mod aggregator;
+mod exposition;
+mod gateway_metrics;
+mod idempotency;
+mod influx;
 mod routes;
 mod pebble;
+mod protobuf;
+mod rate_limit;
+mod regex;
+mod relabel;
+mod selector;
+mod server;
+mod snapshot;
+mod tenants;
 
 #[cfg(feature="clustering")]
 mod clustering;
+#[cfg(feature="statsd")]
+mod statsd;
 
 #[cfg(test)]
 mod aggregator_test;
+#[cfg(test)]
+mod routes_test;
 mod auth;
+#[cfg(test)]
+mod auth_test;
+#[cfg(test)]
+mod server_test;
+#[cfg(test)]
+mod protobuf_test;
+#[cfg(test)]
+mod influx_test;
+#[cfg(test)]
+mod regex_test;
+#[cfg(all(test, feature="clustering"))]
+mod clustering_test;
+#[cfg(all(test, feature="statsd"))]
+mod statsd_test;
 
-use tokio::signal;
-
-#[tokio::main]
-async fn main() {
-    let agg = Aggregator::new();
-
+fn main() {
     let app = App::new("Prometheus Gravel Gateway")
         .arg(
             Arg::with_name("listen")
@@ -30,6 +59,248 @@ async fn main() {
                 .help("The address/port to listen on")
                 .takes_value(true)
                 .default_value("localhost:4278"),
+        )
+        .arg(
+            Arg::with_name("worker-threads")
+                .long("worker-threads")
+                .help("How many threads to handle requests on [default: one per CPU core]")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("summary-quantile-merge")
+                .long("summary-quantile-merge")
+                .help("How to merge the quantiles of pushed summaries")
+                .takes_value(true)
+                .possible_values(&["latest", "min", "max"])
+                .default_value("latest"),
+        )
+        .arg(
+            Arg::with_name("gauge-aggregation")
+                .long("gauge-aggregation")
+                .help("How to merge pushed gauges that don't have a clearmode label")
+                .takes_value(true)
+                .possible_values(&["sum", "min", "max", "last", "mean"])
+                .default_value("last"),
+        )
+        .arg(
+            Arg::with_name("label-precedence")
+                .long("label-precedence")
+                .help("Whether a push's path labels or the labels of the series in its body win, when they both have the same label")
+                .takes_value(true)
+                .possible_values(&["path", "body"])
+                .default_value("path"),
+        )
+        .arg(
+            Arg::with_name("bearer-token-file")
+                .long("bearer-token-file")
+                .help("A file of tokens, one per line, that pushes must send as `Authorization: Bearer <token>`")
+                .takes_value(true)
+                .conflicts_with("job-auth-file"),
+        )
+        .arg(
+            Arg::with_name("auth-header")
+                .long("auth-header")
+                .help("The header that clients send their credentials in, for proxies that don't pass on Authorization")
+                .takes_value(true)
+                .default_value("authorization"),
+        )
+        .arg(
+            Arg::with_name("job-auth-file")
+                .long("job-auth-file")
+                .help("A file of `<token> <job pattern>,...` lines, restricting which jobs each bearer token can push to")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("tenant-auth-file")
+                .long("tenant-auth-file")
+                .help("A file of `<token> <tenant pattern>,...` lines, restricting which tenants each bearer token can push to")
+                .takes_value(true)
+                .conflicts_with_all(&["bearer-token-file", "job-auth-file"]),
+        )
+        .arg(
+            Arg::with_name("counter-aggregation")
+                .long("counter-aggregation")
+                .help("How to merge pushed counters that don't have a clearmode label. max suits counters that are already totals")
+                .takes_value(true)
+                .possible_values(&["sum", "max"])
+                .default_value("sum"),
+        )
+        .arg(
+            Arg::with_name("counter-reset-policy")
+                .long("counter-reset-policy")
+                .help("What to do with a pushed counter that's lower than its last push")
+                .takes_value(true)
+                .possible_values(&["accept", "ignore"])
+                .default_value("accept"),
+        )
+        .arg(
+            Arg::with_name("validate-histograms")
+                .long("validate-histograms")
+                .help("Check histograms for buckets that don't add up when they're scraped, logging the ones that don't or leaving them out too")
+                .takes_value(true)
+                .possible_values(&["off", "log", "drop"])
+                .default_value("off"),
+        )
+        .arg(
+            Arg::with_name("max-body-bytes")
+                .long("max-body-bytes")
+                .help("The largest push body to accept, in bytes. Applies to gzipped bodies both before and after decoding")
+                .takes_value(true)
+                .default_value("10485760"),
+        )
+        .arg(
+            Arg::with_name("max-in-flight-pushes")
+                .long("max-in-flight-pushes")
+                .help("The most pushes to handle at once. Pushes beyond that get a 503 with a Retry-After, rather than queueing")
+                .takes_value(true)
+                .default_value("256"),
+        )
+        .arg(
+            Arg::with_name("rate-limit")
+                .long("rate-limit")
+                .help("How many pushes a second each client (by its credentials, or its address without them) can make. Unlimited if not given")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("rate-limit-burst")
+                .long("rate-limit-burst")
+                .help("How many pushes each client can make at once, on top of the rate limit. Defaults to a second's worth")
+                .takes_value(true)
+                .requires("rate-limit")
+        )
+        .arg(
+            Arg::with_name("default-job")
+                .long("default-job")
+                .takes_value(true)
+                .help("The job label to give pushes whose path doesn't have one")
+        )
+        .arg(
+            Arg::with_name("strict-label-paths")
+                .long("strict-label-paths")
+                .help("Reject push and delete paths whose last label has no value, rather than giving it an empty one")
+        )
+        .arg(
+            Arg::with_name("graphite-prefix")
+                .long("graphite-prefix")
+                .takes_value(true)
+                .help("The dotted path that every series in /metrics/graphite starts with, e.g. gravel.prod")
+        )
+        .arg(
+            Arg::with_name("cors-allowed-origin")
+                .long("cors-allowed-origin")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("An origin that browsers can scrape /metrics and /-/metrics from, or * for any. Can be given more than once")
+        )
+        .arg(
+            Arg::with_name("max-series-per-family")
+                .long("max-series-per-family")
+                .help("The most distinct label sets a metric family can have. Pushes that would add more are rejected")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max-total-series")
+                .long("max-total-series")
+                .help("The most series to hold across every family. Once there are more, the ones pushed to longest ago are evicted")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("drop-label")
+                .long("drop-label")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("A label to remove from every pushed series, so that series which only differ by it are merged together. Can be given more than once")
+        )
+        .arg(
+            Arg::with_name("external-label")
+                .long("external-label")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("A name=value label to add to every pushed series that doesn't already have it. Can be given more than once")
+        )
+        .arg(
+            Arg::with_name("relabel-config-file")
+                .long("relabel-config-file")
+                .takes_value(true)
+                .help("A JSON file of rules that rewrite the labels of pushed series before they're merged")
+        )
+        .arg(
+            Arg::with_name("reject-non-finite")
+                .long("reject-non-finite")
+                .help("Reject pushes with NaN or infinite values, rather than storing them")
+        )
+        .arg(
+            Arg::with_name("normalize-counter-names")
+                .long("normalize-counter-names")
+                .help("Add a _total suffix to pushed counters that don't have one, so that both spellings are merged")
+        )
+        .arg(
+            Arg::with_name("utf8-lossy")
+                .long("utf8-lossy")
+                .help("Replace invalid UTF-8 in pushes with U+FFFD, rather than rejecting them")
+        )
+        .arg(
+            Arg::with_name("skip-malformed-lines")
+                .long("skip-malformed-lines")
+                .help("Skip the malformed lines of text format pushes and merge the rest, rather than rejecting them")
+        )
+        .arg(
+            Arg::with_name("shards")
+                .long("shards")
+                .help("How many independently locked shards to split metric families across")
+                .takes_value(true)
+                .default_value("16"),
+        )
+        .arg(
+            Arg::with_name("ttl")
+                .long("ttl")
+                .help("Evict series that haven't been pushed to for this long, e.g. 5m or 1h")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("series-ttl")
+                .long("series-ttl")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("A selector=duration ttl for the series it selects, e.g. {job=\"backup\"}=26h, overriding --ttl. Can be given more than once")
+        )
+        .arg(
+            Arg::with_name("reset-interval")
+                .long("reset-interval")
+                .help("Clear every series on this interval (lined up with the Unix epoch), e.g. 1m, so that each window's pushes aggregate from nothing")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("idempotency-key-ttl")
+                .long("idempotency-key-ttl")
+                .help("How long to remember the X-Idempotency-Key of a push, so that retries of it aren't merged again")
+                .takes_value(true)
+                .default_value("5m"),
+        )
+        .arg(
+            Arg::with_name("log-level")
+                .long("log-level")
+                .help("The most verbose level of request tracing to log")
+                .takes_value(true)
+                .possible_values(&["error", "warn", "info", "debug", "trace"])
+                .default_value("info"),
+        )
+        .arg(
+            Arg::with_name("snapshot-file")
+                .long("snapshot-file")
+                .help("A file to periodically save the aggregated metrics to, and restore them from on startup")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("snapshot-interval")
+                .long("snapshot-interval")
+                .help("How often to save the aggregated metrics to the snapshot file")
+                .takes_value(true)
+                .default_value("1m"),
         );
     
 
@@ -59,6 +330,24 @@ async fn main() {
             .help("The SRV record to look up to discover peers")
     );
 
+    #[cfg(feature="clustering")]
+    let app = app.arg(
+        Arg::with_name("peers-dns")
+            .long("peers-dns")
+            .takes_value(true)
+            .requires("cluster-enabled")
+            .help("A DNS name and port (e.g. a headless service) to periodically resolve to discover peers, e.g. gravel.monitoring.svc:4278")
+    );
+
+    #[cfg(feature="clustering")]
+    let app = app.arg(
+        Arg::with_name("peers-dns-interval")
+            .long("peers-dns-interval")
+            .takes_value(true)
+            .default_value("30s")
+            .help("How often to resolve --peers-dns")
+    );
+
     #[cfg(feature="clustering")]
     let app = app.arg(
         Arg::with_name("peers-file")
@@ -68,6 +357,134 @@ async fn main() {
             .help("The SRV record to look up to discover peers")
     );
 
+    #[cfg(feature="clustering")]
+    let app = app.arg(
+        Arg::with_name("virtual-nodes")
+            .long("virtual-nodes")
+            .takes_value(true)
+            .default_value("100")
+            .help("How many points each peer gets on the cluster's hash ring")
+    );
+
+    #[cfg(feature="clustering")]
+    let app = app.arg(
+        Arg::with_name("peer-weight")
+            .long("peer-weight")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("A peer=weight pair, giving the peer (which can be this gateway) weight times the jobs of an unweighted one. Can be given more than once")
+    );
+
+    #[cfg(feature="clustering")]
+    let app = app.arg(
+        Arg::with_name("cluster-key-label")
+            .long("cluster-key-label")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("A label whose value decides which peer a push goes to. Can be given more than once [default: job]")
+    );
+
+    #[cfg(feature="clustering")]
+    let app = app.arg(
+        Arg::with_name("replication-factor")
+            .long("replication-factor")
+            .takes_value(true)
+            .default_value("1")
+            .help("How many peers each push is sent to. A push succeeds once a majority of them accept it")
+    );
+
+    #[cfg(feature="clustering")]
+    let app = app.arg(
+        Arg::with_name("forward-retries")
+            .long("forward-retries")
+            .takes_value(true)
+            .default_value("3")
+            .help("How many times to retry forwarding a push to a peer before giving up")
+    );
+
+    #[cfg(feature="clustering")]
+    let app = app.arg(
+        Arg::with_name("forward-retry-delay")
+            .long("forward-retry-delay")
+            .takes_value(true)
+            .default_value("100ms")
+            .help("How long to wait before the first retry of a forward. Each retry after that waits twice as long")
+    );
+
+    #[cfg(feature="clustering")]
+    let app = app.arg(
+        Arg::with_name("forward-timeout")
+            .long("forward-timeout")
+            .takes_value(true)
+            .default_value("5s")
+            .help("How long a single attempt at forwarding a push to a peer can take")
+    );
+
+    #[cfg(feature="clustering")]
+    let app = app.arg(
+        Arg::with_name("forward-pool-size")
+            .long("forward-pool-size")
+            .takes_value(true)
+            .default_value("32")
+            .help("The most idle connections to keep open to each peer")
+    );
+
+    #[cfg(feature="clustering")]
+    let app = app.arg(
+        Arg::with_name("max-concurrent-forwards")
+            .long("max-concurrent-forwards")
+            .takes_value(true)
+            .default_value("256")
+            .help("The most forwards to peers that can be in flight at once")
+    );
+
+    #[cfg(feature="clustering")]
+    let app = app.arg(
+        Arg::with_name("forward-limit-policy")
+            .long("forward-limit-policy")
+            .takes_value(true)
+            .possible_values(&["queue", "fail"])
+            .default_value("queue")
+            .help("Whether forwards over --max-concurrent-forwards wait for a slot, or fail straight away")
+    );
+
+    #[cfg(feature="clustering")]
+    let app = app.arg(
+        Arg::with_name("forward-connect-timeout")
+            .long("forward-connect-timeout")
+            .takes_value(true)
+            .default_value("2s")
+            .help("How long to wait for a connection to a peer to be established")
+    );
+
+    #[cfg(feature="clustering")]
+    let app = app.arg(
+        Arg::with_name("peer-failure-threshold")
+            .long("peer-failure-threshold")
+            .takes_value(true)
+            .default_value("5")
+            .help("How many forwards to a peer have to fail in a row before forwards to it start failing fast")
+    );
+
+    #[cfg(feature="clustering")]
+    let app = app.arg(
+        Arg::with_name("peer-cooldown")
+            .long("peer-cooldown")
+            .takes_value(true)
+            .default_value("30s")
+            .help("How long to fail forwards to an unhealthy peer fast for, before trying it again")
+    );
+
+    #[cfg(feature="statsd")]
+    let app = app.arg(
+        Arg::with_name("statsd-listen")
+            .long("statsd-listen")
+            .takes_value(true)
+            .help("An address to accept StatsD packets on, over both UDP and TCP, e.g. 0.0.0.0:8125")
+    );
+
     #[cfg(feature="tls")]
     let app = app.arg(
         Arg::with_name("tls-key")
@@ -82,6 +499,22 @@ async fn main() {
             .help("The certificate file to use with TLS")
             .requires("tls-key")
             .takes_value(true)
+    )
+    .arg(
+        Arg::with_name("tls-client-ca")
+            .long("tls-client-ca")
+            .help("A PEM file of CA certificates. Clients with a certificate signed by one of them are authenticated by it")
+            .requires("tls-key")
+            .takes_value(true)
+    )
+    .arg(
+        Arg::with_name("tls-client-allowed-name")
+            .long("tls-client-allowed-name")
+            .help("A name (which can have * wildcards) that a client certificate's common name or a DNS name in it has to match to authenticate. Can be given more than once. By default, any certificate from the client CA is allowed")
+            .requires("tls-client-ca")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
     );
 
     #[cfg(feature="auth")]
@@ -91,10 +524,12 @@ async fn main() {
             .help("The file to use for basic authentication validation")
             .long_help(
                 "The file to use for basic authentication validation.
-                This should be a path to a file of bcrypt hashes, one per line,
-                with each line being an allowed hash."
+                This should be an htpasswd style file of username:hash lines,
+                with bcrypt hashes. Lines that are just a hash allow that
+                password with any username."
             )
             .takes_value(true)
+            .conflicts_with_all(&["bearer-token-file", "job-auth-file"])
     );
     
     let matches = app.get_matches();
@@ -106,104 +541,562 @@ async fn main() {
 
     let log = slog::Logger::root(drain, o!());
 
+    // Requests are traced through `tracing`, which logs JSON to stdout too. Clap ensures that the level is valid
+    let log_level: tracing::Level = matches.value_of("log-level").unwrap().parse().unwrap();
+    tracing_subscriber::fmt().json().with_max_level(log_level).init();
+
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    runtime.enable_all();
+    if let Some(threads) = matches.value_of("worker-threads") {
+        match threads.parse::<usize>() {
+            Ok(threads) if threads > 0 => runtime.worker_threads(threads),
+            _ => {
+                error!(log, "Invalid worker thread count {}: must be a positive number", threads);
+                drop(log);
+                std::process::exit(1);
+            }
+        };
+    }
+
+    let runtime = match runtime.build() {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            error!(log, "Failed to start the runtime: {}", e);
+            drop(log);
+            std::process::exit(1);
+        }
+    };
+
+    // Dropping the runtime drops everything that's still holding onto the logger, which flushes it before exiting
+    let started = runtime.block_on(run(matches, log));
+    drop(runtime);
+    if started.is_err() {
+        std::process::exit(1);
+    }
+}
+
+/// Starts the gateway with the options it was given, and serves until it's asked to stop. Errors (after logging why) if
+/// it can't start
+async fn run(matches: ArgMatches<'static>, log: Logger) -> Result<(), ()> {
     // Parse out the listen address
     let address = matches.value_of("listen").unwrap();
     let address: Vec<_> = match address.to_socket_addrs() {
         Ok(addr) => addr.collect(),
         Err(e) => {
             error!(log, "Failed to parse socket address from {}: {}", address, e);
-            return;
+            return Err(());
         }
     };
 
+    // Checked before anything else is started, so that a bad key or certificate fails straight away
+    #[cfg(feature="tls")]
+    let tls = match matches.value_of("tls-key") {
+        // Clap ensures that if one of these exists, so does the other
+        Some(tls_key) => match server::TlsFiles::load(tls_key, matches.value_of("tls-cert").unwrap())
+            .and_then(|tls| match matches.value_of("tls-client-ca") {
+                Some(ca) => tls.with_client_ca(ca),
+                None => Ok(tls),
+            }) {
+            Ok(tls) => Some(tls),
+            Err(e) => {
+                error!(log, "{}", e);
+                return Err(());
+            }
+        },
+        None => None,
+    };
+
     info!(log, "Listening on: {:?}", address);
 
-    #[cfg(feature="clustering")]
-    let mut cluster_conf = None;
-    #[cfg(feature="clustering")]
-    {
-        let cluster_enabled = matches.is_present("cluster-enabled");
-        if cluster_enabled {
-            let self_url = matches.value_of("listen").unwrap().to_owned() + "/metrics";
-            if let Some(peers) = matches.values_of("peers") {
-                let peers = peers.map(|p| p.to_string()).collect();
-                cluster_conf = Some(clustering::ClusterConfig::new_from_static(self_url, peers));
+    let shards = match matches.value_of("shards").unwrap().parse() {
+        Ok(shards) if shards > 0 => shards,
+        _ => {
+            error!(log, "Invalid shard count: {}", matches.value_of("shards").unwrap());
+            return Err(());
+        }
+    };
+
+    let max_series_per_family = match matches.value_of("max-series-per-family").map(|m| m.parse()).transpose() {
+        Ok(max) => max,
+        Err(e) => {
+            error!(log, "Invalid max series per family {}: {}", matches.value_of("max-series-per-family").unwrap(), e);
+            return Err(());
+        }
+    };
+
+    let max_total_series = match matches.value_of("max-total-series").map(|m| m.parse()).transpose() {
+        Ok(Some(0)) => {
+            error!(log, "Invalid max total series: must be at least 1");
+            return Err(());
+        }
+        Ok(max) => max,
+        Err(e) => {
+            error!(log, "Invalid max total series {}: {}", matches.value_of("max-total-series").unwrap(), e);
+            return Err(());
+        }
+    };
+
+    let mut external_labels = HashMap::new();
+    for label in matches.values_of("external-label").into_iter().flatten() {
+        match label.split_once('=') {
+            Some((name, value)) if check_label_names(std::iter::once(name)).is_ok() => external_labels.insert(name.to_owned(), value.to_owned()),
+            _ => {
+                error!(log, "Invalid external label {}: expected name=value", label);
+                return Err(());
             }
-            else if let Some(peers_file) = matches.value_of("peers-file") {
-                match clustering::ClusterConfig::new_from_file(self_url, peers_file) {
-                    Ok(c) => cluster_conf = Some(c),
-                    Err(e) => {
-                        error!(log, "Failed to load cluster config from file {}: {}", peers_file, e);
-                        return;
-                    }
-                }
+        };
+    }
+
+    let drop_labels: Vec<String> = matches.values_of("drop-label").into_iter().flatten().map(String::from).collect();
+    if let Err(e) = check_label_names(drop_labels.iter().map(String::as_str)) {
+        error!(log, "Invalid label to drop: {}", e);
+        return Err(());
+    }
+
+    let relabel_rules = match matches.value_of("relabel-config-file").map(|path| load_relabel_rules(PathBuf::from(path))).transpose() {
+        Ok(rules) => rules.unwrap_or_default(),
+        Err(e) => {
+            error!(log, "Failed to load relabel config {}: {}", matches.value_of("relabel-config-file").unwrap(), e);
+            return Err(());
+        }
+    };
+
+    let mut series_ttls = Vec::new();
+    for series_ttl in matches.values_of("series-ttl").into_iter().flatten() {
+        // Durations never have an = in them, but selectors do
+        let parsed = series_ttl.rsplit_once('=').and_then(|(selector, ttl)| Some((selector.parse().ok()?, pebble::parse_duration(ttl)?)));
+        match parsed {
+            Some(parsed) => series_ttls.push(parsed),
+            None => {
+                error!(log, "Invalid series ttl {}: expected selector=duration", series_ttl);
+                return Err(());
             }
-            else if let Some(peers_srv) = matches.value_of("peers-srv") {
-                match clustering::ClusterConfig::new_from_srv(self_url, peers_srv) {
-                    Ok(c) => cluster_conf = Some(c),
-                    Err(e) => {
-                        error!(log, "Failed to load cluster config from SRV {}: {}", peers_srv, e);
-                        return;
-                    }
-                }
+        }
+    }
+
+    let reset_interval = match matches.value_of("reset-interval").map(|interval| pebble::parse_duration(interval).filter(|interval| !interval.is_zero())) {
+        Some(Some(interval)) => Some(interval),
+        Some(None) => {
+            error!(log, "Failed to parse reset interval: {}", matches.value_of("reset-interval").unwrap());
+            return Err(());
+        },
+        None => None,
+    };
+
+    let idempotency_key_ttl = match pebble::parse_duration(matches.value_of("idempotency-key-ttl").unwrap()) {
+        Some(ttl) => ttl,
+        None => {
+            error!(log, "Failed to parse idempotency key ttl: {}", matches.value_of("idempotency-key-ttl").unwrap());
+            return Err(());
+        }
+    };
+
+    let agg_config = AggregatorConfig {
+        // Clap ensures that this is one of the valid values
+        quantile_merge_policy: matches.value_of("summary-quantile-merge").unwrap().parse().unwrap(),
+        gauge_aggregation: matches.value_of("gauge-aggregation").unwrap().parse().unwrap(),
+        counter_aggregation: matches.value_of("counter-aggregation").unwrap().parse().unwrap(),
+        counter_reset_policy: matches.value_of("counter-reset-policy").unwrap().parse().unwrap(),
+        histogram_validation: matches.value_of("validate-histograms").unwrap().parse().unwrap(),
+        shards,
+        max_series_per_family,
+        max_total_series,
+        reject_non_finite: matches.is_present("reject-non-finite"),
+        external_labels,
+        drop_labels,
+        relabel_rules,
+        idempotency_key_ttl,
+        normalize_counter_names: matches.is_present("normalize-counter-names"),
+        utf8_lossy: matches.is_present("utf8-lossy"),
+        skip_malformed_lines: matches.is_present("skip-malformed-lines"),
+        label_precedence: matches.value_of("label-precedence").unwrap().parse().unwrap(),
+        series_ttls,
+        reset_interval,
+    };
+
+    let mut agg = match matches.value_of("ttl") {
+        Some(ttl) => match pebble::parse_duration(ttl) {
+            Some(ttl) => Aggregator::with_ttl(agg_config, ttl),
+            None => {
+                error!(log, "Failed to parse ttl: {}", ttl);
+                return Err(());
             }
-            else {
-                error!(log, "Cluster enabled, but no peers specified");
-                return;
+        },
+        None => Aggregator::with_config(agg_config),
+    };
+
+    let snapshot_file = matches.value_of("snapshot-file").map(PathBuf::from);
+    if let Some(path) = snapshot_file.as_ref() {
+        let interval = match pebble::parse_duration(matches.value_of("snapshot-interval").unwrap()) {
+            Some(interval) if !interval.is_zero() => interval,
+            _ => {
+                error!(log, "Failed to parse snapshot interval: {}", matches.value_of("snapshot-interval").unwrap());
+                return Err(());
             }
+        };
+
+        // A bad snapshot shouldn't stop the gateway from starting, it just means starting from nothing
+        match agg.restore_from(path).await {
+            Ok(_) => info!(log, "Restored metrics from {}", path.display()),
+            Err(e) => error!(log, "Failed to restore metrics from {}, starting empty: {}", path.display(), e),
         }
+
+        let (agg, path, log) = (agg.clone(), path.clone(), log.clone());
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            // The first tick is immediate, and there's nothing new to save yet
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                if let Err(e) = agg.snapshot_to(&path).await {
+                    error!(log, "Failed to save metrics to {}: {}", path.display(), e);
+                }
+            }
+        });
     }
 
-    let mut config = RoutesConfig{
-        authenticator: Box::new(pass_through_auth()),
+    #[cfg(feature="statsd")]
+    if let Some(statsd_address) = matches.value_of("statsd-listen") {
+        // StatsD has nowhere to put labels, so it gets the same job as pushes without one
+        let labels: HashMap<String, String> = matches.value_of("default-job").map(|job| ("job".to_owned(), job.to_owned())).into_iter().collect();
+        let socket = match tokio::net::UdpSocket::bind(statsd_address).await {
+            Ok(socket) => socket,
+            Err(e) => {
+                error!(log, "Failed to listen for StatsD over UDP on {}: {}", statsd_address, e);
+                return Err(());
+            }
+        };
+        let listener = match tokio::net::TcpListener::bind(statsd_address).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!(log, "Failed to listen for StatsD over TCP on {}: {}", statsd_address, e);
+                return Err(());
+            }
+        };
+
+        info!(log, "Listening for StatsD on: {}", statsd_address);
+        tokio::spawn(statsd::serve_udp(socket, agg.clone(), labels.clone()));
+        tokio::spawn(statsd::serve_tcp(listener, agg.clone(), labels));
+    }
+
+    #[cfg(feature="clustering")]
+    let cluster_conf = match load_cluster_config(&matches, &log) {
+        Ok(cluster_conf) => cluster_conf,
+        Err(e) => {
+            error!(log, "{}", e);
+            return Err(());
+        }
+    };
+
+    let max_body_bytes = match matches.value_of("max-body-bytes").unwrap().parse() {
+        Ok(max_body_bytes) => max_body_bytes,
+        Err(e) => {
+            error!(log, "Invalid max body size {}: {}", matches.value_of("max-body-bytes").unwrap(), e);
+            return Err(());
+        }
+    };
+
+    let rate_limit = match parse_rate_limit(&matches) {
+        Ok(rate_limit) => rate_limit,
+        Err(e) => {
+            error!(log, "{}", e);
+            return Err(());
+        }
+    };
+
+    let max_in_flight_pushes = match matches.value_of("max-in-flight-pushes").unwrap().parse() {
+        Ok(max) if max > 0 => max,
+        _ => {
+            error!(log, "Invalid max in flight pushes {}: must be a positive number of pushes", matches.value_of("max-in-flight-pushes").unwrap());
+            return Err(());
+        }
+    };
+
+    let cors_allowed_origins = matches.values_of("cors-allowed-origin").into_iter().flatten().map(String::from).collect();
+
+    let auth_header = match HeaderName::from_str(matches.value_of("auth-header").unwrap()) {
+        Ok(auth_header) => auth_header,
+        Err(e) => {
+            error!(log, "Invalid auth header {}: {}", matches.value_of("auth-header").unwrap(), e);
+            return Err(());
+        }
+    };
+
+    let authenticator = match load_authenticator(&matches) {
+        Ok(authenticator) => authenticator,
+        Err(e) => {
+            error!(log, "{}", e);
+            return Err(());
+        }
+    };
+
+    let config = SharedRoutesConfig::new(RoutesConfig{
+        authenticator,
+        auth_header,
+        max_body_bytes,
+        rate_limit,
+        max_in_flight_pushes,
+        cors_allowed_origins,
+        default_job: matches.value_of("default-job").map(String::from),
+        strict_label_paths: matches.is_present("strict-label-paths"),
+        graphite_prefix: matches.value_of("graphite-prefix").map(|prefix| prefix.trim_matches('.').to_owned()).filter(|prefix| !prefix.is_empty()),
         #[cfg(feature="clustering")]
         cluster_conf
-    };
+    });
 
-    #[cfg(feature = "auth")]
+    // On a SIGHUP, reload the authenticator and cluster config. If either of them fails to load, the old ones are kept
     {
-        use auth::basic_auth;
-        if let Some(path) = matches.value_of("basic-auth-file") {
-            config = match basic_auth(PathBuf::from(path)) {
-                Ok(authenticator) => RoutesConfig {
-                    authenticator: Box::new(authenticator),
-                    cluster_conf: None
-                },
+        let (config, matches, log) = (config.clone(), matches.clone(), log.clone());
+        tokio::spawn(server::reload_on(server::reload_signals(), move || {
+            let authenticator = match load_authenticator(&matches) {
+                Ok(authenticator) => authenticator,
                 Err(e) => {
-                    error!(log, "Failed to load basic auth file ({}) - {}", path, e);
+                    error!(log, "Failed to reload config, keeping the old one: {}", e);
                     return;
                 }
             };
-        };
+
+            #[cfg(feature="clustering")]
+            let cluster_conf = match load_cluster_config(&matches, &log) {
+                Ok(cluster_conf) => cluster_conf,
+                Err(e) => {
+                    error!(log, "Failed to reload config, keeping the old one: {}", e);
+                    return;
+                }
+            };
+
+            config.reload(authenticator, #[cfg(feature="clustering")] cluster_conf);
+            info!(log, "Reloaded config");
+        }));
     }
-    
-    let routes = routes::get_routes(agg, config);
 
+    let routes = routes::get_routes(agg.clone(), config);
+
+    // On a SIGTERM or ctrl-c, stop taking new connections, and let the requests in flight finish before exiting
     #[cfg(feature="tls")]
-    if let Some(tls_key) = matches.value_of("tls-key") {
-        // Clap ensures that if one of these exists, so does the other
-        let tls_cert = matches.value_of("tls-cert").unwrap();
+    let server = match tls.as_ref() {
+        Some(tls) => server::serve_tls(routes, &address, tls, server::shutdown_signal()).map(|(_, server)| server.boxed()),
+        None => server::serve(routes, &address, server::shutdown_signal()).map(|(_, server)| server.boxed()),
+    };
+
+    // If we don't have TLS support, just bind without it
+    #[cfg(not(feature="tls"))]
+    let server = server::serve(routes, &address, server::shutdown_signal()).map(|(_, server)| server.boxed());
+
+    match server {
+        Ok(server) => server.await,
+        Err(e) => {
+            error!(log, "{}", e);
+            return Err(());
+        }
+    }
 
-        tokio::select! {
-            _ = signal::ctrl_c() => {},
+    info!(log, "Shutting down");
 
-            _ = futures::future::join_all(address.into_iter()
-                .map(move |addr| warp::serve(routes.clone()).tls().key_path(tls_key).cert_path(tls_cert).run(addr))) => {}
+    if let Some(path) = snapshot_file {
+        match agg.snapshot_to(&path).await {
+            Ok(_) => info!(log, "Saved metrics to {}", path.display()),
+            Err(e) => error!(log, "Failed to save metrics to {}: {}", path.display(), e),
+        }
+    }
+
+    return Ok(());
+}
+
+/// The rate limit that the command line asks for, if any
+fn parse_rate_limit(matches: &ArgMatches) -> Result<Option<RateLimit>, String> {
+    let per_second: f64 = match matches.value_of("rate-limit") {
+        Some(rate) => match rate.parse() {
+            Ok(rate) if rate > 0. => rate,
+            _ => return Err(format!("Invalid rate limit {}: must be a positive number of pushes a second", rate)),
+        },
+        None => return Ok(None),
+    };
+
+    let burst = match matches.value_of("rate-limit-burst") {
+        Some(burst) => match burst.parse() {
+            Ok(burst) if burst > 0 => burst,
+            _ => return Err(format!("Invalid rate limit burst {}: must be a positive number of pushes", burst)),
+        },
+        None => per_second.ceil() as u32,
+    };
+
+    Ok(Some(RateLimit { per_second, burst }))
+}
+
+/// Builds the authenticator that the command line asks for, loading its users or tokens from their file.
+/// Like the cluster config, it's loaded again on every SIGHUP
+fn load_authenticator(matches: &ArgMatches) -> Result<Box<dyn Authenticator + Send + Sync>, String> {
+    // Anyone can push, unless one of the auth flags says otherwise
+    let mut authenticator = AuthConfig::None.build().map_err(|e| format!("Failed to set up auth - {}", e))?;
+
+    if let Some(path) = matches.value_of("bearer-token-file") {
+        authenticator = match (AuthConfig::BearerFile { path: PathBuf::from(path) }).build() {
+            Ok(authenticator) => authenticator,
+            Err(e) => return Err(format!("Failed to load bearer token file ({}) - {}", path, e)),
         };
     }
-    else {
-        tokio::select! {
-            _ = signal::ctrl_c() => {},
 
-            _ = futures::future::join_all(address.into_iter()
-            .map(move |addr| warp::serve(routes.clone()).run(addr))) => {}
+    if let Some(path) = matches.value_of("job-auth-file") {
+        authenticator = match job_auth(PathBuf::from(path)) {
+            Ok(authenticator) => Box::new(authenticator),
+            Err(e) => return Err(format!("Failed to load job auth file ({}) - {}", path, e)),
         };
+    }
+
+    if let Some(path) = matches.value_of("tenant-auth-file") {
+        authenticator = match tenant_auth(PathBuf::from(path)) {
+            Ok(authenticator) => Box::new(authenticator),
+            Err(e) => return Err(format!("Failed to load tenant auth file ({}) - {}", path, e)),
+        };
+    }
+
+    #[cfg(feature = "auth")]
+    {
+        if let Some(path) = matches.value_of("basic-auth-file") {
+            authenticator = match (AuthConfig::Htpasswd { path: PathBuf::from(path) }).build() {
+                Ok(authenticator) => authenticator,
+                Err(e) => return Err(format!("Failed to load basic auth file ({}) - {}", path, e)),
+            };
+        };
+    }
+
+    // Client certificates are checked first, falling back to any other auth that's been set up. If there isn't any, a
+    // certificate is the only way in
+    #[cfg(feature="tls")]
+    if matches.is_present("tls-client-ca") {
+        use auth::ClientCertAuthenticator;
+        let has_other_auth = ["bearer-token-file", "job-auth-file", "tenant-auth-file", "basic-auth-file"].iter().any(|arg| matches.is_present(arg));
+        let allowed_names = matches.values_of("tls-client-allowed-name").map(|names| names.map(String::from).collect()).unwrap_or_default();
+        authenticator = Box::new(ClientCertAuthenticator::new(allowed_names, has_other_auth.then_some(authenticator)));
+    }
+
+    return Ok(authenticator);
+}
+
+/// Builds the cluster config from the command line, loading the peers from wherever it says they are.
+/// This is also how the config is reloaded, so it's called again (re-reading the peers) on every SIGHUP
+#[cfg(feature="clustering")]
+fn load_cluster_config(matches: &ArgMatches, log: &Logger) -> Result<Option<clustering::ClusterConfig>, String> {
+    let retry_policy = clustering::RetryPolicy {
+        max_retries: match matches.value_of("forward-retries").unwrap().parse() {
+            Ok(retries) => retries,
+            Err(e) => return Err(format!("Invalid forward retries {}: {}", matches.value_of("forward-retries").unwrap(), e)),
+        },
+        base_delay: match pebble::parse_duration(matches.value_of("forward-retry-delay").unwrap()) {
+            Some(delay) => delay,
+            None => return Err(format!("Failed to parse forward retry delay: {}", matches.value_of("forward-retry-delay").unwrap())),
+        },
+        attempt_timeout: match pebble::parse_duration(matches.value_of("forward-timeout").unwrap()) {
+            Some(timeout) => timeout,
+            None => return Err(format!("Failed to parse forward timeout: {}", matches.value_of("forward-timeout").unwrap())),
+        },
     };
 
-    // If we don't have TLS support, just bind without it
-    #[cfg(not(feature="tls"))]
-    tokio::select! {
-        _ = signal::ctrl_c() => {}
-        _ = futures::future::join_all(address.into_iter().map(move |addr| warp::serve(routes.clone()).run(addr))) => {}
+    let client_config = clustering::ClientConfig {
+        pool_max_idle_per_host: match matches.value_of("forward-pool-size").unwrap().parse() {
+            Ok(size) => size,
+            Err(e) => return Err(format!("Invalid forward pool size {}: {}", matches.value_of("forward-pool-size").unwrap(), e)),
+        },
+        connect_timeout: match pebble::parse_duration(matches.value_of("forward-connect-timeout").unwrap()) {
+            Some(timeout) => timeout,
+            None => return Err(format!("Failed to parse forward connect timeout: {}", matches.value_of("forward-connect-timeout").unwrap())),
+        },
+        ..Default::default()
+    };
+
+    let max_concurrent_forwards = match matches.value_of("max-concurrent-forwards").unwrap().parse() {
+        Ok(max_concurrent_forwards) if max_concurrent_forwards > 0 => max_concurrent_forwards,
+        _ => return Err(format!("Invalid max concurrent forwards: {}", matches.value_of("max-concurrent-forwards").unwrap())),
+    };
+
+    // Clap ensures that this is one of the valid values
+    let forward_limit_policy = matches.value_of("forward-limit-policy").unwrap().parse().unwrap();
+
+    let virtual_nodes = match matches.value_of("virtual-nodes").unwrap().parse() {
+        Ok(virtual_nodes) if virtual_nodes > 0 => virtual_nodes,
+        _ => return Err(format!("Invalid virtual node count: {}", matches.value_of("virtual-nodes").unwrap())),
+    };
+
+    let mut peer_weights = HashMap::new();
+    for peer_weight in matches.values_of("peer-weight").into_iter().flatten() {
+        match peer_weight.rsplit_once('=').map(|(peer, weight)| (peer, weight.parse())) {
+            Some((peer, Ok(weight))) if weight > 0 => peer_weights.insert(peer.to_owned(), weight),
+            _ => return Err(format!("Invalid peer weight {}: expected peer=weight, with a positive weight", peer_weight)),
+        };
+    }
+
+    let replication_factor = match matches.value_of("replication-factor").unwrap().parse() {
+        Ok(replication_factor) if replication_factor > 0 => replication_factor,
+        _ => return Err(format!("Invalid replication factor: {}", matches.value_of("replication-factor").unwrap())),
     };
+
+    let key_labels = matches.values_of("cluster-key-label").map(|labels| labels.map(|l| l.to_owned()).collect()).unwrap_or_default();
+
+    let circuit_breaker_config = clustering::CircuitBreakerConfig {
+        failure_threshold: match matches.value_of("peer-failure-threshold").unwrap().parse() {
+            Ok(threshold) => threshold,
+            Err(e) => return Err(format!("Invalid peer failure threshold {}: {}", matches.value_of("peer-failure-threshold").unwrap(), e)),
+        },
+        cooldown: match pebble::parse_duration(matches.value_of("peer-cooldown").unwrap()) {
+            Some(cooldown) => cooldown,
+            None => return Err(format!("Failed to parse peer cooldown: {}", matches.value_of("peer-cooldown").unwrap())),
+        },
+    };
+
+    let mut cluster_conf = None;
+    let mut peer_discovery = None;
+    let cluster_enabled = matches.is_present("cluster-enabled");
+    if cluster_enabled {
+        let self_url = matches.value_of("listen").unwrap().to_owned() + "/metrics";
+        if let Some(peers) = matches.values_of("peers") {
+            let peers = peers.map(|p| p.to_string()).collect();
+            cluster_conf = Some(clustering::ClusterConfig::new_from_static(self_url, peers));
+        }
+        else if let Some(peers_file) = matches.value_of("peers-file") {
+            match clustering::ClusterConfig::new_from_file(self_url, peers_file) {
+                Ok(c) => cluster_conf = Some(c),
+                Err(e) => return Err(format!("Failed to load cluster config from file {}: {}", peers_file, e)),
+            }
+        }
+        else if let Some(peers_srv) = matches.value_of("peers-srv") {
+            match clustering::ClusterConfig::new_from_srv(self_url, peers_srv) {
+                Ok(c) => cluster_conf = Some(c),
+                Err(e) => return Err(format!("Failed to load cluster config from SRV {}: {}", peers_srv, e)),
+            }
+        }
+        else if let Some(peers_dns) = matches.value_of("peers-dns") {
+            let interval = match pebble::parse_duration(matches.value_of("peers-dns-interval").unwrap()) {
+                Some(interval) if !interval.is_zero() => interval,
+                _ => return Err(format!("Failed to parse peers DNS interval: {}", matches.value_of("peers-dns-interval").unwrap())),
+            };
+
+            // Peers that aren't up yet may not be in DNS, so a failed first lookup just starts the cluster off with us alone
+            let c = clustering::ClusterConfig::new_from_static(self_url, Vec::new());
+            let resolver = clustering::DnsPeerResolver::new(peers_dns.to_owned());
+            if let Err(e) = c.refresh_peers(&resolver) {
+                error!(log, "Failed to look up peers from {}: {}", peers_dns, e);
+            }
+            peer_discovery = Some((resolver, interval));
+            cluster_conf = Some(c);
+        }
+        else {
+            return Err("Cluster enabled, but no peers specified".to_owned());
+        }
+    }
+
+    cluster_conf = cluster_conf.map(|c| c.with_virtual_nodes(virtual_nodes)
+        .with_peer_weights(peer_weights)
+        .with_replication_factor(replication_factor)
+        .with_key_labels(key_labels)
+        .with_retry_policy(retry_policy)
+        .with_client_config(&client_config)
+        .with_forward_limit(max_concurrent_forwards, forward_limit_policy)
+        .with_circuit_breaker(circuit_breaker_config));
+
+    if let (Some(c), Some((resolver, interval))) = (cluster_conf.as_ref(), peer_discovery) {
+        c.spawn_peer_discovery(Box::new(resolver), interval);
+    }
+
+    return Ok(cluster_conf);
 }
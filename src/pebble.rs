@@ -1,7 +1,9 @@
 
 use std::{time::{Duration, SystemTime}, fmt};
 
-type MergeStrategy = fn(old: &PebbleEntry, new: &PebbleEntry) -> f64;
+use serde::{Deserialize, Serialize};
+
+use crate::snapshot::Float;
 
 pub fn sum_merge_strategy(old: &PebbleEntry, new: &PebbleEntry) -> f64 {
     old.value + new.value
@@ -18,6 +20,23 @@ pub fn mean_merge_strategy(old: &PebbleEntry, new: &PebbleEntry) -> f64 {
     top / bottom as f64
 }
 
+/// How the values in a pebble are combined
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PebbleMerge {
+    Sum,
+    Mean,
+}
+
+impl PebbleMerge {
+    fn apply(self, old: &PebbleEntry, new: &PebbleEntry) -> f64 {
+        match self {
+            PebbleMerge::Sum => sum_merge_strategy(old, new),
+            PebbleMerge::Mean => mean_merge_strategy(old, new),
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct PebbleEntry {
     weight: i32,
@@ -34,7 +53,7 @@ impl PebbleEntry {
 #[derive(Clone)]
 pub struct TimePebble {
     buckets: Vec<PebbleEntry>,
-    merge: MergeStrategy,
+    merge: PebbleMerge,
     bucket_size_nanos: u128,
     last_bucket_index: usize,
     last_bucket_time_nanos: u128,
@@ -53,7 +72,7 @@ impl PartialEq for TimePebble {
 }
 
 impl TimePebble {
-    pub fn new(time_span: Duration, granularity: usize, merge: MergeStrategy) -> TimePebble {
+    pub fn new(time_span: Duration, granularity: usize, merge: PebbleMerge) -> TimePebble {
         return TimePebble {
             buckets: vec![PebbleEntry { weight: 0, value: 0. }; 100],
             merge,
@@ -106,7 +125,7 @@ impl TimePebble {
         let (adjusted_time, window_offset) = self.select_bucket(timestamp);
         self.keep_consistent(adjusted_time, window_offset);
 
-        self.buckets[window_offset].value = (self.merge)(&self.buckets[window_offset], &PebbleEntry {
+        self.buckets[window_offset].value = self.merge.apply(&self.buckets[window_offset], &PebbleEntry {
             weight: 1,
             value,
         });
@@ -133,22 +152,65 @@ impl TimePebble {
 
             pebble_value = PebbleEntry {
                 weight: pebble_value.weight + bucket.weight,
-                value: (self.merge)(&pebble_value, bucket)
+                value: self.merge.apply(&pebble_value, bucket)
             }
         }
 
-        return (self.merge)(&pebble_value, &PebbleEntry {
+        return self.merge.apply(&pebble_value, &PebbleEntry {
             weight: 0,
             value: 0.0,
         });
     }
 }
 
+/// Everything in a pebble, for snapshots. Bucket times are in units of the bucket size since the Unix epoch, so a pebble
+/// restored from one carries on where it left off, dropping whatever's fallen out of its window in the meantime
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PebbleSnapshot {
+    merge: PebbleMerge,
+    /// The weight and value of each bucket
+    buckets: Vec<(i32, Float)>,
+    bucket_size_nanos: u64,
+    last_bucket_index: usize,
+    last_bucket_time: u64,
+}
+
+impl TimePebble {
+    pub fn to_snapshot(&self) -> PebbleSnapshot {
+        PebbleSnapshot {
+            merge: self.merge,
+            buckets: self.buckets.iter().map(|bucket| (bucket.weight, Float(bucket.value))).collect(),
+            bucket_size_nanos: self.bucket_size_nanos as u64,
+            last_bucket_index: self.last_bucket_index,
+            last_bucket_time: self.last_bucket_time_nanos as u64,
+        }
+    }
+
+    /// Rebuilds a pebble from a snapshot, checking that it's one this version of the gateway could have made
+    pub fn from_snapshot(snapshot: PebbleSnapshot) -> Result<TimePebble, String> {
+        if snapshot.bucket_size_nanos == 0 || snapshot.buckets.is_empty() || snapshot.last_bucket_index >= snapshot.buckets.len() {
+            return Err(String::from("invalid window - it needs buckets, with a non-zero size"));
+        }
+
+        return Ok(TimePebble {
+            buckets: snapshot.buckets.into_iter().map(|(weight, Float(value))| PebbleEntry { weight, value }).collect(),
+            merge: snapshot.merge,
+            bucket_size_nanos: snapshot.bucket_size_nanos.into(),
+            last_bucket_index: snapshot.last_bucket_index,
+            last_bucket_time_nanos: snapshot.last_bucket_time.into(),
+        });
+    }
+}
+
 pub fn parse_duration(s: &str) -> Option<Duration> {
     let magnitude = match s.chars().filter(|c| c.is_numeric()).collect::<String>().parse() {
         Ok(m) => m,
         Err(_) => return None,
     };
+
+    if s.ends_with("ms") {
+        return Some(Duration::from_millis(magnitude));
+    }
     
     match s.chars().last() {
         Some('s') => Some(Duration::from_secs(magnitude)),
@@ -0,0 +1,516 @@
+use std::{collections::HashMap, convert::TryInto};
+
+use openmetrics_parser::{Exemplar, HistogramBucket, HistogramValue, MetricNumber, ParseError, PrometheusCounterValue, PrometheusMetricFamily, PrometheusType, PrometheusValue, Quantile, Sample, SummaryValue};
+
+use crate::exposition::{escape_help, escape_label_value};
+#[cfg(feature="clustering")]
+use crate::exposition::unescape_label_value;
+
+/// The media type of the Prometheus protobuf exposition format
+const PROTOBUF_MEDIA_TYPE: &str = "application/vnd.google.protobuf";
+
+/// Whether the given Content-Type is the length delimited stream of MetricFamily messages that client libraries push
+pub fn is_delimited_protobuf(content_type: &str) -> bool {
+    let mut parts = content_type.split(';').map(|part| part.trim());
+    if !parts.next().is_some_and(|media_type| media_type.eq_ignore_ascii_case(PROTOBUF_MEDIA_TYPE)) {
+        return false;
+    }
+
+    let params: HashMap<&str, &str> = parts.filter_map(|param| param.split_once('=')).map(|(k, v)| (k.trim(), v.trim())).collect();
+    params.get("proto") == Some(&"io.prometheus.client.MetricFamily") && params.get("encoding") == Some(&"delimited")
+}
+
+/// Decodes a stream of varint length delimited `io.prometheus.client.MetricFamily` messages into families,
+/// like the ones that the text parser produces
+pub fn decode_delimited(mut data: &[u8]) -> Result<Vec<PrometheusMetricFamily>, ParseError> {
+    let mut families = Vec::new();
+    while !data.is_empty() {
+        let mut reader = Reader(data);
+        let len = reader.varint()? as usize;
+        if len > reader.0.len() {
+            return Err(invalid("message is cut short"));
+        }
+
+        let (message, rest) = reader.0.split_at(len);
+        families.push(decode_family(message)?);
+        data = rest;
+    }
+
+    Ok(families)
+}
+
+/// Encodes families as a stream of varint length delimited `io.prometheus.client.MetricFamily` messages, undoing
+/// `decode_delimited`
+#[cfg(feature="clustering")]
+pub fn encode_delimited(families: &[PrometheusMetricFamily]) -> Result<Vec<u8>, ParseError> {
+    let mut out = Writer::default();
+    for family in families {
+        let message = encode_family(family)?;
+        out.varint(message.0.len() as u64);
+        out.0.extend(message.0);
+    }
+
+    Ok(out.0)
+}
+
+fn invalid(reason: &str) -> ParseError {
+    ParseError::ParseError(format!("Invalid protobuf push: {}", reason))
+}
+
+/// The values a protobuf field can have on the wire
+enum Value<'a> {
+    Varint(u64),
+    Fixed64(u64),
+    Fixed32,
+    Bytes(&'a [u8]),
+}
+
+impl<'a> Value<'a> {
+    fn double(&self) -> Result<f64, ParseError> {
+        match self {
+            Value::Fixed64(bits) => Ok(f64::from_bits(*bits)),
+            _ => Err(invalid("expected a double")),
+        }
+    }
+
+    fn varint(&self) -> Result<u64, ParseError> {
+        match self {
+            Value::Varint(v) => Ok(*v),
+            _ => Err(invalid("expected a varint")),
+        }
+    }
+
+    fn bytes(&self) -> Result<&'a [u8], ParseError> {
+        match self {
+            Value::Bytes(bytes) => Ok(bytes),
+            _ => Err(invalid("expected a length delimited field")),
+        }
+    }
+
+    fn string(&self) -> Result<String, ParseError> {
+        String::from_utf8(self.bytes()?.to_vec()).map_err(|_| invalid("invalid UTF-8 in a string"))
+    }
+}
+
+/// Reads fields off the front of an encoded message
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn varint(&mut self) -> Result<u64, ParseError> {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let (&byte, rest) = self.0.split_first().ok_or_else(|| invalid("varint is cut short"))?;
+            self.0 = rest;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+
+        Err(invalid("varint is too long"))
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], ParseError> {
+        if len > self.0.len() {
+            return Err(invalid("field is cut short"));
+        }
+
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    /// The next field number and value, or None at the end of the message
+    fn field(&mut self) -> Result<Option<(u64, Value<'a>)>, ParseError> {
+        if self.0.is_empty() {
+            return Ok(None);
+        }
+
+        let key = self.varint()?;
+        let value = match key & 0x7 {
+            0 => Value::Varint(self.varint()?),
+            1 => Value::Fixed64(u64::from_le_bytes(self.take(8)?.try_into().unwrap())),
+            2 => {
+                let len = self.varint()? as usize;
+                Value::Bytes(self.take(len)?)
+            },
+            5 => {
+                self.take(4)?;
+                Value::Fixed32
+            },
+            _ => return Err(invalid("unsupported wire type")),
+        };
+
+        Ok(Some((key >> 3, value)))
+    }
+}
+
+/// Writes fields onto the end of an encoded message
+#[cfg(feature="clustering")]
+#[derive(Default)]
+struct Writer(Vec<u8>);
+
+#[cfg(feature="clustering")]
+impl Writer {
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.0.push((value as u8) | 0x80);
+            value >>= 7;
+        }
+        self.0.push(value as u8);
+    }
+
+    fn varint_field(&mut self, field: u64, value: u64) {
+        self.varint(field << 3);
+        self.varint(value);
+    }
+
+    fn double_field(&mut self, field: u64, value: f64) {
+        self.varint(field << 3 | 1);
+        self.0.extend(value.to_bits().to_le_bytes());
+    }
+
+    fn bytes_field(&mut self, field: u64, value: &[u8]) {
+        self.varint(field << 3 | 2);
+        self.varint(value.len() as u64);
+        self.0.extend(value);
+    }
+
+    fn message_field(&mut self, field: u64, message: Writer) {
+        self.bytes_field(field, &message.0);
+    }
+}
+
+/// Pushed values come in as doubles, but whole ones are kept as ints, so that they render the same as text pushes
+fn number(value: f64) -> MetricNumber {
+    if value.fract() == 0. && value.abs() < i64::MAX as f64 {
+        return MetricNumber::Int(value as i64);
+    }
+
+    MetricNumber::Float(value)
+}
+
+/// A decoded `io.prometheus.client.Metric`, before it's turned into a sample of its family
+#[derive(Default)]
+struct Metric {
+    labels: Vec<(String, String)>,
+    value: Option<PrometheusValue>,
+    timestamp_ms: Option<i64>,
+}
+
+fn decode_family(message: &[u8]) -> Result<PrometheusMetricFamily, ParseError> {
+    let (mut name, mut help, mut family_type, mut metrics) = (None, String::new(), 0, Vec::new());
+    let mut reader = Reader(message);
+    while let Some((field, value)) = reader.field()? {
+        match field {
+            1 => name = Some(value.string()?),
+            2 => help = escape_help(&value.string()?).into_owned(),
+            3 => family_type = value.varint()?,
+            4 => metrics.push(value.bytes()?),
+            _ => {},
+        }
+    }
+
+    let name = name.ok_or_else(|| invalid("family has no name"))?;
+    let family_type = match family_type {
+        0 => PrometheusType::Counter,
+        1 => PrometheusType::Gauge,
+        2 => PrometheusType::Summary,
+        3 => PrometheusType::Unknown,
+        4 => PrometheusType::Histogram,
+        _ => return Err(invalid(&format!("family {} has an unsupported type", name))),
+    };
+
+    let metrics = metrics.into_iter().map(|metric| decode_metric(metric, &family_type)).collect::<Result<Vec<Metric>, ParseError>>()?;
+
+    // Every sample of a family has to have the same label names, which are taken from the first one
+    let label_names: Vec<String> = metrics.first().map(|m| m.labels.iter().map(|(name, _)| name.clone()).collect()).unwrap_or_default();
+    let mut samples = Vec::with_capacity(metrics.len());
+    for metric in metrics {
+        let mut labels: HashMap<String, String> = metric.labels.into_iter().collect();
+        let label_values = label_names.iter().map(|name| labels.remove(name)).collect::<Option<Vec<String>>>();
+        let label_values = match label_values {
+            Some(values) if labels.is_empty() => values,
+            _ => return Err(invalid(&format!("samples of family {} have different label names", name))),
+        };
+
+        let value = metric.value.ok_or_else(|| invalid(&format!("a sample of family {} has no value of its type", name)))?;
+        samples.push(Sample::new(label_values, metric.timestamp_ms.map(|t| t as f64), value));
+    }
+
+    PrometheusMetricFamily::new(name, label_names, family_type, help, String::new()).with_samples(samples)
+}
+
+fn decode_metric(message: &[u8], family_type: &PrometheusType) -> Result<Metric, ParseError> {
+    let mut metric = Metric::default();
+    let mut reader = Reader(message);
+    while let Some((field, value)) = reader.field()? {
+        // Only the value that matches the family's type counts
+        metric.value = match (field, family_type) {
+            (1, _) => {
+                metric.labels.push(decode_label(value.bytes()?)?);
+                continue;
+            },
+            (6, _) => {
+                metric.timestamp_ms = Some(value.varint()? as i64);
+                continue;
+            },
+            (2, PrometheusType::Gauge) => Some(PrometheusValue::Gauge(number(decode_single_double(value.bytes()?)?))),
+            (3, PrometheusType::Counter) => Some(decode_counter(value.bytes()?)?),
+            (4, PrometheusType::Summary) => Some(decode_summary(value.bytes()?)?),
+            (5, PrometheusType::Unknown) => Some(PrometheusValue::Unknown(number(decode_single_double(value.bytes()?)?))),
+            (7, PrometheusType::Histogram) => Some(decode_histogram(value.bytes()?)?),
+            _ => metric.value,
+        };
+    }
+
+    Ok(metric)
+}
+
+fn decode_label(message: &[u8]) -> Result<(String, String), ParseError> {
+    let (mut name, mut value) = (String::new(), String::new());
+    let mut reader = Reader(message);
+    while let Some((field, field_value)) = reader.field()? {
+        match field {
+            1 => name = field_value.string()?,
+            2 => value = escape_label_value(&field_value.string()?).into_owned(),
+            _ => {},
+        }
+    }
+
+    Ok((name, value))
+}
+
+/// Decodes the Gauge and Untyped messages, which are both just a value in field 1
+fn decode_single_double(message: &[u8]) -> Result<f64, ParseError> {
+    let mut result = 0.;
+    let mut reader = Reader(message);
+    while let Some((field, value)) = reader.field()? {
+        if field == 1 {
+            result = value.double()?;
+        }
+    }
+
+    Ok(result)
+}
+
+fn decode_counter(message: &[u8]) -> Result<PrometheusValue, ParseError> {
+    let mut counter = PrometheusCounterValue { value: MetricNumber::Int(0), exemplar: None };
+    let mut reader = Reader(message);
+    while let Some((field, value)) = reader.field()? {
+        match field {
+            1 => counter.value = number(value.double()?),
+            2 => counter.exemplar = Some(decode_exemplar(value.bytes()?)?),
+            _ => {},
+        }
+    }
+
+    Ok(PrometheusValue::Counter(counter))
+}
+
+fn decode_summary(message: &[u8]) -> Result<PrometheusValue, ParseError> {
+    let mut summary = SummaryValue { sum: None, count: None, created: None, quantiles: Vec::new() };
+    let mut reader = Reader(message);
+    while let Some((field, value)) = reader.field()? {
+        match field {
+            1 => summary.count = Some(value.varint()?),
+            2 => summary.sum = Some(number(value.double()?)),
+            3 => {
+                let (mut quantile, mut quantile_value) = (0., 0.);
+                let mut quantile_reader = Reader(value.bytes()?);
+                while let Some((field, value)) = quantile_reader.field()? {
+                    match field {
+                        1 => quantile = value.double()?,
+                        2 => quantile_value = value.double()?,
+                        _ => {},
+                    }
+                }
+                summary.quantiles.push(Quantile { quantile, value: number(quantile_value) });
+            },
+            _ => {},
+        }
+    }
+
+    Ok(PrometheusValue::Summary(summary))
+}
+
+fn decode_histogram(message: &[u8]) -> Result<PrometheusValue, ParseError> {
+    let mut histogram = HistogramValue { sum: None, count: None, created: None, buckets: Vec::new() };
+    let mut reader = Reader(message);
+    while let Some((field, value)) = reader.field()? {
+        match field {
+            1 => histogram.count = Some(value.varint()?),
+            2 => histogram.sum = Some(number(value.double()?)),
+            3 => {
+                let mut bucket = HistogramBucket { count: MetricNumber::Int(0), upper_bound: 0., exemplar: None };
+                let mut bucket_reader = Reader(value.bytes()?);
+                while let Some((field, value)) = bucket_reader.field()? {
+                    match field {
+                        1 => bucket.count = MetricNumber::Int(value.varint()? as i64),
+                        2 => bucket.upper_bound = value.double()?,
+                        3 => bucket.exemplar = Some(decode_exemplar(value.bytes()?)?),
+                        _ => {},
+                    }
+                }
+                histogram.buckets.push(bucket);
+            },
+            _ => {},
+        }
+    }
+
+    // The +Inf bucket is left implicit in protobuf, but the text format always has one
+    if !histogram.buckets.iter().any(|bucket| bucket.upper_bound == f64::INFINITY) {
+        let count = histogram.count.unwrap_or_default();
+        histogram.buckets.push(HistogramBucket { count: MetricNumber::Int(count as i64), upper_bound: f64::INFINITY, exemplar: None });
+    }
+
+    Ok(PrometheusValue::Histogram(histogram))
+}
+
+fn decode_exemplar(message: &[u8]) -> Result<Exemplar, ParseError> {
+    let (mut labels, mut id, mut timestamp) = (HashMap::new(), 0., None);
+    let mut reader = Reader(message);
+    while let Some((field, value)) = reader.field()? {
+        match field {
+            1 => {
+                let (name, value) = decode_label(value.bytes()?)?;
+                labels.insert(name, value);
+            },
+            2 => id = value.double()?,
+            3 => {
+                // A google.protobuf.Timestamp, of seconds and nanoseconds
+                let (mut seconds, mut nanos) = (0, 0);
+                let mut timestamp_reader = Reader(value.bytes()?);
+                while let Some((field, value)) = timestamp_reader.field()? {
+                    match field {
+                        1 => seconds = value.varint()? as i64,
+                        2 => nanos = value.varint()? as i64,
+                        _ => {},
+                    }
+                }
+                timestamp = Some(seconds as f64 + nanos as f64 / 1e9);
+            },
+            _ => {},
+        }
+    }
+
+    Ok(Exemplar::new(labels, id, timestamp))
+}
+
+#[cfg(feature="clustering")]
+fn encode_family(family: &PrometheusMetricFamily) -> Result<Writer, ParseError> {
+    let mut message = Writer::default();
+    message.bytes_field(1, family.family_name.as_bytes());
+    if !family.help.is_empty() {
+        message.bytes_field(2, unescape_label_value(&family.help).as_bytes());
+    }
+    message.varint_field(3, match family.family_type {
+        PrometheusType::Counter => 0,
+        PrometheusType::Gauge => 1,
+        PrometheusType::Summary => 2,
+        PrometheusType::Unknown => 3,
+        PrometheusType::Histogram => 4,
+    });
+
+    for sample in family.iter_samples() {
+        let mut metric = Writer::default();
+        for (name, value) in sample.get_labelset()?.iter() {
+            metric.message_field(1, encode_label(name, value));
+        }
+
+        match &sample.value {
+            PrometheusValue::Gauge(value) => metric.message_field(2, encode_single_double(value.as_f64())),
+            PrometheusValue::Counter(counter) => metric.message_field(3, encode_counter(counter)),
+            PrometheusValue::Summary(summary) => metric.message_field(4, encode_summary(summary)),
+            PrometheusValue::Unknown(value) => metric.message_field(5, encode_single_double(value.as_f64())),
+            PrometheusValue::Histogram(histogram) => metric.message_field(7, encode_histogram(histogram)),
+        }
+
+        if let Some(timestamp) = sample.timestamp {
+            metric.varint_field(6, timestamp as i64 as u64);
+        }
+        message.message_field(4, metric);
+    }
+
+    Ok(message)
+}
+
+#[cfg(feature="clustering")]
+fn encode_label(name: &str, value: &str) -> Writer {
+    let mut message = Writer::default();
+    message.bytes_field(1, name.as_bytes());
+    message.bytes_field(2, unescape_label_value(value).as_bytes());
+    message
+}
+
+#[cfg(feature="clustering")]
+fn encode_single_double(value: f64) -> Writer {
+    let mut message = Writer::default();
+    message.double_field(1, value);
+    message
+}
+
+#[cfg(feature="clustering")]
+fn encode_counter(counter: &PrometheusCounterValue) -> Writer {
+    let mut message = Writer::default();
+    message.double_field(1, counter.value.as_f64());
+    if let Some(exemplar) = counter.exemplar.as_ref() {
+        message.message_field(2, encode_exemplar(exemplar));
+    }
+    message
+}
+
+#[cfg(feature="clustering")]
+fn encode_summary(summary: &SummaryValue) -> Writer {
+    let mut message = Writer::default();
+    if let Some(count) = summary.count {
+        message.varint_field(1, count);
+    }
+    if let Some(sum) = summary.sum.as_ref() {
+        message.double_field(2, sum.as_f64());
+    }
+    for quantile in summary.quantiles.iter() {
+        let mut quantile_message = Writer::default();
+        quantile_message.double_field(1, quantile.quantile);
+        quantile_message.double_field(2, quantile.value.as_f64());
+        message.message_field(3, quantile_message);
+    }
+    message
+}
+
+#[cfg(feature="clustering")]
+fn encode_histogram(histogram: &HistogramValue) -> Writer {
+    let mut message = Writer::default();
+    if let Some(count) = histogram.count {
+        message.varint_field(1, count);
+    }
+    if let Some(sum) = histogram.sum.as_ref() {
+        message.double_field(2, sum.as_f64());
+    }
+    for bucket in histogram.buckets.iter() {
+        let mut bucket_message = Writer::default();
+        bucket_message.varint_field(1, bucket.count.as_f64() as u64);
+        bucket_message.double_field(2, bucket.upper_bound);
+        if let Some(exemplar) = bucket.exemplar.as_ref() {
+            bucket_message.message_field(3, encode_exemplar(exemplar));
+        }
+        message.message_field(3, bucket_message);
+    }
+    message
+}
+
+#[cfg(feature="clustering")]
+fn encode_exemplar(exemplar: &Exemplar) -> Writer {
+    let mut message = Writer::default();
+    for (name, value) in exemplar.labels.iter() {
+        message.message_field(1, encode_label(name, value));
+    }
+    message.double_field(2, exemplar.id);
+    if let Some(timestamp) = exemplar.timestamp {
+        let mut timestamp_message = Writer::default();
+        timestamp_message.varint_field(1, timestamp.trunc() as i64 as u64);
+        timestamp_message.varint_field(2, (timestamp.fract() * 1e9).round() as i64 as u64);
+        message.message_field(3, timestamp_message);
+    }
+    message
+}
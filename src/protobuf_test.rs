@@ -0,0 +1,134 @@
+use warp::http::{StatusCode, header::AUTHORIZATION};
+
+use crate::aggregator::Aggregator;
+use crate::auth::pass_through_auth;
+use crate::protobuf::{decode_delimited, is_delimited_protobuf};
+#[cfg(feature="clustering")]
+use crate::protobuf::encode_delimited;
+use crate::routes::{RoutesConfig, get_routes};
+
+const CONTENT_TYPE: &str = "application/vnd.google.protobuf; proto=io.prometheus.client.MetricFamily; encoding=delimited";
+
+fn varint(mut value: u64) -> Vec<u8> {
+    let mut out = Vec::new();
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+    out
+}
+
+fn varint_field(field: u64, value: u64) -> Vec<u8> {
+    let mut out = varint(field << 3);
+    out.extend(varint(value));
+    out
+}
+
+fn double_field(field: u64, value: f64) -> Vec<u8> {
+    let mut out = varint(field << 3 | 1);
+    out.extend(value.to_bits().to_le_bytes());
+    out
+}
+
+fn bytes_field(field: u64, value: &[u8]) -> Vec<u8> {
+    let mut out = varint(field << 3 | 2);
+    out.extend(varint(value.len() as u64));
+    out.extend(value);
+    out
+}
+
+fn label(name: &str, value: &str) -> Vec<u8> {
+    bytes_field(1, &[bytes_field(1, name.as_bytes()), bytes_field(2, value.as_bytes())].concat())
+}
+
+/// A delimited histogram family with a single series, with the given (cumulative) bucket counts
+fn histogram_push(buckets: &[(f64, u64)], sum: f64, count: u64) -> Vec<u8> {
+    let mut histogram = [varint_field(1, count), double_field(2, sum)].concat();
+    for (bound, bucket_count) in buckets {
+        histogram.extend(bytes_field(3, &[varint_field(1, *bucket_count), double_field(2, *bound)].concat()));
+    }
+
+    let metric = [label("path", "/"), bytes_field(7, &histogram)].concat();
+    let family = [bytes_field(1, b"latency_seconds"), bytes_field(2, b"How long requests take"), varint_field(3, 4), bytes_field(4, &metric)].concat();
+
+    [varint(family.len() as u64), family].concat()
+}
+
+#[test]
+fn test_is_delimited_protobuf() {
+    assert!(is_delimited_protobuf(CONTENT_TYPE));
+    assert!(is_delimited_protobuf("application/vnd.google.protobuf;encoding=delimited;proto=io.prometheus.client.MetricFamily"));
+    assert!(!is_delimited_protobuf("application/vnd.google.protobuf; proto=io.prometheus.client.MetricFamily; encoding=text"));
+    assert!(!is_delimited_protobuf("text/plain; version=0.0.4"));
+}
+
+#[test]
+fn test_decode_gauges_and_counters() {
+    let gauge = [bytes_field(1, b"up"), varint_field(3, 1), bytes_field(4, &[label("pod", "a"), bytes_field(2, &double_field(1, 1.))].concat())].concat();
+    let counter = [bytes_field(1, b"requests_total"), varint_field(3, 0), bytes_field(4, &bytes_field(3, &double_field(1, 2.5)))].concat();
+    let data = [varint(gauge.len() as u64), gauge, varint(counter.len() as u64), counter].concat();
+
+    let families = decode_delimited(&data).unwrap();
+    let rendered: Vec<String> = families.iter().map(|family| family.to_string()).collect();
+    assert_eq!(rendered, vec!["# TYPE up gauge\nup{pod=\"a\"} 1\n", "# TYPE requests_total counter\nrequests_total 2.5\n"]);
+
+    // A message that's cut short is an error, rather than being silently dropped
+    assert!(decode_delimited(&data[..data.len() - 1]).is_err());
+}
+
+#[cfg(feature="clustering")]
+#[test]
+fn test_encode_round_trips() {
+    let exemplar = bytes_field(2, &[label("trace_id", "a\"b"), double_field(2, 1.)].concat());
+    let counter = [bytes_field(1, b"requests_total"), bytes_field(2, b"Requests\nserved"), varint_field(3, 0), bytes_field(4, &[label("path", "/"), bytes_field(3, &[double_field(1, 2.5), exemplar].concat()), varint_field(6, 1620000000000)].concat())].concat();
+    let data = [varint(counter.len() as u64), counter, histogram_push(&[(0.1, 1), (1., 3)], 1.5, 4)].concat();
+
+    let families = decode_delimited(&data).unwrap();
+    let reencoded = decode_delimited(&encode_delimited(&families).unwrap()).unwrap();
+    let rendered = |families: &[openmetrics_parser::PrometheusMetricFamily]| families.iter().map(|family| family.to_string()).collect::<Vec<String>>();
+    assert_eq!(rendered(&reencoded), rendered(&families));
+    assert_eq!(reencoded[0].iter_samples().next().unwrap().value, families[0].iter_samples().next().unwrap().value);
+}
+
+#[tokio::test]
+async fn test_protobuf_histogram_push() {
+    let agg = Aggregator::new();
+    let routes = get_routes(agg.clone(), RoutesConfig {
+        authenticator: Box::new(pass_through_auth()),
+        auth_header: AUTHORIZATION,
+        max_body_bytes: 1024,
+        rate_limit: None,
+        max_in_flight_pushes: 64,
+        cors_allowed_origins: Vec::new(),
+        default_job: None,
+        strict_label_paths: false,
+        graphite_prefix: None,
+        #[cfg(feature="clustering")]
+        cluster_conf: None,
+    });
+
+    for _ in 0..2 {
+        let resp = warp::test::request().method("POST").path("/metrics")
+            .header("content-type", CONTENT_TYPE)
+            .body(histogram_push(&[(0.1, 1), (1., 3)], 1.5, 4))
+            .reply(&routes).await;
+        assert_eq!(resp.status(), StatusCode::OK, "{:?}", resp.body());
+    }
+
+    // The +Inf bucket is implied by the count
+    assert_eq!(agg.to_string().await, "# HELP latency_seconds How long requests take
+# TYPE latency_seconds histogram
+latency_seconds_bucket{path=\"/\",le=\"0.1\"} 2
+latency_seconds_bucket{path=\"/\",le=\"1\"} 6
+latency_seconds_bucket{path=\"/\",le=\"+Inf\"} 8
+latency_seconds_sum{path=\"/\"} 3
+latency_seconds_count{path=\"/\"} 8
+");
+
+    let resp = warp::test::request().method("POST").path("/metrics")
+        .header("content-type", CONTENT_TYPE)
+        .body(&b"\x05\x0a"[..])
+        .reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+}
@@ -0,0 +1,70 @@
+use std::{collections::HashMap, sync::Mutex, time::{Duration, Instant}};
+
+/// Once this many clients have buckets, the ones that have filled back up are forgotten, since they're no different to
+/// a new one
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// How often the buckets can be pruned, so that past the threshold, checks don't all have to go through every bucket
+const PRUNE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How many pushes each client can make
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    /// How many pushes a second a client can keep up
+    pub per_second: f64,
+    /// How many pushes a client can make at once, after not pushing for a while
+    pub burst: u32,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Every client's bucket, and when the full ones were last pruned
+#[derive(Debug)]
+struct Buckets {
+    by_client: HashMap<String, Bucket>,
+    pruned: Instant,
+}
+
+/// Limits how often each client can push, with a token bucket per client. Clients are identified by whatever the
+/// caller likes, i.e. their credentials or their address
+#[derive(Debug)]
+pub struct RateLimiter {
+    limit: RateLimit,
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: RateLimit) -> RateLimiter {
+        RateLimiter {
+            limit,
+            buckets: Mutex::new(Buckets { by_client: HashMap::new(), pruned: Instant::now() }),
+        }
+    }
+
+    /// Takes a token from the client's bucket. If it's empty, returns how long until it won't be
+    pub fn check(&self, client: &str) -> Result<(), Duration> {
+        let now = Instant::now();
+        let burst = self.limit.burst as f64;
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.by_client.len() >= PRUNE_THRESHOLD && now.saturating_duration_since(buckets.pruned) >= PRUNE_INTERVAL {
+            let per_second = self.limit.per_second;
+            buckets.by_client.retain(|_, bucket| bucket.tokens + now.saturating_duration_since(bucket.updated).as_secs_f64() * per_second < burst);
+            buckets.pruned = now;
+        }
+
+        let bucket = buckets.by_client.entry(client.to_owned()).or_insert(Bucket { tokens: burst, updated: now });
+        bucket.tokens = (bucket.tokens + now.saturating_duration_since(bucket.updated).as_secs_f64() * self.limit.per_second).min(burst);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1. {
+            bucket.tokens -= 1.;
+            return Ok(());
+        }
+
+        Err(Duration::from_secs_f64((1. - bucket.tokens) / self.limit.per_second))
+    }
+}
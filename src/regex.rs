@@ -0,0 +1,391 @@
+use std::str::FromStr;
+
+use crate::aggregator::AggregationError;
+
+/// A small backtracking regular expression engine, covering the RE2 syntax that relabel rules tend to use: literals,
+/// `.`, character classes (including `\d`, `\w`, and `\s`), groups, alternation, and the `*`, `+`, `?` and `{n,m}`
+/// quantifiers. Like in Prometheus, a regex has to match the whole value, not just part of it
+#[derive(Debug, Clone)]
+pub struct Regex {
+    source: String,
+    root: Node,
+    groups: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum ClassItem {
+    Char(char),
+    Range(char, char),
+    Digit(bool),
+    Word(bool),
+    Space(bool),
+}
+
+impl ClassItem {
+    fn matches(&self, c: char) -> bool {
+        match *self {
+            ClassItem::Char(ch) => c == ch,
+            ClassItem::Range(lo, hi) => lo <= c && c <= hi,
+            ClassItem::Digit(negated) => c.is_ascii_digit() != negated,
+            ClassItem::Word(negated) => (c.is_ascii_alphanumeric() || c == '_') != negated,
+            ClassItem::Space(negated) => c.is_whitespace() != negated,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Empty,
+    Any,
+    Start,
+    End,
+    Class { items: Vec<ClassItem>, negated: bool },
+    Group { node: Box<Node>, index: Option<usize> },
+    Concat(Vec<Node>),
+    Alternate(Vec<Node>),
+    Repeat { node: Box<Node>, min: usize, max: Option<usize>, greedy: bool },
+}
+
+/// The start and end (in chars) of what each group matched, with the whole match as group 0
+type Captures = Vec<Option<(usize, usize)>>;
+
+impl Regex {
+    /// The captured groups of the value, if the regex matches all of it. Groups that didn't take part in the match are empty
+    pub fn captures(&self, value: &str) -> Option<Vec<String>> {
+        let chars: Vec<char> = value.chars().collect();
+        let mut captures: Captures = vec![None; self.groups + 1];
+        let matched = Matcher { chars: &chars }.match_node(&self.root, 0, &mut captures, &mut |pos, _| pos == chars.len());
+        if !matched {
+            return None;
+        }
+
+        captures[0] = Some((0, chars.len()));
+        return Some(captures.iter().map(|capture| match capture {
+            Some((start, end)) => chars[*start..*end].iter().collect(),
+            None => String::new(),
+        }).collect());
+    }
+
+}
+
+impl PartialEq for Regex {
+    fn eq(&self, other: &Self) -> bool {
+        self.source == other.source
+    }
+}
+
+impl FromStr for Regex {
+    type Err = AggregationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser { chars: s.chars().collect(), pos: 0, groups: 0 };
+        let root = parser.alternation().map_err(|reason| AggregationError::Error(format!("Invalid regex {}: {}", s, reason)))?;
+        if parser.pos < parser.chars.len() {
+            return Err(AggregationError::Error(format!("Invalid regex {}: unmatched )", s)));
+        }
+
+        Ok(Regex { source: s.to_owned(), root, groups: parser.groups })
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    groups: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek();
+        self.pos += 1;
+        c
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            return true;
+        }
+
+        false
+    }
+
+    fn alternation(&mut self) -> Result<Node, String> {
+        let mut branches = vec![self.concatenation()?];
+        while self.eat('|') {
+            branches.push(self.concatenation()?);
+        }
+
+        if branches.len() == 1 {
+            return Ok(branches.remove(0));
+        }
+
+        Ok(Node::Alternate(branches))
+    }
+
+    fn concatenation(&mut self) -> Result<Node, String> {
+        let mut nodes = Vec::new();
+        while let Some(c) = self.peek() {
+            if c == '|' || c == ')' {
+                break;
+            }
+
+            let atom = self.atom()?;
+            nodes.push(self.quantified(atom)?);
+        }
+
+        match nodes.len() {
+            0 => Ok(Node::Empty),
+            1 => Ok(nodes.remove(0)),
+            _ => Ok(Node::Concat(nodes)),
+        }
+    }
+
+    fn atom(&mut self) -> Result<Node, String> {
+        match self.next() {
+            Some('.') => Ok(Node::Any),
+            Some('^') => Ok(Node::Start),
+            Some('$') => Ok(Node::End),
+            Some('(') => {
+                let index = if self.eat('?') {
+                    if !self.eat(':') {
+                        return Err("only (?:...) groups are supported".to_owned());
+                    }
+                    None
+                } else {
+                    self.groups += 1;
+                    Some(self.groups)
+                };
+
+                let node = self.alternation()?;
+                if !self.eat(')') {
+                    return Err("missing )".to_owned());
+                }
+
+                Ok(Node::Group { node: Box::new(node), index })
+            },
+            Some('[') => self.class(),
+            Some('\\') => Ok(Node::Class { items: vec![self.escape()?], negated: false }),
+            Some(c) if "*+?".contains(c) => Err(format!("nothing to repeat before {}", c)),
+            Some(c) => Ok(Node::Class { items: vec![ClassItem::Char(c)], negated: false }),
+            None => Err("unexpected end".to_owned()),
+        }
+    }
+
+    fn escape(&mut self) -> Result<ClassItem, String> {
+        match self.next() {
+            Some('d') => Ok(ClassItem::Digit(false)),
+            Some('D') => Ok(ClassItem::Digit(true)),
+            Some('w') => Ok(ClassItem::Word(false)),
+            Some('W') => Ok(ClassItem::Word(true)),
+            Some('s') => Ok(ClassItem::Space(false)),
+            Some('S') => Ok(ClassItem::Space(true)),
+            Some('n') => Ok(ClassItem::Char('\n')),
+            Some('t') => Ok(ClassItem::Char('\t')),
+            Some(c) if !c.is_ascii_alphanumeric() => Ok(ClassItem::Char(c)),
+            Some(c) => Err(format!("unsupported escape \\{}", c)),
+            None => Err("trailing \\".to_owned()),
+        }
+    }
+
+    fn class(&mut self) -> Result<Node, String> {
+        let negated = self.eat('^');
+        let mut items = Vec::new();
+        // A ] straight after the opening bracket is a literal one
+        if self.eat(']') {
+            items.push(ClassItem::Char(']'));
+        }
+
+        loop {
+            let item = match self.next() {
+                Some(']') => break,
+                Some('\\') => self.escape()?,
+                Some(c) => ClassItem::Char(c),
+                None => return Err("missing ]".to_owned()),
+            };
+
+            match item {
+                ClassItem::Char(lo) if self.peek() == Some('-') && self.chars.get(self.pos + 1).is_some_and(|&c| c != ']') => {
+                    self.pos += 1;
+                    let hi = match self.next() {
+                        Some('\\') => match self.escape()? {
+                            ClassItem::Char(c) => c,
+                            _ => return Err("invalid class range".to_owned()),
+                        },
+                        Some(c) => c,
+                        None => return Err("missing ]".to_owned()),
+                    };
+
+                    if hi < lo {
+                        return Err(format!("invalid class range {}-{}", lo, hi));
+                    }
+                    items.push(ClassItem::Range(lo, hi));
+                },
+                item => items.push(item),
+            }
+        }
+
+        Ok(Node::Class { items, negated })
+    }
+
+    fn quantified(&mut self, atom: Node) -> Result<Node, String> {
+        let (min, max) = match self.peek() {
+            Some('*') => (0, None),
+            Some('+') => (1, None),
+            Some('?') => (0, Some(1)),
+            Some('{') => match self.counted()? {
+                Some(bounds) => bounds,
+                None => return Ok(atom),
+            },
+            _ => return Ok(atom),
+        };
+
+        // Step past the quantifier, or the closing brace of a counted one
+        self.pos += 1;
+
+        if matches!(atom, Node::Start | Node::End) {
+            return Err("nothing to repeat".to_owned());
+        }
+
+        let greedy = !self.eat('?');
+        Ok(Node::Repeat { node: Box::new(atom), min, max, greedy })
+    }
+
+    /// Parses a `{n}`, `{n,}`, or `{n,m}` quantifier, leaving the position on its closing brace. Braces that
+    /// aren't a valid quantifier are literals, as in RE2
+    fn counted(&mut self) -> Result<Option<(usize, Option<usize>)>, String> {
+        let close = match self.chars[self.pos..].iter().position(|&c| c == '}') {
+            Some(offset) => self.pos + offset,
+            None => return Ok(None),
+        };
+
+        let inner: String = self.chars[self.pos + 1..close].iter().collect();
+        let parse = |s: &str| s.parse::<usize>().ok();
+        let bounds = match inner.split_once(',') {
+            None => parse(&inner).map(|n| (n, Some(n))),
+            Some((min, "")) => parse(min).map(|min| (min, None)),
+            Some((min, max)) => parse(min).zip(parse(max)).map(|(min, max)| (min, Some(max))),
+        };
+
+        match bounds {
+            Some((min, Some(max))) if max < min => Err(format!("invalid repeat {{{}}}", inner)),
+            Some(bounds) => {
+                self.pos = close;
+                Ok(Some(bounds))
+            },
+            None => Ok(None),
+        }
+    }
+}
+
+struct Matcher<'a> {
+    chars: &'a [char],
+}
+
+impl<'a> Matcher<'a> {
+    /// Matches the node at the given position, then calls the continuation with where the match ended, backtracking
+    /// into the other ways the node could have matched until the continuation accepts one
+    fn match_node(&self, node: &Node, pos: usize, captures: &mut Captures, then: &mut dyn FnMut(usize, &mut Captures) -> bool) -> bool {
+        match node {
+            Node::Empty => then(pos, captures),
+            Node::Any => pos < self.chars.len() && then(pos + 1, captures),
+            Node::Start => pos == 0 && then(pos, captures),
+            Node::End => pos == self.chars.len() && then(pos, captures),
+            Node::Class { items, negated } => match self.chars.get(pos) {
+                Some(&c) if items.iter().any(|item| item.matches(c)) != *negated => then(pos + 1, captures),
+                _ => false,
+            },
+            Node::Group { node, index } => {
+                let start = pos;
+                self.match_node(node, pos, captures, &mut |end, captures| {
+                    let index = match index {
+                        Some(index) => *index,
+                        None => return then(end, captures),
+                    };
+
+                    let previous = captures[index];
+                    captures[index] = Some((start, end));
+                    if then(end, captures) {
+                        return true;
+                    }
+
+                    captures[index] = previous;
+                    false
+                })
+            },
+            Node::Concat(nodes) => self.match_sequence(nodes, pos, captures, then),
+            Node::Alternate(branches) => branches.iter().any(|branch| self.match_node(branch, pos, captures, then)),
+            Node::Repeat { node, min, max, greedy } => self.match_repeat(node, *min, *max, *greedy, 0, pos, captures, then),
+        }
+    }
+
+    fn match_sequence(&self, nodes: &[Node], pos: usize, captures: &mut Captures, then: &mut dyn FnMut(usize, &mut Captures) -> bool) -> bool {
+        match nodes.split_first() {
+            None => then(pos, captures),
+            Some((first, rest)) => self.match_node(first, pos, captures, &mut |pos, captures| self.match_sequence(rest, pos, captures, then)),
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn match_repeat(&self, node: &Node, min: usize, max: Option<usize>, greedy: bool, count: usize, pos: usize, captures: &mut Captures, then: &mut dyn FnMut(usize, &mut Captures) -> bool) -> bool {
+        let can_stop = count >= min;
+        let can_continue = max.is_none_or(|max| count < max);
+
+        if greedy {
+            return (can_continue && self.match_another(node, min, max, greedy, count, pos, captures, then)) || (can_stop && then(pos, captures));
+        }
+
+        (can_stop && then(pos, captures)) || (can_continue && self.match_another(node, min, max, greedy, count, pos, captures, then))
+    }
+
+    /// Matches one more repetition of the node, then the rest of the repeat after it
+    #[allow(clippy::too_many_arguments)]
+    fn match_another(&self, node: &Node, min: usize, max: Option<usize>, greedy: bool, count: usize, pos: usize, captures: &mut Captures, then: &mut dyn FnMut(usize, &mut Captures) -> bool) -> bool {
+        self.match_node(node, pos, captures, &mut |next, captures| {
+            // A repetition that didn't consume anything would loop forever, so it can't count towards another one
+            next != pos && self.match_repeat(node, min, max, greedy, count + 1, next, captures, then)
+        })
+    }
+}
+
+/// Expands `$1` and `${1}` references in the replacement to the matching captured groups, with `$$` for a literal `$`.
+/// References to groups that don't exist expand to nothing, like they do in Prometheus
+pub fn expand_replacement(replacement: &str, captures: &[String]) -> String {
+    let mut expanded = String::with_capacity(replacement.len());
+    let mut rest = replacement;
+    while let Some(idx) = rest.find('$') {
+        expanded.push_str(&rest[..idx]);
+        rest = &rest[idx + 1..];
+
+        if let Some(after) = rest.strip_prefix('$') {
+            expanded.push('$');
+            rest = after;
+            continue;
+        }
+
+        let (digits, after) = match rest.strip_prefix('{').and_then(|braced| braced.split_once('}')) {
+            Some((digits, after)) if !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit()) => (digits, after),
+            _ => {
+                let len = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+                (&rest[..len], &rest[len..])
+            }
+        };
+
+        if digits.is_empty() {
+            expanded.push('$');
+            continue;
+        }
+
+        if let Some(capture) = digits.parse::<usize>().ok().and_then(|group| captures.get(group)) {
+            expanded.push_str(capture);
+        }
+        rest = after;
+    }
+
+    expanded.push_str(rest);
+    expanded
+}
@@ -0,0 +1,38 @@
+use crate::regex::{Regex, expand_replacement};
+
+fn captures(regex: &str, value: &str) -> Option<Vec<String>> {
+    regex.parse::<Regex>().unwrap().captures(value)
+}
+
+#[test]
+fn test_regex_matches_whole_value() {
+    assert!(captures("a.c", "abc").is_some());
+    assert!(captures("a.c", "abcd").is_none());
+    assert!(captures("b", "abc").is_none());
+    assert!(captures("api|web", "web").is_some());
+    assert!(captures("[a-c]+\\d{2,3}", "cab123").is_some());
+    assert!(captures("[a-c]+\\d{2,3}", "cab1234").is_none());
+    assert!(captures("[^/]*", "a/b").is_none());
+    assert!(captures("x{,2}", "x{,2}").is_some());
+}
+
+#[test]
+fn test_regex_captures() {
+    assert_eq!(captures("/api/(v\\d+)/users/\\d+", "/api/v1/users/123"), Some(vec!["/api/v1/users/123".to_owned(), "v1".to_owned()]));
+    assert_eq!(captures("(a+?)(a*)", "aaa"), Some(vec!["aaa".to_owned(), "a".to_owned(), "aa".to_owned()]));
+    assert_eq!(captures("(?:(x)|y)z", "yz"), Some(vec!["yz".to_owned(), "".to_owned()]));
+    assert_eq!(captures("(a*)*b", "aaac"), None);
+}
+
+#[test]
+fn test_invalid_regexes() {
+    for regex in ["(a", "a)", "[a", "*a", "a{3,1}", "(?=a)", "\\q"] {
+        assert!(regex.parse::<Regex>().is_err(), "{}", regex);
+    }
+}
+
+#[test]
+fn test_expand_replacement() {
+    let captures = vec!["abc".to_owned(), "a".to_owned(), "b".to_owned()];
+    assert_eq!(expand_replacement("$1-${2}x-$$1-$3-$", &captures), "a-bx-$1--$");
+}
@@ -0,0 +1,91 @@
+use std::{fs::File, io::{self, BufReader}, path::PathBuf};
+
+use serde::{Deserialize, Deserializer};
+
+use crate::{aggregator::check_label_names, regex::{Regex, expand_replacement}};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RelabelAction {
+    /// Sets the target label, leaving the source label as it was
+    #[default]
+    Replace,
+    /// Sets the target label and removes the source label
+    Rename,
+}
+
+/// A rule that rewrites the labels of pushed series, like a Prometheus `relabel_config`. If the regex matches the whole
+/// value of the source label (which is empty if the series doesn't have it), the target label is set to the replacement,
+/// with `$1` and friends expanded to the regex's groups. A replacement that expands to nothing removes the target label
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct RelabelRule {
+    pub source_label: String,
+
+    #[serde(default = "default_regex", deserialize_with = "deserialize_regex")]
+    pub regex: Regex,
+
+    #[serde(default = "default_replacement")]
+    pub replacement: String,
+
+    /// The label to set, which is the source label itself if there isn't one
+    #[serde(default)]
+    pub target_label: Option<String>,
+
+    #[serde(default)]
+    pub action: RelabelAction,
+}
+
+fn default_regex() -> Regex {
+    // This is a valid regex, so can't fail to parse
+    "(.*)".parse().unwrap()
+}
+
+fn default_replacement() -> String {
+    "$1".to_owned()
+}
+
+fn deserialize_regex<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Regex, D::Error> {
+    let regex = String::deserialize(deserializer)?;
+    regex.parse().map_err(serde::de::Error::custom)
+}
+
+impl RelabelRule {
+    fn target_label(&self) -> &str {
+        self.target_label.as_deref().unwrap_or(&self.source_label)
+    }
+
+    /// Applies this rule to the given labels of a series
+    pub fn apply(&self, labels: &mut Vec<(String, String)>) {
+        let value = labels.iter().find(|(name, _)| *name == self.source_label).map(|(_, value)| value.as_str()).unwrap_or_default();
+        let captures = match self.regex.captures(value) {
+            Some(captures) => captures,
+            None => return,
+        };
+
+        let value = expand_replacement(&self.replacement, &captures);
+        if self.action == RelabelAction::Rename {
+            labels.retain(|(name, _)| *name != self.source_label);
+        }
+
+        let target = self.target_label();
+        if value.is_empty() {
+            labels.retain(|(name, _)| name != target);
+            return;
+        }
+
+        match labels.iter_mut().find(|(name, _)| name == target) {
+            Some((_, existing)) => *existing = value,
+            None => labels.push((target.to_owned(), value)),
+        }
+    }
+}
+
+/// Loads a JSON file holding a list of relabel rules, which are applied to pushes in order
+pub fn load_relabel_rules(path: PathBuf) -> Result<Vec<RelabelRule>, io::Error> {
+    let rules: Vec<RelabelRule> = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+    for rule in rules.iter() {
+        check_label_names([rule.source_label.as_str(), rule.target_label()]).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+    }
+
+    return Ok(rules);
+}
@@ -1,74 +1,558 @@
-use std::{collections::HashMap, sync::Arc, convert::Infallible};
+use std::{collections::HashMap, net::SocketAddr, str::FromStr, sync::{Arc, RwLock, atomic::{AtomicU64, Ordering}}, convert::Infallible, io::{Read, Write}, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
 
-use reqwest::StatusCode;
-use warp::{Filter, http::HeaderValue, hyper::{HeaderMap, body::Bytes}, path::Tail, reject::Reject};
+use flate2::{Compression, read::GzDecoder, write::GzEncoder};
+use futures::StreamExt;
 
-use crate::{aggregator::{AggregationError, Aggregator}, auth::Authenticator};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::{debug, error, warn};
+use warp::{Filter, Reply, http::{HeaderMap, Method, Response, StatusCode, header::{ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_HEADERS, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, ETAG, HeaderName, HeaderValue, RETRY_AFTER, VARY}}, hyper::{Body, body::Bytes}, path::Tail, reject::Reject};
 
+use openmetrics_parser::PrometheusMetricFamily;
 #[cfg(feature="clustering")]
-use crate::clustering::ClusterConfig;
+use openmetrics_parser::{PrometheusValue, Sample};
+
+use crate::{aggregator::{AggregationError, Aggregator, ParsedPush, TextFormat, check_label_names}, auth::{Authenticator, ClientCertificate}, gateway_metrics::GatewayMetrics, influx::{Precision, decode_line_protocol}, protobuf::{decode_delimited, is_delimited_protobuf}, rate_limit::{RateLimit, RateLimiter}, selector::Selector, server::RemoteAddr, snapshot::{SnapshotVersion, StoreSnapshot, check_version}, tenants::{Tenants, check_tenant_id}};
+
+#[cfg(feature="clustering")]
+use crate::{aggregator::{LabelPrecedence, render_families}, clustering::{ClusterConfig, RetryPolicy}, gateway_metrics::ForwardOutcome, protobuf::encode_delimited};
+
+/// Set on pushes that one gateway forwards to another, so that the receiver knows to merge them itself
+const FORWARDED_HEADER: &str = "x-gravel-forwarded";
+
+/// Identifies a push in the logs. It's taken from the request if it's there, and passed on to peers the push is forwarded to
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Set by clients on pushes that they might retry, so that a retry of a push that was already merged isn't merged again
+const IDEMPOTENCY_KEY_HEADER: &str = "x-idempotency-key";
+
+/// Tells the client how many malformed lines of its push were skipped, when `--skip-malformed-lines` let it through
+const SKIPPED_LINES_HEADER: &str = "x-gravel-skipped-lines";
+
+/// The query parameter that scrapes can give series selectors in, as with Prometheus' /federate
+const MATCH_PARAM: &str = "match[]";
+
+/// The methods that browsers are told they can scrape with, from an allowed origin
+const CORS_ALLOWED_METHODS: &str = "GET, HEAD, OPTIONS";
+
+/// How long browsers can cache a preflight's answer for, in seconds
+const CORS_MAX_AGE: &str = "600";
+
+/// How long a push that was turned away for there being too many in flight is told to wait, in seconds. Pushes are usually
+/// over quickly, so there's no need for much more than a moment
+const TOO_MANY_PUSHES_RETRY_AFTER: &str = "1";
+
+/// Makes up an ID for a request that didn't come with one. It only has to be unique enough to tell pushes apart in the logs
+fn new_request_id() -> String {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+    format!("{:x}-{:x}", now, NEXT_ID.fetch_add(1, Ordering::Relaxed))
+}
 
 #[derive(Debug)]
 enum GravelError {
     Error(String),
     AuthError,
-    AggregationError(AggregationError)
+    Forbidden,
+    PayloadTooLarge,
+    AggregationError(AggregationError),
+    /// A peer that a push was forwarded to responded with an error, which gets passed on to the client as is
+    #[cfg(feature="clustering")]
+    PeerResponse { status: StatusCode, body: String },
+    /// A push couldn't be forwarded, because as many forwards as are allowed are already in flight
+    #[cfg(feature="clustering")]
+    TooManyForwards,
+    /// A request didn't get a quorum, but some of its owners (maybe including us) did take it, and it stays taken there.
+    /// The cause is why the rest didn't
+    #[cfg(feature="clustering")]
+    PartialSuccess { accepted: usize, owners: usize, cause: Box<GravelError> },
+    /// The client has pushed more often than it's allowed to, and can push again after the given time
+    RateLimited { retry_after: Duration },
+    /// As many pushes as are allowed are already being handled
+    TooManyPushes,
 }
 
 impl Reject for GravelError {}
 
+/// The format that a push's body is in. Exposition format pushes can be text or protobuf, which the Content-Type tells
+/// apart, but line protocol has a route of its own
+#[derive(Debug, Clone, Copy)]
+enum PushFormat {
+    Exposition,
+    LineProtocol(Precision),
+}
+
 pub struct RoutesConfig {
     pub authenticator: Box<dyn Authenticator + Send + Sync>,
+    /// The header that clients send their credentials in, which is normally `Authorization`
+    pub auth_header: HeaderName,
+    /// The largest push body we'll accept, in bytes
+    pub max_body_bytes: u64,
+    /// How often each client can push, if that's limited at all
+    pub rate_limit: Option<RateLimit>,
+    /// The most pushes that can be handled at once, from reading their bodies to merging them. Pushes beyond that get a 503
+    pub max_in_flight_pushes: usize,
+    /// The origins that browsers can scrape from, or `*` for any. Scrapes don't get CORS headers if this is empty
+    pub cors_allowed_origins: Vec<String>,
+    /// The job that pushes get when their path doesn't give one
+    pub default_job: Option<String>,
+    /// Whether a push path whose last label has no value (e.g. /metrics/job) is rejected, rather than giving that
+    /// label an empty value
+    pub strict_label_paths: bool,
+    /// The path that every series in /metrics/graphite starts with, if any
+    pub graphite_prefix: Option<String>,
     #[cfg(feature="clustering")]
     pub cluster_conf: Option<ClusterConfig>
 }
 
-async fn auth(config: Arc<RoutesConfig>, header: String) -> Result<(), warp::Rejection> {
-    if let Ok(true) = config.authenticator.authenticate(&header) {
-        return Ok(());
+/// The routes' config, which can be swapped out while they're serving (i.e. on a SIGHUP). Each request holds on to the
+/// config that was current when it came in, so a reload only changes how the requests after it are handled
+#[derive(Clone)]
+pub struct SharedRoutesConfig(Arc<RwLock<Arc<RoutesConfig>>>);
+
+impl SharedRoutesConfig {
+    pub fn new(config: RoutesConfig) -> SharedRoutesConfig {
+        SharedRoutesConfig(Arc::new(RwLock::new(Arc::new(config))))
+    }
+
+    /// The config that new requests get
+    pub fn current(&self) -> Arc<RoutesConfig> {
+        // A panic while holding the lock can't leave the config half swapped, so a poisoned lock is still fine to read
+        Arc::clone(&self.0.read().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    /// Swaps in a new authenticator and cluster config. The rest of the config is used to build the routes, so it stays as it was
+    pub fn reload(&self, authenticator: Box<dyn Authenticator + Send + Sync>, #[cfg(feature="clustering")] cluster_conf: Option<ClusterConfig>) {
+        let mut current = self.0.write().unwrap_or_else(|e| e.into_inner());
+        *current = Arc::new(RoutesConfig {
+            authenticator,
+            auth_header: current.auth_header.clone(),
+            max_body_bytes: current.max_body_bytes,
+            rate_limit: current.rate_limit,
+            max_in_flight_pushes: current.max_in_flight_pushes,
+            cors_allowed_origins: current.cors_allowed_origins.clone(),
+            default_job: current.default_job.clone(),
+            strict_label_paths: current.strict_label_paths,
+            graphite_prefix: current.graphite_prefix.clone(),
+            #[cfg(feature="clustering")]
+            cluster_conf,
+        });
+    }
+}
+
+impl From<RoutesConfig> for SharedRoutesConfig {
+    fn from(config: RoutesConfig) -> SharedRoutesConfig {
+        SharedRoutesConfig::new(config)
+    }
+}
+
+/// Authenticates a request, passing on the config it was checked against (so that the rest of the request uses the same one)
+/// along with its credentials. A client certificate (which is only there when the server checks them) can authenticate a
+/// request on its own, without a header
+async fn auth(config: Arc<RoutesConfig>, header: Option<String>, certificate: Option<ClientCertificate>) -> Result<(Arc<RoutesConfig>, Option<String>), warp::Rejection> {
+    if certificate.is_some_and(|certificate| config.authenticator.authenticate_certificate(&certificate)) {
+        return Ok((config, header));
+    }
+
+    if let Ok(true) = config.authenticator.authenticate(header.as_deref().unwrap_or_default()) {
+        return Ok((config, header));
     }
 
     return Err(warp::reject::custom(GravelError::AuthError));
 }
 
-pub fn get_routes(aggregator: Aggregator, config: RoutesConfig) -> impl Filter<Extract = impl warp::Reply, Error = Infallible> + Clone {
-    let default_auth = warp::any().map(|| {
-        return String::new();
-    });
+/// Checks that an authenticated request is allowed to touch series with the given labels, in the given tenant's store
+fn authorize(config: &RoutesConfig, header: Option<&str>, tenant: Option<&str>, labels: &HashMap<&str, &str>) -> Result<(), warp::Rejection> {
+    let header = header.unwrap_or_default();
+    if config.authenticator.authorize_tenant(header, tenant) && config.authenticator.authorize(header, labels) {
+        return Ok(());
+    }
+
+    return Err(warp::reject::custom(GravelError::Forbidden));
+}
+
+/// Takes a push's token from its client's bucket, if pushes are being limited. Pushes forwarded by a peer were already
+/// limited there
+fn check_rate_limit(limiter: Option<&RateLimiter>, conf: &RoutesConfig, authorization: Option<&str>, certificate: Option<&ClientCertificate>, remote: Option<SocketAddr>, forwarded: bool) -> Result<(), warp::Rejection> {
+    let limiter = match limiter {
+        Some(limiter) => limiter,
+        None => return Ok(()),
+    };
+
+    if forwarded {
+        return Ok(());
+    }
+
+    let client = rate_limit_client(conf, authorization, certificate, remote);
+    limiter.check(&client).map_err(|retry_after| warp::reject::custom(GravelError::RateLimited { retry_after }))
+}
+
+/// Who an authenticated push is from, for rate limiting: the client certificate or credentials that authenticated it, and
+/// otherwise its address. Without auth, credentials prove nothing (a client could send new ones with every push), so
+/// those clients go by their address too
+fn rate_limit_client(conf: &RoutesConfig, authorization: Option<&str>, certificate: Option<&ClientCertificate>, remote: Option<SocketAddr>) -> String {
+    let certificate = certificate.filter(|certificate| conf.authenticator.authenticate_certificate(certificate));
+    if let Some(name) = certificate.and_then(|certificate| certificate.names.first()) {
+        return format!("cert:{}", name);
+    }
+
+    match (authorization.and_then(|authorization| conf.authenticator.client_id(authorization)), remote) {
+        (Some(id), _) => format!("token:{}", id),
+        (None, Some(remote)) => format!("ip:{}", remote.ip()),
+        (None, None) => String::from("unknown"),
+    }
+}
 
-    let config = Arc::new(config);
-    let auth_config = Arc::clone(&config);
+/// The client's address. Servers that hand connections to the routes themselves (rather than through warp's server) can't
+/// set the one warp knows about, so they put it in the request's extensions instead
+fn remote_addr() -> impl Filter<Extract = (Option<SocketAddr>,), Error = Infallible> + Clone {
+    warp::addr::remote().and(warp::ext::optional::<RemoteAddr>())
+        .map(|remote: Option<SocketAddr>, ext: Option<RemoteAddr>| remote.or(ext.map(|RemoteAddr(addr)| addr)))
+}
+
+/// The forwarded header, if a peer sent it. Any client could say that its request was forwarded, to get around the rate
+/// limit and be handled here whether or not this is where it belongs, so it's only believed from one of the peers' addresses
+fn forwarded_by_peer(config: SharedRoutesConfig) -> impl Filter<Extract = (Option<String>,), Error = warp::Rejection> + Clone {
+    with_config(config)
+        .and(remote_addr())
+        .and(warp::header::optional::<String>(FORWARDED_HEADER))
+        .and_then(trusted_forward)
+}
+
+#[cfg_attr(not(feature="clustering"), allow(unused_variables))]
+async fn trusted_forward(conf: Arc<RoutesConfig>, remote: Option<SocketAddr>, forwarded: Option<String>) -> Result<Option<String>, Infallible> {
+    #[cfg(feature="clustering")]
+    if let (Some(cluster_conf), Some(remote), Some(forwarded)) = (conf.cluster_conf.as_ref(), remote, forwarded) {
+        if cluster_conf.is_peer_address(remote.ip()).await {
+            return Ok(Some(forwarded));
+        }
+    }
+
+    Ok(None)
+}
+
+pub fn get_routes(aggregator: Aggregator, config: impl Into<SharedRoutesConfig>) -> impl Filter<Extract = impl warp::Reply, Error = Infallible> + Clone {
+    let config = config.into();
+    let tenants = Tenants::new(aggregator.clone());
+    let rate_limiter = config.current().rate_limit.map(|limit| Arc::new(RateLimiter::new(limit)));
+    let push_permits = Arc::new(Semaphore::new(config.current().max_in_flight_pushes));
+    let cors_allowed_origins = Arc::new(config.current().cors_allowed_origins.clone());
+
+    // The header's name is only known at runtime, so it can't be picked out with `warp::header`
+    let auth = with_config(config.clone())
+        .and(warp::header::headers_cloned())
+        .and(warp::ext::optional::<ClientCertificate>())
+        .and_then(|conf: Arc<RoutesConfig>, headers: HeaderMap, certificate: Option<ClientCertificate>| {
+            let header = headers.get(&conf.auth_header).and_then(|value| value.to_str().ok()).map(String::from);
+            auth(conf, header, certificate)
+        })
+        .untuple_one();
+
+    // Chunked requests don't have a Content-Length to check up front, so they're let through here and
+    // their size gets checked once they've been buffered
+    let chunked_body = warp::header::optional::<String>("content-length").and_then(|length: Option<String>| async move {
+        match length {
+            None => Ok(()),
+            Some(_) => Err(warp::reject()),
+        }
+    }).untuple_one();
+    let body_limit = warp::body::content_length_limit(config.current().max_body_bytes).or(chunked_body).unify();
 
-    let auth = warp::header::<String>("authorization").or(default_auth).unify().and_then(move |header| auth(auth_config.clone(), header)).untuple_one();
+    // Checked before the body is read, so that a client that's over its limit doesn't cost much
+    let rate_limited_auth = auth.clone()
+        .and(remote_addr())
+        .and(warp::ext::optional::<ClientCertificate>())
+        .and(forwarded_by_peer(config.clone()))
+        .and_then(move |conf: Arc<RoutesConfig>, authorization: Option<String>, remote: Option<SocketAddr>, certificate: Option<ClientCertificate>, forwarded: Option<String>| {
+            let result = check_rate_limit(rate_limiter.as_deref(), &conf, authorization.as_deref(), certificate.as_ref(), remote, forwarded.is_some());
+            async move { result.map(|_| (conf, authorization)) }
+        })
+        .untuple_one();
 
-    let push_metrics_path = warp::path("metrics")
+    // Taken before the body is read and held until the push has been merged, so that a flood of pushes is turned away,
+    // rather than every one of them being buffered while it waits its turn for the aggregator
+    let push_permit = warp::any().and_then(move || {
+        let result = Arc::clone(&push_permits).try_acquire_owned().map_err(|_| warp::reject::custom(GravelError::TooManyPushes));
+        async move { result }
+    });
+
+    let push_metrics_path = with_tenant()
+        .and(warp::path("metrics"))
         .and(warp::post().or(warp::put()))
-        .and(auth)
+        .and(rate_limited_auth.clone())
+        .and(push_permit.clone())
+        .and(body_limit)
         .and(warp::filters::body::bytes())
+        .and(warp::header::optional::<String>("content-type"))
+        .and(warp::header::optional::<String>("content-encoding"))
+        .and(forwarded_by_peer(config.clone()))
+        .and(warp::header::optional::<String>(REQUEST_ID_HEADER).map(|id: Option<String>| id.unwrap_or_else(new_request_id)))
+        .and(warp::header::optional::<String>(IDEMPOTENCY_KEY_HEADER))
         .and(warp::path::tail())
-        .and(with_aggregator(aggregator.clone()))
-        .and(with_config(Arc::clone(&config)))
+        .and(with_tenants(tenants.clone()))
+        .and(warp::any().map(|| PushFormat::Exposition))
+        .and_then(ingest_metrics);
+
+    // Like InfluxDB's v1 and v2 write APIs, minus the database/bucket, which line protocol pushes don't need
+    let line_protocol_format = warp::query::<HashMap<String, String>>().and_then(|query: HashMap<String, String>| async move {
+        match query.get("precision").map(|precision| precision.parse()).transpose() {
+            Ok(precision) => Ok(PushFormat::LineProtocol(precision.unwrap_or_default())),
+            Err(e) => Err(warp::reject::custom(GravelError::Error(e))),
+        }
+    });
+    let write_path = with_tenant()
+        .and(warp::path!("write").or(warp::path!("api" / "v2" / "write")).unify())
+        .and(warp::post().map(|| ()))
+        .and(rate_limited_auth)
+        .and(push_permit)
+        .and(body_limit)
+        .and(warp::filters::body::bytes())
+        .and(warp::header::optional::<String>("content-type"))
+        .and(warp::header::optional::<String>("content-encoding"))
+        .and(forwarded_by_peer(config.clone()))
+        .and(warp::header::optional::<String>(REQUEST_ID_HEADER).map(|id: Option<String>| id.unwrap_or_else(new_request_id)))
+        .and(warp::header::optional::<String>(IDEMPOTENCY_KEY_HEADER))
+        .and(warp::path::tail())
+        .and(with_tenants(tenants.clone()))
+        .and(line_protocol_format)
         .and_then(ingest_metrics);
 
-    let mut get_metrics_headers = HeaderMap::new();
-    get_metrics_headers.insert("Content-Type", HeaderValue::from_static("text/plain; version=0.0.4"));
+    let get_metrics_path = with_store(tenants.clone())
+        .and(warp::path("metrics"))
+        .and(warp::get().or(warp::head()).unify())
+        .and(with_config(config.clone()))
+        .and(warp::path::tail())
+        .and(warp::method())
+        .and(warp::query::<Vec<(String, String)>>())
+        .and(warp::header::optional::<String>("accept"))
+        .and(warp::header::optional::<String>("accept-encoding"))
+        .and(warp::header::optional::<String>("if-none-match"))
+        .and_then(get_metrics);
 
-    let get_metrics_path = warp::path!("metrics")
+    let get_graphite_path = with_store(tenants.clone())
+        .and(warp::path!("metrics" / "graphite"))
+        .and(warp::get())
+        .and(with_config(config.clone()))
+        .and_then(get_graphite_metrics);
+
+    let get_gateway_metrics_path = warp::path!("-" / "metrics")
         .and(warp::get())
         .and(with_aggregator(aggregator.clone()))
-        .and_then(get_metrics)
-        .with(warp::reply::with::headers(get_metrics_headers));
+        .and(with_config(config.clone()))
+        .and_then(get_gateway_metrics);
+
+    // Only the scrape endpoints are readable from browsers. Pushes and deletes never get CORS headers
+    let scrape_paths = {
+        let cors_allowed_origins = cors_allowed_origins.clone();
+        warp::header::optional::<String>("origin")
+            .and(get_metrics_path.or(get_gateway_metrics_path))
+            .map(move |origin: Option<String>, reply| with_cors_headers(reply, origin.as_deref(), &cors_allowed_origins))
+    };
+
+    let cors_preflight_path = with_tenant().and(warp::path!("metrics")).map(|_tenant| ()).untuple_one()
+        .or(warp::path!("-" / "metrics"))
+        .unify()
+        .and(warp::options())
+        .and(warp::header::optional::<String>("origin"))
+        .and(warp::header::optional::<String>(ACCESS_CONTROL_REQUEST_HEADERS.as_str()))
+        .and_then(move |origin: Option<String>, request_headers: Option<String>| {
+            let result = cors_preflight(&cors_allowed_origins, origin.as_deref(), request_headers.as_deref());
+            async move { result }
+        });
+
+    let delete_metrics_path = with_store(tenants.clone())
+        .and(warp::path!("metrics"))
+        .and(warp::delete())
+        .and(auth.clone())
+        .and_then(delete_metrics);
+
+    #[cfg(feature="clustering")]
+    let drain_path = warp::path!("-" / "drain")
+        .and(warp::post())
+        .and(auth.clone())
+        .and(warp::header::optional::<String>(REQUEST_ID_HEADER).map(|id: Option<String>| id.unwrap_or_else(new_request_id)))
+        .and(with_tenants(tenants.clone()))
+        .and_then(drain);
+
+    // Like the snapshot file, these only cover the default store
+    let snapshot_path = warp::path!("-" / "snapshot")
+        .and(warp::get())
+        .and(auth.clone())
+        .and(with_tenants(tenants.clone()))
+        .and_then(get_snapshot);
+
+    let jobs_path = warp::path!("-" / "jobs")
+        .and(warp::get())
+        .and(auth.clone())
+        .and(with_tenants(tenants.clone()))
+        .and_then(get_jobs);
+
+    let restore_path = warp::path!("-" / "restore")
+        .and(warp::post())
+        .and(auth.clone())
+        .and(body_limit)
+        .and(warp::filters::body::bytes())
+        .and(with_tenants(tenants.clone()))
+        .and_then(restore_snapshot);
+
+    let delete_matching_path = with_store(tenants)
+        .and(warp::path("metrics"))
+        .and(warp::delete())
+        .and(auth)
+        .and(forwarded_by_peer(config.clone()))
+        .and(warp::header::optional::<String>(REQUEST_ID_HEADER).map(|id: Option<String>| id.unwrap_or_else(new_request_id)))
+        .and(warp::path::tail())
+        .and_then(delete_matching_metrics);
+
+    let healthy_path = warp::path!("-" / "healthy")
+        .and(warp::get())
+        .map(|| "OK");
+
+    let ready_path = warp::path!("-" / "ready")
+        .and(warp::get())
+        .and(with_config(config))
+        .map(ready);
+
+    let routes = push_metrics_path.or(write_path).or(get_graphite_path).or(scrape_paths).or(cors_preflight_path).or(healthy_path).or(ready_path).or(delete_metrics_path).or(delete_matching_path).or(snapshot_path).or(jobs_path).or(restore_path);
+    #[cfg(feature="clustering")]
+    let routes = routes.or(drain_path);
+    return routes.recover(handle_rejection);
+}
+
+/// Whether a browser on the given origin can read scrapes
+fn is_allowed_origin(allowed_origins: &[String], origin: &str) -> bool {
+    allowed_origins.iter().any(|allowed| allowed == "*" || allowed == origin)
+}
+
+/// Lets the browser that sent a scrape read its response, if it's from an allowed origin. Otherwise the response is left
+/// as it is, and the browser won't show it to the page
+fn with_cors_headers(reply: impl Reply, origin: Option<&str>, allowed_origins: &[String]) -> warp::reply::Response {
+    let mut response = reply.into_response();
+    let origin = match origin {
+        Some(origin) if is_allowed_origin(allowed_origins, origin) => origin,
+        _ => return response,
+    };
+
+    if let Ok(origin) = HeaderValue::from_str(origin) {
+        let headers = response.headers_mut();
+        headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+        headers.append(VARY, HeaderValue::from_static("Origin"));
+    }
+
+    return response;
+}
+
+/// Answers a browser's OPTIONS preflight for a scrape. Without any allowed origins this isn't a route at all, so that
+/// OPTIONS requests are handled as they are without CORS
+fn cors_preflight(allowed_origins: &[String], origin: Option<&str>, request_headers: Option<&str>) -> Result<warp::reply::Response, warp::Rejection> {
+    if allowed_origins.is_empty() {
+        return Err(warp::reject());
+    }
+
+    let origin = match origin {
+        Some(origin) if is_allowed_origin(allowed_origins, origin) => origin,
+        _ => return Err(warp::reject::custom(GravelError::Forbidden)),
+    };
+
+    let mut response = Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .header(ACCESS_CONTROL_ALLOW_ORIGIN, origin)
+        .header(ACCESS_CONTROL_ALLOW_METHODS, CORS_ALLOWED_METHODS)
+        .header(ACCESS_CONTROL_MAX_AGE, CORS_MAX_AGE)
+        .header(VARY, "Origin");
+    // Scrapes don't need any headers beyond the ones browsers always allow, but there's no harm in allowing the ones
+    // the page asked for, since they can only change how the metrics are rendered
+    if let Some(request_headers) = request_headers {
+        response = response.header(ACCESS_CONTROL_ALLOW_HEADERS, request_headers);
+    }
 
-    return push_metrics_path.or(get_metrics_path).recover(handle_rejection);
+    return response.body(Body::empty()).map_err(|err| warp::reject::custom(GravelError::Error(err.to_string())));
 }
 
+/// Turns rejections into plain text responses, so that clients can tell what was wrong with their request
 async fn handle_rejection(err: warp::Rejection) -> Result<impl warp::Reply, std::convert::Infallible> {
-    let gravel_error: Option<&GravelError> = err.find();
-    match gravel_error {
-        Some(GravelError::AuthError) => Ok(warp::reply::with_status(String::from("FORBIDDEN"), StatusCode::FORBIDDEN)),
-        Some(GravelError::AggregationError(err)) => Ok(warp::reply::with_status(err.to_string(), StatusCode::BAD_REQUEST)),
-        Some(GravelError::Error(err)) => Ok(warp::reply::with_status(err.clone(), StatusCode::BAD_REQUEST)),
-        None => Ok(warp::reply::with_status(String::from("INTERNAL_SERVER_ERROR"), StatusCode::INTERNAL_SERVER_ERROR)),
+    let reply = rejection_reply(&err);
+    // Retry-After is in whole seconds, so it's rounded up to be sure that the client can push by then
+    let retry_after = match err.find() {
+        Some(GravelError::RateLimited { retry_after }) => Some(retry_after.as_secs_f64().ceil().max(1.).to_string()),
+        Some(GravelError::TooManyPushes) => Some(TOO_MANY_PUSHES_RETRY_AFTER.to_owned()),
+        _ => None,
+    };
+
+    match retry_after {
+        Some(retry_after) => Ok(warp::reply::with_header(reply, RETRY_AFTER, retry_after).into_response()),
+        None => Ok(reply.into_response()),
+    }
+}
+
+/// What a request to a path that doesn't exist, or with a method it doesn't take, is told about the ones that do
+fn endpoints_help() -> String {
+    let mut endpoints = vec![
+        "POST|PUT /metrics[/job/<job>[/<label>/<value>...]]   push metrics in the text or protobuf format",
+        "POST     /write, /api/v2/write                       push metrics in InfluxDB line protocol",
+        "GET|HEAD /metrics[/job/<job>[/<label>/<value>...]]   scrape the aggregated metrics",
+        "GET      /metrics/graphite                           scrape the aggregated metrics as Graphite lines",
+        "DELETE   /metrics[/job/<job>[/<label>/<value>...]]   delete metrics",
+        "GET      /-/metrics                                  scrape the gateway's own metrics",
+        "GET      /-/healthy, /-/ready                        health checks",
+        "GET      /-/snapshot                                 snapshot the metrics",
+        "POST     /-/restore                                  restore a snapshot",
+        "GET      /-/jobs                                     list the jobs, with their series counts and last pushes",
+    ];
+    if cfg!(feature="clustering") {
+        endpoints.push("POST     /-/drain                                    hand this node's metrics over to its peers");
+    }
+
+    format!("Endpoints (the /metrics ones can also be prefixed with /tenants/<tenant>):\n{}\n", endpoints.iter().map(|endpoint| format!("  {}\n", endpoint)).collect::<String>())
+}
+
+/// The status and message that a rejection gets
+fn rejection_reply(err: &warp::Rejection) -> warp::reply::WithStatus<String> {
+    if err.is_not_found() {
+        return warp::reply::with_status(format!("NOT_FOUND\n\n{}", endpoints_help()), StatusCode::NOT_FOUND);
+    }
+
+    if err.find::<warp::reject::InvalidQuery>().is_some() {
+        return warp::reply::with_status(String::from("INVALID_QUERY"), StatusCode::BAD_REQUEST);
+    }
+
+    if err.find::<warp::reject::PayloadTooLarge>().is_some() {
+        return warp::reply::with_status(String::from("PAYLOAD_TOO_LARGE"), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    match err.find::<GravelError>() {
+        Some(err) => {
+            let (body, status) = error_reply(err);
+            warp::reply::with_status(body, status)
+        },
+        // Checked last, since a route that took the method may have rejected the request for a better reason
+        None if err.find::<warp::reject::MethodNotAllowed>().is_some() => {
+            warp::reply::with_status(format!("METHOD_NOT_ALLOWED\n\n{}", endpoints_help()), StatusCode::METHOD_NOT_ALLOWED)
+        },
+        None => warp::reply::with_status(String::from("INTERNAL_SERVER_ERROR"), StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+/// The body and status that the client gets for one of our errors
+fn error_reply(err: &GravelError) -> (String, StatusCode) {
+    match err {
+        GravelError::AuthError => (String::from("unauthorized"), StatusCode::UNAUTHORIZED),
+        GravelError::Forbidden => (String::from("FORBIDDEN"), StatusCode::FORBIDDEN),
+        GravelError::PayloadTooLarge => (String::from("PAYLOAD_TOO_LARGE"), StatusCode::PAYLOAD_TOO_LARGE),
+        GravelError::AggregationError(err) => (err.to_string(), StatusCode::BAD_REQUEST),
+        GravelError::Error(err) => (err.clone(), StatusCode::BAD_REQUEST),
+        #[cfg(feature="clustering")]
+        GravelError::PeerResponse { status, body } => (body.clone(), *status),
+        #[cfg(feature="clustering")]
+        GravelError::TooManyForwards => (String::from("TOO_MANY_FORWARDS"), StatusCode::SERVICE_UNAVAILABLE),
+        // The client gets told why the rest didn't take it, but also that some did, since retrying a push would merge it
+        // again wherever it was taken
+        #[cfg(feature="clustering")]
+        GravelError::PartialSuccess { accepted, owners, cause } => {
+            let (body, status) = error_reply(cause);
+            (format!("{}\n\nPARTIAL_SUCCESS: {} of {} owners accepted this without a quorum, and kept it. Retrying a push merges it on them again, unless it has an {} header", body, accepted, owners, IDEMPOTENCY_KEY_HEADER), status)
+        },
+        GravelError::RateLimited { .. } => (String::from("RATE_LIMITED"), StatusCode::TOO_MANY_REQUESTS),
+        GravelError::TooManyPushes => (String::from("TOO_MANY_PUSHES"), StatusCode::SERVICE_UNAVAILABLE),
     }
 }
 
@@ -78,77 +562,952 @@ fn with_aggregator(
     warp::any().map(move || agg.clone())
 }
 
+/// Picks the tenant out of a /tenants/<id> prefix on the path. Paths without one are for the default store
+fn with_tenant() -> impl Filter<Extract = (Option<String>,), Error = warp::Rejection> + Clone {
+    let tenant = warp::path("tenants").and(warp::path::param::<String>()).map(Some);
+    tenant.or(warp::any().map(|| None)).unify().and_then(|tenant: Option<String>| async move {
+        match tenant.as_deref().map(check_tenant_id).transpose() {
+            Ok(_) => Ok(tenant),
+            Err(e) => Err(warp::reject::custom(GravelError::Error(e))),
+        }
+    })
+}
+
+fn with_tenants(
+    tenants: Tenants,
+) -> impl Filter<Extract = (Tenants,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || tenants.clone())
+}
+
+/// Picks the tenant out of the path like `with_tenant`, along with its store. Reading a tenant doesn't create its store,
+/// so this is for everything but pushes
+fn with_store(
+    tenants: Tenants,
+) -> impl Filter<Extract = (Option<String>, Aggregator), Error = warp::Rejection> + Clone {
+    with_tenant().and(with_tenants(tenants)).map(|tenant: Option<String>, tenants: Tenants| {
+        let agg = tenants.get(tenant.as_deref());
+        (tenant, agg)
+    }).untuple_one()
+}
+
 fn with_config(
-    conf: Arc<RoutesConfig>,
+    conf: SharedRoutesConfig,
 ) -> impl Filter<Extract = (Arc<RoutesConfig>,), Error = std::convert::Infallible> + Clone {
-    warp::any().map(move || Arc::clone(&conf))
+    warp::any().map(move || conf.current())
+}
+
+/// The path (without the leading slash) that a request for the given tenant and label path is sent to
+#[cfg(feature="clustering")]
+fn metrics_path(tenant: Option<&str>, url_tail: &str) -> String {
+    let prefix = match tenant {
+        Some(tenant) => format!("tenants/{}/metrics", tenant),
+        None => "metrics".to_owned(),
+    };
+
+    match url_tail {
+        "" => prefix,
+        url_tail => format!("{}/{}", prefix, url_tail),
+    }
+}
+
+/// Why a forward to a peer failed
+#[cfg(feature="clustering")]
+enum ForwardFailure {
+    /// The peer couldn't be reached, or failed to handle the push itself (i.e. a 5xx)
+    Unavailable(GravelError),
+    /// The peer is fine, but the push was rejected (e.g. a 4xx because it's invalid)
+    Rejected(GravelError),
+}
+
+/// The header a client sent its credentials in, and what it sent. Forwards pass these on as they are, so that a peer
+/// with auth authenticates (and authorizes) the push just like it would if the client had pushed to it directly
+#[cfg(feature="clustering")]
+type Credentials<'a> = Option<(&'a HeaderName, &'a str)>;
+
+/// Forwards a push to a peer that owns it
+#[cfg(feature="clustering")]
+#[allow(clippy::too_many_arguments)]
+async fn forward_to_peer(cluster_conf: &ClusterConfig, metrics: &GatewayMetrics, peer: &str, data: Bytes, content_type: Option<&str>, path: &str, request_id: &str, idempotency_key: Option<&str>, credentials: Credentials<'_>) -> Result<(), GravelError> {
+    forward_request(cluster_conf, metrics, Method::POST, peer, data, content_type, path, request_id, idempotency_key, credentials).await
 }
 
+/// Forwards a DELETE to a peer that owns the series it deletes
 #[cfg(feature="clustering")]
-async fn forward_to_peer(peer: &str, data: Bytes, url_tail: Tail) -> Result<(), GravelError> {
-    let client = reqwest::Client::new();
-    return match client.post(peer.to_owned() + "/" + url_tail.as_str()).body(data).send().await {
-        Ok(o) => {
-            if o.status().is_success() {
-                return Ok(());
+async fn forward_delete_to_peer(cluster_conf: &ClusterConfig, metrics: &GatewayMetrics, peer: &str, path: &str, request_id: &str, credentials: Credentials<'_>) -> Result<(), GravelError> {
+    forward_request(cluster_conf, metrics, Method::DELETE, peer, Bytes::new(), None, path, request_id, None, credentials).await
+}
+
+/// Forwards a request to the given peer, failing fast if that peer's circuit is open
+#[cfg(feature="clustering")]
+#[allow(clippy::too_many_arguments)]
+#[tracing::instrument(skip(cluster_conf, metrics, data, path, request_id, idempotency_key, credentials))]
+async fn forward_request(cluster_conf: &ClusterConfig, metrics: &GatewayMetrics, method: Method, peer: &str, data: Bytes, content_type: Option<&str>, path: &str, request_id: &str, idempotency_key: Option<&str>, credentials: Credentials<'_>) -> Result<(), GravelError> {
+    let circuit_breaker = cluster_conf.circuit_breaker();
+    if !circuit_breaker.allow(peer) {
+        metrics.record_forward(false);
+        warn!("not forwarding - the peer's circuit is open");
+        return Err(GravelError::Error(format!("Not forwarding to peer {} - its circuit is open after too many failures", peer)));
+    }
+
+    // The slot is held through any retries, since the connection to the peer is too
+    let _permit = match cluster_conf.forward_limiter().acquire().await {
+        Some(permit) => permit,
+        None => {
+            metrics.record_forward(false);
+            warn!("not forwarding - too many forwards are already in flight");
+            return Err(GravelError::TooManyForwards);
+        }
+    };
+
+    let started = Instant::now();
+    let result = send_to_peer(cluster_conf.client(), method, peer, data, content_type, path, request_id, idempotency_key, credentials, cluster_conf.retry_policy()).await;
+    let outcome = match &result {
+        Ok(_) => ForwardOutcome::Success,
+        Err(ForwardFailure::Unavailable(GravelError::PeerResponse { .. }) | ForwardFailure::Rejected(GravelError::PeerResponse { .. })) => ForwardOutcome::ErrorStatus,
+        Err(_) => ForwardOutcome::ConnectionError,
+    };
+    metrics.record_peer_forward(peer, outcome, started.elapsed());
+    metrics.record_forward(result.is_ok());
+    match result {
+        Ok(_) => {
+            circuit_breaker.record_success(peer);
+            Ok(())
+        },
+        Err(ForwardFailure::Rejected(e)) => {
+            circuit_breaker.record_success(peer);
+            Err(e)
+        },
+        Err(ForwardFailure::Unavailable(e)) => {
+            circuit_breaker.record_failure(peer);
+            Err(e)
+        }
+    }
+}
+
+/// Sends a request to a peer, retrying with exponential backoff on connection errors, timeouts, and 5xxs.
+/// Other failures (e.g. a 4xx because the push is invalid) won't get any better by retrying, so they
+/// fail straight away
+#[cfg(feature="clustering")]
+#[allow(clippy::too_many_arguments)]
+async fn send_to_peer(client: &reqwest::Client, method: Method, peer: &str, data: Bytes, content_type: Option<&str>, path: &str, request_id: &str, idempotency_key: Option<&str>, credentials: Credentials<'_>, retry_policy: &RetryPolicy) -> Result<(), ForwardFailure> {
+    let url = format!("{}/{}", peer, path);
+    let mut retry = 0;
+    loop {
+        let mut request = client.request(method.clone(), &url)
+            .header(FORWARDED_HEADER, "true")
+            .header(REQUEST_ID_HEADER, request_id)
+            .timeout(retry_policy.attempt_timeout)
+            .body(data.clone());
+
+        // The peer needs to know the body's format, but not its encoding - it was already decoded here
+        if let Some(content_type) = content_type {
+            request = request.header(CONTENT_TYPE, content_type);
+        }
+
+        // So that the peer can skip a push that it's already merged too
+        if let Some(idempotency_key) = idempotency_key {
+            request = request.header(IDEMPOTENCY_KEY_HEADER, idempotency_key);
+        }
+
+        if let Some((header, value)) = credentials {
+            if let Ok(mut value) = HeaderValue::from_str(value) {
+                value.set_sensitive(true);
+                request = request.header(header, value);
             }
+        }
+
+        let error = match request.send().await {
+            Ok(o) => {
+                let status = o.status();
+                if status.is_success() {
+                    debug!(%status, retries = retry, "forwarded push");
+                    return Ok(());
+                }
+
+                let body = o.text().await.unwrap_or_default();
+                warn!(%status, %body, "peer failed to accept the push");
+                let error = GravelError::PeerResponse { status, body };
+                if !status.is_server_error() {
+                    return Err(ForwardFailure::Rejected(error));
+                }
+
+                error
+            },
+            Err(e) if e.is_connect() || e.is_timeout() => GravelError::Error(format!("Failed to forward to peer {}: {}", url, e)),
+            Err(e) => return Err(ForwardFailure::Rejected(GravelError::Error(format!("Failed to forward to peer {}: {}", url, e))))
+        };
+
+        if retry >= retry_policy.max_retries {
+            warn!(retries = retry, ?error, "giving up on forwarding");
+            return Err(ForwardFailure::Unavailable(error));
+        }
+
+        debug!(retry, ?error, "forward failed, retrying");
+
+        tokio::time::sleep(retry_policy.backoff(retry)).await;
+        retry += 1;
+    }
+}
+
+/// The suffix on a label name in the push path that marks its value as base64 encoded
+const BASE64_LABEL_SUFFIX: &str = "@base64";
+
+/// Parses the push gateway path syntax (e.g. job/foo/instance/bar) into a set of labels.
+/// Like the push gateway, a label named `name@base64` has a URL safe base64 value, which lets
+/// values contain slashes. A lone `=` stands in for an empty value. Names and values are percent
+/// decoded after splitting, so an encoded slash (`%2F`) ends up in the value. A label at the end of the path without a
+/// value gets an empty one, unless `strict` is set, in which case it's an error
+fn parse_label_path(path: &str, strict: bool) -> Result<HashMap<String, String>, GravelError> {
+    let mut labelset = HashMap::new();
+    let mut labels = path.split('/').peekable();
+    while labels.peek().is_some() {
+        let name = labels.next().unwrap();
+        if name.is_empty() {
+            break;
+        }
+        let name = percent_decode(name)?;
+        let value = match labels.next() {
+            Some(value) => percent_decode(value)?,
+            None if strict => return Err(GravelError::Error(format!("Invalid path: label {} has no value", name))),
+            None => String::new(),
+        };
+        match name.strip_suffix(BASE64_LABEL_SUFFIX) {
+            Some(name) => labelset.insert(name.to_owned(), decode_base64_label(name, &value)?),
+            None => labelset.insert(name, value),
+        };
+    }
+    Ok(labelset)
+}
+
+/// Decodes the `%XX` escapes in a path segment, rejecting any that are cut short or aren't hex
+fn percent_decode(segment: &str) -> Result<String, GravelError> {
+    let invalid = || GravelError::Error(format!("Invalid percent encoding in path segment {}", segment));
+
+    let mut decoded = Vec::with_capacity(segment.len());
+    let mut bytes = segment.bytes();
+    while let Some(b) = bytes.next() {
+        if b != b'%' {
+            decoded.push(b);
+            continue;
+        }
+
+        let hex = [bytes.next().ok_or_else(invalid)?, bytes.next().ok_or_else(invalid)?];
+        if !hex.iter().all(u8::is_ascii_hexdigit) {
+            return Err(invalid());
+        }
+
+        // Both digits are ASCII hex, so this can't fail
+        decoded.push(u8::from_str_radix(std::str::from_utf8(&hex).unwrap(), 16).unwrap());
+    }
+
+    String::from_utf8(decoded).map_err(|_| invalid())
+}
+
+fn decode_base64_label(name: &str, value: &str) -> Result<String, GravelError> {
+    // Padding is optional, which also makes the `=` placeholder decode to an empty value
+    let decoded = base64::decode_config(value.trim_end_matches('='), base64::URL_SAFE_NO_PAD)
+        .map_err(|e| GravelError::Error(format!("Invalid base64 value for label {}: {}", name, e)))?;
+    String::from_utf8(decoded).map_err(|_| GravelError::Error(format!("Invalid UTF-8 in base64 value for label {}", name)))
+}
 
-            return Err(GravelError::Error(format!("Failed to forward to peer. Got status: {}", 200)));
+/// Borrows a set of owned labels, in the form the aggregator and authenticators take them
+fn borrow_labels(labels: &HashMap<String, String>) -> HashMap<&str, &str> {
+    labels.iter().map(|(name, value)| (name.as_str(), value.as_str())).collect()
+}
+
+/// Undoes any Content-Encoding applied to a pushed body. Only gzip (and identity) are supported.
+/// Rejects bodies that are larger than `max_bytes`, either as sent or once decoded
+fn decode_body(data: Bytes, content_encoding: Option<&str>, max_bytes: u64) -> Result<Bytes, GravelError> {
+    if data.len() as u64 > max_bytes {
+        return Err(GravelError::PayloadTooLarge);
+    }
+
+    match content_encoding.map(|e| e.trim().to_ascii_lowercase()).as_deref() {
+        None | Some("") | Some("identity") => Ok(data),
+        Some("gzip") => {
+            // Read one byte past the limit, so that a body that's too big can be told apart from one that's right on it
+            let mut decoded = Vec::new();
+            match GzDecoder::new(data.as_ref()).take(max_bytes.saturating_add(1)).read_to_end(&mut decoded) {
+                Ok(_) if decoded.len() as u64 > max_bytes => Err(GravelError::PayloadTooLarge),
+                Ok(_) => Ok(Bytes::from(decoded)),
+                Err(e) => Err(GravelError::Error(format!("Failed to decode gzip body: {}", e)))
+            }
         },
-        Err(e) => Err(GravelError::Error(e.to_string()))
+        Some(encoding) => Err(GravelError::Error(format!("Unsupported Content-Encoding: {}", encoding)))
+    }
+}
+
+/// The peers that should handle a request, going by its labels, and whether that includes this one
+struct Owners {
+    /// The owners that the request has to be forwarded to
+    #[cfg(feature="clustering")]
+    remote: Vec<String>,
+    /// How many owners there are, including us if we're one
+    #[cfg(feature="clustering")]
+    count: usize,
+    local: bool,
+}
+
+impl Owners {
+    /// Finds the owners of the given labels. Requests that a peer forwarded to us are already at one of their
+    /// owners, so they're never forwarded again
+    #[cfg_attr(not(feature="clustering"), allow(unused_variables))]
+    fn for_labels(conf: &RoutesConfig, forwarded: bool, labels: &HashMap<&str, &str>) -> Owners {
+        #[cfg(feature="clustering")]
+        if let Some(cluster_conf) = conf.cluster_conf.as_ref().filter(|_| !forwarded) {
+            return Owners::new(cluster_conf, cluster_conf.get_peers_for_key(&cluster_conf.sharding_key(labels)));
+        }
+
+        Owners::local()
+    }
+
+    /// Just this peer, for requests that aren't being forwarded anywhere
+    fn local() -> Owners {
+        Owners {
+            #[cfg(feature="clustering")]
+            remote: Vec::new(),
+            #[cfg(feature="clustering")]
+            count: 0,
+            local: true,
+        }
+    }
+
+    #[cfg(feature="clustering")]
+    fn new(cluster_conf: &ClusterConfig, peers: Vec<String>) -> Owners {
+        let local = peers.is_empty() || peers.iter().any(|peer| cluster_conf.is_self(peer));
+        let count = peers.len();
+        let remote = peers.into_iter().filter(|peer| !cluster_conf.is_self(peer)).collect();
+        Owners { remote, count, local }
+    }
+
+    /// The owners that the request has to be forwarded to
+    #[cfg(feature="clustering")]
+    fn remote_peers(&self) -> &[String] {
+        &self.remote
+    }
+
+    /// Checks that a majority of the owners (including us, if we're one) accepted the request, given the results of
+    /// forwarding it to the others. Requests are handled here before they're forwarded, and peers can't take back what
+    /// they've handled, so one that some owners accepted without a quorum has still been merged (or deleted) on those.
+    /// That's a partial success, and the error says so, with how many of them took it
+    #[cfg(feature="clustering")]
+    fn check_quorum(&self, results: Vec<Result<(), GravelError>>) -> Result<(), GravelError> {
+        let accepted = results.iter().filter(|r| r.is_ok()).count() + if self.local { 1 } else { 0 };
+        if accepted > self.count / 2 {
+            return Ok(());
+        }
+
+        let mut errors: Vec<GravelError> = results.into_iter().filter_map(|r| r.err()).collect();
+
+        // If a peer said why it didn't take the request (e.g. a 413 or 429), the client gets told the same. Likewise if
+        // we're too busy forwarding, so that the client knows to back off
+        let cause = match errors.iter().position(|e| matches!(e, GravelError::PeerResponse { .. } | GravelError::TooManyForwards)) {
+            Some(idx) => errors.swap_remove(idx),
+            None => {
+                let errors: Vec<String> = errors.into_iter().map(|e| match e {
+                    GravelError::Error(e) => e,
+                    e => format!("{:?}", e)
+                }).collect();
+                GravelError::Error(errors.join("; "))
+            },
+        };
+
+        match accepted {
+            0 => Err(cause),
+            accepted => Err(GravelError::PartialSuccess { accepted, owners: self.count, cause: Box::new(cause) }),
+        }
+    }
+}
+
+/// Part of a push, along with the peers that own it
+struct PushPart {
+    owners: Owners,
+    /// What's forwarded to the owners that aren't us, and its Content-Type
+    #[cfg(feature="clustering")]
+    body: Bytes,
+    #[cfg(feature="clustering")]
+    content_type: Option<String>,
+    /// The part's families, if the push was parsed before it was merged
+    families: Option<Vec<PrometheusMetricFamily>>,
+}
+
+/// Parses a push in any of the exposition formats, going by its Content-Type
+fn parse_push(agg: &Aggregator, data: &[u8], content_type: Option<&str>) -> Result<ParsedPush, AggregationError> {
+    if !content_type.is_some_and(is_delimited_protobuf) {
+        return agg.parse_reader(data, TextFormat::from_content_type(content_type));
+    }
+
+    match decode_delimited(data) {
+        Ok(families) => Ok(ParsedPush { families, skipped_lines: 0 }),
+        Err(e) => {
+            agg.metrics().record_parse_error();
+            Err(e.into())
+        }
+    }
+}
+
+/// Parses a push in any of the exposition formats and merges it, returning how many malformed lines were skipped. Text
+/// pushes go through `parse_and_merge_reader`
+async fn merge_push(agg: &mut Aggregator, data: &[u8], content_type: Option<&str>, labels: &HashMap<&str, &str>) -> Result<usize, AggregationError> {
+    if !content_type.is_some_and(is_delimited_protobuf) {
+        return agg.parse_and_merge_reader(data, TextFormat::from_content_type(content_type), labels).await;
     }
+
+    let parsed = parse_push(agg, data, content_type)?;
+    agg.merge_families(parsed.families, labels).await?;
+    Ok(parsed.skipped_lines)
+}
+
+/// Some of a push's families, and the peers that own them
+#[cfg(feature="clustering")]
+type OwnedFamilies = (Vec<String>, Vec<PrometheusMetricFamily>);
+
+/// Splits a push's families up by the peers that own each of their series. A series is owned by the peers that its own
+/// labels shard to, combined with the push's path labels the same way they are when it's merged, so a body can have series for
+/// several jobs, for instance, that belong to different peers
+#[cfg(feature="clustering")]
+fn split_by_owners(cluster_conf: &ClusterConfig, families: Vec<PrometheusMetricFamily>, path_labels: &HashMap<&str, &str>, precedence: LabelPrecedence) -> Result<Vec<OwnedFamilies>, AggregationError> {
+    let mut parts: Vec<OwnedFamilies> = Vec::new();
+    for family in families {
+        let label_names = family.get_label_names().to_vec();
+        let mut samples_by_owners: Vec<(Vec<String>, Vec<Sample<PrometheusValue>>)> = Vec::new();
+        for sample in family.iter_samples() {
+            let label_values: Vec<String> = sample.get_labelset()?.iter_values().cloned().collect();
+            let mut labels = path_labels.clone();
+            for (name, value) in label_names.iter().zip(label_values.iter()) {
+                match precedence {
+                    LabelPrecedence::Body if !value.is_empty() => { labels.insert(name.as_str(), value.as_str()); },
+                    _ => { labels.entry(name.as_str()).or_insert(value.as_str()); },
+                }
+            }
+
+            let peers = cluster_conf.get_peers_for_key(&cluster_conf.sharding_key(&labels));
+            let sample = Sample::new(label_values, sample.timestamp, sample.value.clone());
+            match samples_by_owners.iter_mut().find(|(owners, _)| *owners == peers) {
+                Some((_, samples)) => samples.push(sample),
+                None => samples_by_owners.push((peers, vec![sample])),
+            }
+        }
+
+        for (peers, samples) in samples_by_owners {
+            let part = PrometheusMetricFamily::new(family.family_name.clone(), label_names.clone(), family.family_type.clone(), family.help.clone(), family.unit.clone())
+                .with_samples(samples)?;
+            match parts.iter_mut().find(|(owners, _)| *owners == peers) {
+                Some((_, families)) => families.push(part),
+                None => parts.push((peers, vec![part])),
+            }
+        }
+    }
+
+    Ok(parts)
+}
+
+/// Serializes some of a push's families in the format that the push came in, so that the peers they're forwarded to get
+/// the same exemplars, units, and `_created` series as the whole push had, under the same Content-Type
+#[cfg(feature="clustering")]
+fn serialize_part(families: &[PrometheusMetricFamily], content_type: Option<&str>) -> Result<Bytes, AggregationError> {
+    if content_type.is_some_and(is_delimited_protobuf) {
+        return Ok(Bytes::from(encode_delimited(families)?));
+    }
+
+    Ok(Bytes::from(render_families(families, TextFormat::from_content_type(content_type))))
 }
 
 /// The routes for POST /metrics requests - takes a Prometheus exposition format
 /// and merges it into the existing metrics. Also supports push gateway syntax - /metrics/job/foo
 /// adds a job="foo" label to all the metrics
+#[allow(clippy::too_many_arguments)]
+#[tracing::instrument(name = "push", skip_all, fields(request_id = %request_id, path = %url_tail.as_str()))]
 async fn ingest_metrics<T>(
+    tenant: Option<String>,
     _method: T,
+    conf: Arc<RoutesConfig>,
+    authorization: Option<String>,
+    _permit: OwnedSemaphorePermit,
     data: Bytes,
+    content_type: Option<String>,
+    content_encoding: Option<String>,
+    forwarded: Option<String>,
+    request_id: String,
+    idempotency_key: Option<String>,
     url_tail: Tail,
-    mut agg: Aggregator,
-    conf: Arc<RoutesConfig>
+    tenants: Tenants,
+    format: PushFormat,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let labels = {
-        let mut labelset = HashMap::new();
-        let mut labels = url_tail.as_str().split("/").peekable();
-        while labels.peek().is_some() {
-            let name = labels.next().unwrap();
-            if name.is_empty() {
-                break;
+    let mut path_labels = parse_label_path(url_tail.as_str(), conf.strict_label_paths).map_err(reject_push)?;
+    if let Some(default_job) = conf.default_job.as_ref() {
+        path_labels.entry(String::from("job")).or_insert_with(|| default_job.clone());
+    }
+    let labels = borrow_labels(&path_labels);
+    debug!(?tenant, ?labels, "parsed path labels");
+    if let Err(e) = authorize(&conf, authorization.as_deref(), tenant.as_deref(), &labels) {
+        warn!(?tenant, ?labels, "push isn't authorized for its tenant and labels");
+        return Err(e);
+    }
+
+    // Checked up front, so that invalid pushes aren't forwarded on just to fail there
+    if let Err(e) = check_label_names(labels.keys().copied()) {
+        return Err(reject_push(GravelError::AggregationError(e)));
+    }
+
+    let data = decode_body(data, content_encoding.as_deref(), conf.max_body_bytes).map_err(reject_push)?;
+    debug!(bytes = data.len(), "decoded body");
+    let metrics = tenants.default_store().metrics();
+    metrics.record_push(data.len());
+
+    // Line protocol is converted to the text format up front, so that from here on (including when it's forwarded to
+    // peers) it's handled like any other push
+    let (data, content_type) = match format {
+        PushFormat::Exposition => (data, content_type),
+        PushFormat::LineProtocol(precision) => (line_protocol_to_text(&data, precision, metrics).map_err(reject_push)?, None),
+    };
+
+    // We're clustering, so might need to forward the metrics to the peers that own them. Which peers those are depends on
+    // each series' labels, so the push is parsed up front, and split up if its series belong to different peers. A push
+    // that's all for the same ones is forwarded as it is
+    #[cfg(feature="clustering")]
+    let cluster_conf = conf.cluster_conf.as_ref().filter(|_| forwarded.is_none());
+    let mut skipped_lines = 0;
+    let mut parts: Vec<PushPart> = Vec::new();
+    #[cfg(feature="clustering")]
+    if let Some(cluster_conf) = cluster_conf {
+        let parsed = parse_push(tenants.default_store(), &data, content_type.as_deref()).map_err(|e| reject_push(GravelError::AggregationError(e)))?;
+        skipped_lines = parsed.skipped_lines;
+        let split = split_by_owners(cluster_conf, parsed.families, &labels, tenants.default_store().label_precedence()).map_err(|e| reject_push(GravelError::AggregationError(e)))?;
+        let whole = split.len() == 1;
+        for (peers, families) in split {
+            let body = match whole {
+                true => data.clone(),
+                false => serialize_part(&families, content_type.as_deref()).map_err(|e| reject_push(GravelError::AggregationError(e)))?,
+            };
+            parts.push(PushPart { owners: Owners::new(cluster_conf, peers), body, content_type: content_type.clone(), families: Some(families) });
+        }
+    }
+    if parts.is_empty() {
+        parts.push(PushPart {
+            owners: Owners::for_labels(&conf, forwarded.is_some(), &labels),
+            #[cfg(feature="clustering")]
+            body: data.clone(),
+            #[cfg(feature="clustering")]
+            content_type: content_type.clone(),
+            families: None,
+        });
+    }
+    if parts.len() > 1 {
+        debug!(parts = parts.len(), "split push up between its owners");
+    }
+
+    // The tenant's store is only created here if we own some of the push
+    let local = parts.iter().any(|part| part.owners.local);
+    let mut agg = if local { tenants.get_or_create(tenant.as_deref()) } else { tenants.get(tenant.as_deref()) };
+
+    // A push that was already merged, that the client retried, is skipped here but still forwarded, since the peers
+    // this owner forwards to might not have got it the first time. They skip it themselves if they did
+    let job = labels.get("job").copied().unwrap_or_default();
+    let duplicate = local && idempotency_key.as_deref().is_some_and(|key| !agg.idempotency_keys().claim(job, key));
+    if duplicate {
+        debug!("already merged a push with this idempotency key, skipping it");
+    }
+
+    if local && !duplicate {
+        let started = Instant::now();
+        let result = match parts.iter().all(|part| part.families.is_some()) {
+            true => {
+                let families = parts.iter_mut().filter(|part| part.owners.local).flat_map(|part| part.families.take().unwrap_or_default()).collect();
+                agg.merge_families(families, &labels).await
+            },
+            false => merge_push(&mut agg, &data, content_type.as_deref(), &labels).await.map(|skipped| skipped_lines = skipped),
+        };
+        agg.metrics().record_merge(started.elapsed());
+
+        if let Err(e) = result {
+            // The push wasn't merged, so retrying it shouldn't be skipped
+            if let Some(key) = idempotency_key.as_deref() {
+                agg.idempotency_keys().release(job, key);
             }
-            let value = labels.next().unwrap_or_default();
-            labelset.insert(name, value);
+            return Err(reject_push(GravelError::AggregationError(e)));
+        }
+        debug!("merged push");
+    }
+
+    #[cfg(feature="clustering")]
+    if let Some(cluster_conf) = cluster_conf {
+        let path = metrics_path(tenant.as_deref(), url_tail.as_str());
+        let credentials = authorization.as_deref().map(|authorization| (&conf.auth_header, authorization));
+        let (path, request_id, idempotency_key) = (&path, &request_id, idempotency_key.as_deref());
+        let forwards = parts.iter().map(|part| async move {
+            let peers = part.owners.remote_peers();
+            let results = futures::future::join_all(peers.iter().map(|peer| forward_to_peer(cluster_conf, metrics, peer, part.body.clone(), part.content_type.as_deref(), path, request_id, idempotency_key, credentials))).await;
+            part.owners.check_quorum(results)
+        });
+
+        for result in futures::future::join_all(forwards).await {
+            result.map_err(reject_push)?;
+        }
+    }
+
+    let mut response = "".into_response();
+    if skipped_lines > 0 {
+        warn!(skipped = skipped_lines, "skipped malformed lines in push");
+        response.headers_mut().insert(SKIPPED_LINES_HEADER, HeaderValue::from(skipped_lines));
+    }
+    Ok(response)
+}
+
+/// Decodes a line protocol push into the text format, counting (and warning about) the fields that had to be skipped
+fn line_protocol_to_text(data: &[u8], precision: Precision, metrics: &GatewayMetrics) -> Result<Bytes, GravelError> {
+    let body = std::str::from_utf8(data).map_err(|_| GravelError::Error(String::from("Invalid UTF-8 in line protocol push")))?;
+    let write = match decode_line_protocol(body, precision) {
+        Ok(write) => write,
+        Err(e) => {
+            metrics.record_parse_error();
+            return Err(GravelError::AggregationError(e.into()));
         }
-        labelset
     };
 
-    // We're clustering, so might need to forward the metrics
+    if write.skipped_fields > 0 {
+        warn!(skipped = write.skipped_fields, "skipped line protocol fields that aren't numbers");
+        metrics.record_skipped_fields(write.skipped_fields as u64);
+    }
+
+    Ok(Bytes::from(write.families.iter().map(|family| family.to_string()).collect::<String>()))
+}
+
+/// Logs why a push is being rejected, before turning the error into a rejection
+fn reject_push(e: GravelError) -> warp::Rejection {
+    error!(reason = ?e, "rejecting push");
+    warp::reject::custom(e)
+}
+
+/// Checks whether an Accept style header (i.e. a comma separated list of values with optional q weights)
+/// allows the given value
+fn header_allows(header: &str, value: &str) -> bool {
+    header.split(',').any(|item| {
+        let mut parts = item.split(';').map(|p| p.trim());
+        let name = parts.next().unwrap_or_default();
+        // An explicit q=0 means the client _doesn't_ want this value
+        let rejected = parts.any(|p| p.strip_prefix("q=").and_then(|q| q.parse::<f64>().ok()) == Some(0.));
+        name.eq_ignore_ascii_case(value) && !rejected
+    })
+}
+
+/// The route for GET /-/ready requests. The aggregator is ready as soon as the routes exist, so this only fails
+/// when clustering and too many peers are unreachable to make a quorum
+#[cfg_attr(not(feature="clustering"), allow(unused_variables))]
+fn ready(conf: Arc<RoutesConfig>) -> warp::reply::WithStatus<String> {
+    #[cfg(feature="clustering")]
     if let Some(cluster_conf) = conf.cluster_conf.as_ref() {
-        let job = labels.get("job").unwrap_or(&"");
-        if let Some(peer) = cluster_conf.get_peer_for_key(job) {
-            if !cluster_conf.is_self(peer) {
-                match forward_to_peer(peer, data, url_tail).await {
-                    Ok(_) => return Ok(""),
-                    Err(e) => return Err(warp::reject::custom(e))
+        // A draining peer is on its way out, so it shouldn't be sent anything new
+        if cluster_conf.is_draining() {
+            return warp::reply::with_status(String::from("Draining"), StatusCode::SERVICE_UNAVAILABLE);
+        }
+
+        if !cluster_conf.has_quorum() {
+            return warp::reply::with_status(format!("Unreachable peers: {}", cluster_conf.unreachable_peers().join(", ")), StatusCode::SERVICE_UNAVAILABLE);
+        }
+    }
+
+    warp::reply::with_status(String::from("OK"), StatusCode::OK)
+}
+
+/// The route for POST /-/drain requests - takes this gateway off the hash ring, and hands every series it holds over to
+/// the peers that own them now, so that it can be shut down without losing anything. Series that none of their owners
+/// take are kept as they were, and the drain can be retried to hand them over again
+#[cfg(feature="clustering")]
+#[tracing::instrument(name = "drain", skip_all, fields(request_id = %request_id))]
+async fn drain(conf: Arc<RoutesConfig>, authorization: Option<String>, request_id: String, tenants: Tenants) -> Result<impl warp::Reply, warp::Rejection> {
+    let cluster_conf = match conf.cluster_conf.as_ref() {
+        Some(cluster_conf) => cluster_conf,
+        None => return Err(warp::reject::custom(GravelError::Error(String::from("Not clustered, so there's nowhere to drain to")))),
+    };
+
+    cluster_conf.drain();
+    let credentials = authorization.as_deref().map(|authorization| (&conf.auth_header, authorization));
+    let mut failed = 0;
+    for (tenant, mut agg) in tenants.all() {
+        let path = metrics_path(tenant.as_deref(), "");
+        for taken in agg.take_series_by(|labels| cluster_conf.sharding_key(labels)).await {
+            let peers = cluster_conf.get_peers_for_key(&taken.key);
+            let results = futures::future::join_all(peers.iter().map(|peer| {
+                forward_to_peer(cluster_conf, agg.metrics(), peer, Bytes::from(taken.body.clone()), None, &path, &request_id, None, credentials)
+            })).await;
+
+            // Once an owner has them, they outlive us. Putting them back for the owners that failed would hand them over
+            // to the ones that didn't a second time on the next drain, doubling what's summed
+            if results.iter().any(Result::is_ok) {
+                if results.iter().any(Result::is_err) {
+                    warn!(?tenant, key = %taken.key, "only some of the owners took the series that were handed over");
                 }
+                continue;
             }
+
+            warn!(?tenant, key = %taken.key, "failed to hand series over, keeping them");
+            failed += 1;
+            let key = taken.key.clone();
+            if let Err(e) = agg.put_back(taken).await {
+                error!(?tenant, %key, error = %e, "failed to keep series that couldn't be handed over");
+            }
+        }
+    }
+
+    if failed > 0 {
+        return Ok(warp::reply::with_status(format!("Failed to hand over the series of {} keys, drain again to retry", failed), StatusCode::SERVICE_UNAVAILABLE));
+    }
+
+    debug!("drained");
+    Ok(warp::reply::with_status(String::from("OK"), StatusCode::OK))
+}
+
+/// The route for GET /-/metrics requests - renders the gateway's own metrics, which are kept apart from the aggregated ones
+#[cfg_attr(not(feature="clustering"), allow(unused_variables))]
+async fn get_gateway_metrics(agg: Aggregator, conf: Arc<RoutesConfig>) -> Result<impl warp::Reply, warp::Rejection> {
+    #[allow(unused_mut)]
+    let mut families = agg.metrics().families(agg.store_stats().await);
+
+    #[cfg(feature="clustering")]
+    if let Some(cluster_conf) = conf.cluster_conf.as_ref() {
+        families.push(cluster_conf.circuit_state_family());
+        families.push(cluster_conf.forwards_in_flight_family());
+    }
+
+    let body: String = families.iter().map(|family| family.to_string()).collect();
+    Ok(Response::builder().header(CONTENT_TYPE, "text/plain; version=0.0.4").body(Body::from(body)).unwrap())
+}
+
+/// The ETag of a scrape, from the generation of the store it's of, so it changes whenever anything the scrape would
+/// return does without the scrape having to be rendered first. It's weak, since a push that lands part way through a
+/// scrape can show up in it without the ETag having caught up. The formats and gzipped scrapes are different
+/// representations of the same thing, so they get ETags of their own
+fn scrape_etag(generation: u64, openmetrics: bool, gzipped: bool) -> String {
+    let format = match openmetrics {
+        true => "-openmetrics",
+        false => "",
+    };
+
+    let encoding = match gzipped {
+        true => "-gzip",
+        false => "",
+    };
+
+    format!("W/\"{:016x}{}{}\"", generation, format, encoding)
+}
+
+/// A writer that throws away everything written to it, only counting how much there was
+#[derive(Default)]
+struct ByteCount(usize);
+
+impl Write for ByteCount {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Renders a scrape without keeping it, for how many bytes it is (once gzipped, if it's going to be). This is only for
+/// HEAD requests, which get the `Content-Length` that the scrape would have without it ever being held in memory at once
+async fn measure_scrape(agg: &Aggregator, selectors: Vec<Selector>, openmetrics: bool, gzip: bool) -> std::io::Result<usize> {
+    let mut length = 0;
+    let mut encoder = gzip.then(|| GzEncoder::new(ByteCount::default(), Compression::default()));
+
+    let rendered = agg.render_stream(selectors, openmetrics);
+    futures::pin_mut!(rendered);
+    while let Some(chunk) = rendered.next().await {
+        length += chunk.len();
+        if let Some(encoder) = encoder.as_mut() {
+            encoder.write_all(chunk.as_bytes())?;
         }
     }
 
-    let body = match String::from_utf8(data.to_vec()) {
-        Ok(s) => s,
-        Err(_) => {
-            return Err(warp::reject::custom(GravelError::Error("Invalid UTF-8 in body".into())));
+    if let Some(encoder) = encoder {
+        length = encoder.finish()?.0;
+    }
+
+    Ok(length)
+}
+
+/// Streams a scrape into a body, a family at a time, gzipping it as it goes if asked to. The channel only takes the
+/// next chunk once the client has read the last one, so only a family or so of the scrape is ever in memory
+fn stream_scrape(agg: &Aggregator, selectors: Vec<Selector>, openmetrics: bool, gzip: bool) -> Body {
+    let (mut sender, body) = Body::channel();
+    let rendered = agg.render_stream(selectors, openmetrics);
+    tokio::spawn(async move {
+        futures::pin_mut!(rendered);
+        let mut encoder = gzip.then(|| GzEncoder::new(Vec::new(), Compression::default()));
+        while let Some(chunk) = rendered.next().await {
+            let bytes = match encoder.as_mut() {
+                Some(encoder) => match encoder.write_all(chunk.as_bytes()) {
+                    Ok(_) => std::mem::take(encoder.get_mut()),
+                    Err(e) => {
+                        error!(error = %e, "failed to gzip scrape");
+                        sender.abort();
+                        return;
+                    }
+                },
+                None => chunk.into_bytes(),
+            };
+
+            // The client went away, so there's no one to render the rest for
+            if !bytes.is_empty() && sender.send_data(Bytes::from(bytes)).await.is_err() {
+                return;
+            }
         }
+
+        if let Some(encoder) = encoder {
+            match encoder.finish() {
+                Ok(rest) => { let _ = sender.send_data(Bytes::from(rest)).await; },
+                Err(e) => {
+                    error!(error = %e, "failed to gzip scrape");
+                    sender.abort();
+                },
+            }
+        }
+    });
+
+    body
+}
+
+/// Whether an `If-None-Match` header matches the given ETag, i.e. whether the client already has what it would get.
+/// Like a GET should, this compares them weakly, so a `W/` on either doesn't matter
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    let strip_weak = |tag: &str| tag.trim().trim_start_matches("W/").to_owned();
+    let etag = strip_weak(etag);
+    if_none_match.split(',').any(|tag| tag.trim() == "*" || strip_weak(tag) == etag)
+}
+
+/// The route for GET /metrics[/job/<job>[/<label>/<value>...]] requests - renders the aggregated metrics (just the series
+/// with the path's labels, if it has any), in OpenMetrics format if the client asks for it (and the Prometheus text
+/// format otherwise), gzipping them if the client allows it. Scrapes get an ETag, and ones with an `If-None-Match` that
+/// matches it get a 304 without a body
+#[allow(clippy::too_many_arguments)]
+async fn get_metrics(_tenant: Option<String>, agg: Aggregator, conf: Arc<RoutesConfig>, url_tail: Tail, method: Method, query: Vec<(String, String)>, accept: Option<String>, accept_encoding: Option<String>, if_none_match: Option<String>) -> Result<impl warp::Reply, warp::Rejection> {
+    // Like Prometheus federation, every `match[]` parameter is a selector, and series matching any of them are returned
+    let mut selectors = query.iter()
+        .filter(|(key, _)| key == MATCH_PARAM)
+        .map(|(_, selector)| Selector::from_str(selector))
+        .collect::<Result<Vec<Selector>, AggregationError>>()
+        .map_err(|e| warp::reject::custom(GravelError::AggregationError(e)))?;
+
+    // A label path (e.g. /metrics/job/foo) narrows the scrape down to the series with those labels, the ones that a push
+    // to the same path would have given them
+    let path_labels = parse_label_path(url_tail.as_str(), conf.strict_label_paths).map_err(warp::reject::custom)?;
+    if !path_labels.is_empty() {
+        let path_selector = Selector::from_labels(&borrow_labels(&path_labels));
+        selectors = match selectors.is_empty() {
+            true => vec![path_selector],
+            false => selectors.into_iter().map(|selector| selector.and(&path_selector)).collect(),
+        };
+    }
+
+    let openmetrics = accept.as_deref().is_some_and(|a| header_allows(a, "application/openmetrics-text"));
+    let content_type = match openmetrics {
+        true => "application/openmetrics-text; version=1.0.0; charset=utf-8",
+        false => "text/plain; version=0.0.4",
     };
 
-    match agg.parse_and_merge(&body, &labels).await {
-        Ok(_) => Ok(""),
-        Err(e) => Err(warp::reject::custom(GravelError::AggregationError(e))),
+    let gzip = accept_encoding.as_deref().is_some_and(|a| header_allows(a, "gzip"));
+    // The ETag is known before anything's rendered, so if nothing's changed since the client last scraped, the 304 goes
+    // back without the store being rendered (or gzipped) at all
+    let etag = scrape_etag(agg.generation(), openmetrics, gzip);
+    let response = Response::builder().header(CONTENT_TYPE, content_type).header(ETAG, &etag);
+    if if_none_match.as_deref().is_some_and(|tags| etag_matches(tags, &etag)) {
+        return Ok(response.status(StatusCode::NOT_MODIFIED).body(Body::empty()).unwrap());
     }
+
+    let response = match gzip {
+        true => response.header(CONTENT_ENCODING, "gzip"),
+        false => response,
+    };
+
+    if method == Method::HEAD {
+        let length = match measure_scrape(&agg, selectors, openmetrics, gzip).await {
+            Ok(length) => length,
+            Err(e) => return Err(warp::reject::custom(GravelError::Error(format!("Failed to gzip response: {}", e))))
+        };
+        return Ok(response.header(CONTENT_LENGTH, length).body(Body::empty()).unwrap());
+    }
+
+    Ok(response.body(stream_scrape(&agg, selectors, openmetrics, gzip)).unwrap())
+}
+
+/// The route for GET /metrics/graphite requests - renders the aggregated metrics in Graphite plaintext, for dashboards
+/// that can't read the Prometheus formats
+async fn get_graphite_metrics(_tenant: Option<String>, agg: Aggregator, conf: Arc<RoutesConfig>) -> Result<impl warp::Reply, warp::Rejection> {
+    let body = agg.to_graphite_string(conf.graphite_prefix.as_deref()).await;
+    Ok(Response::builder().header(CONTENT_TYPE, "text/plain").body(Body::from(body)).unwrap())
+}
+
+/// Serves everything in the default store, in a form that `/-/restore` can bring back (here, or on another gateway)
+async fn get_snapshot(conf: Arc<RoutesConfig>, authorization: Option<String>, tenants: Tenants) -> Result<impl warp::Reply, warp::Rejection> {
+    // It's every job, so it's authorized like wiping everything is
+    authorize(&conf, authorization.as_deref(), None, &HashMap::new())?;
+    Ok(warp::reply::json(&tenants.get(None).to_snapshot().await))
+}
+
+/// Lists every job in the default store, with how many series it has and when it was last pushed to
+async fn get_jobs(conf: Arc<RoutesConfig>, authorization: Option<String>, tenants: Tenants) -> Result<impl warp::Reply, warp::Rejection> {
+    // Like a snapshot, it's every job, so it's authorized like wiping everything is
+    authorize(&conf, authorization.as_deref(), None, &HashMap::new())?;
+    Ok(warp::reply::json(&tenants.get(None).job_summary().await))
+}
+
+/// Replaces everything in the default store with a snapshot from `/-/snapshot`
+async fn restore_snapshot(conf: Arc<RoutesConfig>, authorization: Option<String>, body: Bytes, tenants: Tenants) -> Result<impl warp::Reply, warp::Rejection> {
+    authorize(&conf, authorization.as_deref(), None, &HashMap::new())?;
+    let snapshot: StoreSnapshot = match serde_json::from_slice(&body) {
+        Ok(snapshot) => snapshot,
+        Err(e) => {
+            // A snapshot in another version's format may well not parse, which is better reported as the version being wrong
+            let error = match serde_json::from_slice::<SnapshotVersion>(&body).map(|snapshot| check_version(snapshot.version)) {
+                Ok(Err(e)) => e,
+                _ => format!("Invalid snapshot: {}", e),
+            };
+            return Err(warp::reject::custom(GravelError::Error(error)));
+        }
+    };
+
+    tenants.get(None).restore_snapshot(snapshot).await.map_err(|e| warp::reject::custom(GravelError::AggregationError(e)))?;
+    debug!("restored snapshot");
+    Ok("")
+}
+
+/// The route for DELETE /metrics requests - wipes every family from the aggregator
+async fn delete_metrics(tenant: Option<String>, mut agg: Aggregator, conf: Arc<RoutesConfig>, authorization: Option<String>) -> Result<impl warp::Reply, warp::Rejection> {
+    // Wiping everything touches every job, so it's authorized as if there were no labels
+    authorize(&conf, authorization.as_deref(), tenant.as_deref(), &HashMap::new())?;
+    agg.clear().await;
+    Ok("")
 }
 
-async fn get_metrics(agg: Aggregator) -> Result<impl warp::Reply, warp::Rejection> {
-    Ok(agg.to_string().await)
-}
\ No newline at end of file
+/// The route for DELETE /metrics/<label>/<value>... requests - removes every series carrying
+/// the given labels. Bare DELETE /metrics is handled by `delete_metrics`. When clustering, the delete
+/// goes to the peers that own the labels, the same as a push with them would
+#[allow(clippy::too_many_arguments)]
+#[cfg_attr(not(feature="clustering"), allow(unused_variables))]
+async fn delete_matching_metrics(tenant: Option<String>, mut agg: Aggregator, conf: Arc<RoutesConfig>, authorization: Option<String>, forwarded: Option<String>, request_id: String, url_tail: Tail) -> Result<impl warp::Reply, warp::Rejection> {
+    let path_labels = parse_label_path(url_tail.as_str(), conf.strict_label_paths).map_err(warp::reject::custom)?;
+    let labels = borrow_labels(&path_labels);
+    authorize(&conf, authorization.as_deref(), tenant.as_deref(), &labels)?;
+    if labels.is_empty() {
+        return Err(warp::reject::custom(GravelError::Error("No labels given to delete by".into())));
+    }
+
+    let owners = Owners::for_labels(&conf, forwarded.is_some(), &labels);
+    if owners.local {
+        agg.delete_matching(&labels).await;
+    }
+
+    #[cfg(feature="clustering")]
+    if let Some(cluster_conf) = conf.cluster_conf.as_ref().filter(|_| forwarded.is_none()) {
+        let peers = owners.remote_peers();
+        let path = metrics_path(tenant.as_deref(), url_tail.as_str());
+        let credentials = authorization.as_deref().map(|authorization| (&conf.auth_header, authorization));
+        let results = futures::future::join_all(peers.iter().map(|peer| forward_delete_to_peer(cluster_conf, agg.metrics(), peer, &path, &request_id, credentials))).await;
+        owners.check_quorum(results).map_err(warp::reject::custom)?;
+    }
+
+    Ok("")
+}
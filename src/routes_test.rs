@@ -0,0 +1,1986 @@
+use std::{collections::HashMap, io::Write};
+
+use flate2::{Compression, write::GzEncoder};
+use warp::http::{StatusCode, header::{AUTHORIZATION, HeaderName}};
+
+use crate::aggregator::{Aggregator, AggregatorConfig};
+use crate::auth::{AuthConfig, Authenticator, BearerAuthenticator, JobAuthenticator, TenantAuthenticator, pass_through_auth};
+use crate::rate_limit::RateLimit;
+use crate::routes::{RoutesConfig, SharedRoutesConfig, get_routes};
+use crate::server::reload_on;
+#[cfg(feature="clustering")]
+use crate::clustering::ClusterConfig;
+
+struct DenyAllAuthenticator {}
+
+impl Authenticator for DenyAllAuthenticator {
+    fn authenticate(&self, _: &str) -> Result<bool, anyhow::Error> {
+        Ok(false)
+    }
+}
+
+fn test_config() -> RoutesConfig {
+    RoutesConfig {
+        authenticator: Box::new(pass_through_auth()),
+        auth_header: AUTHORIZATION,
+        max_body_bytes: 1024,
+        rate_limit: None,
+        max_in_flight_pushes: 64,
+        cors_allowed_origins: Vec::new(),
+        default_job: None,
+        strict_label_paths: false,
+        graphite_prefix: None,
+        #[cfg(feature="clustering")]
+        cluster_conf: None,
+    }
+}
+
+/// Strips the last push timestamps out of a scrape, for tests that only care about what was pushed
+fn without_last_pushes(scrape: &str) -> String {
+    scrape.lines().filter(|line| !line.contains("gravel_last_push_timestamp_seconds")).map(|line| format!("{}\n", line)).collect()
+}
+
+#[tokio::test]
+async fn test_delete_clears_all_metrics() {
+    let routes = get_routes(Aggregator::new(), test_config());
+
+    let resp = warp::test::request().method("POST").path("/metrics")
+        .body("# TYPE foo_total counter\nfoo_total 1\n# TYPE bar gauge\nbar 2\n")
+        .reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let resp = warp::test::request().method("GET").path("/metrics").reply(&routes).await;
+    assert!(!resp.body().is_empty(), "expected pushed metrics to be scraped");
+
+    let resp = warp::test::request().method("DELETE").path("/metrics").reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(resp.body().is_empty());
+
+    let resp = warp::test::request().method("GET").path("/metrics").reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(resp.body().is_empty(), "expected no metrics after DELETE, got {:?}", resp.body());
+}
+
+#[tokio::test]
+async fn test_delete_requires_auth() {
+    let agg = Aggregator::new();
+    let mut config = test_config();
+    config.authenticator = Box::new(DenyAllAuthenticator{});
+    let routes = get_routes(agg.clone(), config);
+
+    let mut push_agg = agg.clone();
+    push_agg.parse_and_merge("foo 1\n", &Default::default()).await.unwrap();
+
+    let resp = warp::test::request().method("DELETE").path("/metrics").reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    assert!(!agg.to_string().await.is_empty(), "DELETE without auth should not clear metrics");
+}
+
+#[tokio::test]
+async fn test_delete_single_job() {
+    let agg = Aggregator::new();
+    let routes = get_routes(agg.clone(), test_config());
+
+    for job in ["foo", "bar"] {
+        let resp = warp::test::request().method("POST").path(&format!("/metrics/job/{}", job))
+            .body("requests_total 1\n")
+            .reply(&routes).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    let resp = warp::test::request().method("DELETE").path("/metrics/job/foo").reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let output = agg.to_string().await;
+    assert!(!output.contains("job=\"foo\""), "job foo should have been deleted: {}", output);
+    assert!(output.contains("job=\"bar\""), "job bar should have survived: {}", output);
+}
+
+#[tokio::test]
+async fn test_delete_multiple_labels() {
+    let agg = Aggregator::new();
+    let routes = get_routes(agg.clone(), test_config());
+
+    for path in ["/metrics/job/foo/instance/bar", "/metrics/job/foo/instance/baz"] {
+        let resp = warp::test::request().method("POST").path(path)
+            .body("requests_total 1\n")
+            .reply(&routes).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    let resp = warp::test::request().method("DELETE").path("/metrics/job/foo/instance/bar").reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let output = agg.to_string().await;
+    assert!(!output.contains("instance=\"bar\""), "instance bar should have been deleted: {}", output);
+    assert!(output.contains("instance=\"baz\""), "instance baz should have survived: {}", output);
+}
+
+#[tokio::test]
+async fn test_delete_nonexistent_job() {
+    let routes = get_routes(Aggregator::new(), test_config());
+
+    let resp = warp::test::request().method("DELETE").path("/metrics/job/nope").reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_delete_empty_labelset_rejected() {
+    let agg = Aggregator::new();
+    let routes = get_routes(agg.clone(), test_config());
+    agg.clone().parse_and_merge("foo 1\n", &Default::default()).await.unwrap();
+
+    let resp = warp::test::request().method("DELETE").path("/metrics//foo").reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    assert!(!agg.to_string().await.is_empty());
+}
+
+fn gzip(data: &str) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data.as_bytes()).unwrap();
+    encoder.finish().unwrap()
+}
+
+#[tokio::test]
+async fn test_push_gzip_body() {
+    let agg = Aggregator::new();
+    let routes = get_routes(agg.clone(), test_config());
+
+    let resp = warp::test::request().method("POST").path("/metrics")
+        .header("content-encoding", "gzip")
+        .body(gzip("# TYPE foo_total counter\nfoo_total 5\n"))
+        .reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(agg.to_string().await.contains("foo_total 5"));
+}
+
+#[tokio::test]
+async fn test_push_identity_body() {
+    let agg = Aggregator::new();
+    let routes = get_routes(agg.clone(), test_config());
+
+    let resp = warp::test::request().method("POST").path("/metrics")
+        .header("content-encoding", "identity")
+        .body("foo 1\n")
+        .reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(agg.to_string().await.contains("foo 1"));
+}
+
+#[tokio::test]
+async fn test_push_invalid_gzip_body() {
+    let agg = Aggregator::new();
+    let routes = get_routes(agg.clone(), test_config());
+
+    let resp = warp::test::request().method("POST").path("/metrics")
+        .header("content-encoding", "gzip")
+        .body("foo 1\n")
+        .reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    assert!(agg.to_string().await.is_empty());
+}
+
+#[tokio::test]
+async fn test_scrape_gzip() {
+    use std::io::Read;
+    use flate2::read::GzDecoder;
+
+    let agg = Aggregator::new();
+    let routes = get_routes(agg.clone(), test_config());
+    agg.clone().parse_and_merge("# TYPE foo_total counter\nfoo_total 5\n# TYPE bar gauge\nbar 2\n", &Default::default()).await.unwrap();
+
+    let plain = warp::test::request().method("GET").path("/metrics").reply(&routes).await;
+    assert_eq!(plain.status(), StatusCode::OK);
+    assert!(plain.headers().get("content-encoding").is_none());
+    assert_eq!(plain.headers().get("content-type").unwrap(), "text/plain; version=0.0.4");
+
+    let gzipped = warp::test::request().method("GET").path("/metrics")
+        .header("accept-encoding", "deflate, gzip;q=0.8")
+        .reply(&routes).await;
+    assert_eq!(gzipped.status(), StatusCode::OK);
+    assert_eq!(gzipped.headers().get("content-encoding").unwrap(), "gzip");
+    assert_eq!(gzipped.headers().get("content-type").unwrap(), "text/plain; version=0.0.4");
+
+    let mut decoded = Vec::new();
+    GzDecoder::new(gzipped.body().as_ref()).read_to_end(&mut decoded).unwrap();
+    assert_eq!(decoded, plain.body().as_ref());
+
+    let refused = warp::test::request().method("GET").path("/metrics")
+        .header("accept-encoding", "gzip;q=0")
+        .reply(&routes).await;
+    assert!(refused.headers().get("content-encoding").is_none());
+    assert_eq!(refused.body(), plain.body());
+}
+
+#[tokio::test]
+async fn test_scrape_openmetrics() {
+    let agg = Aggregator::new();
+    let routes = get_routes(agg.clone(), test_config());
+    agg.clone().parse_and_merge("# TYPE foo_total counter\nfoo_total 5\n", &Default::default()).await.unwrap();
+
+    let resp = warp::test::request().method("GET").path("/metrics")
+        .header("accept", "application/openmetrics-text; version=1.0.0,text/plain;version=0.0.4;q=0.5")
+        .reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers().get("content-type").unwrap(), "application/openmetrics-text; version=1.0.0; charset=utf-8");
+    assert_eq!(resp.body(), "# TYPE foo counter\nfoo_total 5\n# EOF\n");
+
+    let resp = warp::test::request().method("GET").path("/metrics")
+        .header("accept", "text/plain")
+        .reply(&routes).await;
+    assert_eq!(resp.headers().get("content-type").unwrap(), "text/plain; version=0.0.4");
+    assert_eq!(resp.body(), "# TYPE foo_total counter\nfoo_total 5\n");
+}
+
+#[tokio::test]
+async fn test_push_format_follows_content_type() {
+    let agg = Aggregator::new();
+    let routes = get_routes(agg.clone(), test_config());
+    let push = |content_type: &str, body: &str| warp::test::request().method("POST").path("/metrics/job/foo")
+        .header("content-type", content_type)
+        .body(body);
+
+    // OpenMetrics declares counters without their _total suffix, and has to end with # EOF
+    let openmetrics = "# TYPE requests counter\n# HELP requests Requests served.\nrequests_total{code=\"200\"} 3\nrequests_created{code=\"200\"} 1700000000\n# EOF\n";
+    let resp = push("application/openmetrics-text; version=1.0.0; charset=utf-8", openmetrics).reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::OK, "{:?}", resp.body());
+    let resp = push("application/openmetrics-text; version=1.0.0", "# TYPE requests counter\nrequests_total{code=\"200\"} 1\n").reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+    // The text format declares them with it, and doesn't need anything at the end
+    let resp = push("text/plain; version=0.0.4", "# TYPE requests_total counter\nrequests_total{code=\"200\"} 2\n").reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::OK, "{:?}", resp.body());
+
+    let scrape = without_last_pushes(&agg.to_string().await);
+    assert_eq!(scrape, "# TYPE requests_created gauge
+requests_created{code=\"200\",job=\"foo\"} 1700000000
+# HELP requests_total Requests served.
+# TYPE requests_total counter
+requests_total{code=\"200\",job=\"foo\"} 5
+");
+}
+
+#[tokio::test]
+async fn test_scrape_with_selectors() {
+    let agg = Aggregator::new();
+    let routes = get_routes(agg.clone(), test_config());
+    agg.clone().parse_and_merge("# TYPE foo_total counter\nfoo_total{job=\"api\"} 5\nfoo_total{job=\"web\"} 3\n# TYPE bar gauge\nbar{job=\"api\"} 2\n", &Default::default()).await.unwrap();
+
+    // match[]=foo_total&match[]={job="api"}
+    let resp = warp::test::request().method("GET").path("/metrics?match%5B%5D=foo_total&match%5B%5D=%7Bjob%3D%22api%22%7D").reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.body(), "# TYPE bar gauge\nbar{job=\"api\"} 2\n# TYPE foo_total counter\nfoo_total{job=\"api\"} 5\nfoo_total{job=\"web\"} 3\n");
+
+    let resp = warp::test::request().method("GET").path("/metrics?match%5B%5D=foo_total%7Bjob%3D%22web%22%7D")
+        .header("accept", "application/openmetrics-text")
+        .reply(&routes).await;
+    assert_eq!(resp.body(), "# TYPE foo counter\nfoo_total{job=\"web\"} 3\n# EOF\n");
+
+    let resp = warp::test::request().method("GET").path("/metrics?match%5B%5D=%7Bjob%3D~%22a.*%22%7D").reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_scrape_a_label_path() {
+    let agg = Aggregator::new();
+    let routes = get_routes(agg.clone(), test_config());
+    for path in ["/metrics/job/api/instance/a", "/metrics/job/api/instance/b", "/metrics/job/web/instance/a", "/metrics/job/a%2Fb/instance/c"] {
+        let resp = warp::test::request().method("POST").path(path).body("# TYPE foo_total counter\nfoo_total 1\n# TYPE bar gauge\nbar 2\n").reply(&routes).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    // The job's last push is a series with its label too, so it's in the job's scrape
+    let resp = warp::test::request().method("GET").path("/metrics/job/api").reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let scrape = String::from_utf8_lossy(resp.body());
+    assert!(scrape.starts_with("# TYPE bar gauge
+bar{instance=\"a\",job=\"api\"} 2
+bar{instance=\"b\",job=\"api\"} 2
+# TYPE foo_total counter
+foo_total{instance=\"a\",job=\"api\"} 1
+foo_total{instance=\"b\",job=\"api\"} 1
+"), "{}", scrape);
+    assert!(scrape.contains("\ngravel_last_push_timestamp_seconds{job=\"api\"} "), "{}", scrape);
+    assert!(!scrape.contains("web"), "{}", scrape);
+
+    let resp = warp::test::request().method("GET").path("/metrics/job/api/instance/b").reply(&routes).await;
+    assert_eq!(resp.body(), "# TYPE bar gauge\nbar{instance=\"b\",job=\"api\"} 2\n# TYPE foo_total counter\nfoo_total{instance=\"b\",job=\"api\"} 1\n");
+
+    // Values are decoded like they are for pushes
+    let resp = warp::test::request().method("GET").path("/metrics/job@base64/YS9i/instance/c").reply(&routes).await;
+    assert_eq!(resp.body(), "# TYPE bar gauge\nbar{instance=\"c\",job=\"a/b\"} 2\n# TYPE foo_total counter\nfoo_total{instance=\"c\",job=\"a/b\"} 1\n");
+
+    // match[] selectors are narrowed down to the path's series
+    let resp = warp::test::request().method("GET").path("/metrics/instance/a?match%5B%5D=foo_total").reply(&routes).await;
+    assert_eq!(resp.body(), "# TYPE foo_total counter\nfoo_total{instance=\"a\",job=\"api\"} 1\nfoo_total{instance=\"a\",job=\"web\"} 1\n");
+
+    let resp = warp::test::request().method("GET").path("/metrics/job/nobody").reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.body(), "");
+
+    // A bare scrape still has everything, and the Graphite scrape isn't taken for a label path
+    let resp = warp::test::request().method("GET").path("/metrics").reply(&routes).await;
+    assert_eq!(String::from_utf8_lossy(resp.body()).lines().filter(|line| !line.starts_with('#')).count(), 11);
+    let resp = warp::test::request().method("GET").path("/metrics/graphite").reply(&routes).await;
+    assert!(String::from_utf8_lossy(resp.body()).starts_with("bar.instance.a.job.api 2 "), "{:?}", resp.body());
+}
+
+#[tokio::test]
+async fn test_scrape_head() {
+    let agg = Aggregator::new();
+    let routes = get_routes(agg.clone(), test_config());
+    agg.clone().parse_and_merge("# TYPE foo_total counter\nfoo_total 5\n", &Default::default()).await.unwrap();
+
+    let get = warp::test::request().method("GET").path("/metrics").reply(&routes).await;
+    let head = warp::test::request().method("HEAD").path("/metrics").reply(&routes).await;
+    assert_eq!(head.status(), StatusCode::OK);
+    assert_eq!(head.headers().get("content-type").unwrap(), "text/plain; version=0.0.4");
+    assert_eq!(head.headers().get("content-length").unwrap().to_str().unwrap(), get.body().len().to_string());
+    assert!(head.body().is_empty());
+
+    let head = warp::test::request().method("HEAD").path("/metrics").header("accept", "application/openmetrics-text").reply(&routes).await;
+    assert_eq!(head.headers().get("content-type").unwrap(), "application/openmetrics-text; version=1.0.0; charset=utf-8");
+    assert!(head.body().is_empty());
+}
+
+#[tokio::test]
+async fn test_push_over_body_limit() {
+    let agg = Aggregator::new();
+    let routes = get_routes(agg.clone(), test_config());
+    let body = "foo 1\n".repeat(200);
+
+    let resp = warp::test::request().method("POST").path("/metrics")
+        .body(body.clone())
+        .reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+    // Small enough on the wire, but not once it's been decoded
+    let resp = warp::test::request().method("POST").path("/metrics")
+        .header("content-encoding", "gzip")
+        .body(gzip(&body))
+        .reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    assert!(agg.to_string().await.is_empty());
+}
+
+#[tokio::test]
+async fn test_per_job_authorization() {
+    let agg = Aggregator::new();
+    let mut allowed_jobs = HashMap::new();
+    allowed_jobs.insert("token-a".to_owned(), vec!["team-a-*".to_owned()]);
+    let mut config = test_config();
+    config.authenticator = Box::new(JobAuthenticator::new(allowed_jobs));
+    let routes = get_routes(agg.clone(), config);
+
+    let push = |path: &'static str, token: &'static str| warp::test::request().method("POST").path(path)
+        .header("authorization", token)
+        .body("requests_total 1\n");
+
+    let resp = push("/metrics/job/team-a-web", "Bearer token-a").reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // A valid token, but not for this job
+    let resp = push("/metrics/job/team-b-web", "Bearer token-a").reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    let resp = push("/metrics", "Bearer token-a").reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    let resp = push("/metrics/job/team-a-web", "Bearer token-b").reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    let resp = warp::test::request().method("DELETE").path("/metrics").header("authorization", "Bearer token-a").reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    let resp = warp::test::request().method("DELETE").path("/metrics/job/team-b-web").header("authorization", "Bearer token-a").reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    assert_eq!(without_last_pushes(&agg.to_string().await), "requests_total{job=\"team-a-web\"} 1\n");
+}
+
+#[tokio::test]
+async fn test_custom_auth_header() {
+    let agg = Aggregator::new();
+    let mut config = test_config();
+    config.authenticator = Box::new(JobAuthenticator::new(vec![("token".to_owned(), vec!["*".to_owned()])].into_iter().collect()));
+    config.auth_header = HeaderName::from_static("x-gateway-token");
+    let routes = get_routes(agg.clone(), config);
+
+    let push = |header: &'static str| warp::test::request().method("POST").path("/metrics/job/web")
+        .header(header, "Bearer token")
+        .body("requests_total 1\n");
+
+    let resp = push("x-gateway-token").reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // Credentials in the usual header are ignored
+    let resp = push("authorization").reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    let resp = warp::test::request().method("DELETE").path("/metrics/job/web").header("x-gateway-token", "Bearer token").reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(without_last_pushes(&agg.to_string().await).is_empty());
+}
+
+#[tokio::test]
+async fn test_max_in_flight_pushes() {
+    use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpStream};
+
+    let agg = Aggregator::new();
+    let (addr, server) = warp::serve(get_routes(agg.clone(), RoutesConfig { max_in_flight_pushes: 2, ..test_config() })).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(server);
+
+    // Two slow pushes, whose bodies haven't all arrived yet, hold every permit
+    let body = "# TYPE requests_total counter\nrequests_total 1\n";
+    let (head, tail) = body.split_at(10);
+    let mut slow = Vec::new();
+    for _ in 0..2 {
+        let mut conn = TcpStream::connect(addr).await.unwrap();
+        let request = format!("POST /metrics/job/slow HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}", body.len(), head);
+        conn.write_all(request.as_bytes()).await.unwrap();
+        slow.push(conn);
+    }
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+    let client = reqwest::Client::new();
+    let push = || client.post(format!("http://{}/metrics/job/fast", addr)).body(body).send();
+    let resp = push().await.unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(resp.headers()["retry-after"], "1");
+    assert_eq!(resp.text().await.unwrap(), "TOO_MANY_PUSHES");
+
+    // Scrapes aren't pushes, so they aren't held up
+    assert_eq!(client.get(format!("http://{}/metrics", addr)).send().await.unwrap().status(), reqwest::StatusCode::OK);
+
+    // Once the slow pushes finish, their permits are free again
+    for mut conn in slow {
+        conn.write_all(tail.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        conn.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    }
+    assert_eq!(push().await.unwrap().status(), reqwest::StatusCode::OK);
+    let scrape = agg.to_string().await;
+    assert!(scrape.contains("requests_total{job=\"slow\"} 2\n") && scrape.contains("requests_total{job=\"fast\"} 1\n"), "{}", scrape);
+}
+
+#[tokio::test]
+async fn test_rate_limit() {
+    let routes = get_routes(Aggregator::new(), RoutesConfig {
+        authenticator: Box::new(BearerAuthenticator::new(vec![String::from("a"), String::from("b")])),
+        rate_limit: Some(RateLimit { per_second: 10., burst: 2 }),
+        ..test_config()
+    });
+
+    let push = |token: &str| warp::test::request().method("POST").path("/metrics/job/foo")
+        .header("authorization", format!("Bearer {}", token))
+        .body("requests_total 1\n");
+
+    for _ in 0..2 {
+        assert_eq!(push("a").reply(&routes).await.status(), StatusCode::OK);
+    }
+    let resp = push("a").reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(resp.headers()["retry-after"], "1");
+
+    // Other clients have their own buckets, and the limited one gets a token back after a tenth of a second
+    assert_eq!(push("b").reply(&routes).await.status(), StatusCode::OK);
+    tokio::time::sleep(std::time::Duration::from_millis(120)).await;
+    assert_eq!(push("a").reply(&routes).await.status(), StatusCode::OK);
+    assert_eq!(push("a").reply(&routes).await.status(), StatusCode::TOO_MANY_REQUESTS);
+
+    // Saying the push was forwarded by a peer doesn't get around the limit when there aren't any peers
+    let forwarded = push("a").header("x-gravel-forwarded", "1").reply(&routes).await;
+    assert_eq!(forwarded.status(), StatusCode::TOO_MANY_REQUESTS);
+
+    // Without auth, clients are told apart by their address, whatever credentials they send
+    let routes = get_routes(Aggregator::new(), RoutesConfig {
+        rate_limit: Some(RateLimit { per_second: 10., burst: 2 }),
+        ..test_config()
+    });
+    let anonymous = |ip: [u8; 4], token: &str| warp::test::request().method("POST").path("/metrics/job/foo")
+        .remote_addr((ip, 1234).into())
+        .header("authorization", format!("Bearer {}", token))
+        .body("requests_total 1\n");
+    for token in &["a", "b"] {
+        assert_eq!(anonymous([10, 0, 0, 1], token).reply(&routes).await.status(), StatusCode::OK);
+    }
+    assert_eq!(anonymous([10, 0, 0, 1], "c").reply(&routes).await.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(anonymous([10, 0, 0, 2], "c").reply(&routes).await.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_streamed_scrape() {
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    let agg = Aggregator::new();
+    let routes = get_routes(agg.clone(), test_config());
+    let mut push = String::new();
+    for family in 0..500 {
+        push.push_str(&format!("# TYPE family_{} gauge\n", family));
+        for series in 0..20 {
+            push.push_str(&format!("family_{}{{series=\"{}\"}} {}\n", family, series, series));
+        }
+    }
+    agg.clone().parse_and_merge(&push, &HashMap::new()).await.unwrap();
+
+    let resp = warp::test::request().method("GET").path("/metrics").reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(String::from_utf8(resp.body().to_vec()).unwrap(), agg.to_string().await);
+
+    let resp = warp::test::request().method("GET").path("/metrics").header("accept-encoding", "gzip").reply(&routes).await;
+    let mut body = String::new();
+    GzDecoder::new(&resp.body()[..]).read_to_string(&mut body).unwrap();
+    assert_eq!(body, agg.to_string().await);
+
+    let head = warp::test::request().method("HEAD").path("/metrics").header("accept-encoding", "gzip").reply(&routes).await;
+    assert_eq!(head.headers()["content-length"].to_str().unwrap(), resp.body().len().to_string());
+}
+
+#[tokio::test]
+async fn test_scrape_etag() {
+    let routes = get_routes(Aggregator::new(), test_config());
+    let push = |body: &'static str| warp::test::request().method("POST").path("/metrics/job/foo").body(body);
+    assert_eq!(push("requests_total 1\n").reply(&routes).await.status(), StatusCode::OK);
+
+    let resp = warp::test::request().method("GET").path("/metrics").reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let etag = resp.headers()["etag"].to_str().unwrap().to_owned();
+
+    // The ETag is of the store's generation, not of what was rendered, so it's weak
+    assert!(etag.starts_with("W/\""), "{}", etag);
+
+    // Nothing was pushed in between, so the ETag is the same and the client already has it
+    let resp = warp::test::request().method("GET").path("/metrics").header("if-none-match", &etag).reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(resp.headers()["etag"], etag.as_str());
+    assert!(resp.body().is_empty());
+
+    let resp = warp::test::request().method("GET").path("/metrics").header("if-none-match", format!("\"other\", {}", etag.trim_start_matches("W/"))).reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+
+    // A HEAD that matches is answered before the scrape is measured, so it doesn't get a length
+    let resp = warp::test::request().method("HEAD").path("/metrics").header("if-none-match", &etag).reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+    assert!(!resp.headers().contains_key("content-length"));
+
+    // The gzipped scrape is a different representation, so it doesn't match
+    let resp = warp::test::request().method("GET").path("/metrics").header("if-none-match", &etag).header("accept-encoding", "gzip").reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_ne!(resp.headers()["etag"], etag.as_str());
+
+    // And so is the OpenMetrics one
+    let resp = warp::test::request().method("GET").path("/metrics").header("if-none-match", &etag).header("accept", "application/openmetrics-text").reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_ne!(resp.headers()["etag"], etag.as_str());
+
+    assert_eq!(push("requests_total 1\n").reply(&routes).await.status(), StatusCode::OK);
+    let resp = warp::test::request().method("GET").path("/metrics").header("if-none-match", &etag).reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_ne!(resp.headers()["etag"], etag.as_str());
+    assert!(String::from_utf8(resp.body().to_vec()).unwrap().contains("requests_total{job=\"foo\"} 2\n"));
+
+    // Deleting changes what a scrape returns just as much as pushing does
+    let etag = resp.headers()["etag"].to_str().unwrap().to_owned();
+    assert_eq!(warp::test::request().method("DELETE").path("/metrics/job/foo").reply(&routes).await.status(), StatusCode::OK);
+    let resp = warp::test::request().method("GET").path("/metrics").header("if-none-match", &etag).reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_ne!(resp.headers()["etag"], etag.as_str());
+}
+
+#[tokio::test]
+async fn test_graphite_metrics() {
+    let routes = get_routes(Aggregator::new(), RoutesConfig {
+        graphite_prefix: Some(String::from("gravel")),
+        ..test_config()
+    });
+
+    let push = warp::test::request().method("POST").path("/metrics/job/foo").body("# TYPE requests_total counter\nrequests_total{code=\"200\"} 2\n").reply(&routes).await;
+    assert_eq!(push.status(), StatusCode::OK);
+
+    let resp = warp::test::request().method("GET").path("/metrics/graphite").reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = String::from_utf8(resp.body().to_vec()).unwrap();
+    let lines: Vec<&str> = body.lines().collect();
+    assert_eq!(lines.len(), 2, "{}", body);
+    assert!(lines[0].starts_with("gravel.gravel_last_push_timestamp_seconds.job.foo "));
+    assert!(lines[1].starts_with("gravel.requests_total.code.200.job.foo 2 "));
+}
+
+#[tokio::test]
+async fn test_cors() {
+    let routes = get_routes(Aggregator::new(), RoutesConfig {
+        cors_allowed_origins: vec![String::from("https://dashboard.example.com")],
+        ..test_config()
+    });
+
+    let preflight = warp::test::request().method("OPTIONS").path("/metrics")
+        .header("origin", "https://dashboard.example.com")
+        .header("access-control-request-method", "GET")
+        .reply(&routes).await;
+    assert_eq!(preflight.status(), StatusCode::NO_CONTENT);
+    assert_eq!(preflight.headers()["access-control-allow-origin"], "https://dashboard.example.com");
+    assert_eq!(preflight.headers()["access-control-allow-methods"], "GET, HEAD, OPTIONS");
+
+    let preflight = warp::test::request().method("OPTIONS").path("/-/metrics")
+        .header("origin", "https://evil.example.com")
+        .header("access-control-request-method", "GET")
+        .reply(&routes).await;
+    assert_eq!(preflight.status(), StatusCode::FORBIDDEN);
+
+    for path in ["/metrics", "/-/metrics"] {
+        let resp = warp::test::request().path(path).header("origin", "https://dashboard.example.com").reply(&routes).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()["access-control-allow-origin"], "https://dashboard.example.com");
+        assert_eq!(resp.headers()["vary"], "Origin");
+
+        let resp = warp::test::request().path(path).header("origin", "https://evil.example.com").reply(&routes).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(!resp.headers().contains_key("access-control-allow-origin"));
+    }
+
+    // Pushes are never readable from browsers, and without any allowed origins neither are scrapes
+    let resp = warp::test::request().method("POST").path("/metrics/job/foo").header("origin", "https://dashboard.example.com").body("requests_total 1\n").reply(&routes).await;
+    assert!(!resp.headers().contains_key("access-control-allow-origin"));
+
+    let routes = get_routes(Aggregator::new(), test_config());
+    let resp = warp::test::request().path("/metrics").header("origin", "https://dashboard.example.com").reply(&routes).await;
+    assert!(!resp.headers().contains_key("access-control-allow-origin"));
+    let preflight = warp::test::request().method("OPTIONS").path("/metrics")
+        .header("origin", "https://dashboard.example.com")
+        .header("access-control-request-method", "GET")
+        .reply(&routes).await;
+    assert!(!preflight.headers().contains_key("access-control-allow-origin"));
+}
+
+#[tokio::test]
+async fn test_idempotency_key_dedupes_retries() {
+    let agg = Aggregator::new();
+    let routes = get_routes(agg.clone(), test_config());
+
+    let push = |job: &str, key: &str, body: &'static str| warp::test::request().method("POST").path(&format!("/metrics/job/{}", job))
+        .header("x-idempotency-key", key)
+        .body(body);
+
+    for _ in 0..2 {
+        assert_eq!(push("web", "push-1", "requests_total 1\n").reply(&routes).await.status(), StatusCode::OK);
+    }
+    assert!(agg.to_string().await.contains("requests_total{job=\"web\"} 1\n"), "{}", agg.to_string().await);
+
+    // Keys are per job, and a push that failed can be retried with the same key
+    assert_eq!(push("api", "push-1", "requests_total 1\n").reply(&routes).await.status(), StatusCode::OK);
+    assert_eq!(push("web", "push-2", "requests_total{\n").reply(&routes).await.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(push("web", "push-2", "requests_total 1\n").reply(&routes).await.status(), StatusCode::OK);
+    let scrape = agg.to_string().await;
+    assert!(scrape.contains("requests_total{job=\"web\"} 2\n") && scrape.contains("requests_total{job=\"api\"} 1\n"), "{}", scrape);
+}
+
+#[tokio::test]
+async fn test_idempotency_key_retries_of_failed_pushes() {
+    let agg = Aggregator::with_config(AggregatorConfig { max_series_per_family: Some(1), ..AggregatorConfig::default() });
+    let routes = get_routes(agg.clone(), test_config());
+    let push = |body: &'static str| warp::test::request().method("POST").path("/metrics/job/web")
+        .header("x-idempotency-key", "push-1")
+        .body(body);
+
+    // The second family is over its limit, so the first one mustn't be merged either, or the retry would count it twice
+    let body = "# TYPE requests_total counter\nrequests_total 1\n# TYPE up gauge\nup{pod=\"a\"} 1\nup{pod=\"b\"} 1\n";
+    for _ in 0..2 {
+        assert_eq!(push(body).reply(&routes).await.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(agg.to_string().await, "");
+    }
+}
+
+#[tokio::test]
+async fn test_invalid_utf8_push() {
+    let body = &b"# HELP up Whether it\xffs up\n# TYPE up gauge\nup 1\n"[..];
+
+    let strict = Aggregator::new();
+    let routes = get_routes(strict.clone(), test_config());
+    let resp = warp::test::request().method("POST").path("/metrics").body(body).reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    assert!(strict.to_string().await.is_empty());
+
+    let lossy = Aggregator::with_config(AggregatorConfig { utf8_lossy: true, ..AggregatorConfig::default() });
+    let routes = get_routes(lossy.clone(), test_config());
+    let resp = warp::test::request().method("POST").path("/metrics").body(body).reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(without_last_pushes(&lossy.to_string().await), "# HELP up Whether it\u{fffd}s up\n# TYPE up gauge\nup 1\n");
+}
+
+#[tokio::test]
+async fn test_skip_malformed_lines() {
+    let body = "# TYPE requests_total counter\nrequests_total{code=\"200\"} 5\nrequests_total{code=\"500\" 1\nrequests_total{code=\"404\"} 2\n# TYPE up gauge\nup{instance=\"a\"} 1 2 3\nup{instance=\"b\"} 1\n";
+
+    let strict = Aggregator::new();
+    let routes = get_routes(strict.clone(), test_config());
+    let resp = warp::test::request().method("POST").path("/metrics/job/foo").body(body).reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    assert!(strict.to_string().await.is_empty());
+
+    let lenient = Aggregator::with_config(AggregatorConfig { skip_malformed_lines: true, ..AggregatorConfig::default() });
+    let routes = get_routes(lenient.clone(), test_config());
+    let resp = warp::test::request().method("POST").path("/metrics/job/foo").body(body).reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers().get("x-gravel-skipped-lines").unwrap(), "2");
+    assert_eq!(without_last_pushes(&lenient.to_string().await), "# TYPE requests_total counter
+requests_total{code=\"200\",job=\"foo\"} 5
+requests_total{code=\"404\",job=\"foo\"} 2
+# TYPE up gauge
+up{instance=\"b\",job=\"foo\"} 1
+");
+
+    // Pushes without any malformed lines don't get the header
+    let resp = warp::test::request().method("POST").path("/metrics/job/foo").body("# TYPE up gauge\nup{instance=\"c\"} 1\n").reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(resp.headers().get("x-gravel-skipped-lines").is_none());
+
+    // Nor do pushes with nothing but malformed lines, which are rejected like they would have been anyway
+    let resp = warp::test::request().method("POST").path("/metrics/job/foo").body("up{ 1\n").reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+    let resp = warp::test::request().method("GET").path("/-/metrics").reply(&routes).await;
+    let metrics = String::from_utf8(resp.body().to_vec()).unwrap();
+    assert!(metrics.contains("gravel_parse_errors_total 2\n"), "{}", metrics);
+    assert!(metrics.contains("gravel_push_parse_errors_total 1\n"), "{}", metrics);
+}
+
+/// Scrapes the given path, returning the body without the last push timestamps
+async fn scrape_path<F>(routes: &F, path: &str) -> String where F: warp::Filter + 'static, F::Extract: warp::Reply + Send {
+    let resp = warp::test::request().method("GET").path(path).reply(routes).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    without_last_pushes(std::str::from_utf8(resp.body()).unwrap())
+}
+
+#[tokio::test]
+async fn test_tenants_are_isolated() {
+    let agg = Aggregator::new();
+    let routes = get_routes(agg.clone(), test_config());
+
+    for (path, body) in [("/tenants/a/metrics/job/web", "requests_total 1\n"), ("/tenants/b/metrics/job/web", "requests_total 5\n"), ("/metrics/job/web", "requests_total 10\n")] {
+        let resp = warp::test::request().method("POST").path(path).body(body).reply(&routes).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    assert_eq!(scrape_path(&routes, "/tenants/a/metrics").await, "requests_total{job=\"web\"} 1\n");
+    assert_eq!(scrape_path(&routes, "/tenants/b/metrics").await, "requests_total{job=\"web\"} 5\n");
+    assert_eq!(scrape_path(&routes, "/metrics").await, "requests_total{job=\"web\"} 10\n");
+    assert_eq!(without_last_pushes(&agg.to_string().await), "requests_total{job=\"web\"} 10\n");
+
+    // Tenants that haven't been pushed to are empty, and deleting from one tenant leaves the others alone
+    assert_eq!(scrape_path(&routes, "/tenants/c/metrics").await, "");
+    let resp = warp::test::request().method("DELETE").path("/tenants/a/metrics/job/web").reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(scrape_path(&routes, "/tenants/a/metrics").await, "");
+    assert_eq!(scrape_path(&routes, "/tenants/b/metrics").await, "requests_total{job=\"web\"} 5\n");
+
+    let resp = warp::test::request().method("POST").path("/tenants/a!/metrics").body("requests_total 1\n").reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_tenant_scoped_tokens() {
+    let mut config = test_config();
+    config.authenticator = Box::new(TenantAuthenticator::new(vec![
+        ("token-a".to_owned(), vec!["a".to_owned()]),
+        ("admin".to_owned(), vec!["*".to_owned()]),
+    ].into_iter().collect()));
+    let routes = get_routes(Aggregator::new(), config);
+
+    let push = |path: &str, token: &str| warp::test::request().method("POST").path(path)
+        .header("authorization", format!("Bearer {}", token))
+        .body("requests_total 1\n");
+
+    assert_eq!(push("/tenants/a/metrics/job/web", "token-a").reply(&routes).await.status(), StatusCode::OK);
+    assert_eq!(push("/tenants/b/metrics/job/web", "token-a").reply(&routes).await.status(), StatusCode::FORBIDDEN);
+    assert_eq!(push("/metrics/job/web", "token-a").reply(&routes).await.status(), StatusCode::FORBIDDEN);
+    assert_eq!(push("/tenants/b/metrics/job/web", "admin").reply(&routes).await.status(), StatusCode::OK);
+    assert_eq!(push("/metrics/job/web", "admin").reply(&routes).await.status(), StatusCode::OK);
+    assert_eq!(scrape_path(&routes, "/tenants/b/metrics").await, "requests_total{job=\"web\"} 1\n");
+}
+
+#[tokio::test]
+async fn test_reload_swaps_authenticator() {
+    let token_file = std::env::temp_dir().join(format!("gravel-reload-{}.tokens", std::process::id()));
+    std::fs::write(&token_file, "old\n").unwrap();
+
+    let mut config = test_config();
+    config.authenticator = AuthConfig::BearerFile { path: token_file.clone() }.build().unwrap();
+    let config = SharedRoutesConfig::new(config);
+    let routes = get_routes(Aggregator::new(), config.clone());
+
+    // Reload the same way main does: if the tokens fail to load, the old ones are kept
+    let (signal, signals) = futures::channel::mpsc::unbounded();
+    let (reloaded, mut reloads) = futures::channel::mpsc::unbounded();
+    let path = token_file.clone();
+    tokio::spawn(reload_on(signals, move || {
+        if let Ok(authenticator) = (AuthConfig::BearerFile { path: path.clone() }).build() {
+            config.reload(authenticator, #[cfg(feature="clustering")] None);
+        }
+        reloaded.unbounded_send(()).unwrap();
+    }));
+
+    let push = |token: &str| warp::test::request().method("POST").path("/metrics/job/web")
+        .header("authorization", format!("Bearer {}", token))
+        .body("requests_total 1\n");
+
+    assert_eq!(push("old").reply(&routes).await.status(), StatusCode::OK);
+    assert_eq!(push("new").reply(&routes).await.status(), StatusCode::UNAUTHORIZED);
+
+    std::fs::write(&token_file, "new\n").unwrap();
+    signal.unbounded_send(()).unwrap();
+    futures::StreamExt::next(&mut reloads).await;
+
+    assert_eq!(push("old").reply(&routes).await.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(push("new").reply(&routes).await.status(), StatusCode::OK);
+
+    std::fs::remove_file(&token_file).unwrap();
+    signal.unbounded_send(()).unwrap();
+    futures::StreamExt::next(&mut reloads).await;
+
+    assert_eq!(push("new").reply(&routes).await.status(), StatusCode::OK);
+}
+
+/// Starts a fake peer on a random local port, returning its address
+#[cfg(feature="clustering")]
+fn spawn_peer<F>(filter: F) -> std::net::SocketAddr where F: warp::Filter + Clone + Send + Sync + 'static, F::Extract: warp::Reply {
+    let (addr, server) = warp::serve(filter).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(server);
+    addr
+}
+
+/// Finds a job that the cluster will forward to the given peer, rather than handling itself
+#[cfg(feature="clustering")]
+fn job_for_peer(cluster_conf: &ClusterConfig, peer: &str) -> String {
+    (0..1000).map(|i| format!("job{}", i))
+        .find(|job| cluster_conf.get_peer_for_key(&job.as_str()).is_some_and(|p| p.ends_with(peer)))
+        .expect("no job hashes to the peer")
+}
+
+/// Pushes to a peer that answers every forward with the given status and body, returning the response the client got
+#[cfg(feature="clustering")]
+async fn push_to_failing_peer(status: StatusCode, body: &'static str) -> warp::http::Response<warp::hyper::body::Bytes> {
+    use warp::Filter;
+
+    let peer = spawn_peer(warp::any().map(move || warp::reply::with_status(body, status)));
+    let retry_policy = crate::clustering::RetryPolicy { max_retries: 0, ..Default::default() };
+    let cluster_conf = ClusterConfig::new_from_static("127.0.0.1:1".to_owned(), vec![peer.to_string()]).with_retry_policy(retry_policy);
+    let job = job_for_peer(&cluster_conf, &peer.to_string());
+
+    let agg = Aggregator::new();
+    let mut config = test_config();
+    config.cluster_conf = Some(cluster_conf);
+    let routes = get_routes(agg.clone(), config);
+
+    let resp = warp::test::request().method("POST").path(&format!("/metrics/job/{}", job))
+        .body("requests_total 1\n")
+        .reply(&routes).await;
+    assert!(agg.to_string().await.is_empty());
+    resp
+}
+
+#[cfg(feature="clustering")]
+#[tokio::test]
+async fn test_forward_error_passes_on_peer_response() {
+    let resp = push_to_failing_peer(StatusCode::INTERNAL_SERVER_ERROR, "peer is broken").await;
+    assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(resp.body().as_ref(), b"peer is broken");
+
+    let resp = push_to_failing_peer(StatusCode::TOO_MANY_REQUESTS, "slow down").await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(resp.body().as_ref(), b"slow down");
+}
+
+#[cfg(feature="clustering")]
+#[tokio::test]
+async fn test_delete_is_forwarded_to_owner() {
+    let peer_agg = Aggregator::new();
+    let peer = spawn_peer(get_routes(peer_agg.clone(), test_config()));
+    let cluster_conf = ClusterConfig::new_from_static("127.0.0.1:1".to_owned(), vec![peer.to_string()]);
+    let remote_job = job_for_peer(&cluster_conf, &peer.to_string());
+    let local_job = job_for_peer(&cluster_conf, "127.0.0.1:1");
+
+    let agg = Aggregator::new();
+    let mut config = test_config();
+    config.cluster_conf = Some(cluster_conf);
+    let routes = get_routes(agg.clone(), config);
+
+    for job in [&remote_job, &local_job] {
+        let resp = warp::test::request().method("POST").path(&format!("/metrics/job/{}", job))
+            .body("requests_total 1\n")
+            .reply(&routes).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+    assert_eq!(without_last_pushes(&peer_agg.to_string().await), format!("requests_total{{job=\"{}\"}} 1\n", remote_job));
+    assert_eq!(without_last_pushes(&agg.to_string().await), format!("requests_total{{job=\"{}\"}} 1\n", local_job));
+
+    let resp = warp::test::request().method("DELETE").path(&format!("/metrics/job/{}", remote_job)).reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(without_last_pushes(&peer_agg.to_string().await).is_empty());
+    assert_eq!(without_last_pushes(&agg.to_string().await), format!("requests_total{{job=\"{}\"}} 1\n", local_job));
+
+    let resp = warp::test::request().method("DELETE").path(&format!("/metrics/job/{}", local_job)).reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(without_last_pushes(&agg.to_string().await).is_empty());
+}
+
+#[cfg(feature="clustering")]
+#[tokio::test]
+async fn test_drain_hands_series_to_successor() {
+    let peer_agg = Aggregator::new();
+    let peer_routes = get_routes(peer_agg.clone(), test_config());
+    let peer = spawn_peer(peer_routes.clone());
+    let cluster_conf = ClusterConfig::new_from_static("127.0.0.1:1".to_owned(), vec![peer.to_string()]);
+    let job = job_for_peer(&cluster_conf, "127.0.0.1:1");
+
+    let agg = Aggregator::new();
+    let mut config = test_config();
+    config.cluster_conf = Some(cluster_conf);
+    let routes = get_routes(agg.clone(), config);
+
+    let push = |path: &str| warp::test::request().method("POST").path(path).body("# TYPE requests_total counter\nrequests_total 1\n");
+    assert_eq!(push(&format!("/metrics/job/{}", job)).reply(&routes).await.status(), StatusCode::OK);
+    assert_eq!(push(&format!("/tenants/a/metrics/job/{}", job)).reply(&routes).await.status(), StatusCode::OK);
+    assert!(without_last_pushes(&peer_agg.to_string().await).is_empty());
+
+    let resp = warp::test::request().method("POST").path("/-/drain").reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::OK, "{:?}", resp.body());
+    assert!(without_last_pushes(&agg.to_string().await).is_empty());
+    let expected = format!("# TYPE requests_total counter\nrequests_total{{job=\"{}\"}} 1\n", job);
+    assert_eq!(without_last_pushes(&peer_agg.to_string().await), expected);
+    let tenant = warp::test::request().path("/tenants/a/metrics").reply(&peer_routes).await;
+    assert_eq!(without_last_pushes(std::str::from_utf8(tenant.body()).unwrap()), expected);
+
+    // Once it's draining, the gateway isn't ready, and pushes that still get to it go to the successor
+    let resp = warp::test::request().path("/-/ready").reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(push(&format!("/metrics/job/{}", job)).reply(&routes).await.status(), StatusCode::OK);
+    assert!(without_last_pushes(&agg.to_string().await).is_empty());
+    assert_eq!(without_last_pushes(&peer_agg.to_string().await), expected.replace("} 1", "} 2"));
+}
+
+#[cfg(feature="clustering")]
+#[tokio::test]
+async fn test_drain_keeps_series_that_no_owner_took() {
+    use warp::Filter;
+
+    async fn drain_to(peers: Vec<String>) -> (StatusCode, String) {
+        let retry_policy = crate::clustering::RetryPolicy { max_retries: 0, ..Default::default() };
+        let cluster_conf = ClusterConfig::new_from_static("127.0.0.1:1".to_owned(), peers)
+            .with_replication_factor(2)
+            .with_retry_policy(retry_policy);
+
+        let agg = Aggregator::new();
+        agg.clone().parse_and_merge("# TYPE requests_total counter\nrequests_total{job=\"foo\"} 1\n", &HashMap::new()).await.unwrap();
+        let mut config = test_config();
+        config.cluster_conf = Some(cluster_conf);
+        let resp = warp::test::request().method("POST").path("/-/drain").reply(&get_routes(agg.clone(), config)).await;
+        (resp.status(), agg.to_string().await)
+    }
+
+    let up = spawn_peer(warp::any().map(warp::reply)).to_string();
+    let down = spawn_peer(warp::any().map(|| warp::reply::with_status("", StatusCode::INTERNAL_SERVER_ERROR))).to_string();
+
+    // Once we're off the ring, every peer owns every series. One of them having them is enough, since keeping them
+    // would hand them to it again on the next drain
+    assert_eq!(drain_to(vec![up, down.clone()]).await, (StatusCode::OK, String::new()));
+
+    let (status, kept) = drain_to(vec![down]).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(kept, "# TYPE requests_total counter\nrequests_total{job=\"foo\"} 1\n");
+}
+
+#[cfg(feature="clustering")]
+#[tokio::test]
+async fn test_forward_passes_on_credentials() {
+    let token_auth = || Box::new(JobAuthenticator::new(vec![("secret".to_owned(), vec!["*".to_owned()])].into_iter().collect()));
+    let peer_agg = Aggregator::new();
+    let mut peer_config = test_config();
+    peer_config.authenticator = token_auth();
+    let peer = spawn_peer(get_routes(peer_agg.clone(), peer_config));
+    let cluster_conf = ClusterConfig::new_from_static("127.0.0.1:1".to_owned(), vec![peer.to_string()]);
+    let job = job_for_peer(&cluster_conf, &peer.to_string());
+
+    let mut config = test_config();
+    config.authenticator = token_auth();
+    config.cluster_conf = Some(cluster_conf);
+    let routes = get_routes(Aggregator::new(), config);
+
+    let resp = warp::test::request().method("POST").path(&format!("/metrics/job/{}", job))
+        .header("authorization", "Bearer secret")
+        .body("requests_total 1\n")
+        .reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::OK, "{:?}", resp.body());
+    assert_eq!(without_last_pushes(&peer_agg.to_string().await), format!("requests_total{{job=\"{}\"}} 1\n", job));
+
+    let resp = warp::test::request().method("DELETE").path(&format!("/metrics/job/{}", job))
+        .header("authorization", "Bearer secret")
+        .reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::OK, "{:?}", resp.body());
+    assert!(without_last_pushes(&peer_agg.to_string().await).is_empty());
+}
+
+#[cfg(feature="clustering")]
+#[tokio::test]
+async fn test_tenant_pushes_are_forwarded_to_the_tenant() {
+    let peer_routes = get_routes(Aggregator::new(), test_config());
+    let peer = spawn_peer(peer_routes.clone());
+    let cluster_conf = ClusterConfig::new_from_static("127.0.0.1:1".to_owned(), vec![peer.to_string()]);
+    let job = job_for_peer(&cluster_conf, &peer.to_string());
+
+    let mut config = test_config();
+    config.cluster_conf = Some(cluster_conf);
+    let routes = get_routes(Aggregator::new(), config);
+
+    let resp = warp::test::request().method("POST").path(&format!("/tenants/a/metrics/job/{}", job))
+        .body("requests_total 1\n")
+        .reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(scrape_path(&peer_routes, "/tenants/a/metrics").await, format!("requests_total{{job=\"{}\"}} 1\n", job));
+    assert_eq!(scrape_path(&peer_routes, "/metrics").await, "");
+    assert_eq!(scrape_path(&routes, "/tenants/a/metrics").await, "");
+}
+
+#[cfg(feature="clustering")]
+#[tokio::test]
+async fn test_forward_retries_transient_failures() {
+    use std::{sync::{Arc, atomic::{AtomicUsize, Ordering}}, time::Duration};
+    use warp::Filter;
+    use crate::clustering::RetryPolicy;
+
+    // Fails the first two pushes, then accepts the rest
+    let attempts = Arc::new(AtomicUsize::new(0));
+    let peer_attempts = Arc::clone(&attempts);
+    let peer = spawn_peer(warp::any().map(move || {
+        let status = if peer_attempts.fetch_add(1, Ordering::SeqCst) < 2 { StatusCode::SERVICE_UNAVAILABLE } else { StatusCode::OK };
+        warp::reply::with_status("", status)
+    }));
+
+    let retry_policy = RetryPolicy { max_retries: 3, base_delay: Duration::from_millis(10), attempt_timeout: Duration::from_secs(1) };
+    let cluster_conf = ClusterConfig::new_from_static("127.0.0.1:1".to_owned(), vec![peer.to_string()]).with_retry_policy(retry_policy);
+    let job = job_for_peer(&cluster_conf, &peer.to_string());
+
+    let mut config = test_config();
+    config.cluster_conf = Some(cluster_conf);
+    let routes = get_routes(Aggregator::new(), config);
+
+    let resp = warp::test::request().method("POST").path(&format!("/metrics/job/{}", job))
+        .body("requests_total 1\n")
+        .reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(attempts.load(Ordering::SeqCst), 3);
+}
+
+#[cfg(feature="clustering")]
+#[tokio::test]
+async fn test_forward_doesnt_retry_client_errors() {
+    use std::{sync::{Arc, atomic::{AtomicUsize, Ordering}}, time::Duration};
+    use warp::Filter;
+    use crate::clustering::RetryPolicy;
+
+    let attempts = Arc::new(AtomicUsize::new(0));
+    let peer_attempts = Arc::clone(&attempts);
+    let peer = spawn_peer(warp::any().map(move || {
+        peer_attempts.fetch_add(1, Ordering::SeqCst);
+        warp::reply::with_status("", StatusCode::BAD_REQUEST)
+    }));
+
+    let retry_policy = RetryPolicy { max_retries: 3, base_delay: Duration::from_millis(10), attempt_timeout: Duration::from_secs(1) };
+    let cluster_conf = ClusterConfig::new_from_static("127.0.0.1:1".to_owned(), vec![peer.to_string()]).with_retry_policy(retry_policy);
+    let job = job_for_peer(&cluster_conf, &peer.to_string());
+
+    let mut config = test_config();
+    config.cluster_conf = Some(cluster_conf);
+    let routes = get_routes(Aggregator::new(), config);
+
+    let resp = warp::test::request().method("POST").path(&format!("/metrics/job/{}", job))
+        .body("requests_total 1\n")
+        .reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(attempts.load(Ordering::SeqCst), 1);
+}
+
+#[cfg(feature="clustering")]
+#[tokio::test]
+async fn test_many_forwards_share_a_client() {
+    use std::sync::{Arc, atomic::{AtomicUsize, Ordering}};
+    use warp::Filter;
+
+    let received = Arc::new(AtomicUsize::new(0));
+    let peer_received = Arc::clone(&received);
+    let peer = spawn_peer(warp::body::bytes().map(move |body: warp::hyper::body::Bytes| {
+        assert_eq!(body.as_ref(), b"requests_total 1\n");
+        peer_received.fetch_add(1, Ordering::SeqCst);
+        warp::reply()
+    }));
+
+    let cluster_conf = ClusterConfig::new_from_static("127.0.0.1:1".to_owned(), vec![peer.to_string()]);
+    let job = job_for_peer(&cluster_conf, &peer.to_string());
+
+    let mut config = test_config();
+    config.cluster_conf = Some(cluster_conf);
+    let routes = get_routes(Aggregator::new(), config);
+
+    for _ in 0..100 {
+        let resp = warp::test::request().method("POST").path(&format!("/metrics/job/{}", job))
+            .body("requests_total 1\n")
+            .reply(&routes).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    assert_eq!(received.load(Ordering::SeqCst), 100);
+}
+
+#[cfg(feature="clustering")]
+#[tokio::test]
+async fn test_circuit_breaker_trips_and_recovers() {
+    use std::{sync::{Arc, atomic::{AtomicBool, AtomicUsize, Ordering}}, time::Duration};
+    use warp::Filter;
+    use crate::clustering::{CircuitBreakerConfig, RetryPolicy};
+
+    let healthy = Arc::new(AtomicBool::new(false));
+    let attempts = Arc::new(AtomicUsize::new(0));
+    let (peer_healthy, peer_attempts) = (Arc::clone(&healthy), Arc::clone(&attempts));
+    let peer = spawn_peer(warp::any().map(move || {
+        peer_attempts.fetch_add(1, Ordering::SeqCst);
+        let status = if peer_healthy.load(Ordering::SeqCst) { StatusCode::OK } else { StatusCode::INTERNAL_SERVER_ERROR };
+        warp::reply::with_status("", status)
+    }));
+
+    let cluster_conf = ClusterConfig::new_from_static("127.0.0.1:1".to_owned(), vec![peer.to_string()])
+        .with_retry_policy(RetryPolicy { max_retries: 0, ..Default::default() })
+        .with_circuit_breaker(CircuitBreakerConfig { failure_threshold: 2, cooldown: Duration::from_millis(200) });
+    let job = job_for_peer(&cluster_conf, &peer.to_string());
+
+    let mut config = test_config();
+    config.cluster_conf = Some(cluster_conf);
+    let routes = get_routes(Aggregator::new(), config);
+    let push = || warp::test::request().method("POST").path(&format!("/metrics/job/{}", job)).body("requests_total 1\n");
+    let circuit_gauge = |open| format!("gravel_peer_circuit_open{{peer=\"http://{}\"}} {}", peer, open);
+
+    for _ in 0..2 {
+        assert_eq!(push().reply(&routes).await.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    assert_eq!(attempts.load(Ordering::SeqCst), 2);
+
+    // The circuit is open, so this shouldn't even reach the peer
+    let resp = push().reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    assert!(String::from_utf8(resp.body().to_vec()).unwrap().contains("circuit is open"));
+    assert_eq!(attempts.load(Ordering::SeqCst), 2);
+
+    let scrape = warp::test::request().method("GET").path("/-/metrics").reply(&routes).await;
+    assert!(String::from_utf8(scrape.body().to_vec()).unwrap().contains(&circuit_gauge(1)));
+
+    // Once the cooldown's up, a push gets through to probe the peer, and closes the circuit when it succeeds
+    healthy.store(true, Ordering::SeqCst);
+    tokio::time::sleep(Duration::from_millis(250)).await;
+    assert_eq!(push().reply(&routes).await.status(), StatusCode::OK);
+    assert_eq!(attempts.load(Ordering::SeqCst), 3);
+
+    let scrape = warp::test::request().method("GET").path("/-/metrics").reply(&routes).await;
+    let scrape = String::from_utf8(scrape.body().to_vec()).unwrap();
+    assert!(scrape.contains(&circuit_gauge(0)));
+    assert!(scrape.contains("gravel_forwards_total{result=\"success\"} 1\n"), "{}", scrape);
+    assert!(scrape.contains("gravel_forwards_total{result=\"failure\"} 3\n"), "{}", scrape);
+}
+
+#[cfg(feature="clustering")]
+#[tokio::test]
+async fn test_forwards_are_counted_by_peer() {
+    use warp::Filter;
+
+    let healthy = spawn_peer(warp::any().map(|| ""));
+    let failing = spawn_peer(warp::any().map(|| warp::reply::with_status("", StatusCode::INTERNAL_SERVER_ERROR)));
+    // Nothing's listening on this once the listener's dropped, so connecting to it fails
+    let down = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+
+    let peers = vec![healthy.to_string(), failing.to_string(), down.to_string()];
+    let cluster_conf = ClusterConfig::new_from_static("127.0.0.1:1".to_owned(), peers)
+        .with_retry_policy(crate::clustering::RetryPolicy { max_retries: 0, ..Default::default() });
+    let jobs: Vec<String> = [healthy, failing, down].iter().map(|peer| job_for_peer(&cluster_conf, &peer.to_string())).collect();
+
+    let mut config = test_config();
+    config.cluster_conf = Some(cluster_conf);
+    let routes = get_routes(Aggregator::new(), config);
+    for (job, pushes) in jobs.iter().zip([2, 1, 1]) {
+        for _ in 0..pushes {
+            warp::test::request().method("POST").path(&format!("/metrics/job/{}", job)).body("requests_total 1\n").reply(&routes).await;
+        }
+    }
+
+    let resp = warp::test::request().method("GET").path("/-/metrics").reply(&routes).await;
+    let scrape = String::from_utf8(resp.body().to_vec()).unwrap();
+    let forwards = |peer: std::net::SocketAddr, result: &str, count: u64| format!("gravel_forward_total{{peer=\"http://{}\",result=\"{}\"}} {}\n", peer, result, count);
+    for expected in [
+        forwards(healthy, "success", 2), forwards(healthy, "error_status", 0),
+        forwards(failing, "success", 0), forwards(failing, "error_status", 1),
+        forwards(down, "connection_error", 1), forwards(down, "error_status", 0),
+    ] {
+        assert!(scrape.contains(&expected), "{} not in {}", expected, scrape);
+    }
+    assert!(scrape.contains(&format!("gravel_forward_duration_seconds_count{{peer=\"http://{}\"}} 2\n", healthy)), "{}", scrape);
+}
+
+#[cfg(feature="clustering")]
+#[tokio::test]
+async fn test_pushes_replicate_to_every_owner() {
+    use std::sync::{Arc, atomic::{AtomicUsize, Ordering}};
+    use warp::Filter;
+
+    let received: Vec<Arc<AtomicUsize>> = (0..3).map(|_| Arc::new(AtomicUsize::new(0))).collect();
+    let peers: Vec<String> = received.iter().map(|received| {
+        let received = Arc::clone(received);
+        spawn_peer(warp::header::<String>("x-gravel-forwarded").map(move |_| {
+            received.fetch_add(1, Ordering::SeqCst);
+            warp::reply()
+        })).to_string()
+    }).collect();
+
+    let cluster_conf = ClusterConfig::new_from_static("127.0.0.1:1".to_owned(), peers.clone()).with_replication_factor(2);
+
+    // Find a job that's owned by two of the peers, and not by us
+    let (job, owners) = (0..1000).map(|i| format!("job{}", i)).find_map(|job| {
+        let owners: Vec<usize> = cluster_conf.get_peers_for_key(&job.as_str()).iter()
+            .filter_map(|owner| peers.iter().position(|peer| owner.ends_with(peer.as_str())))
+            .collect();
+        if owners.len() == 2 { Some((job, owners)) } else { None }
+    }).expect("no job is owned by two peers");
+
+    let agg = Aggregator::new();
+    let mut config = test_config();
+    config.cluster_conf = Some(cluster_conf);
+    let routes = get_routes(agg.clone(), config);
+
+    let resp = warp::test::request().method("POST").path(&format!("/metrics/job/{}", job))
+        .body("requests_total 1\n")
+        .reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    for (i, received) in received.iter().enumerate() {
+        let expected = if owners.contains(&i) { 1 } else { 0 };
+        assert_eq!(received.load(Ordering::SeqCst), expected, "peer {} got the wrong number of pushes", i);
+    }
+    assert!(agg.to_string().await.is_empty());
+}
+
+#[cfg(feature="clustering")]
+#[tokio::test]
+async fn test_replicated_push_needs_a_quorum() {
+    use warp::Filter;
+
+    let up = spawn_peer(warp::any().map(warp::reply)).to_string();
+    let down = spawn_peer(warp::any().map(|| warp::reply::with_status("", StatusCode::INTERNAL_SERVER_ERROR))).to_string();
+    let retry_policy = crate::clustering::RetryPolicy { max_retries: 0, ..Default::default() };
+    let cluster_conf = ClusterConfig::new_from_static("127.0.0.1:1".to_owned(), vec![up.clone(), down.clone()])
+        .with_replication_factor(3)
+        .with_retry_policy(retry_policy);
+
+    let agg = Aggregator::new();
+    let mut config = test_config();
+    config.cluster_conf = Some(cluster_conf);
+    let routes = get_routes(agg.clone(), config);
+
+    // With every node owning every job, we and the healthy peer make a quorum of 2/3
+    let resp = warp::test::request().method("POST").path("/metrics/job/foo")
+        .body("requests_total 1\n")
+        .reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(agg.to_string().await.contains("requests_total{job=\"foo\"} 1"));
+
+    // Pushes forwarded from another peer get merged here, and not sent on again
+    let resp = warp::test::request().method("POST").path("/metrics/job/foo")
+        .remote_addr(up.parse().unwrap())
+        .header("x-gravel-forwarded", "true")
+        .body("requests_total 1\n")
+        .reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(agg.to_string().await.contains("requests_total{job=\"foo\"} 2"));
+
+    // Without a quorum the push fails, but it was still merged here, and the client is told as much
+    let retry_policy = crate::clustering::RetryPolicy { max_retries: 0, ..Default::default() };
+    let also_down = spawn_peer(warp::any().map(|| warp::reply::with_status("", StatusCode::INTERNAL_SERVER_ERROR))).to_string();
+    let cluster_conf = ClusterConfig::new_from_static("127.0.0.1:1".to_owned(), vec![down.clone(), also_down])
+        .with_replication_factor(3)
+        .with_retry_policy(retry_policy);
+    let agg = Aggregator::new();
+    let mut config = test_config();
+    config.cluster_conf = Some(cluster_conf);
+    let routes = get_routes(agg.clone(), config);
+
+    let resp = warp::test::request().method("POST").path("/metrics/job/foo")
+        .body("requests_total 1\n")
+        .reply(&routes).await;
+    assert!(!resp.status().is_success());
+    let body = String::from_utf8(resp.body().to_vec()).unwrap();
+    assert!(body.contains("PARTIAL_SUCCESS: 1 of 3 owners accepted this"), "{}", body);
+    assert!(agg.to_string().await.contains("requests_total{job=\"foo\"} 1"));
+}
+
+#[cfg(feature="clustering")]
+#[tokio::test]
+async fn test_rate_limit_only_trusts_forwards_from_peers() {
+    let cluster_conf = ClusterConfig::new_from_static("127.0.0.1:1".to_owned(), vec!["10.0.0.2:4278".to_owned()]);
+    let job = job_for_peer(&cluster_conf, "127.0.0.1:1");
+    let mut config = test_config();
+    config.cluster_conf = Some(cluster_conf);
+    config.rate_limit = Some(RateLimit { per_second: 0.001, burst: 1 });
+    let routes = get_routes(Aggregator::new(), config);
+
+    let push = |ip: [u8; 4]| warp::test::request().method("POST").path(&format!("/metrics/job/{}", job))
+        .remote_addr((ip, 1234).into())
+        .header("x-gravel-forwarded", "true")
+        .body("requests_total 1\n");
+
+    // A client that isn't one of the peers can't get around its limit by saying its push was forwarded
+    assert_eq!(push([10, 0, 0, 9]).reply(&routes).await.status(), StatusCode::OK);
+    assert_eq!(push([10, 0, 0, 9]).reply(&routes).await.status(), StatusCode::TOO_MANY_REQUESTS);
+
+    // But the peer was limited where the push came in, so its forwards aren't limited again
+    for _ in 0..3 {
+        assert_eq!(push([10, 0, 0, 2]).reply(&routes).await.status(), StatusCode::OK);
+    }
+}
+
+#[cfg(feature="clustering")]
+#[tokio::test]
+async fn test_forward_by_custom_key_label() {
+    use std::sync::{Arc, atomic::{AtomicUsize, Ordering}};
+    use warp::Filter;
+
+    let received = Arc::new(AtomicUsize::new(0));
+    let peer_received = Arc::clone(&received);
+    let peer = spawn_peer(warp::any().map(move || {
+        peer_received.fetch_add(1, Ordering::SeqCst);
+        warp::reply()
+    })).to_string();
+
+    let cluster_conf = ClusterConfig::new_from_static("127.0.0.1:1".to_owned(), vec![peer.clone()])
+        .with_key_labels(vec!["tenant".to_owned()]);
+    let tenant = (0..1000).map(|i| format!("tenant{}", i)).find(|tenant| {
+        let labels: HashMap<&str, &str> = vec![("tenant", tenant.as_str())].into_iter().collect();
+        cluster_conf.get_peers_for_key(&cluster_conf.sharding_key(&labels))[0].ends_with(&peer)
+    }).expect("no tenant hashes to the peer");
+
+    let agg = Aggregator::new();
+    let mut config = test_config();
+    config.cluster_conf = Some(cluster_conf);
+    let routes = get_routes(agg.clone(), config);
+
+    // The job doesn't matter, only the tenant, so these all go to the same peer
+    for job in &["foo", "bar", "baz"] {
+        let resp = warp::test::request().method("POST").path(&format!("/metrics/job/{}/tenant/{}", job, tenant))
+            .body("requests_total 1\n")
+            .reply(&routes).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    assert_eq!(received.load(Ordering::SeqCst), 3);
+    assert!(agg.to_string().await.is_empty());
+}
+
+#[cfg(feature="clustering")]
+#[tokio::test]
+async fn test_push_for_several_jobs_is_split_between_owners() {
+    let peer_agg = Aggregator::new();
+    let peer = spawn_peer(get_routes(peer_agg.clone(), test_config()));
+    let cluster_conf = ClusterConfig::new_from_static("127.0.0.1:1".to_owned(), vec![peer.to_string()]);
+    let remote_job = job_for_peer(&cluster_conf, &peer.to_string());
+    let local_job = job_for_peer(&cluster_conf, "127.0.0.1:1");
+
+    let agg = Aggregator::new();
+    let mut config = test_config();
+    config.cluster_conf = Some(cluster_conf);
+    let routes = get_routes(agg.clone(), config);
+
+    let body = format!("# TYPE requests_total counter
+requests_total{{job=\"{remote}\",path=\"/a\"}} 1
+requests_total{{job=\"{local}\",path=\"/a\"}} 2
+requests_total{{job=\"{remote}\",path=\"/b\"}} 3
+# TYPE temperature gauge
+temperature{{job=\"{local}\"}} 20
+", remote = remote_job, local = local_job);
+    let resp = warp::test::request().method("POST").path("/metrics/env/prod").body(body).reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::OK, "{:?}", resp.body());
+
+    assert_eq!(agg.to_string().await, format!("# TYPE requests_total counter
+requests_total{{job=\"{local}\",path=\"/a\",env=\"prod\"}} 2
+# TYPE temperature gauge
+temperature{{job=\"{local}\",env=\"prod\"}} 20
+", local = local_job));
+    assert_eq!(peer_agg.to_string().await, format!("# TYPE requests_total counter
+requests_total{{job=\"{remote}\",path=\"/a\",env=\"prod\"}} 1
+requests_total{{job=\"{remote}\",path=\"/b\",env=\"prod\"}} 3
+", remote = remote_job));
+
+    // A job in the path wins over the body's, so the whole push goes to its owner
+    let body = format!("# TYPE requests_total counter\nrequests_total{{job=\"{local}\",path=\"/c\"}} 1\n", local = local_job);
+    let resp = warp::test::request().method("POST").path(&format!("/metrics/job/{}/env/prod", remote_job)).body(body).reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::OK, "{:?}", resp.body());
+    assert!(!agg.to_string().await.contains("path=\"/c\""));
+    assert!(peer_agg.to_string().await.contains(&format!("requests_total{{job=\"{}\",path=\"/c\",env=\"prod\"}} 1\n", remote_job)));
+}
+
+#[cfg(feature="clustering")]
+#[tokio::test]
+async fn test_body_job_wins_when_configured() {
+    use crate::aggregator::{AggregatorConfig, LabelPrecedence};
+
+    let body_wins = || AggregatorConfig { label_precedence: LabelPrecedence::Body, ..AggregatorConfig::default() };
+    let peer_agg = Aggregator::with_config(body_wins());
+    let peer = spawn_peer(get_routes(peer_agg.clone(), test_config()));
+    let cluster_conf = ClusterConfig::new_from_static("127.0.0.1:1".to_owned(), vec![peer.to_string()]);
+    let body_job = job_for_peer(&cluster_conf, &peer.to_string());
+    let path_job = job_for_peer(&cluster_conf, "127.0.0.1:1");
+
+    let agg = Aggregator::with_config(body_wins());
+    let mut config = test_config();
+    config.cluster_conf = Some(cluster_conf);
+    let routes = get_routes(agg.clone(), config);
+
+    // The path's job would have kept the push here, but the body's job belongs to the peer
+    let resp = warp::test::request().method("POST").path(&format!("/metrics/job/{}", path_job))
+        .body(format!("# TYPE requests_total counter\nrequests_total{{job=\"{}\"}} 1\n", body_job))
+        .reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::OK, "{:?}", resp.body());
+    assert!(agg.to_string().await.is_empty());
+    let output = peer_agg.to_string().await;
+    assert!(output.ends_with(&format!("# TYPE requests_total counter\nrequests_total{{job=\"{}\"}} 1\n", body_job)), "{}", output);
+    assert!(!output.contains(&path_job), "{}", output);
+}
+
+#[tokio::test]
+async fn test_merge_histograms() {
+    let routes = get_routes(Aggregator::new(), test_config());
+    let scrape = || async {
+        let resp = warp::test::request().method("GET").path("/-/metrics").reply(&routes).await;
+        String::from_utf8(resp.body().to_vec()).unwrap()
+    };
+
+    let before = scrape().await;
+    assert!(before.contains("gravel_merge_duration_seconds_count 0\n"), "{}", before);
+    assert!(before.contains("gravel_ingest_body_bytes_count 0\n"), "{}", before);
+
+    for _ in 0..3 {
+        warp::test::request().method("POST").path("/metrics/job/foo").body("requests_total 1\n").reply(&routes).await;
+    }
+
+    let after = scrape().await;
+    assert!(after.contains("gravel_merge_duration_seconds_count 3\n"), "{}", after);
+    assert!(after.contains("gravel_merge_duration_seconds_bucket{le=\"+Inf\"} 3\n"), "{}", after);
+    assert!(after.contains("gravel_ingest_body_bytes_count 3\n"), "{}", after);
+    assert!(after.contains("gravel_ingest_body_bytes_bucket{le=\"256\"} 3\n"), "{}", after);
+    assert!(after.contains("gravel_ingest_body_bytes_sum 51\n"), "{}", after);
+}
+
+#[tokio::test]
+async fn test_build_info() {
+    let routes = get_routes(Aggregator::new(), test_config());
+    let resp = warp::test::request().method("GET").path("/-/metrics").reply(&routes).await;
+    let scrape = String::from_utf8(resp.body().to_vec()).unwrap();
+
+    let line = scrape.lines().find(|line| line.starts_with("gravel_build_info{")).unwrap_or_else(|| panic!("{}", scrape));
+    assert!(line.starts_with(&format!("gravel_build_info{{version=\"{}\",rustc=\"", env!("CARGO_PKG_VERSION"))), "{}", line);
+    assert!(line.ends_with("\"} 1"), "{}", line);
+    assert_eq!(line.contains("clustering"), cfg!(feature="clustering"), "{}", line);
+}
+
+/// Gets the value of an unlabelled gauge from a scrape of /-/metrics
+fn gauge_value(scrape: &str, name: &str) -> i64 {
+    let prefix = format!("{} ", name);
+    let line = scrape.lines().find(|line| line.starts_with(&prefix)).unwrap_or_else(|| panic!("{}", scrape));
+    line[prefix.len()..].parse().unwrap()
+}
+
+#[tokio::test]
+async fn test_store_size_metrics() {
+    let routes = get_routes(Aggregator::new(), test_config());
+    let scrape = || async {
+        let resp = warp::test::request().method("GET").path("/-/metrics").reply(&routes).await;
+        String::from_utf8(resp.body().to_vec()).unwrap()
+    };
+
+    let empty = scrape().await;
+    assert_eq!(gauge_value(&empty, "gravel_series_total"), 0);
+    assert_eq!(gauge_value(&empty, "gravel_store_bytes_estimate"), 0);
+
+    let body: String = (0..10).map(|i| format!("requests_total{{code=\"{}\"}} 1\n", i)).collect();
+    warp::test::request().method("POST").path("/metrics/job/foo").body(format!("# TYPE requests_total counter\n{}", body)).reply(&routes).await;
+    warp::test::request().method("POST").path("/metrics/job/bar").body("# TYPE queue_depth gauge\nqueue_depth 3\n").reply(&routes).await;
+    let full = scrape().await;
+    assert_eq!(gauge_value(&full, "gravel_series_total"), 11);
+    assert_eq!(gauge_value(&full, "gravel_families_total"), 2);
+    let bytes = gauge_value(&full, "gravel_store_bytes_estimate");
+    assert!(bytes > 0, "{}", full);
+
+    // Deleting a job's series takes them out of the counts
+    warp::test::request().method("DELETE").path("/metrics/job/foo").reply(&routes).await;
+    let deleted = scrape().await;
+    assert_eq!(gauge_value(&deleted, "gravel_series_total"), 1);
+    assert!(gauge_value(&deleted, "gravel_store_bytes_estimate") < bytes, "{}", deleted);
+}
+
+#[tokio::test]
+async fn test_gateway_metrics() {
+    let agg = Aggregator::new();
+    let routes = get_routes(agg.clone(), test_config());
+
+    for body in &["# TYPE requests_total counter\nrequests_total{code=\"200\"} 1\n", "# TYPE requests_total counter\nrequests_total{code=\"500\"} 1\n", "not a metric{\n"] {
+        warp::test::request().method("POST").path("/metrics/job/foo")
+            .body(*body)
+            .reply(&routes).await;
+    }
+
+    let resp = warp::test::request().method("GET").path("/-/metrics").reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let body = String::from_utf8(resp.body().to_vec()).unwrap();
+    assert!(body.contains("gravel_pushes_total 3\n"), "{}", body);
+    assert!(body.contains("gravel_push_parse_errors_total 1\n"), "{}", body);
+    assert!(body.contains("gravel_ingested_bytes_total 132\n"), "{}", body);
+    assert!(body.contains("gravel_series 2\n"), "{}", body);
+    assert!(body.contains("gravel_forwards_total{result=\"success\"} 0\n"), "{}", body);
+
+    // None of that leaks into the aggregated metrics
+    let resp = warp::test::request().method("GET").path("/metrics").reply(&routes).await;
+    let body = String::from_utf8(resp.body().to_vec()).unwrap();
+    assert!(!without_last_pushes(&body).contains("gravel_"), "{}", body);
+}
+
+#[tokio::test]
+async fn test_health_and_readiness() {
+    let routes = get_routes(Aggregator::new(), test_config());
+
+    for path in &["/-/healthy", "/-/ready"] {
+        let resp = warp::test::request().method("GET").path(path).reply(&routes).await;
+        assert_eq!(resp.status(), StatusCode::OK, "{}", path);
+    }
+}
+
+#[cfg(feature="clustering")]
+#[tokio::test]
+async fn test_readiness_needs_a_quorum_of_peers() {
+    use crate::clustering::CircuitBreakerConfig;
+
+    let routes_with_unreachable = |unreachable: &[&str]| {
+        let cluster_conf = ClusterConfig::new_from_static("127.0.0.1:1".to_owned(), vec!["peer1:4278".to_owned(), "peer2:4278".to_owned()])
+            .with_circuit_breaker(CircuitBreakerConfig { failure_threshold: 1, ..Default::default() });
+        for peer in unreachable {
+            cluster_conf.circuit_breaker().record_failure(peer);
+        }
+
+        let mut config = test_config();
+        config.cluster_conf = Some(cluster_conf);
+        get_routes(Aggregator::new(), config)
+    };
+
+    // Two out of three is still a quorum
+    let routes = routes_with_unreachable(&["http://peer1:4278"]);
+    let resp = warp::test::request().method("GET").path("/-/ready").reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let routes = routes_with_unreachable(&["http://peer1:4278", "http://peer2:4278"]);
+    let resp = warp::test::request().method("GET").path("/-/ready").reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(resp.body(), "Unreachable peers: http://peer1:4278, http://peer2:4278");
+
+    // Not being ready doesn't make us unhealthy
+    let resp = warp::test::request().method("GET").path("/-/healthy").reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_rejection_bodies() {
+    let mut config = test_config();
+    config.authenticator = Box::new(JobAuthenticator::new(vec![("token".to_owned(), vec!["*".to_owned()])].into_iter().collect()));
+    let routes = get_routes(Aggregator::new(), config);
+    let push = |token: &str, body: &'static [u8]| warp::test::request().method("POST").path("/metrics/job/foo")
+        .header("authorization", token)
+        .body(body);
+
+    let resp = push("Bearer wrong", b"requests_total 1\n").reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(resp.body(), "unauthorized");
+
+    let resp = push("Bearer token", b"requests_total{ 1\n").reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    assert!(!resp.body().is_empty());
+
+    let resp = push("Bearer token", b"requests_total \xff\n").reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(resp.body(), "Invalid UTF-8 in body");
+
+    let resp = warp::test::request().method("GET").path("/nothing-here").reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_unknown_paths_and_methods_list_the_endpoints() {
+    let routes = get_routes(Aggregator::new(), test_config());
+
+    let resp = warp::test::request().method("GET").path("/metric").reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    let body = String::from_utf8(resp.body().to_vec()).unwrap();
+    assert!(body.starts_with("NOT_FOUND\n"), "{}", body);
+    assert!(body.contains("\n  GET|HEAD /metrics[/job/<job>"), "{}", body);
+    assert!(body.contains("\n  POST|PUT /metrics[/job/<job>"), "{}", body);
+
+    // /metrics/job/<job> only takes pushes, scrapes, and deletes
+    let resp = warp::test::request().method("PATCH").path("/metrics/job/foo").reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::METHOD_NOT_ALLOWED);
+    let body = String::from_utf8(resp.body().to_vec()).unwrap();
+    assert!(body.starts_with("METHOD_NOT_ALLOWED\n"), "{}", body);
+    assert!(body.contains("\n  DELETE   /metrics"), "{}", body);
+
+    // Rejections that say more than that still win
+    let resp = warp::test::request().method("POST").path("/metrics/job/foo").body("requests_total{ 1\n").reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_base64_path_labels() {
+    let agg = Aggregator::new();
+    let routes = get_routes(agg.clone(), test_config());
+
+    let resp = warp::test::request().method("POST").path("/metrics/job/foo/path@base64/L2FwaS92MQ==")
+        .body("requests_total 1\n")
+        .reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // `=` is an empty value, and padding is optional
+    let resp = warp::test::request().method("POST").path("/metrics/job@base64/YmFy/path@base64/=")
+        .body("errors_total 1\n")
+        .reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let resp = warp::test::request().method("POST").path("/metrics/job/foo/path@base64/not*base64")
+        .body("requests_total 1\n")
+        .reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+    assert_eq!(without_last_pushes(&agg.to_string().await), "errors_total{job=\"bar\",path=\"\"} 1\nrequests_total{job=\"foo\",path=\"/api/v1\"} 1\n");
+}
+
+#[tokio::test]
+async fn test_percent_encoded_path_labels() {
+    let agg = Aggregator::new();
+    let routes = get_routes(agg.clone(), test_config());
+
+    let resp = warp::test::request().method("POST").path("/metrics/job/my%20job")
+        .body("requests_total 1\n")
+        .reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // An encoded slash is part of the value, rather than separating labels
+    let resp = warp::test::request().method("POST").path("/metrics/job/api%2Fv1")
+        .body("errors_total 1\n")
+        .reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    for path in &["/metrics/job/foo%2", "/metrics/job/foo%zz", "/metrics/job/foo%+f", "/metrics/job/%ff"] {
+        let resp = warp::test::request().method("POST").path(path)
+            .body("requests_total 1\n")
+            .reply(&routes).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{}", path);
+    }
+
+    assert_eq!(without_last_pushes(&agg.to_string().await), "errors_total{job=\"api/v1\"} 1\nrequests_total{job=\"my job\"} 1\n");
+}
+
+#[tokio::test]
+async fn test_invalid_path_label_names() {
+    let agg = Aggregator::new();
+    let routes = get_routes(agg.clone(), test_config());
+
+    let resp = warp::test::request().method("POST").path("/metrics/job/foo/foo-bar/baz")
+        .body("requests_total 1\n")
+        .reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    assert!(String::from_utf8(resp.body().to_vec()).unwrap().contains("foo-bar"));
+    assert!(agg.to_string().await.is_empty());
+}
+
+#[tokio::test]
+async fn test_default_job() {
+    let agg = Aggregator::new();
+    let routes = get_routes(agg.clone(), RoutesConfig {
+        default_job: Some(String::from("batch")),
+        ..test_config()
+    });
+
+    for (path, body) in [("/metrics", "requests_total 1\n"), ("/metrics/instance/a", "errors_total 1\n"), ("/metrics/job/web", "requests_total 1\n")] {
+        let resp = warp::test::request().method("POST").path(path).body(body).reply(&routes).await;
+        assert_eq!(resp.status(), StatusCode::OK, "{}", path);
+    }
+
+    // The default is only for pushes without a job, so one that gives its own keeps it
+    let scrape = agg.to_string().await;
+    assert!(scrape.contains("requests_total{job=\"batch\"} 1\n"), "{}", scrape);
+    assert!(scrape.contains("errors_total{instance=\"a\",job=\"batch\"} 1\n"), "{}", scrape);
+    assert!(scrape.contains("requests_total{job=\"web\"} 1\n"), "{}", scrape);
+    assert!(scrape.contains("gravel_last_push_timestamp_seconds{job=\"batch\"}"), "{}", scrape);
+}
+
+#[tokio::test]
+async fn test_label_path_without_value() {
+    let agg = Aggregator::new();
+    let routes = get_routes(agg.clone(), test_config());
+    let resp = warp::test::request().method("POST").path("/metrics/job/web/instance").body("requests_total 1\n").reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(without_last_pushes(&agg.to_string().await), "requests_total{instance=\"\",job=\"web\"} 1\n");
+
+    let agg = Aggregator::new();
+    let routes = get_routes(agg.clone(), RoutesConfig {
+        strict_label_paths: true,
+        ..test_config()
+    });
+    let resp = warp::test::request().method("POST").path("/metrics/job/web/instance").body("requests_total 1\n").reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    assert!(String::from_utf8(resp.body().to_vec()).unwrap().contains("label instance has no value"));
+    assert!(agg.to_string().await.is_empty());
+
+    // Deletes are held to it too, while an explicitly empty value is still fine
+    let resp = warp::test::request().method("DELETE").path("/metrics/job").reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let resp = warp::test::request().method("POST").path("/metrics/job/web/instance/=").body("requests_total 1\n").reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_line_protocol_push() {
+    let agg = Aggregator::new();
+    let routes = get_routes(agg.clone(), RoutesConfig {
+        default_job: Some(String::from("telegraf")),
+        ..test_config()
+    });
+
+    let batch = "http,path=/api requests=10i,mean_latency=0.25,status=\"ok\" 1600000000000\njobs,clearmode=aggregate queued=5i\n";
+    for path in ["/write?precision=ms", "/api/v2/write"] {
+        let resp = warp::test::request().method("POST").path(path).body(batch).reply(&routes).await;
+        assert_eq!(resp.status(), StatusCode::OK, "{:?}", resp.body());
+    }
+
+    // Gauges are replaced by default, unless the line asks for them to be summed
+    assert_eq!(without_last_pushes(&agg.to_string().await), "# TYPE http_mean_latency gauge
+http_mean_latency{path=\"/api\",job=\"telegraf\"} 0.25 1600000000000
+# TYPE http_requests gauge
+http_requests{path=\"/api\",job=\"telegraf\"} 10 1600000000000
+# TYPE jobs_queued gauge
+jobs_queued{job=\"telegraf\"} 10
+");
+
+    let metrics = warp::test::request().path("/-/metrics").reply(&routes).await;
+    assert!(std::str::from_utf8(metrics.body()).unwrap().contains("gravel_line_protocol_skipped_fields_total 2\n"));
+
+    let resp = warp::test::request().method("POST").path("/write?precision=m").body(batch).reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let resp = warp::test::request().method("POST").path("/write").body("http requests=lots\n").reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+}
+
+/// Collects everything a tracing subscriber writes, so that tests can check what was logged
+#[derive(Clone, Default)]
+struct CapturedLogs(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+impl Write for CapturedLogs {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl CapturedLogs {
+    fn contents(&self) -> String {
+        String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+    }
+}
+
+#[tokio::test]
+async fn test_rejected_push_is_logged() {
+    let logs = CapturedLogs::default();
+    let writer = logs.clone();
+    let subscriber = tracing_subscriber::fmt().with_ansi(false).with_writer(move || writer.clone()).finish();
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let routes = get_routes(Aggregator::new(), test_config());
+    let resp = warp::test::request().method("POST").path("/metrics/job/foo")
+        .header("x-request-id", "push-1234")
+        .body(&b"requests_total \xff\n"[..])
+        .reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+    let logs = logs.contents();
+    let line = logs.lines().find(|line| line.contains("ERROR")).expect("no error was logged");
+    assert!(line.contains("Invalid UTF-8 in body"), "{}", line);
+    assert!(line.contains("push-1234"), "{}", line);
+}
+
+#[cfg(feature="clustering")]
+#[tokio::test]
+async fn test_request_id_is_forwarded() {
+    use std::sync::{Arc, Mutex};
+    use warp::Filter;
+
+    let received = Arc::new(Mutex::new(None));
+    let peer_received = Arc::clone(&received);
+    let peer = spawn_peer(warp::header::optional::<String>("x-request-id").map(move |id| {
+        *peer_received.lock().unwrap() = id;
+        warp::reply()
+    })).to_string();
+
+    let cluster_conf = ClusterConfig::new_from_static("127.0.0.1:1".to_owned(), vec![peer.clone()]);
+    let job = job_for_peer(&cluster_conf, &peer);
+    let mut config = test_config();
+    config.cluster_conf = Some(cluster_conf);
+    let routes = get_routes(Aggregator::new(), config);
+
+    let resp = warp::test::request().method("POST").path(&format!("/metrics/job/{}", job))
+        .header("x-request-id", "push-5678")
+        .body("requests_total 1\n")
+        .reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(received.lock().unwrap().as_deref(), Some("push-5678"));
+
+    // Pushes without one get one made up, which is still passed on
+    warp::test::request().method("POST").path(&format!("/metrics/job/{}", job))
+        .body("requests_total 1\n")
+        .reply(&routes).await;
+    assert!(received.lock().unwrap().as_deref().is_some_and(|id| !id.is_empty() && id != "push-5678"));
+}
+
+/// Pushes to a peer that takes a while to answer, `pushes` at once, with at most `limit` forwards allowed in flight.
+/// Returns the statuses the client got, and the most forwards that were ever in flight at the peer
+#[cfg(feature="clustering")]
+async fn push_to_slow_peer(pushes: usize, limit: usize, policy: crate::clustering::ForwardLimitPolicy) -> (Vec<StatusCode>, usize) {
+    use std::{sync::{Arc, atomic::{AtomicUsize, Ordering}}, time::Duration};
+    use warp::Filter;
+
+    let in_flight = Arc::new(AtomicUsize::new(0));
+    let most_in_flight = Arc::new(AtomicUsize::new(0));
+    let (peer_in_flight, peer_most_in_flight) = (Arc::clone(&in_flight), Arc::clone(&most_in_flight));
+    let peer = spawn_peer(warp::any().and_then(move || {
+        let (in_flight, most_in_flight) = (Arc::clone(&peer_in_flight), Arc::clone(&peer_most_in_flight));
+        async move {
+            most_in_flight.fetch_max(in_flight.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            in_flight.fetch_sub(1, Ordering::SeqCst);
+            Ok::<_, warp::Rejection>("")
+        }
+    }));
+
+    let cluster_conf = ClusterConfig::new_from_static("127.0.0.1:1".to_owned(), vec![peer.to_string()]).with_forward_limit(limit, policy);
+    let job = job_for_peer(&cluster_conf, &peer.to_string());
+
+    let mut config = test_config();
+    config.cluster_conf = Some(cluster_conf);
+    let routes = get_routes(Aggregator::new(), config);
+
+    let pushes = (0..pushes).map(|_| warp::test::request().method("POST").path(&format!("/metrics/job/{}", job)).body("requests_total 1\n").reply(&routes));
+    let statuses = futures::future::join_all(pushes).await.into_iter().map(|resp| resp.status()).collect();
+    (statuses, most_in_flight.load(Ordering::SeqCst))
+}
+
+#[cfg(feature="clustering")]
+#[tokio::test]
+async fn test_forwards_queue_over_the_limit() {
+    let (statuses, most_in_flight) = push_to_slow_peer(10, 3, crate::clustering::ForwardLimitPolicy::Queue).await;
+    assert!(statuses.iter().all(|status| *status == StatusCode::OK), "{:?}", statuses);
+    assert!(most_in_flight <= 3, "{} forwards were in flight at once", most_in_flight);
+}
+
+#[cfg(feature="clustering")]
+#[tokio::test]
+async fn test_forwards_fail_fast_over_the_limit() {
+    let (statuses, most_in_flight) = push_to_slow_peer(10, 3, crate::clustering::ForwardLimitPolicy::Fail).await;
+    assert_eq!(statuses.iter().filter(|status| **status == StatusCode::OK).count(), 3, "{:?}", statuses);
+    assert!(statuses.iter().all(|status| *status == StatusCode::OK || *status == StatusCode::SERVICE_UNAVAILABLE), "{:?}", statuses);
+    assert!(most_in_flight <= 3, "{} forwards were in flight at once", most_in_flight);
+}
+
+#[tokio::test]
+async fn test_snapshot_round_trip() {
+    let mut config = test_config();
+    config.max_body_bytes = 1 << 20;
+    let routes = get_routes(Aggregator::new(), config);
+
+    let push = |path: &str, body: &str| warp::test::request().method("POST").path(path).body(body);
+    assert_eq!(push("/metrics/job/a", "# TYPE requests_total counter\nrequests_total{path=\"/\"} 3\n# TYPE latency histogram\nlatency_bucket{le=\"0.5\"} 1\nlatency_bucket{le=\"+Inf\"} 2\nlatency_sum 1.5\nlatency_count 2\n").reply(&routes).await.status(), StatusCode::OK);
+    assert_eq!(push("/metrics/job/b", "# TYPE rpc summary\nrpc{quantile=\"0.5\"} 0.25\nrpc_sum 4.5\nrpc_count 9\n# TYPE queued gauge\nqueued{clearmode=\"sum1m\"} 3\n").reply(&routes).await.status(), StatusCode::OK);
+    let before = warp::test::request().method("GET").path("/metrics").reply(&routes).await.into_body();
+
+    let snapshot = warp::test::request().method("GET").path("/-/snapshot").reply(&routes).await;
+    assert_eq!(snapshot.status(), StatusCode::OK);
+    assert_eq!(snapshot.headers()["content-type"], "application/json");
+
+    assert_eq!(warp::test::request().method("DELETE").path("/metrics").reply(&routes).await.status(), StatusCode::OK);
+    assert!(warp::test::request().method("GET").path("/metrics").reply(&routes).await.body().is_empty());
+
+    let resp = warp::test::request().method("POST").path("/-/restore").body(snapshot.body().clone()).reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::OK, "{:?}", resp.body());
+    assert_eq!(warp::test::request().method("GET").path("/metrics").reply(&routes).await.into_body(), before);
+
+    // Pushes merge into the restored series as they would have into the originals, including the windowed sum
+    assert_eq!(push("/metrics/job/a", "# TYPE requests_total counter\nrequests_total{path=\"/\"} 1\n").reply(&routes).await.status(), StatusCode::OK);
+    assert_eq!(push("/metrics/job/b", "# TYPE queued gauge\nqueued{clearmode=\"sum1m\"} 2\n").reply(&routes).await.status(), StatusCode::OK);
+    let after = String::from_utf8(warp::test::request().method("GET").path("/metrics").reply(&routes).await.into_body().to_vec()).unwrap();
+    assert!(after.contains("requests_total{path=\"/\",job=\"a\"} 4\n"), "{}", after);
+    assert!(after.contains("queued{job=\"b\"} 5\n"), "{}", after);
+}
+
+#[tokio::test]
+async fn test_jobs_are_listed_with_their_series() {
+    let routes = get_routes(Aggregator::new(), test_config());
+    let push = |path: &str, body: &str| warp::test::request().method("POST").path(path).body(body);
+    assert_eq!(push("/metrics/job/api", "# TYPE requests_total counter\nrequests_total{path=\"/\"} 3\nrequests_total{path=\"/login\"} 1\n# TYPE queued gauge\nqueued 2\n").reply(&routes).await.status(), StatusCode::OK);
+    assert_eq!(push("/metrics/job/batch", "# TYPE requests_total counter\nrequests_total{path=\"/\"} 5\n").reply(&routes).await.status(), StatusCode::OK);
+
+    let resp = warp::test::request().method("GET").path("/-/jobs").reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers()["content-type"], "application/json");
+    let jobs: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+    let jobs = jobs.as_array().unwrap();
+    assert_eq!(jobs.len(), 2, "{:?}", jobs);
+    assert_eq!((&jobs[0]["job"], &jobs[0]["series"]), (&"api".into(), &3.into()));
+    assert_eq!((&jobs[1]["job"], &jobs[1]["series"]), (&"batch".into(), &1.into()));
+    assert!(jobs.iter().all(|job| job["last_push"].as_f64().unwrap() > 0.), "{:?}", jobs);
+
+    let mut config = test_config();
+    config.authenticator = Box::new(DenyAllAuthenticator {});
+    let routes = get_routes(Aggregator::new(), config);
+    assert_eq!(warp::test::request().method("GET").path("/-/jobs").reply(&routes).await.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn test_restore_rejects_invalid_snapshots() {
+    let routes = get_routes(Aggregator::new(), test_config());
+    let resp = warp::test::request().method("POST").path("/metrics/job/a").body("# TYPE requests_total counter\nrequests_total 3\n").reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let before = warp::test::request().method("GET").path("/metrics").reply(&routes).await.into_body();
+
+    let restore = |body: &str| warp::test::request().method("POST").path("/-/restore").body(body);
+    let resp = restore("{\"version\":2,\"stores\":[]}").reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(resp.body(), "Unsupported snapshot version 2, expected 1");
+
+    let resp = restore("requests_total 3").reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    assert!(String::from_utf8_lossy(resp.body()).starts_with("Invalid snapshot"), "{:?}", resp.body());
+
+    // A snapshot that parses, but with a gauge's value in a counter family
+    let mismatched = "{\"version\":1,\"last_pushes\":{},\"families\":[{\"name\":\"requests_total\",\"type\":\"counter\",\"help\":\"\",\"unit\":\"\",\"label_names\":[],\"series\":[{\"label_values\":[],\"timestamp\":null,\"value\":{\"kind\":\"gauge\",\"value\":1},\"last_pushed\":0,\"last_counter_value\":null}]}]}";
+    let resp = restore(mismatched).reply(&routes).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(resp.body(), "invalid snapshot of requests_total: a gauge value can't be in a counter family");
+
+    // None of which touched the store
+    assert_eq!(warp::test::request().method("GET").path("/metrics").reply(&routes).await.into_body(), before);
+
+    let mut config = test_config();
+    config.authenticator = Box::new(DenyAllAuthenticator {});
+    let routes = get_routes(Aggregator::new(), config);
+    assert_eq!(warp::test::request().method("GET").path("/-/snapshot").reply(&routes).await.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(restore(mismatched).reply(&routes).await.status(), StatusCode::UNAUTHORIZED);
+}
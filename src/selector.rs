@@ -0,0 +1,144 @@
+use std::{collections::HashMap, str::FromStr};
+
+use openmetrics_parser::LabelSet;
+
+use crate::{aggregator::{AggregationError, is_valid_name}, exposition::escape_label_value};
+
+/// The pseudo label that matches on a metric's name, as in Prometheus
+const NAME_LABEL: &str = "__name__";
+
+#[derive(Debug, Clone, PartialEq)]
+enum MatchOp {
+    Equal,
+    NotEqual,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct LabelMatcher {
+    name: String,
+    op: MatchOp,
+    value: String,
+}
+
+impl LabelMatcher {
+    /// Labels that aren't there match as if they were empty, like they do in Prometheus
+    fn matches(&self, value: Option<&str>) -> bool {
+        let value = value.unwrap_or("");
+        match self.op {
+            MatchOp::Equal => value == self.value,
+            MatchOp::NotEqual => value != self.value,
+        }
+    }
+}
+
+/// A series selector like those in Prometheus' `match[]` federation parameter, e.g. `http_requests_total{job="api"}`.
+/// A series is selected if it matches every one of the selector's matchers. Only `=` and `!=` matchers are supported
+#[derive(Debug, Clone, PartialEq)]
+pub struct Selector {
+    matchers: Vec<LabelMatcher>,
+}
+
+impl Selector {
+    /// Selects the series that carry every one of the given labels, such as those from a push path
+    pub fn from_labels(labels: &HashMap<&str, &str>) -> Selector {
+        let mut matchers: Vec<LabelMatcher> = labels.iter()
+            .map(|(&name, &value)| LabelMatcher { name: name.to_owned(), op: MatchOp::Equal, value: escape_label_value(value).into_owned() })
+            .collect();
+        matchers.sort_by(|a, b| a.name.cmp(&b.name));
+        Selector { matchers }
+    }
+
+    /// Narrows the selector down to the series that the other one selects too
+    pub fn and(mut self, other: &Selector) -> Selector {
+        self.matchers.extend(other.matchers.iter().cloned());
+        self
+    }
+
+    /// Whether a series of the given family, with the given labels, is selected
+    pub fn matches(&self, family_name: &str, labels: &LabelSet) -> bool {
+        self.matches_with(family_name, |name| labels.get_label_value(name))
+    }
+
+    /// Like `matches`, for a series whose labels are looked up with the given function
+    pub fn matches_with<'a, F>(&self, family_name: &str, label: F) -> bool where F: Fn(&str) -> Option<&'a str> {
+        self.matchers.iter().all(|matcher| match matcher.name.as_str() {
+            NAME_LABEL => matcher.matches(Some(family_name)),
+            name => matcher.matches(label(name)),
+        })
+    }
+
+    /// Whether any series of the given family could be selected, going by the name alone
+    pub fn could_match_family(&self, family_name: &str) -> bool {
+        self.matchers.iter().filter(|matcher| matcher.name == NAME_LABEL).all(|matcher| matcher.matches(Some(family_name)))
+    }
+}
+
+impl FromStr for Selector {
+    type Err = AggregationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || AggregationError::Error(format!("Invalid selector: {}", s));
+        let s = s.trim();
+
+        let (name, mut rest) = match s.find('{') {
+            Some(idx) => (s[..idx].trim(), s[idx + 1..].strip_suffix('}').ok_or_else(invalid)?),
+            None => (s, ""),
+        };
+
+        let mut matchers = Vec::new();
+        if !name.is_empty() {
+            if !is_valid_name(name, true) {
+                return Err(AggregationError::InvalidName(name.to_owned()));
+            }
+
+            matchers.push(LabelMatcher { name: NAME_LABEL.to_owned(), op: MatchOp::Equal, value: name.to_owned() });
+        }
+
+        loop {
+            rest = rest.trim_start();
+            if rest.is_empty() {
+                break;
+            }
+
+            let op_idx = rest.find(['=', '!']).ok_or_else(invalid)?;
+            let label = rest[..op_idx].trim();
+            if !is_valid_name(label, false) {
+                return Err(AggregationError::InvalidName(label.to_owned()));
+            }
+
+            let (op, after_op) = if let Some(after) = rest[op_idx..].strip_prefix("!=") {
+                (MatchOp::NotEqual, after)
+            } else if rest[op_idx..].starts_with("=~") || rest[op_idx..].starts_with("!~") {
+                return Err(AggregationError::Error(format!("Regex matchers aren't supported: {}", s)));
+            } else if let Some(after) = rest[op_idx..].strip_prefix('=') {
+                (MatchOp::Equal, after)
+            } else {
+                return Err(invalid());
+            };
+
+            // Label values are stored with their escapes as is, so they're compared that way too
+            let value = after_op.trim_start().strip_prefix('"').ok_or_else(invalid)?;
+            let mut escaped = false;
+            let end = value.char_indices().find(|&(_, c)| {
+                let is_end = c == '"' && !escaped;
+                escaped = c == '\\' && !escaped;
+                is_end
+            }).ok_or_else(invalid)?.0;
+
+            matchers.push(LabelMatcher { name: label.to_owned(), op, value: value[..end].to_owned() });
+
+            rest = value[end + 1..].trim_start();
+            rest = match rest.strip_prefix(',') {
+                Some(rest) => rest,
+                None if rest.is_empty() => rest,
+                None => return Err(invalid()),
+            };
+        }
+
+        if matchers.is_empty() {
+            return Err(invalid());
+        }
+
+        Ok(Selector { matchers })
+    }
+}
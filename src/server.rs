@@ -0,0 +1,271 @@
+use std::{convert::Infallible, future::Future, net::SocketAddr};
+#[cfg(feature="tls")]
+use std::sync::Arc;
+
+use futures::{FutureExt, Stream, StreamExt};
+#[cfg(feature="tls")]
+use futures::future::{BoxFuture, Shared};
+#[cfg(feature="tls")]
+use tracing::{debug, warn};
+use warp::{Filter, Reply};
+
+#[cfg(feature="tls")]
+use crate::auth::ClientCertificate;
+
+/// The address of the client that sent a request, for servers that can't tell warp about it themselves
+#[derive(Clone, Copy, Debug)]
+pub struct RemoteAddr(pub SocketAddr);
+
+/// Resolves once the process is asked to stop, with a SIGTERM or a SIGINT (i.e. ctrl-c)
+pub async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        // If the handler can't be installed, we can still stop on ctrl-c
+        if let Ok(mut sigterm) = signal(SignalKind::terminate()) {
+            tokio::select! {
+                _ = sigterm.recv() => {},
+                _ = tokio::signal::ctrl_c() => {},
+            };
+            return;
+        }
+    }
+
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Yields every time the process gets a SIGHUP, which is how it's asked to reload its config
+pub fn reload_signals() -> impl Stream<Item = ()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        // Without a handler, a SIGHUP would kill the process, so it's better to fail to start than to carry on without one
+        let hangup = signal(SignalKind::hangup()).expect("failed to install a SIGHUP handler");
+        return futures::stream::unfold(hangup, |mut hangup| async move {
+            hangup.recv().await.map(|_| ((), hangup))
+        }).boxed();
+    }
+
+    #[cfg(not(unix))]
+    return futures::stream::pending().boxed();
+}
+
+/// Calls `reload` for each of the given signals, until they stop coming
+pub async fn reload_on(signals: impl Stream<Item = ()>, mut reload: impl FnMut()) {
+    futures::pin_mut!(signals);
+    while signals.next().await.is_some() {
+        reload();
+    }
+}
+
+/// Binds the routes to every one of the given addresses, returning the addresses that were actually bound (which differ when
+/// binding to port 0), and a future that serves them. HTTP/2 is served alongside HTTP/1.1, to clients that open with its
+/// preface (i.e. h2c with prior knowledge). Once `shutdown` resolves, the servers stop accepting connections, and the
+/// future resolves when the requests that were already in flight have finished. Errors if any of the addresses can't be
+/// bound (e.g. because something else is listening on it)
+pub fn serve<F>(routes: F, addresses: &[SocketAddr], shutdown: impl Future<Output = ()> + Send + 'static) -> Result<(Vec<SocketAddr>, impl Future<Output = ()>), String>
+    where F: Filter<Error = Infallible> + Clone + Send + Sync + 'static, F::Extract: Reply {
+    let shutdown = shutdown.boxed().shared();
+    let (bound, servers): (Vec<_>, Vec<_>) = addresses.iter()
+        .map(|addr| warp::serve(routes.clone()).try_bind_with_graceful_shutdown(*addr, shutdown.clone()).map_err(|e| format!("Failed to listen on {}: {}", addr, e)))
+        .collect::<Result<Vec<_>, String>>()?
+        .into_iter()
+        .unzip();
+
+    Ok((bound, futures::future::join_all(servers).map(|_| ())))
+}
+
+/// A TLS private key and certificate chain, read and checked up front, so that bad ones stop the gateway from starting with
+/// a clear error, rather than a panic once it's part way through binding
+#[cfg(feature="tls")]
+pub struct TlsFiles {
+    chain: Vec<rustls::Certificate>,
+    private_key: rustls::PrivateKey,
+    /// The CAs that clients' certificates are checked against, if clients can authenticate with them
+    client_roots: Option<rustls::RootCertStore>,
+}
+
+#[cfg(feature="tls")]
+impl TlsFiles {
+    /// Reads the PEM key and certificate files, checking that they parse the same way the server will parse them. Like
+    /// the server, the key can be PKCS#8 or RSA
+    pub fn load(key_path: &str, cert_path: &str) -> Result<TlsFiles, String> {
+        use rustls::{NoClientAuth, ServerConfig, internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys}};
+
+        let key = std::fs::read(key_path).map_err(|e| format!("Failed to read TLS key {}: {}", key_path, e))?;
+        let cert = std::fs::read(cert_path).map_err(|e| format!("Failed to read TLS certificate {}: {}", cert_path, e))?;
+
+        let chain = match certs(&mut &cert[..]) {
+            Ok(chain) if !chain.is_empty() => chain,
+            _ => return Err(format!("Invalid TLS certificate {}: expected a PEM certificate chain", cert_path)),
+        };
+
+        let private_key = match pkcs8_private_keys(&mut &key[..]) {
+            Ok(mut keys) if !keys.is_empty() => keys.remove(0),
+            _ => match rsa_private_keys(&mut &key[..]) {
+                Ok(mut keys) if !keys.is_empty() => keys.remove(0),
+                _ => return Err(format!("Invalid TLS key {}: expected a PEM PKCS#8 or RSA private key", key_path)),
+            },
+        };
+
+        ServerConfig::new(NoClientAuth::new()).set_single_cert(chain.clone(), private_key.clone())
+            .map_err(|e| format!("Invalid TLS key {} for certificate {}: {}", key_path, cert_path, e))?;
+
+        Ok(TlsFiles { chain, private_key, client_roots: None })
+    }
+
+    /// Reads the PEM file of CA certificates that clients' certificates have to be signed by to authenticate with them
+    pub fn with_client_ca(mut self, ca_path: &str) -> Result<TlsFiles, String> {
+        let ca = std::fs::read(ca_path).map_err(|e| format!("Failed to read TLS client CA {}: {}", ca_path, e))?;
+        let mut roots = rustls::RootCertStore::empty();
+        match roots.add_pem_file(&mut &ca[..]) {
+            Ok((added, 0)) if added > 0 => {},
+            _ => return Err(format!("Invalid TLS client CA {}: expected PEM CA certificates", ca_path)),
+        }
+
+        self.client_roots = Some(roots);
+        Ok(self)
+    }
+}
+
+/// Asks clients for a certificate, but lets the handshake through whether or not it's trusted (as long as the client proves
+/// it has the certificate's key), so that the certificate can be checked afterwards and an untrusted client gets a 401 rather
+/// than a TLS alert that most clients report as some unhelpful connection error
+#[cfg(feature="tls")]
+struct DeferredClientCertVerifier {
+    roots: rustls::RootCertStore,
+}
+
+#[cfg(feature="tls")]
+impl rustls::ClientCertVerifier for DeferredClientCertVerifier {
+    fn client_auth_mandatory(&self, _sni: Option<&tokio_rustls::webpki::DNSName>) -> Option<bool> {
+        Some(false)
+    }
+
+    fn client_auth_root_subjects(&self, _sni: Option<&tokio_rustls::webpki::DNSName>) -> Option<rustls::DistinguishedNames> {
+        Some(self.roots.get_subjects())
+    }
+
+    fn verify_client_cert(&self, _presented_certs: &[rustls::Certificate], _sni: Option<&tokio_rustls::webpki::DNSName>) -> Result<rustls::ClientCertVerified, rustls::TLSError> {
+        Ok(rustls::ClientCertVerified::assertion())
+    }
+}
+
+/// Checks the certificate chain a client presented against the client CAs, returning the names in it if it's trusted
+#[cfg(feature="tls")]
+fn client_certificate(roots: &rustls::RootCertStore, presented_certs: &[rustls::Certificate]) -> Option<ClientCertificate> {
+    use openssl::{nid::Nid, x509::X509};
+    use rustls::AllowAnyAuthenticatedClient;
+
+    if let Err(e) = AllowAnyAuthenticatedClient::new(roots.clone()).verify_client_cert(presented_certs, None) {
+        debug!(error = %e, "client presented an untrusted certificate");
+        return None;
+    }
+
+    let certificate = X509::from_der(&presented_certs.first()?.0).ok()?;
+    let common_names = certificate.subject_name().entries_by_nid(Nid::COMMONNAME)
+        .filter_map(|entry| entry.data().as_utf8().ok().map(|name| name.to_string()));
+    let dns_names = certificate.subject_alt_names().into_iter().flatten()
+        .filter_map(|name| name.dnsname().map(String::from));
+
+    Some(ClientCertificate { names: common_names.chain(dns_names).collect() })
+}
+
+/// Like `serve`, but over TLS with the given key and certificate, and with HTTP/2 for the clients that ask for it by ALPN.
+/// If there's a client CA, clients are asked for their certificates, and the trusted ones are passed on to the routes in
+/// the requests' extensions
+#[cfg(feature="tls")]
+pub fn serve_tls<F>(routes: F, addresses: &[SocketAddr], tls: &TlsFiles, shutdown: impl Future<Output = ()> + Send + 'static) -> Result<(Vec<SocketAddr>, impl Future<Output = ()>), String>
+    where F: Filter<Error = Infallible> + Clone + Send + Sync + 'static, F::Extract: Reply {
+    let shutdown = shutdown.boxed().shared();
+    let mut config = match &tls.client_roots {
+        Some(roots) => rustls::ServerConfig::new(Arc::new(DeferredClientCertVerifier { roots: roots.clone() })),
+        None => rustls::ServerConfig::new(rustls::NoClientAuth::new()),
+    };
+    // Already checked when the files were loaded
+    config.set_single_cert(tls.chain.clone(), tls.private_key.clone()).expect("TLS key and certificate were checked");
+    config.set_protocols(&[b"h2".to_vec(), b"http/1.1".to_vec()]);
+    let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(config));
+    let roots = tls.client_roots.clone().map(Arc::new);
+
+    let (bound, servers): (Vec<_>, Vec<_>) = addresses.iter()
+        .map(|addr| serve_rustls(routes.clone(), *addr, acceptor.clone(), roots.clone(), shutdown.clone()))
+        .collect::<Result<Vec<_>, String>>()?
+        .into_iter()
+        .unzip();
+
+    Ok((bound, futures::future::join_all(servers).map(|_| ())))
+}
+
+/// Serves the routes over TLS on the given address. Warp's own TLS server can't get at the clients' certificates, and
+/// panics if the address can't be bound, so this runs the connections itself
+#[cfg(feature="tls")]
+fn serve_rustls<F>(routes: F, addr: SocketAddr, acceptor: tokio_rustls::TlsAcceptor, roots: Option<Arc<rustls::RootCertStore>>, shutdown: Shared<BoxFuture<'static, ()>>) -> Result<(SocketAddr, BoxFuture<'static, ()>), String>
+    where F: Filter<Error = Infallible> + Clone + Send + Sync + 'static, F::Extract: Reply {
+    use warp::hyper::{Body, Request, server::conn::Http, service::{Service, service_fn}};
+
+    let listener = std::net::TcpListener::bind(addr)
+        .and_then(|listener| {
+            listener.set_nonblocking(true)?;
+            tokio::net::TcpListener::from_std(listener)
+        })
+        .map_err(|e| format!("Failed to listen on {}: {}", addr, e))?;
+    let bound = listener.local_addr().unwrap_or(addr);
+    let service = warp::service(routes);
+
+    let server = async move {
+        // Every connection holds a sender, so once they've all been dropped, everything in flight has finished
+        let (in_flight, mut finished) = tokio::sync::mpsc::channel::<()>(1);
+        loop {
+            let (stream, remote) = tokio::select! {
+                _ = shutdown.clone() => break,
+                accepted = listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        warn!(error = %e, "failed to accept a connection");
+                        continue;
+                    }
+                },
+            };
+
+            let (acceptor, service, roots, shutdown, in_flight) = (acceptor.clone(), service.clone(), roots.clone(), shutdown.clone(), in_flight.clone());
+            tokio::spawn(async move {
+                let _in_flight = in_flight;
+                let stream = match acceptor.accept(stream).await {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        debug!(%remote, error = %e, "TLS handshake failed");
+                        return;
+                    }
+                };
+
+                use rustls::Session;
+                let certificate = roots.and_then(|roots| client_certificate(&roots, &stream.get_ref().1.get_peer_certificates()?));
+                let http2 = stream.get_ref().1.get_alpn_protocol() == Some(b"h2");
+                let service = service_fn(move |mut request: Request<Body>| {
+                    request.extensions_mut().insert(RemoteAddr(remote));
+                    if let Some(certificate) = certificate.clone() {
+                        request.extensions_mut().insert(certificate);
+                    }
+                    service.clone().call(request)
+                });
+
+                // Connections that negotiated HTTP/2 don't need to be sniffed for its preface
+                let connection = Http::new().http2_only(http2).serve_connection(stream, service);
+                futures::pin_mut!(connection);
+                tokio::select! {
+                    _ = connection.as_mut() => return,
+                    _ = shutdown => connection.as_mut().graceful_shutdown(),
+                }
+                let _ = connection.await;
+            });
+        }
+
+        drop(in_flight);
+        let _ = finished.recv().await;
+    };
+
+    Ok((bound, server.boxed()))
+}
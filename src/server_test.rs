@@ -0,0 +1,242 @@
+use std::time::Duration;
+
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpStream, sync::oneshot};
+use warp::Filter;
+
+use crate::server::serve;
+#[cfg(feature="tls")]
+use crate::server::{TlsFiles, serve_tls};
+
+#[tokio::test]
+async fn test_shutdown_lets_in_flight_requests_finish() {
+    let routes = warp::path("slow").and_then(|| async {
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        Ok::<_, warp::Rejection>("done")
+    }).recover(|_| async { Ok::<_, std::convert::Infallible>("rejected") });
+
+    let (shutdown, shutdown_signal) = oneshot::channel::<()>();
+    let (addresses, server) = serve(routes, &[([127, 0, 0, 1], 0).into()], async { let _ = shutdown_signal.await; }).unwrap();
+    let server = tokio::spawn(server);
+
+    let mut conn = TcpStream::connect(addresses[0]).await.unwrap();
+    conn.write_all(b"GET /slow HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await.unwrap();
+
+    // Shut down while the request is still being handled
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    shutdown.send(()).unwrap();
+
+    let mut response = String::new();
+    conn.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    assert!(response.ends_with("done"), "{}", response);
+
+    tokio::time::timeout(Duration::from_secs(1), server).await.expect("server didn't stop").unwrap();
+    assert!(TcpStream::connect(addresses[0]).await.is_err(), "server is still accepting connections");
+}
+
+#[tokio::test]
+async fn test_push_on_ephemeral_port() {
+    use crate::{aggregator::Aggregator, auth::pass_through_auth, routes::{RoutesConfig, get_routes}};
+
+    let agg = Aggregator::new();
+    let routes = get_routes(agg.clone(), RoutesConfig {
+        authenticator: Box::new(pass_through_auth()),
+        auth_header: warp::http::header::AUTHORIZATION,
+        max_body_bytes: 1024,
+        rate_limit: None,
+        max_in_flight_pushes: 64,
+        cors_allowed_origins: Vec::new(),
+        default_job: None,
+        strict_label_paths: false,
+        graphite_prefix: None,
+        #[cfg(feature="clustering")]
+        cluster_conf: None,
+    });
+
+    let (shutdown, shutdown_signal) = oneshot::channel::<()>();
+    let (addresses, server) = serve(routes, &[([127, 0, 0, 1], 0).into()], async { let _ = shutdown_signal.await; }).unwrap();
+    assert_ne!(addresses[0].port(), 0);
+    let server = tokio::spawn(server);
+
+    let url = format!("http://{}/metrics", addresses[0]);
+    let resp = reqwest::Client::new().post(format!("{}/job/ephemeral", url)).body("# TYPE requests_total counter\nrequests_total 1\n").send().await.unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::OK);
+    assert!(agg.to_string().await.contains("requests_total{job=\"ephemeral\"} 1\n"));
+
+    // Something's already listening there, so it can't be bound again
+    let error = serve(warp::any().map(warp::reply), &addresses, async {}).err().unwrap();
+    assert!(error.starts_with(&format!("Failed to listen on {}: ", addresses[0])), "{}", error);
+
+    shutdown.send(()).unwrap();
+    tokio::time::timeout(Duration::from_secs(1), server).await.expect("server didn't stop").unwrap();
+}
+
+#[tokio::test]
+async fn test_push_over_h2c() {
+    use crate::{aggregator::Aggregator, auth::pass_through_auth, routes::{RoutesConfig, get_routes}};
+
+    let agg = Aggregator::new();
+    let routes = get_routes(agg.clone(), RoutesConfig {
+        authenticator: Box::new(pass_through_auth()),
+        auth_header: warp::http::header::AUTHORIZATION,
+        max_body_bytes: 1024,
+        rate_limit: None,
+        max_in_flight_pushes: 64,
+        cors_allowed_origins: Vec::new(),
+        default_job: None,
+        strict_label_paths: false,
+        graphite_prefix: None,
+        #[cfg(feature="clustering")]
+        cluster_conf: None,
+    });
+
+    let (shutdown, shutdown_signal) = oneshot::channel::<()>();
+    let (addresses, server) = serve(routes, &[([127, 0, 0, 1], 0).into()], async { let _ = shutdown_signal.await; }).unwrap();
+    let server = tokio::spawn(server);
+
+    // Pushes from one client share its connection, as separate streams
+    let client = reqwest::Client::builder().http2_prior_knowledge().build().unwrap();
+    let url = format!("http://{}/metrics/job/h2c", addresses[0]);
+    let pushes = (0..10).map(|_| client.post(&url).body("# TYPE requests_total counter\nrequests_total 1\n").send());
+    for resp in futures::future::join_all(pushes).await {
+        let resp = resp.unwrap();
+        assert_eq!(resp.version(), reqwest::Version::HTTP_2);
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+    }
+    assert!(agg.to_string().await.contains("requests_total{job=\"h2c\"} 10\n"));
+
+    // Bodies over the limit are still rejected, whether or not they say how big they are up front
+    let too_big = format!("# TYPE requests_total counter\nrequests_total{{padding=\"{}\"}} 1\n", "x".repeat(1024));
+    let resp = client.post(&url).body(too_big.clone()).send().await.unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::PAYLOAD_TOO_LARGE);
+    let stream = futures::stream::iter(vec![Ok::<_, std::io::Error>(too_big)]);
+    let resp = client.post(&url).body(reqwest::Body::wrap_stream(stream)).send().await.unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::PAYLOAD_TOO_LARGE);
+    assert!(agg.to_string().await.contains("requests_total{job=\"h2c\"} 10\n"));
+
+    shutdown.send(()).unwrap();
+    tokio::time::timeout(Duration::from_secs(1), server).await.expect("server didn't stop").unwrap();
+}
+
+#[cfg(feature="tls")]
+fn testdata(file: &str) -> String {
+    format!("{}/testdata/tls/{}", env!("CARGO_MANIFEST_DIR"), file)
+}
+
+#[cfg(feature="tls")]
+#[tokio::test]
+async fn test_push_over_tls() {
+    use crate::{aggregator::Aggregator, auth::pass_through_auth, routes::{RoutesConfig, get_routes}};
+
+    let agg = Aggregator::new();
+    let routes = get_routes(agg.clone(), RoutesConfig {
+        authenticator: Box::new(pass_through_auth()),
+        auth_header: warp::http::header::AUTHORIZATION,
+        max_body_bytes: 1024,
+        rate_limit: None,
+        max_in_flight_pushes: 64,
+        cors_allowed_origins: Vec::new(),
+        default_job: None,
+        strict_label_paths: false,
+        graphite_prefix: None,
+        #[cfg(feature="clustering")]
+        cluster_conf: None,
+    });
+
+    let tls = TlsFiles::load(&testdata("key.pem"), &testdata("cert.pem")).unwrap();
+    let (shutdown, shutdown_signal) = oneshot::channel::<()>();
+    let (addresses, server) = serve_tls(routes, &[([127, 0, 0, 1], 0).into()], &tls, async { let _ = shutdown_signal.await; }).unwrap();
+    let server = tokio::spawn(server);
+
+    // The certificate is self-signed, so it's trusted as its own root. It's for localhost, which is pinned to the
+    // server's address, since whatever localhost resolves to first might not be the one it's listening on
+    let cert = reqwest::Certificate::from_pem(&std::fs::read(testdata("cert.pem")).unwrap()).unwrap();
+    let client = reqwest::Client::builder().add_root_certificate(cert).resolve("localhost", addresses[0]).build().unwrap();
+    let url = format!("https://localhost:{}/metrics", addresses[0].port());
+
+    let resp = client.post(format!("{}/job/tls", url)).body("# TYPE requests_total counter\nrequests_total 1\n").send().await.unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::OK);
+    // Clients that offer HTTP/2 get it, by ALPN
+    assert_eq!(resp.version(), reqwest::Version::HTTP_2);
+
+    let body = client.get(&url).send().await.unwrap().text().await.unwrap();
+    assert!(body.contains("requests_total{job=\"tls\"} 1\n"), "{}", body);
+
+    // Plain HTTP isn't served on the TLS port
+    assert!(reqwest::get(format!("http://127.0.0.1:{}/metrics", addresses[0].port())).await.map_or(true, |resp| !resp.status().is_success()));
+
+    // Something's already listening there, so it can't be bound again
+    let error = serve_tls(warp::any().map(warp::reply), &addresses, &tls, async {}).err().unwrap();
+    assert!(error.starts_with(&format!("Failed to listen on {}: ", addresses[0])), "{}", error);
+
+    shutdown.send(()).unwrap();
+    tokio::time::timeout(Duration::from_secs(1), server).await.expect("server didn't stop").unwrap();
+}
+
+#[cfg(feature="tls")]
+#[tokio::test]
+async fn test_client_certificate_auth() {
+    use crate::{aggregator::Aggregator, auth::ClientCertAuthenticator, routes::{RoutesConfig, get_routes}};
+
+    let routes = get_routes(Aggregator::new(), RoutesConfig {
+        authenticator: Box::new(ClientCertAuthenticator::new(vec!["pusher.example.com".to_owned()], None)),
+        auth_header: warp::http::header::AUTHORIZATION,
+        max_body_bytes: 1024,
+        rate_limit: None,
+        max_in_flight_pushes: 64,
+        cors_allowed_origins: Vec::new(),
+        default_job: None,
+        strict_label_paths: false,
+        graphite_prefix: None,
+        #[cfg(feature="clustering")]
+        cluster_conf: None,
+    });
+
+    let tls = TlsFiles::load(&testdata("key.pem"), &testdata("cert.pem")).unwrap().with_client_ca(&testdata("client-ca.pem")).unwrap();
+    let (shutdown, shutdown_signal) = oneshot::channel::<()>();
+    let (addresses, server) = serve_tls(routes, &[([127, 0, 0, 1], 0).into()], &tls, async { let _ = shutdown_signal.await; }).unwrap();
+    let server = tokio::spawn(server);
+
+    let url = format!("https://localhost:{}/metrics/job/mtls", addresses[0].port());
+    let push = |identity: Option<&str>| {
+        let cert = reqwest::Certificate::from_pem(&std::fs::read(testdata("cert.pem")).unwrap()).unwrap();
+        let mut client = reqwest::Client::builder().add_root_certificate(cert).resolve("localhost", addresses[0]);
+        if let Some(identity) = identity {
+            client = client.identity(reqwest::Identity::from_pkcs12_der(&std::fs::read(testdata(identity)).unwrap(), "gravel").unwrap());
+        }
+        client.build().unwrap().post(&url).body("# TYPE requests_total counter\nrequests_total 1\n").send()
+    };
+
+    // Both client certificates are for pusher.example.com, but only one is signed by the client CA
+    assert_eq!(push(Some("client.p12")).await.unwrap().status(), reqwest::StatusCode::OK);
+    assert_eq!(push(Some("untrusted-client.p12")).await.unwrap().status(), reqwest::StatusCode::UNAUTHORIZED);
+    assert_eq!(push(None).await.unwrap().status(), reqwest::StatusCode::UNAUTHORIZED);
+
+    shutdown.send(()).unwrap();
+    tokio::time::timeout(Duration::from_secs(1), server).await.expect("server didn't stop").unwrap();
+}
+
+#[cfg(feature="tls")]
+#[test]
+fn test_invalid_client_ca() {
+    let tls = || TlsFiles::load(&testdata("key.pem"), &testdata("cert.pem")).unwrap();
+    let missing = tls().with_client_ca(&testdata("missing.pem")).err().unwrap();
+    assert!(missing.starts_with("Failed to read TLS client CA"), "{}", missing);
+
+    let not_a_ca = tls().with_client_ca(&testdata("key.pem")).err().unwrap();
+    assert!(not_a_ca.starts_with("Invalid TLS client CA"), "{}", not_a_ca);
+}
+
+#[cfg(feature="tls")]
+#[test]
+fn test_invalid_tls_files() {
+    let missing = TlsFiles::load(&testdata("missing.pem"), &testdata("cert.pem")).err().unwrap();
+    assert!(missing.starts_with("Failed to read TLS key"), "{}", missing);
+
+    // The key and certificate the wrong way around
+    let swapped = TlsFiles::load(&testdata("cert.pem"), &testdata("key.pem")).err().unwrap();
+    assert!(swapped.starts_with("Invalid TLS certificate"), "{}", swapped);
+
+    let not_a_key = TlsFiles::load(&testdata("cert.pem"), &testdata("cert.pem")).err().unwrap();
+    assert!(not_a_key.starts_with("Invalid TLS key"), "{}", not_a_key);
+}
@@ -0,0 +1,202 @@
+use std::{collections::BTreeMap, fmt};
+
+use openmetrics_parser::{Exemplar, MetricNumber};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::pebble::PebbleSnapshot;
+
+/// The version of the snapshot format, which goes up with any change that an older gateway couldn't restore correctly
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+/// Errors if a snapshot of the given version can't be restored by this gateway
+pub fn check_version(version: u32) -> Result<(), String> {
+    match version {
+        SNAPSHOT_VERSION => Ok(()),
+        _ => Err(format!("Unsupported snapshot version {}, expected {}", version, SNAPSHOT_VERSION)),
+    }
+}
+
+/// Everything in a store, as served by `/-/snapshot` and restored by `/-/restore`. Unlike the snapshot file (which is in the
+/// text format), this keeps what's tracked about each series to merge pushes into it, so a restored store carries on
+/// exactly where the snapshotted one left off
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StoreSnapshot {
+    pub version: u32,
+    pub families: Vec<FamilySnapshot>,
+    /// When each job was last pushed to, in seconds since the Unix epoch
+    pub last_pushes: BTreeMap<String, f64>,
+}
+
+/// Just the version of a snapshot, which is read first so that a snapshot from another version gets a clear error,
+/// rather than whatever its differences happen to fail to parse with
+#[derive(Deserialize)]
+pub struct SnapshotVersion {
+    pub version: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FamilySnapshot {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub family_type: String,
+    pub help: String,
+    pub unit: String,
+    pub label_names: Vec<String>,
+    pub series: Vec<SeriesSnapshot>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SeriesSnapshot {
+    pub label_values: Vec<String>,
+    pub timestamp: Option<f64>,
+    pub value: ValueSnapshot,
+    /// When the series was last pushed to, in seconds since the Unix epoch
+    pub last_pushed: f64,
+    /// The raw value of the last counter pushed to the series, for spotting resets
+    pub last_counter_value: Option<f64>,
+}
+
+/// A series' aggregated value. The windowed and running means that some clear modes turn values into are kept as they
+/// are, rather than as the gauges they render as, so that pushes keep merging into them the same way
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ValueSnapshot {
+    Unknown { value: Number },
+    Gauge { value: Number },
+    Counter { value: Number, exemplar: Option<ExemplarSnapshot> },
+    Histogram { sum: Option<Number>, count: Option<u64>, created: Option<f64>, buckets: Vec<BucketSnapshot> },
+    Summary { sum: Option<Number>, count: Option<u64>, created: Option<f64>, quantiles: Vec<QuantileSnapshot> },
+    /// From a `sum<duration>` or `mean<duration>` clearmode
+    Window { window: PebbleSnapshot },
+    /// From a `mean` gauge aggregation
+    Mean { sum: Float, count: u64 },
+}
+
+impl ValueSnapshot {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ValueSnapshot::Unknown { .. } => "unknown",
+            ValueSnapshot::Gauge { .. } => "gauge",
+            ValueSnapshot::Counter { .. } => "counter",
+            ValueSnapshot::Histogram { .. } => "histogram",
+            ValueSnapshot::Summary { .. } => "summary",
+            ValueSnapshot::Window { .. } => "window",
+            ValueSnapshot::Mean { .. } => "mean",
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BucketSnapshot {
+    pub upper_bound: Float,
+    pub count: Number,
+    pub exemplar: Option<ExemplarSnapshot>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct QuantileSnapshot {
+    pub quantile: Float,
+    pub value: Number,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExemplarSnapshot {
+    pub labels: BTreeMap<String, String>,
+    pub value: Float,
+    pub timestamp: Option<f64>,
+}
+
+impl From<&Exemplar> for ExemplarSnapshot {
+    fn from(exemplar: &Exemplar) -> Self {
+        ExemplarSnapshot {
+            labels: exemplar.labels.iter().map(|(name, value)| (name.clone(), value.clone())).collect(),
+            value: Float(exemplar.id),
+            timestamp: exemplar.timestamp,
+        }
+    }
+}
+
+impl From<ExemplarSnapshot> for Exemplar {
+    fn from(exemplar: ExemplarSnapshot) -> Self {
+        Exemplar::new(exemplar.labels.into_iter().collect(), exemplar.value.0, exemplar.timestamp)
+    }
+}
+
+/// A value that was pushed as an int or a float, which is kept as it was so that it renders the same once restored
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Number {
+    Int(i64),
+    Float(Float),
+}
+
+impl From<MetricNumber> for Number {
+    fn from(number: MetricNumber) -> Self {
+        match number {
+            MetricNumber::Int(int) => Number::Int(int),
+            MetricNumber::Float(float) => Number::Float(Float(float)),
+        }
+    }
+}
+
+impl From<Number> for MetricNumber {
+    fn from(number: Number) -> Self {
+        match number {
+            Number::Int(int) => MetricNumber::Int(int),
+            Number::Float(Float(float)) => MetricNumber::Float(float),
+        }
+    }
+}
+
+/// A float that can be infinite or NaN (e.g. a histogram's `+Inf` bucket), which JSON has no numbers for. Those are
+/// written the way the text format writes them, as the strings `+Inf`, `-Inf`, and `NaN`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Float(pub f64);
+
+impl Serialize for Float {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.0 {
+            f if f.is_nan() => serializer.serialize_str("NaN"),
+            f if f == f64::INFINITY => serializer.serialize_str("+Inf"),
+            f if f == f64::NEG_INFINITY => serializer.serialize_str("-Inf"),
+            f => serializer.serialize_f64(f),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Float {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct FloatVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for FloatVisitor {
+            type Value = Float;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a number, or one of +Inf, -Inf, or NaN")
+            }
+
+            fn visit_f64<E: serde::de::Error>(self, v: f64) -> Result<Float, E> {
+                Ok(Float(v))
+            }
+
+            fn visit_i64<E: serde::de::Error>(self, v: i64) -> Result<Float, E> {
+                Ok(Float(v as f64))
+            }
+
+            fn visit_u64<E: serde::de::Error>(self, v: u64) -> Result<Float, E> {
+                Ok(Float(v as f64))
+            }
+
+            fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<Float, E> {
+                match v {
+                    "NaN" => Ok(Float(f64::NAN)),
+                    "+Inf" => Ok(Float(f64::INFINITY)),
+                    "-Inf" => Ok(Float(f64::NEG_INFINITY)),
+                    _ => Err(E::invalid_value(serde::de::Unexpected::Str(v), &self)),
+                }
+            }
+        }
+
+        deserializer.deserialize_any(FloatVisitor)
+    }
+}
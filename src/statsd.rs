@@ -0,0 +1,212 @@
+use std::{collections::HashMap, time::Instant};
+
+use openmetrics_parser::{HistogramBucket, HistogramValue, MetricNumber, ParseError, PrometheusCounterValue, PrometheusMetricFamily, PrometheusType, PrometheusValue, Sample};
+use tokio::{io::{AsyncBufReadExt, BufReader}, net::{TcpListener, UdpSocket}};
+use tracing::{debug, error, warn};
+
+use crate::{aggregator::{AggregationError, Aggregator, CLEARMODE_LABEL_NAME}, influx::sanitize_name};
+
+/// The biggest UDP packet we can receive. StatsD clients keep theirs well under this so they don't get fragmented
+const MAX_PACKET_BYTES: usize = 65535;
+
+/// The buckets that timers are observed into, in seconds. These are the Prometheus client libraries' defaults
+const TIMER_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1., 2.5, 5., 10.];
+
+/// The value of a stat, summed up over every line for it in a packet
+enum Stat {
+    /// The total increment, scaled up by the sample rates
+    Counter(f64),
+    /// The gauge's value, and whether it's a change to the old one (from a `+` or `-` value) rather than a new one
+    Gauge { value: f64, delta: bool },
+    /// How many timings fell in each bucket (rather than at or below it), with an extra one for those over the last
+    /// bound, and the sum of them all in seconds
+    Timer { buckets: Vec<u64>, sum: f64 },
+}
+
+impl Stat {
+    fn type_name(&self) -> &'static str {
+        match self {
+            Stat::Counter(_) => "counter",
+            Stat::Gauge { .. } => "gauge",
+            Stat::Timer { .. } => "timer",
+        }
+    }
+
+    fn into_family(self, name: String) -> Result<PrometheusMetricFamily, ParseError> {
+        let (name, family_type, label_names, labels, value) = match self {
+            Stat::Counter(value) => (name, PrometheusType::Counter, vec![], vec![], PrometheusValue::Counter(PrometheusCounterValue {
+                value: number(value),
+                exemplar: None,
+            })),
+            // Gauges are set or changed whatever the gauge aggregation is, since that's what the client asked for
+            Stat::Gauge { value, delta } => {
+                let clearmode = if delta { "aggregate" } else { "replace" };
+                (name, PrometheusType::Gauge, vec![CLEARMODE_LABEL_NAME.to_owned()], vec![clearmode.to_owned()], PrometheusValue::Gauge(number(value)))
+            },
+            Stat::Timer { buckets, sum } => {
+                let mut count = 0;
+                let bounds = TIMER_BUCKETS.iter().copied().chain(std::iter::once(f64::INFINITY));
+                let buckets = bounds.zip(buckets).map(|(upper_bound, bucket)| {
+                    count += bucket;
+                    HistogramBucket { count: MetricNumber::Int(count as i64), upper_bound, exemplar: None }
+                }).collect();
+
+                (format!("{}_seconds", name), PrometheusType::Histogram, vec![], vec![], PrometheusValue::Histogram(HistogramValue {
+                    sum: Some(MetricNumber::Float(sum)),
+                    count: Some(count),
+                    created: None,
+                    buckets,
+                }))
+            },
+        };
+
+        PrometheusMetricFamily::new(name, label_names, family_type, String::new(), String::new())
+            .with_samples(std::iter::once(Sample::new(labels, None, value)))
+    }
+}
+
+/// Whole values are kept as ints so that they render the same as text pushes
+fn number(value: f64) -> MetricNumber {
+    match value.fract() == 0. && value.abs() < i64::MAX as f64 {
+        true => MetricNumber::Int(value as i64),
+        false => MetricNumber::Float(value),
+    }
+}
+
+fn invalid(line: &str, reason: &str) -> ParseError {
+    ParseError::ParseError(format!("Invalid StatsD line {}: {}", line, reason))
+}
+
+/// Decodes a StatsD packet of `name:value|type` lines (optionally with a `|@rate` sample rate) into families. Counters
+/// (`c`) are summed, with each increment scaled up by its sample rate. Gauges (`g`) are set, or changed if their value
+/// starts with a `+` or `-`. Timers (`ms`) become `<name>_seconds` histograms. Names are made into valid Prometheus
+/// names, so `api.requests` becomes `api_requests`
+pub fn decode_statsd(packet: &str) -> Result<Vec<PrometheusMetricFamily>, ParseError> {
+    let mut stats: Vec<(String, Stat)> = Vec::new();
+    for line in packet.lines().map(str::trim) {
+        if line.is_empty() {
+            continue;
+        }
+
+        let (name, rest) = line.split_once(':').ok_or_else(|| invalid(line, "no value"))?;
+        let mut sections = rest.split('|');
+        // split always gives at least one section
+        let value = sections.next().unwrap();
+        let stat_type = sections.next().ok_or_else(|| invalid(line, "no type"))?;
+        let mut rate = 1.;
+        for section in sections {
+            match section.strip_prefix('@') {
+                Some(r) => match r.parse::<f64>() {
+                    Ok(r) if r > 0. && r <= 1. => rate = r,
+                    _ => return Err(invalid(line, &format!("sample rate {} isn't between 0 and 1", r))),
+                },
+                None if section.starts_with('#') => return Err(invalid(line, "tags aren't supported")),
+                None => return Err(invalid(line, &format!("unknown section {}", section))),
+            }
+        }
+
+        let parsed: f64 = value.parse().map_err(|_| invalid(line, &format!("value {} isn't a number", value)))?;
+        if !parsed.is_finite() {
+            return Err(invalid(line, &format!("value {} isn't finite", value)));
+        }
+
+        let name = sanitize_name(name);
+        let idx = stats.iter().position(|(stat, _)| *stat == name);
+        let stat = match (stat_type, idx.map(|idx| &mut stats[idx].1)) {
+            ("c", Some(Stat::Counter(total))) => { *total += parsed / rate; continue },
+            ("c", None) => Stat::Counter(parsed / rate),
+            ("g", Some(Stat::Gauge { value: gauge, .. })) if value.starts_with(['+', '-']) => { *gauge += parsed; continue },
+            ("g", Some(Stat::Gauge { value: gauge, delta })) => { *gauge = parsed; *delta = false; continue },
+            ("g", None) => Stat::Gauge { value: parsed, delta: value.starts_with(['+', '-']) },
+            ("ms", existing) => {
+                let seconds = parsed / 1000.;
+                let bucket = TIMER_BUCKETS.iter().position(|bound| seconds <= *bound).unwrap_or(TIMER_BUCKETS.len());
+                match existing {
+                    Some(Stat::Timer { buckets, sum }) => { buckets[bucket] += 1; *sum += seconds; continue },
+                    None => {
+                        let mut buckets = vec![0; TIMER_BUCKETS.len() + 1];
+                        buckets[bucket] += 1;
+                        Stat::Timer { buckets, sum: seconds }
+                    },
+                    Some(existing) => return Err(invalid(line, &format!("{} is a {}, but was sent as a timer", name, existing.type_name()))),
+                }
+            },
+            ("c" | "g", Some(existing)) => return Err(invalid(line, &format!("{} is a {}, but was sent as a {}", name, existing.type_name(), stat_type))),
+            (stat_type, _) => return Err(invalid(line, &format!("type {} isn't supported", stat_type))),
+        };
+        stats.push((name, stat));
+    }
+
+    stats.into_iter().map(|(name, stat)| stat.into_family(name)).collect()
+}
+
+/// Decodes a StatsD packet and merges it into the aggregator, with the given labels, recording it in the gateway
+/// metrics like a push
+pub async fn merge_packet(agg: &mut Aggregator, packet: &[u8], labels: &HashMap<&str, &str>) -> Result<(), AggregationError> {
+    agg.metrics().record_push(packet.len());
+    let families = match std::str::from_utf8(packet).map_err(|e| ParseError::ParseError(e.to_string())).and_then(decode_statsd) {
+        Ok(families) => families,
+        Err(e) => {
+            agg.metrics().record_parse_error();
+            return Err(e.into());
+        }
+    };
+
+    let started = Instant::now();
+    let result = agg.merge_families(families, labels).await;
+    agg.metrics().record_merge(started.elapsed());
+    result
+}
+
+/// Merges every StatsD packet sent to the socket into the aggregator, forever
+pub async fn serve_udp(socket: UdpSocket, mut agg: Aggregator, labels: HashMap<String, String>) {
+    let labels: HashMap<&str, &str> = labels.iter().map(|(name, value)| (name.as_str(), value.as_str())).collect();
+    let mut buf = vec![0; MAX_PACKET_BYTES];
+    loop {
+        let (len, from) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                error!(error = %e, "failed to receive a StatsD packet");
+                continue;
+            }
+        };
+
+        match merge_packet(&mut agg, &buf[..len], &labels).await {
+            Ok(_) => debug!(%from, bytes = len, "merged StatsD packet"),
+            Err(e) => warn!(%from, error = %e, "rejected StatsD packet"),
+        }
+    }
+}
+
+/// Accepts StatsD connections on the listener, merging every line sent on them into the aggregator, forever
+pub async fn serve_tcp(listener: TcpListener, agg: Aggregator, labels: HashMap<String, String>) {
+    loop {
+        let (stream, from) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!(error = %e, "failed to accept a StatsD connection");
+                continue;
+            }
+        };
+
+        let (mut agg, labels) = (agg.clone(), labels.clone());
+        tokio::spawn(async move {
+            let labels: HashMap<&str, &str> = labels.iter().map(|(name, value)| (name.as_str(), value.as_str())).collect();
+            let mut lines = BufReader::new(stream).lines();
+            loop {
+                let line = match lines.next_line().await {
+                    Ok(Some(line)) => line,
+                    Ok(None) => return,
+                    Err(e) => {
+                        warn!(%from, error = %e, "failed to read from a StatsD connection");
+                        return;
+                    }
+                };
+
+                if let Err(e) = merge_packet(&mut agg, line.as_bytes(), &labels).await {
+                    warn!(%from, error = %e, "rejected StatsD line");
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,72 @@
+use std::collections::HashMap;
+
+use tokio::{io::AsyncWriteExt, net::{TcpListener, TcpStream, UdpSocket}};
+
+use crate::{aggregator::Aggregator, statsd::{decode_statsd, merge_packet, serve_tcp, serve_udp}};
+
+#[tokio::test]
+async fn test_statsd_counters_and_gauges() {
+    let mut agg = Aggregator::new();
+    let labels = HashMap::from([("job", "statsd")]);
+    merge_packet(&mut agg, b"api.requests:1|c\napi.requests:2|c|@0.5\nqueue-depth:10|g\nqueue-depth:-3|g", &labels).await.unwrap();
+    let output = agg.to_string().await;
+    assert!(output.contains("# TYPE api_requests counter\napi_requests{job=\"statsd\"} 5\n"));
+    assert!(output.contains("# TYPE queue_depth gauge\nqueue_depth{job=\"statsd\"} 7\n"));
+
+    // Counters keep adding up across packets, and gauges are set unless they have a sign
+    merge_packet(&mut agg, b"api.requests:1|c|@0.1\nqueue-depth:+2|g", &labels).await.unwrap();
+    let output = agg.to_string().await;
+    assert!(output.contains("api_requests{job=\"statsd\"} 15\n"));
+    assert!(output.contains("queue_depth{job=\"statsd\"} 9\n"));
+
+    merge_packet(&mut agg, b"queue-depth:4|g", &labels).await.unwrap();
+    assert!(agg.to_string().await.contains("queue_depth{job=\"statsd\"} 4\n"));
+}
+
+#[tokio::test]
+async fn test_statsd_timers() {
+    let mut agg = Aggregator::new();
+    merge_packet(&mut agg, b"db.query:20|ms\ndb.query:200|ms\ndb.query:20000|ms", &HashMap::new()).await.unwrap();
+    let output = agg.to_string().await;
+    assert!(output.contains("# TYPE db_query_seconds histogram\n"));
+    assert!(output.contains("db_query_seconds_bucket{le=\"0.01\"} 0\n"));
+    assert!(output.contains("db_query_seconds_bucket{le=\"0.025\"} 1\n"));
+    assert!(output.contains("db_query_seconds_bucket{le=\"0.25\"} 2\n"));
+    assert!(output.contains("db_query_seconds_bucket{le=\"10\"} 2\n"));
+    assert!(output.contains("db_query_seconds_bucket{le=\"+Inf\"} 3\n"));
+    assert!(output.contains("db_query_seconds_sum 20.22\n"));
+    assert!(output.contains("db_query_seconds_count 3\n"));
+}
+
+#[test]
+fn test_invalid_statsd() {
+    for packet in ["requests", "requests:1", "requests:one|c", "requests:1|c|@2", "requests:1|c|#env:prod", "users:bob|s", "requests:1|c\nrequests:1|g"] {
+        assert!(decode_statsd(packet).is_err(), "{} should be invalid", packet);
+    }
+}
+
+#[tokio::test]
+async fn test_statsd_listeners() {
+    let agg = Aggregator::new();
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let udp_address = socket.local_addr().unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let tcp_address = listener.local_addr().unwrap();
+    tokio::spawn(serve_udp(socket, agg.clone(), HashMap::new()));
+    tokio::spawn(serve_tcp(listener, agg.clone(), HashMap::new()));
+
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    client.send_to(b"udp.hits:3|c", udp_address).await.unwrap();
+    let mut stream = TcpStream::connect(tcp_address).await.unwrap();
+    stream.write_all(b"tcp.hits:1|c\ntcp.hits:1|c\n").await.unwrap();
+    stream.shutdown().await.unwrap();
+
+    let expected = "# TYPE tcp_hits counter\ntcp_hits 2\n# TYPE udp_hits counter\nudp_hits 3\n";
+    for _ in 0..100 {
+        if agg.to_string().await == expected {
+            return;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    assert_eq!(agg.to_string().await, expected);
+}
@@ -0,0 +1,80 @@
+use std::{collections::HashMap, sync::{Arc, RwLock}};
+
+use crate::aggregator::Aggregator;
+
+/// The longest tenant ID that's accepted
+const MAX_TENANT_ID_LENGTH: usize = 128;
+
+/// Checks that a tenant ID (from a /tenants/<id>/metrics path) is something we'd be happy to pass on to peers in a URL
+pub fn check_tenant_id(id: &str) -> Result<(), String> {
+    if id.is_empty() || id.len() > MAX_TENANT_ID_LENGTH {
+        return Err(format!("Invalid tenant ID: must be between 1 and {} characters", MAX_TENANT_ID_LENGTH));
+    }
+
+    if !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.') {
+        return Err(format!("Invalid tenant ID {}: can only contain letters, digits, `-`, `_`, and `.`", id));
+    }
+
+    Ok(())
+}
+
+/// The metric stores that pushes go to. Pushes to /metrics go to the default store, and pushes to /tenants/<id>/metrics
+/// go to that tenant's own, so that different tenants' series never collide. A tenant's store is created on its first push
+#[derive(Clone)]
+pub struct Tenants {
+    default: Aggregator,
+    tenants: Arc<RwLock<HashMap<String, Aggregator>>>,
+    /// Stands in for the tenants that haven't been pushed to, so that reading them doesn't create them
+    empty: Aggregator,
+}
+
+impl Tenants {
+    /// Sets up the stores, with new tenants' stores being made like the default one
+    pub fn new(default: Aggregator) -> Tenants {
+        Tenants {
+            empty: default.new_tenant(),
+            default,
+            tenants: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    /// The store to push to, which for a tenant that hasn't been pushed to before is a new one
+    pub fn get_or_create(&self, tenant: Option<&str>) -> Aggregator {
+        let tenant = match tenant {
+            Some(tenant) => tenant,
+            None => return self.default.clone(),
+        };
+
+        if let Some(agg) = self.tenants.read().unwrap().get(tenant) {
+            return agg.clone();
+        }
+
+        // Someone else may have created it while we didn't have the lock
+        self.tenants.write().unwrap().entry(tenant.to_owned()).or_insert_with(|| self.default.new_tenant()).clone()
+    }
+
+    /// The store to read from (or delete from). Tenants that haven't been pushed to get an empty one
+    pub fn get(&self, tenant: Option<&str>) -> Aggregator {
+        let tenant = match tenant {
+            Some(tenant) => tenant,
+            None => return self.default.clone(),
+        };
+
+        self.tenants.read().unwrap().get(tenant).unwrap_or(&self.empty).clone()
+    }
+
+    /// Every store that's been pushed to, along with its tenant (which is None for the default store)
+    #[cfg(feature="clustering")]
+    pub fn all(&self) -> Vec<(Option<String>, Aggregator)> {
+        let tenants = self.tenants.read().unwrap();
+        let mut stores: Vec<(Option<String>, Aggregator)> = tenants.iter().map(|(tenant, agg)| (Some(tenant.clone()), agg.clone())).collect();
+        stores.sort_by(|a, b| a.0.cmp(&b.0));
+        stores.insert(0, (None, self.default.clone()));
+        stores
+    }
+
+    /// The default store, whose gateway metrics are shared by every tenant's
+    pub fn default_store(&self) -> &Aggregator {
+        &self.default
+    }
+}